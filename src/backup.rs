@@ -0,0 +1,469 @@
+use crate::checksum::Checksum;
+use crate::codecs::limits::{ExtractLimits, LimitTracker, RatioBase};
+use crate::codecs::safe_path::{PathGuard, PathPolicy};
+use crate::codecs::{CodecFactory, EntryLink, EntryMeta, Format};
+use crate::{Result, ZipError};
+use chrono::{Local, SecondsFormat};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use walkdir::WalkDir;
+
+/// Name of the entry holding each archive's [`BackupManifest`]
+pub const MANIFEST_ENTRY: &str = ".cazip-backup.json";
+
+/// What one path looked like at the last backup
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FileState {
+    pub kind: FileKind,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    /// Unix permission bits; state files from before they were recorded have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// SHA-256 of the contents, for files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    File,
+    Directory,
+    Symlink(String),
+}
+
+/// Contents of the state file
+#[derive(Serialize, Deserialize, Default)]
+pub struct BackupState {
+    /// Sequence number of the last archive written
+    pub sequence: u32,
+    /// File name of the last archive written
+    pub last: Option<String>,
+    pub entries: BTreeMap<String, FileState>,
+}
+
+impl BackupState {
+    /// Read a state file; a missing one means no backup was taken yet
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(text) => serde_json::from_str(&text).map_err(|e| ZipError::Other(format!("Invalid state file {:?}: {}", path, e))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write the state file through a temp file, so a failed run keeps the old one
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = serde_json::to_string_pretty(self).map_err(|e| ZipError::Other(e.to_string()))?;
+        let staging = path.with_extension("tmp");
+        fs::write(&staging, text)?;
+        fs::rename(&staging, path)?;
+        Ok(())
+    }
+}
+
+/// Stored in every backup archive under [`MANIFEST_ENTRY`]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BackupManifest {
+    pub sequence: u32,
+    /// The archive this one applies on top of; `None` for a full backup
+    pub parent: Option<String>,
+    pub created: String,
+    /// Paths removed from the source since the parent
+    pub deleted: Vec<String>,
+}
+
+pub struct BackupOptions {
+    /// Archive names start with this
+    pub name: String,
+    pub format: Format,
+    pub state: PathBuf,
+    /// Write a full backup even when there is a previous one
+    pub full: bool,
+    pub password: Option<String>,
+}
+
+/// What a backup run wrote
+pub struct BackupReport {
+    pub archive: PathBuf,
+    pub full: bool,
+    pub written: usize,
+    pub deleted: usize,
+    pub unchanged: usize,
+}
+
+/// Back up `source` into a new archive in `output`
+pub fn backup(source: &Path, output: &Path, options: &BackupOptions) -> Result<BackupReport> {
+    fs::create_dir_all(output)?;
+    let previous = BackupState::load(&options.state)?;
+    let full = options.full || previous.last.is_none();
+    let current = scan(source, output, &options.state, if full { None } else { Some(&previous) })?;
+
+    let changed: Vec<&String> = current.entries.iter()
+        .filter(|(path, state)| full || previous.entries.get(*path) != Some(*state) && !same_contents(previous.entries.get(*path), state))
+        .map(|(path, _)| path)
+        .collect();
+    let deleted: Vec<String> = if full {
+        Vec::new()
+    } else {
+        previous.entries.keys().filter(|path| !current.entries.contains_key(*path)).cloned().collect()
+    };
+
+    let sequence = previous.sequence + 1;
+    let extension: &str = options.format.into();
+    let archive_name = format!("{}-{:06}-{}.{}", options.name, sequence, if full { "full" } else { "incr" }, extension);
+    let archive = output.join(&archive_name);
+
+    let manifest = BackupManifest {
+        sequence,
+        parent: if full { None } else { previous.last.clone() },
+        created: Local::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        deleted,
+    };
+
+    let mut codec = CodecFactory::new(options.format, None, options.password.clone(), None, false, None).create_codec()?;
+    let result = write_archive(&mut *codec, source, &archive, &manifest, &changed, &current);
+    if result.is_err() {
+        let _ = fs::remove_file(&archive);
+    }
+    result?;
+
+    let report = BackupReport {
+        archive,
+        full,
+        written: changed.len(),
+        deleted: manifest.deleted.len(),
+        unchanged: current.entries.len() - changed.len(),
+    };
+
+    BackupState { sequence, last: Some(archive_name), ..current }.save(&options.state)?;
+    Ok(report)
+}
+
+fn write_archive(
+    codec: &mut dyn crate::codecs::Codec,
+    source: &Path,
+    archive: &Path,
+    manifest: &BackupManifest,
+    changed: &[&String],
+    current: &BackupState,
+) -> Result<()> {
+    let mut writer = codec.entry_writer(archive)?;
+    if !writer.capabilities().directories {
+        return Err(ZipError::UnsupportedOperation(
+            "Backups need a format that holds directories: zip, 7z, tar, xz or zst".to_string()
+        ));
+    }
+
+    let text = serde_json::to_vec_pretty(manifest).map_err(|e| ZipError::Other(e.to_string()))?;
+    let meta = EntryMeta { name: MANIFEST_ENTRY.to_string(), is_dir: false, size: Some(text.len() as u64), modified: None, mode: None, link: None };
+    writer.write_entry(&meta, &mut text.as_slice())?;
+
+    for path in changed {
+        let state = &current.entries[*path];
+        let full_path = source.join(path);
+        info!("Backing up: {}", path);
+
+        let meta = EntryMeta {
+            name: path.to_string(),
+            is_dir: state.kind == FileKind::Directory,
+            size: Some(state.size),
+            modified: state.modified,
+            mode: state.mode,
+            link: match &state.kind {
+                FileKind::Symlink(target) => Some(EntryLink::Symbolic(target.clone())),
+                _ => None,
+            },
+        };
+
+        match state.kind {
+            FileKind::File => writer.write_entry(&meta, &mut File::open(&full_path)?)?,
+            _ => writer.write_entry(&meta, &mut io::empty())?,
+        }
+    }
+
+    writer.finish()
+}
+
+/// Whether a file only had its time touched: same size, mode and hash as before
+fn same_contents(before: Option<&FileState>, after: &FileState) -> bool {
+    before.is_some_and(|before| {
+        before.kind == FileKind::File && before.size == after.size && before.mode == after.mode && before.sha256 == after.sha256
+    })
+}
+
+/// Record every path under `source`, hashing files whose size or time differs from `previous`
+fn scan(source: &Path, output: &Path, state_file: &Path, previous: Option<&BackupState>) -> Result<BackupState> {
+    // The backups themselves may live inside the source
+    let skip: Vec<PathBuf> = [output, state_file].iter().filter_map(|p| fs::canonicalize(p).ok()).collect();
+    let mut state = BackupState::default();
+
+    let walker = WalkDir::new(source).min_depth(1).sort_by_file_name().into_iter()
+        .filter_entry(|entry| fs::canonicalize(entry.path()).map_or(true, |path| !skip.contains(&path)));
+
+    for entry in walker {
+        let entry = entry.map_err(io::Error::from)?;
+        let name = entry.path().strip_prefix(source)?
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let metadata = fs::symlink_metadata(entry.path())?;
+        let modified = metadata.modified().ok();
+        let mode = mode_of(&metadata);
+
+        let file = if metadata.is_symlink() {
+            let target = fs::read_link(entry.path())?.to_string_lossy().into_owned();
+            FileState { kind: FileKind::Symlink(target), size: 0, modified, mode, sha256: None }
+        } else if metadata.is_dir() {
+            FileState { kind: FileKind::Directory, size: 0, modified: None, mode, sha256: None }
+        } else {
+            // Like rsync, a file with the same size and time is taken to be unchanged
+            let known = previous.and_then(|p| p.entries.get(&name))
+                .filter(|old| old.kind == FileKind::File && old.size == metadata.len() && old.modified == modified)
+                .and_then(|old| old.sha256.clone());
+            let sha256 = match known {
+                Some(hash) => hash,
+                None => Checksum::Sha256.digest(&mut File::open(entry.path())?)?,
+            };
+            FileState { kind: FileKind::File, size: metadata.len(), modified, mode, sha256: Some(sha256) }
+        };
+        state.entries.insert(name, file);
+    }
+
+    Ok(state)
+}
+
+#[cfg(unix)]
+fn mode_of(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn mode_of(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Backup archives named `<name>-<sequence>-<full|incr>.<ext>` in `dir`, in sequence order
+pub fn chain_archives(dir: &Path, name: &str) -> Result<Vec<(u32, bool, PathBuf)>> {
+    let mut archives = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let Some(rest) = file_name.strip_prefix(name).and_then(|r| r.strip_prefix('-')) else { continue };
+        let mut parts = rest.splitn(3, ['-', '.']);
+
+        if let (Some(sequence), Some(kind)) = (parts.next().and_then(|s| s.parse().ok()), parts.next())
+            && (kind == "full" || kind == "incr")
+        {
+            archives.push((sequence, kind == "full", path));
+        }
+    }
+
+    archives.sort_by_key(|(sequence, _, _)| *sequence);
+    Ok(archives)
+}
+
+/// Restore the last full backup in `dir` up to `until` and the incrementals after it
+/// onto `target`, within `limits` across the chain. Returns the archives applied.
+pub fn restore(
+    dir: &Path,
+    name: &str,
+    target: &Path,
+    until: Option<u32>,
+    password: Option<String>,
+    limits: ExtractLimits,
+) -> Result<Vec<PathBuf>> {
+    let archives: Vec<_> = chain_archives(dir, name)?
+        .into_iter()
+        .filter(|(sequence, _, _)| until.is_none_or(|until| *sequence <= until))
+        .collect();
+    let start = archives.iter().rposition(|(_, full, _)| *full)
+        .ok_or_else(|| ZipError::Other(format!("No full backup named {:?} found in {:?}", name, dir)))?;
+
+    fs::create_dir_all(target)?;
+    let guard = PathGuard::new(target, PathPolicy::Reject)?;
+    let tracker = LimitTracker::new(limits);
+    let applied = tracker.guard(replay(&archives[start..], password, &guard, &tracker))?;
+
+    guard.finish()?;
+    Ok(applied)
+}
+
+fn replay(archives: &[(u32, bool, PathBuf)], password: Option<String>, guard: &PathGuard, tracker: &LimitTracker) -> Result<Vec<PathBuf>> {
+    let mut parent: Option<String> = None;
+    let mut applied = Vec::new();
+
+    for (_, _, archive) in archives {
+        info!("Restoring {:?}", archive);
+        let format = Format::detect(archive).ok_or(ZipError::UnknownFormat)?;
+        let mut codec = CodecFactory::new(format, None, password.clone(), None, false, None).create_codec()?;
+        let mut manifest = None;
+
+        codec.visit_entries(archive, &mut |meta: &EntryMeta, data: &mut dyn Read| {
+            if meta.name == MANIFEST_ENTRY {
+                manifest = Some(serde_json::from_reader::<_, BackupManifest>(data).map_err(|e| ZipError::Other(e.to_string()))?);
+                return Ok(());
+            }
+            match guard.resolve(&meta.name) {
+                Some(path) => restore_entry(&path, meta, data, guard, tracker),
+                None => io::copy(data, &mut io::sink()).map(|_| ()).map_err(ZipError::from),
+            }
+        })?;
+
+        let manifest = manifest.ok_or_else(|| ZipError::Other(format!("{:?} is not a backup archive", archive)))?;
+        if manifest.parent != parent {
+            return Err(ZipError::Other(format!(
+                "{:?} follows {:?}, not {:?}; the backup chain is broken", archive, manifest.parent, parent
+            )));
+        }
+
+        // Children sort after their directory, so they go first
+        for path in manifest.deleted.iter().rev() {
+            let Some(path) = guard.resolve(path) else { continue };
+            remove_existing(&path)?;
+        }
+
+        parent = archive.file_name().map(|n| n.to_string_lossy().into_owned());
+        applied.push(archive.clone());
+    }
+
+    Ok(applied)
+}
+
+/// Write one entry to `path`, replacing whatever is there, skipping symlinks that
+/// leave the guard's root and copying data through `tracker`'s limits
+pub fn restore_entry(path: &Path, meta: &EntryMeta, data: &mut dyn Read, guard: &PathGuard, tracker: &LimitTracker) -> Result<()> {
+    tracker.begin_entry(&meta.name)?;
+
+    if meta.is_dir {
+        if !path.is_dir() {
+            remove_existing(path)?;
+        }
+        tracker.create_dir_all(path)?;
+        return Ok(());
+    }
+
+    remove_existing(path)?;
+    if let Some(parent) = path.parent() {
+        tracker.create_dir_all(parent)?;
+    }
+
+    match &meta.link {
+        Some(EntryLink::Symbolic(target)) => {
+            if !guard.allow_link(&meta.name, path, Path::new(target)) {
+                return Ok(());
+            }
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(target, path)?;
+            #[cfg(not(unix))]
+            warn!("Cannot restore symlink {:?} -> {} on this platform", path, target);
+        }
+        Some(EntryLink::Hard(_)) => warn!("Skipped hard link {:?}; backups do not write them", path),
+        None => {
+            let mut file = tracker.create_file(path)?;
+            tracker.copy(&meta.name, data, &mut file, RatioBase::Unknown)?;
+            if let Some(modified) = meta.modified {
+                file.set_modified(modified)?;
+            }
+            #[cfg(unix)]
+            if let Some(mode) = meta.mode {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn remove_existing(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path)?,
+        Ok(_) => fs::remove_file(path)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restoring_a_chain_replays_changes_and_deletions() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, output, target) = (dir.path().join("src"), dir.path().join("out"), dir.path().join("restored"));
+        fs::create_dir_all(source.join("d")).unwrap();
+        fs::write(source.join("a.txt"), "one").unwrap();
+        fs::write(source.join("d/b.txt"), "two").unwrap();
+
+        let options = BackupOptions {
+            name: "nightly".to_string(),
+            format: Format::Zip,
+            state: output.join("state.json"),
+            full: false,
+            password: None,
+        };
+        assert!(backup(&source, &output, &options).unwrap().full);
+
+        fs::write(source.join("a.txt"), "changed").unwrap();
+        fs::remove_dir_all(source.join("d")).unwrap();
+        let report = backup(&source, &output, &options).unwrap();
+        assert_eq!((report.full, report.written, report.deleted), (false, 1, 2));
+
+        assert_eq!(restore(&output, "nightly", &target, None, None, ExtractLimits::default()).unwrap().len(), 2);
+        assert_eq!(fs::read_to_string(target.join("a.txt")).unwrap(), "changed");
+        assert!(!target.join("d").exists());
+
+        let names: Vec<_> = chain_archives(&output, "nightly").unwrap().into_iter().map(|(seq, full, _)| (seq, full)).collect();
+        assert_eq!(names, [(1, true), (2, false)]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn mode_changes_are_backed_up_and_restore_keeps_its_guards() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let (source, output) = (dir.path().join("src"), dir.path().join("out"));
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("run.sh"), "echo hi").unwrap();
+        fs::set_permissions(source.join("run.sh"), fs::Permissions::from_mode(0o644)).unwrap();
+
+        let options = BackupOptions {
+            name: "b".to_string(),
+            format: Format::Tar,
+            state: output.join("state.json"),
+            full: false,
+            password: None,
+        };
+        backup(&source, &output, &options).unwrap();
+
+        fs::set_permissions(source.join("run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(backup(&source, &output, &options).unwrap().written, 1);
+
+        let restored = dir.path().join("restored");
+        restore(&output, "b", &restored, None, None, ExtractLimits::default()).unwrap();
+        assert_eq!(fs::metadata(restored.join("run.sh")).unwrap().permissions().mode() & 0o777, 0o755);
+
+        let small = ExtractLimits { max_total_size: Some(3), ..ExtractLimits::default() };
+        let limited = dir.path().join("limited");
+        assert!(matches!(restore(&output, "b", &limited, None, None, small), Err(ZipError::LimitExceeded(_))));
+        assert!(!limited.join("run.sh").exists());
+
+        symlink("../../../etc", source.join("escape")).unwrap();
+        backup(&source, &output, &options).unwrap();
+        let escaped = dir.path().join("escaped");
+        assert!(matches!(restore(&output, "b", &escaped, None, None, ExtractLimits::default()), Err(ZipError::UnsafePath(_))));
+        assert!(escaped.join("escape").symlink_metadata().is_err());
+    }
+}
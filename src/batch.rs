@@ -0,0 +1,235 @@
+use crate::{Result, ZipError};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Instant;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobCommand {
+    Compress,
+    Extract,
+    Convert,
+    Test,
+}
+
+impl JobCommand {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Compress => "compress",
+            Self::Extract => "extract",
+            Self::Convert => "convert",
+            Self::Test => "test",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Job {
+    /// Name in the report; defaults to the job's position
+    pub name: Option<String>,
+    pub command: JobCommand,
+    /// Arguments after the command name, as on the command line
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct JobFile {
+    pub jobs: Vec<Job>,
+}
+
+impl JobFile {
+    /// Read a job list from TOML when the file ends in `.toml`, JSON otherwise
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let is_toml = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("toml"));
+
+        let file: Self = if is_toml {
+            toml::from_str(&text).map_err(|e| ZipError::Other(format!("Invalid job file {:?}: {}", path, e)))?
+        } else {
+            serde_json::from_str(&text).map_err(|e| ZipError::Other(format!("Invalid job file {:?}: {}", path, e)))?
+        };
+
+        if file.jobs.is_empty() {
+            return Err(ZipError::Other(format!("{:?} lists no jobs", path)));
+        }
+        Ok(file)
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Ok,
+    Failed,
+    /// Not started because an earlier job failed
+    Skipped,
+}
+
+#[derive(Serialize, Debug)]
+pub struct JobResult {
+    pub name: String,
+    pub command: JobCommand,
+    pub status: JobStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_ms: u128,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BatchReport {
+    pub succeeded: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    /// One result per job, in the order of the job file
+    pub jobs: Vec<JobResult>,
+}
+
+/// Run the jobs with `run`, `parallel` at a time (0 for one per CPU), each on a thread of
+/// its own so it keeps the whole rayon pool. Unless `keep_going`, a failure skips the rest.
+pub fn run(jobs: &[Job], parallel: usize, keep_going: bool, run: &(dyn Fn(&Job) -> Result<()> + Sync)) -> BatchReport {
+    let parallel = match parallel {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+
+    // Each worker takes the next job in file order until none are left
+    let worker = || {
+        let mut done = Vec::new();
+        loop {
+            let index = next.fetch_add(1, Ordering::SeqCst);
+            let Some(job) = jobs.get(index) else { return done };
+            done.push((index, run_job(index, job, keep_going, &failed, run)));
+        }
+    };
+
+    let mut results: Vec<(usize, JobResult)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..parallel.min(jobs.len())).map(|_| scope.spawn(worker)).collect();
+        workers.into_iter().flat_map(|worker| worker.join().expect("batch job panicked")).collect()
+    });
+    results.sort_by_key(|(index, _)| *index);
+    let results: Vec<JobResult> = results.into_iter().map(|(_, result)| result).collect();
+
+    let count = |status| results.iter().filter(|r| r.status == status).count();
+    BatchReport {
+        succeeded: count(JobStatus::Ok),
+        failed: count(JobStatus::Failed),
+        skipped: count(JobStatus::Skipped),
+        jobs: results,
+    }
+}
+
+/// Run one job unless an earlier failure means it should be skipped
+fn run_job(index: usize, job: &Job, keep_going: bool, failed: &AtomicBool, run: &(dyn Fn(&Job) -> Result<()> + Sync)) -> JobResult {
+    let name = job.name.clone().unwrap_or_else(|| format!("job-{}", index + 1));
+    let mut result = JobResult { name, command: job.command, status: JobStatus::Skipped, error: None, duration_ms: 0 };
+    if !keep_going && failed.load(Ordering::SeqCst) {
+        return result;
+    }
+
+    info!("Starting {} ({})", result.name, job.command.as_str());
+    let start = Instant::now();
+    // A panicking job fails like any other instead of taking the batch down
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| run(job))).unwrap_or_else(|payload| {
+        let message = payload.downcast_ref::<&str>().copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown cause");
+        Err(ZipError::Other(format!("job panicked: {}", message)))
+    });
+    result.duration_ms = start.elapsed().as_millis();
+
+    match outcome {
+        Ok(()) => {
+            info!("{} finished in {} ms", result.name, result.duration_ms);
+            result.status = JobStatus::Ok;
+        }
+        Err(e) => {
+            error!("{} failed: {}", result.name, e);
+            failed.store(true, Ordering::SeqCst);
+            result.status = JobStatus::Failed;
+            result.error = Some(e.to_string());
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stops_after_a_failure_unless_asked_to_keep_going() {
+        let toml_file: JobFile = toml::from_str(r#"
+            [[jobs]]
+            command = "compress"
+            args = ["a.zip", "a"]
+
+            [[jobs]]
+            name = "broken"
+            command = "test"
+            args = ["missing.zip"]
+
+            [[jobs]]
+            command = "extract"
+            args = ["out", "a.zip"]
+        "#).unwrap();
+        let json_file: JobFile = serde_json::from_str(r#"{"jobs": [
+            {"command": "compress", "args": ["a.zip", "a"]},
+            {"name": "broken", "command": "test", "args": ["missing.zip"]},
+            {"command": "extract", "args": ["out", "a.zip"]}
+        ]}"#).unwrap();
+        assert_eq!(format!("{:?}", toml_file), format!("{:?}", json_file));
+
+        let fail_tests = |job: &Job| match job.command {
+            JobCommand::Test => Err(ZipError::Other("corrupt".to_string())),
+            _ => Ok(()),
+        };
+        let report = run(&toml_file.jobs, 1, false, &fail_tests);
+        let statuses: Vec<_> = report.jobs.iter().map(|r| (r.name.as_str(), r.status)).collect();
+        assert_eq!(statuses, [("job-1", JobStatus::Ok), ("broken", JobStatus::Failed), ("job-3", JobStatus::Skipped)]);
+        assert_eq!(report.jobs[1].error.as_deref(), Some("Other error: corrupt"));
+
+        let report = run(&toml_file.jobs, 1, true, &fail_tests);
+        assert_eq!((report.succeeded, report.failed, report.skipped), (2, 1, 0));
+
+        let panic_tests = |job: &Job| match job.command {
+            JobCommand::Test => panic!("corrupt"),
+            _ => Ok(()),
+        };
+        let report = run(&toml_file.jobs, 2, true, &panic_tests);
+        assert_eq!((report.succeeded, report.failed, report.skipped), (2, 1, 0));
+        assert_eq!(report.jobs[1].error.as_deref(), Some("Other error: job panicked: corrupt"));
+    }
+
+    #[test]
+    fn jobs_run_side_by_side_outside_any_rayon_pool() {
+        let file: JobFile = serde_json::from_str(r#"{"jobs": [
+            {"command": "test", "args": ["a.zip"]},
+            {"command": "test", "args": ["b.zip"]}
+        ]}"#).unwrap();
+
+        let running = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+        let job = |_: &Job| {
+            assert!(rayon::current_thread_index().is_none());
+            most.fetch_max(running.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+            thread::sleep(std::time::Duration::from_millis(50));
+            running.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        };
+
+        assert_eq!(run(&file.jobs, 2, false, &job).succeeded, 2);
+        assert_eq!(most.swap(0, Ordering::SeqCst), 2);
+        assert_eq!(run(&file.jobs, 1, false, &job).succeeded, 2);
+        assert_eq!(most.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,270 @@
+use crate::codecs::{Codec, EntryMeta};
+use crate::utils::to_hex;
+use crate::{Result, ZipError};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use walkdir::WalkDir;
+
+/// Hash algorithm used for a manifest
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Checksum {
+    Sha256,
+    Blake3,
+    Crc32,
+}
+
+impl FromStr for Checksum {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().replace('-', "").as_str() {
+            "sha256" => Ok(Self::Sha256),
+            "blake3" | "b3" => Ok(Self::Blake3),
+            "crc32" => Ok(Self::Crc32),
+            _ => Err(format!("unknown checksum: {} (expected sha256, blake3 or crc32)", s)),
+        }
+    }
+}
+
+impl Checksum {
+    /// Conventional manifest name, used for embedded manifests
+    pub fn manifest_name(&self) -> &'static str {
+        match self {
+            Self::Sha256 => "SHA256SUMS",
+            Self::Blake3 => "B3SUMS",
+            Self::Crc32 => "CRC32SUMS",
+        }
+    }
+
+    /// Extension of a sidecar manifest next to the archive
+    pub fn sidecar_extension(&self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Blake3 => "b3",
+            Self::Crc32 => "crc32",
+        }
+    }
+
+    /// Sidecar manifest path for `archive`
+    pub fn sidecar_path(&self, archive: &Path) -> PathBuf {
+        let mut name = archive.as_os_str().to_os_string();
+        name.push(".");
+        name.push(self.sidecar_extension());
+        PathBuf::from(name)
+    }
+
+    pub const ALL: [Self; 3] = [Self::Sha256, Self::Blake3, Self::Crc32];
+
+    /// Guess the algorithm from a manifest file name such as `SHA256SUMS` or `a.zip.b3`
+    pub fn from_manifest_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy().to_lowercase();
+        Self::ALL.into_iter().find(|algorithm| {
+            name.starts_with(&algorithm.manifest_name().to_lowercase())
+                || name.ends_with(&format!(".{}", algorithm.sidecar_extension()))
+        })
+    }
+
+    /// Hex digest of everything `reader` yields
+    pub fn digest<R: Read + ?Sized>(&self, reader: &mut R) -> io::Result<String> {
+        let mut hasher = Hasher::new(*self);
+        io::copy(reader, &mut hasher)?;
+        Ok(hasher.finish())
+    }
+}
+
+/// Running hash that can sit behind `io::copy`
+enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+    Crc32(crc32fast::Hasher),
+}
+
+impl Hasher {
+    fn new(algorithm: Checksum) -> Self {
+        match algorithm {
+            Checksum::Sha256 => Self::Sha256(Sha256::new()),
+            Checksum::Blake3 => Self::Blake3(Box::default()),
+            Checksum::Crc32 => Self::Crc32(crc32fast::Hasher::new()),
+        }
+    }
+
+    fn finish(self) -> String {
+        match self {
+            Self::Sha256(hasher) => to_hex(&hasher.finalize()),
+            Self::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+            Self::Crc32(hasher) => format!("{:08x}", hasher.finalize()),
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Sha256(hasher) => Digest::update(hasher, buf),
+            Self::Blake3(hasher) => {
+                hasher.update(buf);
+            }
+            Self::Crc32(hasher) => hasher.update(buf),
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Digests keyed by `/`-separated relative path, written as `sha256sum -c` reads them
+#[derive(Debug, Default, PartialEq)]
+pub struct Manifest {
+    pub entries: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    /// Hash every file entry of an archive, skipping an embedded manifest named `skip`;
+    /// `compress --checksum` reads the finished archive back so digests match what was stored
+    pub fn of_archive(codec: &mut dyn Codec, archive: &Path, algorithm: Checksum, skip: Option<&str>) -> Result<Self> {
+        let mut manifest = Self::default();
+
+        codec.visit_entries(archive, &mut |meta: &EntryMeta, reader: &mut dyn Read| {
+            if meta.is_dir || Some(meta.name.as_str()) == skip {
+                return Ok(());
+            }
+            manifest.entries.insert(meta.name.clone(), algorithm.digest(reader)?);
+            Ok(())
+        })?;
+
+        Ok(manifest)
+    }
+
+    /// Read a manifest embedded in an archive under one of the conventional names
+    pub fn embedded(codec: &mut dyn Codec, archive: &Path) -> Result<Option<(Checksum, Self)>> {
+        let mut found = None;
+
+        codec.visit_entries(archive, &mut |meta: &EntryMeta, reader: &mut dyn Read| {
+            let algorithm = Checksum::ALL.into_iter().find(|algorithm| algorithm.manifest_name() == meta.name);
+
+            if let (Some(algorithm), None) = (algorithm, &found) {
+                let mut text = String::new();
+                reader.read_to_string(&mut text)?;
+                found = Some((algorithm, text));
+            }
+            Ok(())
+        })?;
+
+        found.map(|(algorithm, text)| Ok((algorithm, Self::parse(&text)?))).transpose()
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut manifest = Self::default();
+
+        for (number, line) in text.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // `sha256sum -b` marks binary mode with `*` in place of the second space
+            let (digest, path) = line.split_once("  ")
+                .or_else(|| line.split_once(" *"))
+                .ok_or_else(|| ZipError::Other(format!("Malformed manifest line {}: {}", number + 1, line)))?;
+            manifest.entries.insert(path.to_string(), digest.to_lowercase());
+        }
+
+        Ok(manifest)
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| ZipError::Other(format!("Cannot read manifest {:?}: {}", path, e)))?;
+        Self::parse(&text)
+    }
+
+    pub fn to_text(&self) -> String {
+        self.entries.iter().map(|(path, digest)| format!("{}  {}\n", digest, path)).collect()
+    }
+
+    /// Compare files under `dir` against the manifest, ignoring the file `skip` (the manifest itself)
+    pub fn check_dir(&self, dir: &Path, algorithm: Checksum, skip: Option<&str>) -> Result<Vec<(String, CheckStatus)>> {
+        let mut actual = BTreeMap::new();
+
+        for path in self.entries.keys() {
+            let file = dir.join(path);
+            if file.is_file() {
+                actual.insert(path.clone(), algorithm.digest(&mut File::open(&file)?)?);
+            }
+        }
+
+        // Files the manifest does not know about are reported as well
+        for entry in WalkDir::new(dir).into_iter().filter_map(|e| e.ok()).filter(|e| e.file_type().is_file()) {
+            let relative = entry.path().strip_prefix(dir)?;
+            let name = relative.components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            if Some(name.as_str()) != skip && !self.entries.contains_key(&name) && !actual.contains_key(&name) {
+                actual.insert(name, String::new());
+            }
+        }
+
+        Ok(self.compare(&Manifest { entries: actual }))
+    }
+
+    /// Compare another manifest (the actual contents) against this one
+    pub fn compare(&self, actual: &Manifest) -> Vec<(String, CheckStatus)> {
+        let mut results: Vec<_> = self.entries.iter().map(|(path, expected)| {
+            let status = match actual.entries.get(path) {
+                None => CheckStatus::Missing,
+                Some(digest) if digest == expected => CheckStatus::Ok,
+                Some(_) => CheckStatus::Mismatch,
+            };
+            (path.clone(), status)
+        }).collect();
+
+        results.extend(actual.entries.keys()
+            .filter(|path| !self.entries.contains_key(*path))
+            .map(|path| (path.clone(), CheckStatus::Unlisted)));
+
+        results
+    }
+}
+
+/// Outcome of checking one path
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CheckStatus {
+    Ok,
+    Mismatch,
+    Missing,
+    /// Present but not in the manifest
+    Unlisted,
+}
+
+impl CheckStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ok => "OK",
+            Self::Mismatch => "FAILED",
+            Self::Missing => "MISSING",
+            Self::Unlisted => "NOT IN MANIFEST",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_text_round_trip() {
+        let mut manifest = Manifest::default();
+        manifest.entries.insert("dir/a b.txt".to_string(), Checksum::Crc32.digest(&mut &b"hello"[..]).unwrap());
+        manifest.entries.insert("z".to_string(), Checksum::Sha256.digest(&mut &b""[..]).unwrap());
+
+        assert_eq!(manifest.entries["dir/a b.txt"], "3610a686");
+        assert_eq!(Manifest::parse(&manifest.to_text()).unwrap(), manifest);
+        assert_eq!(Checksum::from_manifest_path(Path::new("out/a.zip.b3")), Some(Checksum::Blake3));
+        assert_eq!(Checksum::from_manifest_path(Path::new("SHA256SUMS")), Some(Checksum::Sha256));
+    }
+}
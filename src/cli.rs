@@ -1,6 +1,25 @@
-pub(crate) use crate::{codecs, codecs::Format, Result};
-use clap::{Parser, Subcommand};
-use log::{debug, info};
+pub(crate) use crate::{codecs, codecs::Format, utils, Result};
+use crate::backup::{self, BackupOptions};
+use crate::batch::{self, Job, JobFile};
+use crate::checksum::{CheckStatus, Checksum, Manifest};
+use crate::codecs::safe_path::PathPolicy;
+use crate::codecs::limits::ExtractLimits;
+use crate::codecs::zip::{ZipCodec, ZipEncryption};
+use crate::diff::{Diff, DiffOptions, Tree};
+use crate::envelope::{self, Identity, Recipient};
+use crate::grep::{self, GrepOptions};
+use crate::parity::{self, ParityOptions};
+use crate::password::PasswordSource;
+use crate::recursive::{Placement, RecursiveExtractor, Snapshot};
+use crate::rotate::{self, RetentionPolicy};
+use crate::signature::{self, SignatureFile, SignerKey, SigningIdentity};
+use crate::store::{ChunkCompression, Store};
+use crate::watch::{self, PathFilter, WatchOptions};
+use clap::{Args, Parser, Subcommand};
+use log::{debug, error, info, warn};
+use rayon::prelude::*;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 use crate::script::ScriptRunner;
@@ -23,13 +42,13 @@ pub enum Commands {
     /// 压缩文件
     #[command(alias = "c")]
     Compress {
-        /// 压缩后的文件路径
+        /// 压缩后的文件路径，`-` 表示写到标准输出
         target: PathBuf,
 
-        /// 要压缩的源文件路径
+        /// 要压缩的源文件路径，`-` 表示标准输入
         source: Vec<PathBuf>,
 
-        /// 压缩格式: zip, gz, 7z, xz
+        /// 压缩格式: zip, gz, 7z, xz, tar, zst
         #[arg(short, long)]
         format: Option<Format>,
 
@@ -37,9 +56,15 @@ pub enum Commands {
         #[arg(short, long)]
         method: Option<String>,
 
-        /// 加密密码
-        #[arg(short, long)]
-        password: Option<String>,
+        #[command(flatten)]
+        password: PasswordArgs,
+
+        /// zip 加密方式: aes128, aes192, aes256（默认）, zipcrypto（不安全，仅用于兼容旧工具）
+        #[arg(long)]
+        encryption: Option<ZipEncryption>,
+
+        #[command(flatten)]
+        recipients: RecipientArgs,
 
         /// 使用命令行工具而不是Rust后端
         #[arg(short = 'e', long)]
@@ -52,24 +77,39 @@ pub enum Commands {
         /// 压缩等级（不同格式支持不同范围）
         #[arg(short, long)]
         level: Option<u8>,
+
+        /// 为每个文件生成校验清单: sha256, blake3, crc32；默认写到压缩包旁的 <压缩包>.sha256 等文件。
+        /// 清单在压缩完成后回读压缩包计算，因此会多读一遍压缩包
+        #[arg(long, value_name = "ALGORITHM")]
+        checksum: Option<Checksum>,
+
+        /// 把校验清单作为 SHA256SUMS 等条目写入压缩包内（仅 zip）
+        #[arg(long, requires = "checksum")]
+        checksum_embed: bool,
+
+        /// 把目标路径当作模板，展开其中的 chrono 日期占位符，如 `backups/site-%Y%m%d-%H%M.zip`
+        #[arg(long)]
+        target_template: bool,
     },
 
     /// 解压文件
     #[command(alias = "e")]
     Extract {
-        /// 解压目标目录
+        /// 解压目标目录，`-` 表示解压到标准输出
         target: PathBuf,
 
-        /// 要解压的源文件
+        /// 要解压的源文件，`-` 表示标准输入
         source: Vec<PathBuf>,
 
-        /// 压缩格式: zip, gz, 7z, xz
+        /// 压缩格式: zip, gz, 7z, xz, tar, zst
         #[arg(short, long)]
         format: Option<Format>,
 
-        /// 加密密码
-        #[arg(short, long)]
-        password: Option<String>,
+        #[command(flatten)]
+        password: PasswordArgs,
+
+        #[command(flatten)]
+        identities: IdentityArgs,
 
         /// 使用命令行工具而不是Rust后端
         #[arg(short = 'e', long)]
@@ -78,6 +118,28 @@ pub enum Commands {
         /// 从压缩包中提取指定文件
         #[arg(long, value_delimiter = ',', requires = "use_external")]
         files: Option<Vec<String>>,
+
+        /// 多个压缩包时并行解压
+        #[arg(long)]
+        parallel: bool,
+
+        /// 每个压缩包解压到目标目录下以其名称命名的子目录
+        #[arg(long)]
+        separate: bool,
+
+        #[command(flatten)]
+        limits: LimitArgs,
+
+        #[command(flatten)]
+        recursive: RecursiveArgs,
+
+        /// 含 `..`、绝对路径或越界符号链接的条目的处理方式: reject（拒绝并报错）, sanitize（改写到目标目录内）
+        #[arg(long, default_value = "reject")]
+        unsafe_paths: PathPolicy,
+
+        /// 抢救模式：zip 按本地文件头重建中央目录；gz/xz 跳过损坏的成员或数据块继续解压。解出所有完好的条目并报告丢失的部分（zip、gz、xz）
+        #[arg(long)]
+        salvage: bool,
     },
 
     /// 执行脚本处理文件
@@ -105,215 +167,1789 @@ pub enum Commands {
         /// 压缩包文件路径
         source: PathBuf,
 
-        /// 压缩格式: zip, gz, 7z, xz
+        /// 压缩格式: zip, gz, 7z, xz, tar, zst
         #[arg(short, long)]
         format: Option<Format>,
+
+        #[command(flatten)]
+        identities: IdentityArgs,
     },
-}
 
-impl Cli {
-    /// 从文件扩展名识别格式
-    fn identify_format(format_opt: &Option<Format>, path: &Path, is_extract: bool) -> Result<Format> {
-        if let Some(format) = format_opt {
-            return Ok(*format);
-        }
+    /// 测试压缩包能否完整解压（解压到临时目录后丢弃）
+    #[command(alias = "t")]
+    Test {
+        /// 要测试的压缩包，`-` 表示标准输入
+        source: Vec<PathBuf>,
 
-        if let Some(ext) = path.extension() {
-            let ext_str = ext.to_string_lossy().to_lowercase();
-            Ok(Format::from(ext_str.as_str()))
-        } else {
-            // 如果没有提供扩展名，默认为ZIP
-            Ok(Format::Zip)
-        }
-    }
+        /// 压缩格式: zip, gz, 7z, xz, tar, zst
+        #[arg(short, long)]
+        format: Option<Format>,
 
-    /// 记录调试信息
-    fn log_debug_info(
-        source: &[PathBuf],
-        target: Option<&PathBuf>,
-        is_compress: bool,
+        #[command(flatten)]
+        password: PasswordArgs,
+
+        #[command(flatten)]
+        identities: IdentityArgs,
+
+        /// 使用命令行工具而不是Rust后端
+        #[arg(short = 'e', long)]
+        use_external: bool,
+    },
+
+    /// 生成用于公钥加密的密钥对，私钥写入文件，公钥打印到标准输出
+    Keygen {
+        /// 私钥文件路径（不会覆盖已有文件）
+        output: PathBuf,
+
+        /// 生成 Ed25519 签名密钥，而不是加密密钥
+        #[arg(long)]
+        sign: bool,
+    },
+
+    /// 按校验清单检查解压目录或压缩包内容
+    Check {
+        /// 解压后的目录或压缩包
+        path: PathBuf,
+
+        /// 清单文件；默认依次查找目录中的 SHA256SUMS/B3SUMS/CRC32SUMS、压缩包旁的 .sha256/.b3/.crc32 文件和压缩包内嵌的清单
+        #[arg(short, long)]
+        manifest: Option<PathBuf>,
+
+        /// 清单的哈希算法: sha256, blake3, crc32（默认按清单文件名推断）
+        #[arg(short, long)]
+        algorithm: Option<Checksum>,
+
+        /// 压缩格式: zip, gz, 7z, xz, tar, zst
+        #[arg(short, long)]
         format: Option<Format>,
-        method: Option<&str>,
-        password: Option<&String>
-    ) {
-        let yes = "✓";
-        let no = "✗";
 
-        let source_paths = source.iter()
-            .map(|p| std::path::absolute(p).unwrap_or_else(|_| p.to_path_buf()))
-            .collect::<Vec<_>>();
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
 
-        debug!("Source: {:?}", source_paths);
+    /// 从损坏的 zip 中抢救完好的条目，重建中央目录后写入新压缩包
+    Salvage {
+        /// 损坏的 zip 文件
+        source: PathBuf,
 
-        if let Some(target_path) = target {
-            if let Ok(abs_target) = std::path::absolute(target_path) {
-                debug!("Target: {:?}", abs_target);
-            } else {
-                debug!("Target: {:?}", target_path);
-            }
-        } else {
-            debug!("Target: None (list mode)");
-        }
+        /// 重建后的 zip 路径，`-` 表示写到标准输出
+        output: PathBuf,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+
+    /// 为压缩包或分卷组生成 Reed–Solomon 恢复数据（旁挂 .par 文件）
+    Parity {
+        /// 要保护的文件，分卷按顺序列出
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+
+        /// 恢复文件路径（默认为 <第一个文件>.par，需与被保护文件放在同一目录）
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// 冗余比例（%），可修复的损坏块数约为数据块数的这一比例
+        #[arg(short, long, default_value_t = 10, value_parser = clap::value_parser!(u8).range(1..=100))]
+        redundancy: u8,
+
+        /// 块大小，可带单位如 64K、1M
+        #[arg(long, default_value = "64K", value_parser = utils::parse_size)]
+        block_size: u64,
+    },
+
+    /// 用恢复数据检查并修复损坏的压缩包或分卷
+    Repair {
+        /// 由 `parity` 生成的 .par 文件
+        parity: PathBuf,
+
+        /// 只检查，不修改文件
+        #[arg(long)]
+        check_only: bool,
+    },
+
+    /// 向已有压缩包添加文件，替换同名条目；其余条目原样复制，不重新压缩
+    Add {
+        #[command(flatten)]
+        args: EditArgs,
+    },
+
+    /// 只把新增或内容有变化的文件写入已有压缩包
+    Update {
+        #[command(flatten)]
+        args: EditArgs,
+    },
+
+    /// 从已有压缩包中删除条目，目录连同其下内容一起删除
+    Delete {
+        /// 要修改的压缩包
+        archive: PathBuf,
+
+        /// 要删除的条目名
+        #[arg(required = true)]
+        names: Vec<String>,
+
+        /// 压缩格式: zip, gz, 7z, xz, tar, zst
+        #[arg(short, long)]
+        format: Option<Format>,
+    },
+
+    /// 把压缩包逐条目流式转换为另一种格式，不落地解压；目标格式存不下的内容会给出警告
+    Convert {
+        /// 要转换的压缩包，`-` 表示标准输入
+        source: PathBuf,
+
+        /// 转换后的压缩包路径，`-` 表示写到标准输出
+        output: PathBuf,
+
+        /// 源格式: zip, gz, 7z, xz, tar, zst（默认按文件头识别）
+        #[arg(long)]
+        from: Option<Format>,
+
+        /// 目标格式: zip, gz, 7z, xz, tar, zst（默认按扩展名识别）
+        #[arg(short, long)]
+        to: Option<Format>,
+
+        /// 目标为 zip 时的压缩算法: deflate, bzip2, zstd
+        #[arg(short, long)]
+        method: Option<String>,
+
+        /// 目标格式的压缩等级
+        #[arg(short, long)]
+        level: Option<u8>,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+
+    /// 把压缩包中指定条目的内容输出到标准输出，不解压
+    Cat {
+        /// 压缩包，`-` 表示标准输入
+        source: PathBuf,
+
+        /// 条目名，按给出的顺序输出
+        #[arg(required = true)]
+        names: Vec<String>,
+
+        /// 压缩格式: zip, gz, 7z, xz, tar, zst
+        #[arg(short, long)]
+        format: Option<Format>,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+
+    /// 在压缩包条目的内容中搜索正则表达式，不解压；输出条目路径、行号和匹配行
+    Grep {
+        /// 正则表达式
+        pattern: String,
+
+        /// 要搜索的压缩包
+        #[arg(required = true)]
+        archives: Vec<PathBuf>,
+
+        /// 忽略大小写
+        #[arg(short, long)]
+        ignore_case: bool,
+
+        /// 只搜索匹配该通配符的条目，可多次指定，如 `*.log`、`var/**/app.log`
+        #[arg(short, long, value_name = "GLOB")]
+        glob: Vec<String>,
+
+        /// 只输出有匹配的条目路径
+        #[arg(short = 'l', long)]
+        files_with_matches: bool,
+
+        /// 不进入压缩包里的压缩包
+        #[arg(long)]
+        no_nested: bool,
+
+        #[command(flatten)]
+        limits: LimitArgs,
+
+        /// 并行搜索的线程数（仅 zip 和 7z 可并行），0 表示按 CPU 核数
+        #[arg(short, long, default_value_t = 0)]
+        jobs: usize,
+
+        /// 压缩格式: zip, gz, 7z, xz, tar, zst
+        #[arg(short, long)]
+        format: Option<Format>,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+
+    /// 比较两个压缩包，或压缩包与目录：列出新增、删除的条目，以及大小、内容、权限和修改时间的变化
+    Diff {
+        /// 旧的压缩包或目录
+        old: PathBuf,
+
+        /// 新的压缩包或目录
+        new: PathBuf,
+
+        /// 以 JSON 输出
+        #[arg(long)]
+        json: bool,
+
+        /// 不比较修改时间
+        #[arg(long)]
+        ignore_mtime: bool,
+
+        /// 不比较权限
+        #[arg(long)]
+        ignore_mode: bool,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+
+    /// 增量备份目录：首次（或 --full）写入完整备份，之后只写入新增和修改的文件以及删除列表
+    Backup {
+        /// 要备份的目录
+        source: PathBuf,
+
+        /// 存放备份压缩包的目录
+        output: PathBuf,
+
+        /// 备份名称，压缩包命名为 `<名称>-<序号>-<full|incr>.<格式>`
+        #[arg(long, default_value = "backup")]
+        name: String,
+
+        /// 压缩格式: zip, 7z, xz, tar, zst（默认 zip）
+        #[arg(short, long)]
+        format: Option<Format>,
+
+        /// 记录上次备份时各文件路径、大小、修改时间和哈希的状态文件（默认 `<备份目录>/<名称>.state.json`）
+        #[arg(long, value_name = "FILE")]
+        state: Option<PathBuf>,
+
+        /// 忽略上次的状态，写入完整备份
+        #[arg(long)]
+        full: bool,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+
+    /// 从备份目录恢复：依次应用最近的完整备份及其后的增量备份
+    Restore {
+        /// 存放备份压缩包的目录
+        backups: PathBuf,
+
+        /// 恢复到的目录
+        target: PathBuf,
+
+        /// 备份名称
+        #[arg(long, default_value = "backup")]
+        name: String,
+
+        /// 只恢复到该序号的备份为止
+        #[arg(long, value_name = "SEQUENCE")]
+        until: Option<u32>,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+
+        #[command(flatten)]
+        limits: LimitArgs,
+    },
+
+    /// 用 Ed25519 私钥为压缩包生成分离签名
+    Sign {
+        /// 要签名的压缩包
+        archive: PathBuf,
+
+        /// 签名私钥文件（由 `keygen --sign` 生成）
+        #[arg(short, long, value_name = "FILE")]
+        key: PathBuf,
+
+        /// 同时签入每个条目的 SHA-256 清单
+        #[arg(long)]
+        manifest: bool,
+
+        /// 签名文件路径（默认为 <压缩包>.sig）
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// 压缩格式: zip, gz, 7z, xz, tar, zst
+        #[arg(short, long)]
+        format: Option<Format>,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+
+    /// 校验压缩包的分离签名
+    VerifySignature {
+        /// 要校验的压缩包
+        archive: PathBuf,
+
+        /// 签名文件路径（默认为 <压缩包>.sig）
+        #[arg(short, long)]
+        signature: Option<PathBuf>,
+
+        /// 信任的签名公钥（ed25519:...）或公钥文件，可重复；至少指定一个
+        #[arg(long, value_name = "KEY", required = true)]
+        trusted_key: Vec<String>,
+
+        /// 压缩格式: zip, gz, 7z, xz, tar, zst
+        #[arg(short, long)]
+        format: Option<Format>,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+    },
+
+    /// 按保留策略清理按日期模板命名的压缩包，保留每天、每周、每月最新的一个
+    #[command(alias = "prune")]
+    Rotate {
+        /// 与 `compress --target-template` 相同的模板，只有文件名部分可含日期占位符
+        template: String,
+
+        /// 保留最近 N 天中每天最新的压缩包
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        daily: usize,
+
+        /// 保留最近 M 周中每周最新的压缩包
+        #[arg(long, value_name = "M", default_value_t = 0)]
+        weekly: usize,
+
+        /// 保留最近 K 个月中每月最新的压缩包
+        #[arg(long, value_name = "K", default_value_t = 0)]
+        monthly: usize,
+
+        /// 只列出会保留和删除的压缩包，不删除
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// 去重快照仓库：文件按内容切块，相同的块只保存一次
+    Store {
+        #[command(subcommand)]
+        command: StoreCommands,
+    },
+
+    /// 监视目录，有变动时重新生成或增量更新压缩包（仅 Linux）
+    Watch {
+        /// 压缩包路径
+        target: PathBuf,
+
+        /// 要监视的目录
+        source: PathBuf,
+
+        /// 压缩格式: zip, gz, 7z, xz, tar, zst
+        #[arg(short, long)]
+        format: Option<Format>,
+
+        /// 压缩算法: deflate, bzip2, zstd
+        #[arg(short, long)]
+        method: Option<String>,
+
+        /// 压缩等级（不同格式支持不同范围）
+        #[arg(short, long)]
+        level: Option<u8>,
+
+        #[command(flatten)]
+        password: PasswordArgs,
+
+        /// 只打包匹配的文件，如 `*.pdf`，可重复
+        #[arg(long, value_name = "GLOB")]
+        include: Vec<String>,
+
+        /// 跳过匹配的文件或目录，如 `*.tmp`、`.git`，可重复
+        #[arg(long, value_name = "GLOB")]
+        exclude: Vec<String>,
+
+        /// 最后一次变动后等待多少毫秒再打包，合并一连串的变动
+        #[arg(long, value_name = "MS", default_value_t = 2000)]
+        debounce: u64,
+
+        /// 增量更新已有压缩包而不是每次重新生成（zip 以外的格式有删除时仍会重新生成）；
+        /// 与重新生成一样遵循 --include 和 --exclude
+        #[arg(long)]
+        update: bool,
+    },
+
+    /// 按任务文件批量执行 compress、extract、convert、test，并输出每个任务结果的 JSON 报告
+    Batch {
+        /// 任务文件，扩展名为 .toml 时按 TOML 解析，否则按 JSON；每个任务写明 command 和与命令行相同的 args
+        job_file: PathBuf,
+
+        /// 同时执行的任务数，0 表示按 CPU 核数；默认 1，按文件中的顺序逐个执行，后面的任务可以使用前面任务的输出。
+        /// 任务本身的并行处理不受此限制
+        #[arg(short, long, default_value_t = 1)]
+        jobs: usize,
+
+        /// 有任务失败时继续执行其余任务（默认跳过尚未开始的任务）
+        #[arg(short, long)]
+        keep_going: bool,
+
+        /// 报告写入的文件（默认输出到标准输出）；有任务写到标准输出（`-`）时必须指定
+        #[arg(long)]
+        report: Option<PathBuf>,
+    },
+}
+
+/// 批量任务的参数按对应子命令解析
+#[derive(Parser)]
+#[command(no_binary_name = true)]
+struct JobArgs {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+pub enum StoreCommands {
+    /// 为目录创建快照，仓库不存在时自动创建
+    Snapshot {
+        /// 仓库目录
+        store: PathBuf,
+
+        /// 要快照的目录
+        source: PathBuf,
+
+        /// 新建仓库时块的压缩方式: zstd, xz
+        #[arg(short, long)]
+        compression: Option<ChunkCompression>,
+    },
+
+    /// 把快照恢复到目录
+    Restore {
+        /// 仓库目录
+        store: PathBuf,
+
+        /// 恢复到的目录
+        target: PathBuf,
+
+        /// 快照 ID（默认最新的快照）
+        #[arg(short, long)]
+        snapshot: Option<String>,
+
+        #[command(flatten)]
+        limits: LimitArgs,
+    },
+
+    /// 列出仓库中的快照
+    ListSnapshots {
+        /// 仓库目录
+        store: PathBuf,
+
+        /// 以 JSON 输出
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// 删除快照，并回收不再被任何快照引用的块
+    Prune {
+        /// 仓库目录
+        store: PathBuf,
+
+        /// 要删除的快照 ID，可多次指定
+        #[arg(short, long)]
+        snapshot: Vec<String>,
+
+        /// 只保留最新的 N 个快照
+        #[arg(long, value_name = "N")]
+        keep_last: Option<usize>,
+
+        /// 只报告会删除的内容
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+/// 向已有压缩包写入文件的参数
+#[derive(Args, Clone)]
+pub struct EditArgs {
+    /// 要修改的压缩包
+    archive: PathBuf,
+
+    /// 要写入的文件或目录，条目命名方式与 `compress` 相同
+    #[arg(required = true)]
+    source: Vec<PathBuf>,
+
+    /// 压缩格式: zip, gz, 7z, xz, tar, zst
+    #[arg(short, long)]
+    format: Option<Format>,
+
+    /// 新条目的压缩算法: deflate, bzip2, zstd
+    #[arg(short, long)]
+    method: Option<String>,
+
+    /// 新条目的压缩等级
+    #[arg(short, long)]
+    level: Option<u8>,
+
+    #[command(flatten)]
+    password: PasswordArgs,
+
+    /// 新条目的 zip 加密方式: aes128, aes192, aes256（默认）, zipcrypto
+    #[arg(long)]
+    encryption: Option<ZipEncryption>,
+}
+
+/// 公钥加密的接收者
+#[derive(Args, Clone)]
+pub struct RecipientArgs {
+    /// 接收者公钥（x25519:...），可重复；指定后整个压缩包会加密为只有接收者能打开的信封
+    #[arg(short = 'r', long = "recipient", value_name = "KEY")]
+    recipients: Vec<Recipient>,
+
+    /// 从文件读取接收者公钥，每行一个，`#` 开头为注释
+    #[arg(long, value_name = "FILE")]
+    recipients_file: Option<PathBuf>,
+}
+
+impl RecipientArgs {
+    fn load(self) -> Result<Vec<Recipient>> {
+        let mut recipients = self.recipients;
+
+        if let Some(path) = self.recipients_file {
+            for line in fs::read_to_string(&path)?.lines().map(str::trim) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                recipients.push(line.parse().map_err(|e| {
+                    ZipError::Other(format!("{:?}: {}", path, e))
+                })?);
+            }
+        }
+
+        Ok(recipients)
+    }
+}
+
+/// 打开公钥加密信封所用的私钥
+#[derive(Args, Clone)]
+pub struct IdentityArgs {
+    /// 私钥文件，可重复；用于解开公钥加密的压缩包
+    #[arg(short = 'i', long = "identity", value_name = "FILE")]
+    identities: Vec<PathBuf>,
+}
+
+impl IdentityArgs {
+    fn load(self) -> Result<Vec<Identity>> {
+        let mut identities = Vec::new();
+        for path in &self.identities {
+            identities.extend(Identity::load(path)?);
+        }
+        Ok(identities)
+    }
+}
+
+/// compress 命令除目标和源以外的设置
+struct CompressOptions {
+    format: Option<Format>,
+    method: Option<String>,
+    password: PasswordArgs,
+    encryption: Option<ZipEncryption>,
+    recipients: RecipientArgs,
+    use_external: bool,
+    volume_size: Option<usize>,
+    level: Option<u8>,
+    checksum: Option<Checksum>,
+    checksum_embed: bool,
+}
+
+/// extract 命令除目标和源以外的设置，解压每个压缩包时再化为 [`ExtractOptions`]
+struct ExtractCommandOptions {
+    format: Option<Format>,
+    password: PasswordArgs,
+    identities: IdentityArgs,
+    use_external: bool,
+    files: Option<Vec<String>>,
+    parallel: bool,
+    separate: bool,
+    limits: LimitArgs,
+    recursive: RecursiveArgs,
+    unsafe_paths: PathPolicy,
+    salvage: bool,
+}
+
+/// 解压单个压缩包所需的设置
+struct ExtractOptions<'a> {
+    format: Option<Format>,
+    password: Option<String>,
+    use_external: bool,
+    files: Option<&'a [String]>,
+    limits: ExtractLimits,
+    unsafe_paths: PathPolicy,
+    identities: &'a [Identity],
+    salvage: bool,
+}
+
+/// 解开信封后的明文压缩包，随临时目录一起删除
+struct OpenedEnvelope {
+    _dir: tempfile::TempDir,
+    path: PathBuf,
+}
+
+/// 密码来源，最多指定一种
+#[derive(Args, Clone)]
+#[group(multiple = false)]
+pub struct PasswordArgs {
+    /// 加密密码（会出现在进程列表中，建议改用下面几种方式）
+    #[arg(short, long)]
+    password: Option<String>,
+
+    /// 从文件第一行读取密码
+    #[arg(long, value_name = "FILE")]
+    password_file: Option<PathBuf>,
+
+    /// 从环境变量读取密码
+    #[arg(long, value_name = "VAR")]
+    password_env: Option<String>,
+
+    /// 从已打开的文件描述符读取密码（第一行）
+    #[arg(long, value_name = "FD")]
+    password_fd: Option<i32>,
+
+    /// 在终端中输入密码，不回显
+    #[arg(long)]
+    ask_password: bool,
+}
+
+impl PasswordArgs {
+    /// 数据从标准输入（`-`）读取时，密码不能也从描述符 0 读取
+    fn reject_stdin_fd(&self, inputs: &[PathBuf]) -> Result<()> {
+        if self.password_fd == Some(0) && inputs.iter().any(|p| utils::is_stdio(p)) {
+            return Err(ZipError::Other(
+                "--password-fd 0 cannot be used while data is read from stdin (-)".to_string()
+            ));
+        }
+        Ok(())
+    }
+
+    /// 读取密码；`confirm` 为真时终端输入需确认一次
+    fn resolve(self, confirm: bool) -> Result<Option<String>> {
+        let source = if let Some(password) = self.password {
+            warn!("--password is visible to other users in the process list; prefer --password-file, --password-env, --password-fd or --ask-password");
+            PasswordSource::Arg(password)
+        } else if let Some(path) = self.password_file {
+            PasswordSource::File(path)
+        } else if let Some(name) = self.password_env {
+            PasswordSource::Env(name)
+        } else if let Some(fd) = self.password_fd {
+            PasswordSource::Fd(fd)
+        } else if self.ask_password {
+            PasswordSource::Prompt
+        } else {
+            return Ok(None);
+        };
+
+        source.read(confirm).map(Some)
+    }
+}
+
+/// 递归解压：解出的条目中仍是压缩包的（按文件头识别）继续解压
+#[derive(Args, Clone, Copy)]
+pub struct RecursiveArgs {
+    /// 递归解压嵌套的压缩包，每一层都使用相同的安全限制，总大小和条目数限制由所有层共享
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// 最多向下解压的嵌套层数
+    #[arg(long, default_value_t = 8, requires = "recursive")]
+    recursive_depth: usize,
+
+    /// 用同名目录替换嵌套的压缩包，而不是解压到旁边去掉扩展名的目录
+    #[arg(long, requires = "recursive")]
+    in_place: bool,
+}
+
+/// 解压资源限制，防止压缩炸弹；设为 0 表示不限制
+#[derive(Args, Clone, Copy)]
+pub struct LimitArgs {
+    /// 解压总大小上限，可带单位如 512M、10G（默认不限制）；
+    /// 使用外部工具时按暂存目录大小定期检查，超出即终止工具
+    #[arg(long, value_parser = utils::parse_size)]
+    max_total_size: Option<u64>,
+
+    /// 单个条目的最大压缩比
+    #[arg(long, default_value_t = 1000)]
+    max_ratio: u64,
+
+    /// 最多解压的条目数
+    #[arg(long, default_value_t = 1_000_000)]
+    max_entries: u64,
+
+    /// 条目路径的最大目录深度
+    #[arg(long, default_value_t = 256)]
+    max_depth: usize,
+}
+
+impl From<LimitArgs> for ExtractLimits {
+    fn from(args: LimitArgs) -> Self {
+        Self {
+            max_total_size: args.max_total_size.filter(|&n| n > 0),
+            max_ratio: Some(args.max_ratio).filter(|&n| n > 0),
+            max_entries: Some(args.max_entries).filter(|&n| n > 0),
+            max_depth: Some(args.max_depth).filter(|&n| n > 0),
+        }
+    }
+}
+
+impl Cli {
+    /// 从文件扩展名识别格式
+    fn identify_format(format_opt: &Option<Format>, path: &Path, is_extract: bool) -> Result<Format> {
+        if let Some(format) = format_opt {
+            return Ok(*format);
+        }
+
+        // 解压时优先根据文件头识别
+        let detected = if is_extract && !utils::is_stdio(path) {
+            Format::detect(path)
+        } else {
+            None
+        };
+
+        if let Some(format) = detected {
+            return Ok(format);
+        }
+
+        if let Some(ext) = path.extension() {
+            let ext_str = ext.to_string_lossy().to_lowercase();
+            Ok(Format::from(ext_str.as_str()))
+        } else {
+            // 如果没有提供扩展名，默认为ZIP
+            Ok(Format::Zip)
+        }
+    }
+
+    /// 记录调试信息
+    fn log_debug_info(
+        source: &[PathBuf],
+        target: Option<&PathBuf>,
+        is_compress: bool,
+        format: Option<Format>,
+        method: Option<&str>,
+        password: Option<&String>
+    ) {
+        let yes = "✓";
+        let no = "✗";
+
+        let source_paths = source.iter()
+            .map(|p| std::path::absolute(p).unwrap_or_else(|_| p.to_path_buf()))
+            .collect::<Vec<_>>();
+
+        debug!("Source: {:?}", source_paths);
+
+        if let Some(target_path) = target {
+            if let Ok(abs_target) = std::path::absolute(target_path) {
+                debug!("Target: {:?}", abs_target);
+            } else {
+                debug!("Target: {:?}", target_path);
+            }
+        } else {
+            debug!("Target: None (list mode)");
+        }
+
+        debug!("Compress: {}", if is_compress { yes } else { no });
+
+        if is_compress && let Some(format) = format {
+            debug!("Format: {:?}", format);
+            debug!("Method: {:?}", method.unwrap_or("default"));
+        }
+
+        if password.is_some() {
+            debug!("Password: <redacted>");
+        }
+    }
+
+    /// 验证输入参数
+    fn validate_source_not_empty(source: &[PathBuf]) -> Result<()> {
+        if source.is_empty() {
+            return Err(ZipError::Other("No source files specified".to_string()));
+        }
+        Ok(())
+    }
+
+    /// 执行压缩操作
+    fn execute_compress(target: PathBuf, source: Vec<PathBuf>, options: CompressOptions, debug: bool) -> Result<()> {
+        let CompressOptions {
+            format: format_opt,
+            method,
+            password,
+            encryption,
+            recipients,
+            use_external,
+            volume_size,
+            level,
+            checksum,
+            checksum_embed,
+        } = options;
+        Self::validate_source_not_empty(&source)?;
+        password.reject_stdin_fd(&source)?;
+        let password = password.resolve(true)?;
+        let recipients = recipients.load()?;
+
+        if !recipients.is_empty() && volume_size.is_some() {
+            return Err(ZipError::UnsupportedOperation(
+                "Split volumes cannot be encrypted to recipients".to_string()
+            ));
+        }
+
+        if encryption.is_some() && password.is_none() {
+            return Err(ZipError::Other("--encryption requires a password".to_string()));
+        }
+
+        let format = Self::identify_format(&format_opt, &target, false)?;
+
+        if checksum.is_some() {
+            if volume_size.is_some() {
+                return Err(ZipError::UnsupportedOperation(
+                    "Checksum manifests cannot be written for split volumes".to_string()
+                ));
+            }
+            if checksum_embed && !matches!(format, Format::Zip) {
+                return Err(ZipError::UnsupportedOperation(
+                    "--checksum-embed is only supported for zip".to_string()
+                ));
+            }
+            // 旁挂清单会暴露加密内容的文件名和哈希
+            if !checksum_embed && (utils::is_stdio(&target) || !recipients.is_empty()) {
+                return Err(ZipError::Other(
+                    "A sidecar manifest needs a plain file target; use --checksum-embed".to_string()
+                ));
+            }
+        }
+
+        if debug {
+            Self::log_debug_info(
+                &source,
+                Some(&target),
+                true,
+                Some(format),
+                method.as_deref(),
+                password.as_ref()
+            );
+        }
+
+        let codec_factory = codecs::CodecFactory::new(
+            format,
+            method.as_deref(),
+            password,
+            volume_size,
+            use_external,
+            level,
+        ).with_encryption(encryption);
+
+        let mut codec = codec_factory.create_codec()?;
+
+        // 设置压缩等级（如果有）
+        if let Some(lv) = level {
+            let (min, max) = codec.compression_level_range();
+            if lv < min || lv > max {
+                return Err(ZipError::Other(format!("压缩等级超出范围: {}-{}", min, max)));
+            }
+            codec.set_compression_level(lv);
+        }
+
+        let source_paths: Vec<&Path> = source.iter().map(|p| p.as_path()).collect();
+
+        if recipients.is_empty() && checksum.is_none() {
+            return codec.compress(&source_paths, &target, None);
+        }
+
+        // 先在目标旁的临时目录中生成压缩包，计算校验清单或整体加密为信封后再放到目标位置
+        let staging_parent = match target.parent() {
+            Some(parent) if !utils::is_stdio(&target) && !parent.as_os_str().is_empty() => {
+                utils::ensure_directory_exists(parent)?;
+                parent.to_path_buf()
+            }
+            _ => std::env::temp_dir(),
+        };
+        let staging = tempfile::tempdir_in(staging_parent)?;
+        let inner_name = target.file_name().filter(|_| !utils::is_stdio(&target)).unwrap_or("archive".as_ref());
+        codec.compress(&source_paths, &staging.path().join(inner_name), None)?;
+
+        // 有些格式会补全扩展名，所以取临时目录中实际生成的文件
+        let inner = fs::read_dir(staging.path())?
+            .next()
+            .ok_or_else(|| ZipError::Other("Compression produced no output".to_string()))??
+            .path();
+
+        let mut sidecar = None;
+        if let Some(algorithm) = checksum {
+            // 回读写好的压缩包而不是在写入时计算：各编解码器（包括外部工具）都不必改动，
+            // 清单记录的也正是压缩包里实际存下的内容
+            info!("Computing {} checksums from the written archive", algorithm.manifest_name());
+            let manifest = Manifest::of_archive(codec.as_mut(), &inner, algorithm, None)?.to_text();
+
+            if checksum_embed {
+                codec.append_entry(&inner, algorithm.manifest_name(), manifest.as_bytes())?;
+            } else {
+                sidecar = Some((algorithm, manifest));
+            }
+        }
+
+        if !recipients.is_empty() {
+            info!("Encrypting archive to {} recipients", recipients.len());
+            return envelope::seal(&inner, &target, &recipients);
+        }
+
+        if utils::is_stdio(&target) {
+            std::io::copy(&mut File::open(&inner)?, &mut utils::create_output(&target)?)?;
+            return Ok(());
+        }
+
+        let archive = target.with_file_name(inner.file_name().unwrap_or_default());
+        fs::rename(&inner, &archive)?;
+
+        if let Some((algorithm, manifest)) = sidecar {
+            let path = algorithm.sidecar_path(&archive);
+            fs::write(&path, manifest)?;
+            info!("Checksum manifest written to {:?}", path);
+        }
+        Ok(())
+    }
+
+    fn execute_extract(target: PathBuf, source: Vec<PathBuf>, options: ExtractCommandOptions, debug: bool) -> Result<()> {
+        let ExtractCommandOptions {
+            format: format_opt,
+            password,
+            identities,
+            use_external,
+            files,
+            parallel,
+            separate,
+            limits,
+            recursive,
+            unsafe_paths,
+            salvage,
+        } = options;
+        Self::validate_source_not_empty(&source)?;
+        password.reject_stdin_fd(&source)?;
+        let password = password.resolve(false)?;
+        let identities = identities.load()?;
+
+        if source.len() > 1 && source.iter().any(|p| utils::is_stdio(p)) {
+            return Err(ZipError::Other("stdin cannot be combined with other archives".to_string()));
+        }
+
+        if debug {
+            Self::log_debug_info(
+                &source,
+                Some(&target),
+                false,
+                format_opt,
+                None,
+                password.as_ref()
+            );
+        }
+
+        let jobs: Vec<(&PathBuf, PathBuf)> = source
+            .iter()
+            .map(|archive| {
+                let archive_target = if separate && !utils::is_stdio(&target) {
+                    target.join(utils::archive_stem(archive))
+                } else {
+                    target.clone()
+                };
+                (archive, archive_target)
+            })
+            .collect();
+
+        if recursive.recursive && utils::is_stdio(&target) {
+            return Err(ZipError::Other("--recursive cannot extract to stdout".to_string()));
+        }
+        // 目标目录中原有的文件不算作嵌套的压缩包
+        let before = if recursive.recursive { Some(Snapshot::of(&target)?) } else { None };
+        let extractor = RecursiveExtractor {
+            depth: recursive.recursive_depth,
+            placement: if recursive.in_place { Placement::InPlace } else { Placement::Sibling },
+            limits: limits.into(),
+            path_policy: unsafe_paths,
+            password: password.clone(),
+        };
+
+        let options = ExtractOptions {
+            format: format_opt,
+            password,
+            use_external,
+            files: files.as_deref(),
+            limits: limits.into(),
+            unsafe_paths,
+            identities: &identities,
+            salvage,
+        };
+
+        let extract_job = |(archive, archive_target): &(&PathBuf, PathBuf)| {
+            Self::extract_archive(archive, archive_target, &options)
+        };
+
+        if jobs.len() == 1 {
+            extract_job(&jobs[0])?;
+        } else {
+            Self::extract_all(&jobs, parallel, extract_job)?;
+        }
+
+        if let Some(before) = before {
+            let count = extractor.run(&target, &before)?;
+            info!("Extracted {} nested archives", count);
+        }
+        Ok(())
+    }
+
+    /// 多个压缩包：逐个报告错误，不因单个失败而中断
+    fn extract_all<F>(jobs: &[(&PathBuf, PathBuf)], parallel: bool, extract_job: F) -> Result<()>
+    where
+        F: Fn(&(&PathBuf, PathBuf)) -> Result<()> + Sync,
+    {
+        let results: Vec<Result<()>> = if parallel {
+            jobs.par_iter().map(&extract_job).collect()
+        } else {
+            jobs.iter().map(&extract_job).collect()
+        };
+
+        let mut failed = 0;
+        for ((archive, _), result) in jobs.iter().zip(&results) {
+            match result {
+                Ok(()) => info!("Extracted {:?}", archive),
+                Err(e) => {
+                    error!("Failed to extract {:?}: {}", archive, e);
+                    failed += 1;
+                }
+            }
+        }
+
+        if failed > 0 {
+            return Err(ZipError::Other(format!("{} of {} archives failed to extract", failed, jobs.len())));
+        }
+        Ok(())
+    }
+
+    /// 压缩包是公钥加密信封时，用私钥解到临时文件；否则返回 `None`
+    fn open_envelope(archive: &Path, identities: &[Identity]) -> Result<Option<OpenedEnvelope>> {
+        // 标准输入无法预读文件头，给出私钥即视为信封
+        let sealed = if utils::is_stdio(archive) {
+            !identities.is_empty()
+        } else {
+            envelope::is_envelope(archive)
+        };
+
+        if !sealed {
+            return Ok(None);
+        }
+
+        if identities.is_empty() {
+            return Err(ZipError::Other(format!(
+                "{:?} is encrypted to recipients; pass --identity with a matching private key", archive
+            )));
+        }
+
+        info!("Decrypting envelope {:?}", archive);
+        let dir = tempfile::tempdir()?;
+        let name = archive.file_name().filter(|_| !utils::is_stdio(archive)).unwrap_or("stdin".as_ref());
+        let path = dir.path().join(name);
+
+        envelope::open(archive, identities, &mut File::create(&path)?)?;
+        Ok(Some(OpenedEnvelope { _dir: dir, path }))
+    }
+
+    /// 解压单个压缩包，格式按该文件单独识别
+    fn extract_archive(archive: &Path, target: &Path, options: &ExtractOptions) -> Result<()> {
+        let opened = Self::open_envelope(archive, options.identities)?;
+        let archive = opened.as_ref().map_or(archive, |o| o.path.as_path());
+
+        let format = Self::identify_format(&options.format, archive, true)?;
+        debug!("Archive {:?} detected as {:?}", archive, format);
+
+        let codec_factory = codecs::CodecFactory::new(
+            format,
+            None,
+            options.password.clone(),
+            None,
+            options.use_external,
+            None,
+        )
+        .with_limits(options.limits)
+        .with_path_policy(options.unsafe_paths)
+        .with_salvage(options.salvage);
+
+        let mut codec = codec_factory.create_codec()?;
+
+        if let Some(parts) = options.files {
+            codec.extract_parts(&[archive], target, parts)
+        } else {
+            codec.extract(&[archive], target)
+        }
+    }
+
+    fn execute_script(
+        source: Vec<PathBuf>,
+        script_file: PathBuf,
+        virtual_env_dir: Option<PathBuf>,
+        unzip: bool,
+    ) -> Result<()> {
+        let virtual_env = virtual_env_dir.map(|dir| VirtualEnv::new(dir.as_path()));
+
+        let script = ScriptRunner::new(
+            source.clone(),
+            virtual_env.as_ref().unwrap().get_interpreter_path().as_path(),
+            unzip,
+            &script_file,
+        )?;
+
+        script.run()
+    }
+
+    fn execute_list(
+        source: PathBuf,
+        format_opt: Option<Format>,
+        identities: IdentityArgs,
+        debug: bool
+    ) -> Result<()> {
+        let identities = identities.load()?;
+        let opened = Self::open_envelope(&source, &identities)?;
+        let source = opened.as_ref().map_or(source, |o| o.path.clone());
+
+        let format = Self::identify_format(&format_opt, &source, true)?;
+
+        if debug {
+            Self::log_debug_info(
+                std::slice::from_ref(&source),
+                None,
+                false,
+                Some(format),
+                None,
+                None
+            );
+        }
+
+        let contents = crate::file_tree::list_archive_contents_json(
+            &source,
+            format.into(),
+            debug
+        )?;
+
+        println!("{}", contents);
+        Ok(())
+    }
+
+    fn execute_test(
+        source: Vec<PathBuf>,
+        format_opt: Option<Format>,
+        password: PasswordArgs,
+        identities: IdentityArgs,
+        use_external: bool,
+    ) -> Result<()> {
+        Self::validate_source_not_empty(&source)?;
+        password.reject_stdin_fd(&source)?;
+        let identities = identities.load()?;
+
+        let options = ExtractOptions {
+            format: format_opt,
+            password: password.resolve(false)?,
+            use_external,
+            files: None,
+            limits: ExtractLimits::default(),
+            unsafe_paths: PathPolicy::Reject,
+            identities: &identities,
+            salvage: false,
+        };
+
+        let mut failed = 0;
+        for archive in &source {
+            let scratch = tempfile::tempdir()?;
+            match Self::extract_archive(archive, scratch.path(), &options) {
+                Ok(()) => println!("{}: OK", archive.display()),
+                Err(e) => {
+                    println!("{}: FAILED ({})", archive.display(), e);
+                    failed += 1;
+                }
+            }
+        }
+
+        if failed > 0 {
+            return Err(ZipError::Other(format!("{} of {} archives failed the test", failed, source.len())));
+        }
+        Ok(())
+    }
+
+    fn execute_keygen(output: PathBuf, sign: bool) -> Result<()> {
+        if sign {
+            let key = SigningIdentity::generate();
+            key.save(&output)?;
+
+            info!("Signing key written to {:?}", output);
+            println!("{}", key.public());
+            return Ok(());
+        }
+
+        let identity = Identity::generate();
+        identity.save(&output)?;
+
+        info!("Private key written to {:?}", output);
+        println!("{}", identity.recipient());
+        Ok(())
+    }
+
+    /// 为清单计算条目哈希而打开压缩包
+    fn manifest_codec(archive: &Path, format_opt: &Option<Format>, password: PasswordArgs) -> Result<Box<dyn codecs::Codec>> {
+        let format = Self::identify_format(format_opt, archive, true)?;
+        codecs::CodecFactory::new(format, None, password.resolve(false)?, None, false, None).create_codec()
+    }
+
+    fn execute_check(
+        path: PathBuf,
+        manifest: Option<PathBuf>,
+        algorithm: Option<Checksum>,
+        format_opt: Option<Format>,
+        password: PasswordArgs,
+    ) -> Result<()> {
+        let from_file = |manifest: &Path| -> Result<(Checksum, Manifest)> {
+            let algorithm = algorithm.or_else(|| Checksum::from_manifest_path(manifest)).ok_or_else(|| {
+                ZipError::Other(format!("Cannot tell the checksum algorithm of {:?}; pass --algorithm", manifest))
+            })?;
+            Ok((algorithm, Manifest::read(manifest)?))
+        };
+
+        let results = if path.is_dir() {
+            let manifest = manifest
+                .or_else(|| Checksum::ALL.iter().map(|a| path.join(a.manifest_name())).find(|p| p.is_file()))
+                .ok_or_else(|| ZipError::Other(format!("No checksum manifest found in {:?}", path)))?;
+            let (algorithm, expected) = from_file(&manifest)?;
+            let skip = manifest.strip_prefix(&path).ok().map(|p| p.to_string_lossy().into_owned());
+
+            expected.check_dir(&path, algorithm, skip.as_deref())?
+        } else {
+            let mut codec = Self::manifest_codec(&path, &format_opt, password)?;
+            let sidecar = Checksum::ALL.iter().map(|a| a.sidecar_path(&path)).find(|p| p.is_file());
+
+            let (algorithm, expected, skip) = match manifest.or(sidecar) {
+                Some(manifest) => {
+                    let (algorithm, expected) = from_file(&manifest)?;
+                    (algorithm, expected, None)
+                }
+                None => {
+                    let (algorithm, expected) = Manifest::embedded(codec.as_mut(), &path)?.ok_or_else(|| {
+                        ZipError::Other(format!("No checksum manifest found for {:?}", path))
+                    })?;
+                    (algorithm, expected, Some(algorithm.manifest_name()))
+                }
+            };
+
+            let actual = Manifest::of_archive(codec.as_mut(), &path, algorithm, skip)?;
+            expected.compare(&actual)
+        };
+
+        let failed = results.iter().filter(|(_, status)| *status != CheckStatus::Ok).count();
+        for (name, status) in &results {
+            println!("{}: {}", name, status.as_str());
+        }
+
+        if failed > 0 {
+            return Err(ZipError::Other(format!("{} of {} files failed the check", failed, results.len())));
+        }
+        Ok(())
+    }
+
+    fn execute_salvage(source: PathBuf, output: PathBuf, password: PasswordArgs) -> Result<()> {
+        let codec = ZipCodec::new(Default::default(), password.resolve(false)?, None);
+        let report = codec.salvage_to(&source, utils::create_output(&output)?)?;
+
+        info!("Recovered {} entries into {:?}", report.recovered.len(), output);
+        for name in &report.unverified {
+            warn!("{} is encrypted and was kept without checking; pass a password to verify it", name);
+        }
+
+        if !report.lost.is_empty() {
+            return Err(ZipError::Other(format!(
+                "{} entries could not be recovered: {}", report.lost.len(), report.lost.join(", ")
+            )));
+        }
+        Ok(())
+    }
+
+    fn execute_parity(files: Vec<PathBuf>, output: Option<PathBuf>, redundancy: u8, block_size: u64) -> Result<()> {
+        let block_size = usize::try_from(block_size)
+            .ok()
+            .filter(|size| (1..=u32::MAX as usize).contains(size))
+            .ok_or_else(|| ZipError::Other(format!("Invalid block size: {}", block_size)))?;
+
+        let output = output.unwrap_or_else(|| parity::sidecar_path(&files[0]));
+        let output_dir = fs::canonicalize(output.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new(".")))?;
+        for file in &files {
+            if fs::canonicalize(file)?.parent() != Some(output_dir.as_path()) {
+                return Err(ZipError::Other(format!(
+                    "{:?} is not in the same directory as {:?}; repair looks for protected files next to the parity file",
+                    file, output
+                )));
+            }
+        }
+
+        parity::create(&files, &output, ParityOptions { redundancy, block_size })?;
+        info!("Recovery data written to {:?}", output);
+        Ok(())
+    }
+
+    fn execute_repair(parity_path: PathBuf, check_only: bool) -> Result<()> {
+        let report = parity::repair(&parity_path, check_only)?;
+
+        for file in &report.resized_files {
+            warn!("{:?} has the wrong length{}", file, if check_only { "" } else { "; restored" });
+        }
+        if report.damaged_parity > 0 {
+            warn!("{} recovery blocks are damaged; regenerate the parity file after repair", report.damaged_parity);
+        }
+
+        if report.is_clean() {
+            println!("{}: all blocks intact", parity_path.display());
+            return Ok(());
+        }
+
+        if report.unrecoverable_stripes > 0 {
+            return Err(ZipError::Other(format!(
+                "{} damaged blocks, {} stripes cannot be rebuilt from the remaining recovery data",
+                report.damaged_blocks, report.unrecoverable_stripes
+            )));
+        }
+
+        if check_only {
+            return Err(ZipError::Other(format!("{} damaged blocks found, all repairable", report.damaged_blocks)));
+        }
+
+        println!("{}: repaired {} damaged blocks", parity_path.display(), report.repaired_blocks);
+        Ok(())
+    }
+
+    /// 检查并设置压缩等级（如果有）
+    fn apply_level(codec: &mut dyn codecs::Codec, level: Option<u8>) -> Result<()> {
+        if let Some(lv) = level {
+            let (min, max) = codec.compression_level_range();
+            if lv < min || lv > max {
+                return Err(ZipError::Other(format!("压缩等级超出范围: {}-{}", min, max)));
+            }
+            codec.set_compression_level(lv);
+        }
+        Ok(())
+    }
+
+    /// 向已有压缩包写入文件；`only_changed` 时跳过未改动的文件
+    fn execute_add(args: EditArgs, only_changed: bool) -> Result<()> {
+        let format = Self::identify_format(&args.format, &args.archive, true)?;
+        let password = args.password.resolve(true)?;
+
+        if args.encryption.is_some() && password.is_none() {
+            return Err(ZipError::Other("--encryption requires a password".to_string()));
+        }
+
+        let mut codec = codecs::CodecFactory::new(format, args.method.as_deref(), password, None, false, args.level)
+            .with_encryption(args.encryption)
+            .create_codec()?;
+
+        Self::apply_level(codec.as_mut(), args.level)?;
+
+        let source: Vec<&Path> = args.source.iter().map(|p| p.as_path()).collect();
+        codec.add_entries(&args.archive, &source, only_changed)
+    }
+
+    fn execute_delete(archive: PathBuf, names: Vec<String>, format_opt: Option<Format>) -> Result<()> {
+        let format = Self::identify_format(&format_opt, &archive, true)?;
+        let mut codec = codecs::CodecFactory::new(format, None, None, None, false, None).create_codec()?;
+        codec.delete_entries(&archive, &names)
+    }
+
+    /// 一次读取源压缩包的条目，直接写入目标格式
+    fn execute_convert(
+        source: PathBuf,
+        output: PathBuf,
+        from: Option<Format>,
+        to: Option<Format>,
+        method: Option<String>,
+        level: Option<u8>,
+        password: PasswordArgs,
+    ) -> Result<()> {
+        let from = Self::identify_format(&from, &source, true)?;
+        let to = Self::identify_format(&to, &output, false)?;
+
+        if !utils::is_stdio(&source) && output.exists() && fs::canonicalize(&source)? == fs::canonicalize(&output)? {
+            return Err(ZipError::Other("The converted archive cannot overwrite its source".to_string()));
+        }
+        password.reject_stdin_fd(std::slice::from_ref(&source))?;
+
+        let mut reader = codecs::CodecFactory::new(from, None, password.resolve(false)?, None, false, None).create_codec()?;
+        let mut writer = codecs::CodecFactory::new(to, method.as_deref(), None, None, false, level).create_codec()?;
+        Self::apply_level(writer.as_mut(), level)?;
+
+        let report = match codecs::convert::convert(reader.as_mut(), &source, writer.entry_writer(&output)?) {
+            Ok(report) => report,
+            Err(e) => {
+                // 不留下写了一半的压缩包
+                if !utils::is_stdio(&output) {
+                    fs::remove_file(&output).ok();
+                }
+                return Err(e);
+            }
+        };
+        report.warn_losses(to.into());
+
+        info!("Converted {} entries from {:?} to {:?}", report.written, from, to);
+        Ok(())
+    }
+
+    fn execute_cat(source: PathBuf, names: Vec<String>, format_opt: Option<Format>, password: PasswordArgs) -> Result<()> {
+        let format = Self::identify_format(&format_opt, &source, true)?;
+        password.reject_stdin_fd(std::slice::from_ref(&source))?;
+        let mut codec = codecs::CodecFactory::new(format, None, password.resolve(false)?, None, false, None).create_codec()?;
+
+        let mut stdout = BufWriter::new(std::io::stdout().lock());
+        let result = codec.cat_entries(&source, &names, &mut stdout);
+        stdout.flush()?;
+        result
+    }
+
+    /// 由命令行参数构造搜索选项
+    fn grep_options(
+        pattern: &str,
+        ignore_case: bool,
+        globs: &[String],
+        files_with_matches: bool,
+        no_nested: bool,
+        limits: LimitArgs,
+        password: PasswordArgs,
+    ) -> Result<GrepOptions> {
+        Ok(GrepOptions {
+            pattern: regex::bytes::RegexBuilder::new(pattern)
+                .case_insensitive(ignore_case)
+                .build()
+                .map_err(|e| ZipError::Other(e.to_string()))?,
+            entries: globs.iter()
+                .map(|glob| grep::glob_to_regex(glob))
+                .collect::<std::result::Result<_, _>>()
+                .map_err(|e| ZipError::Other(e.to_string()))?,
+            nested: !no_nested,
+            names_only: files_with_matches,
+            password: password.resolve(false)?,
+            limits: limits.into(),
+        })
+    }
 
-        debug!("Compress: {}", if is_compress { yes } else { no });
+    fn execute_grep(options: GrepOptions, archives: Vec<PathBuf>, jobs: usize, format_opt: Option<Format>) -> Result<()> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build()
+            .map_err(|e| ZipError::Other(e.to_string()))?;
 
-        if is_compress && format.is_some() {
-            debug!("Format: {:?}", format.unwrap());
-            debug!("Method: {:?}", method.unwrap_or("default"));
+        // 每个条目的结果整体输出，并行时不同条目的行不会交错
+        let stdout = std::io::stdout();
+        let report = |found: grep::Found| {
+            let mut out = stdout.lock();
+            let _ = if options.names_only {
+                writeln!(out, "{}", found.path)
+            } else if found.binary {
+                writeln!(out, "Binary entry {} matches", found.path)
+            } else {
+                found.lines.iter().try_for_each(|(line, text)| writeln!(out, "{}:{}:{}", found.path, line, text))
+            };
+        };
+
+        let mut matched = 0;
+        for archive in &archives {
+            let format = Self::identify_format(&format_opt, archive, true)?;
+            matched += pool.install(|| grep::search(format, archive, &options, &report))?;
         }
 
-        if let Some(pwd) = password {
-            debug!("Password: {}, encryption: AES256", pwd);
+        // 与 grep(1) 一样，没有匹配时以非零状态退出
+        if matched == 0 {
+            return Err(ZipError::Other(format!("No entries match {:?}", options.pattern.as_str())));
         }
+        Ok(())
     }
 
-    /// 验证输入参数
-    fn validate_source_not_empty(source: &[PathBuf]) -> Result<()> {
-        if source.is_empty() {
-            return Err(ZipError::Other("No source files specified".to_string()));
+    /// 压缩包或目录的条目快照
+    fn diff_tree(path: &Path, password: Option<String>) -> Result<Tree> {
+        if path.is_dir() {
+            return Tree::of_dir(path);
+        }
+
+        let format = Self::identify_format(&None, path, true)?;
+        let mut codec = codecs::CodecFactory::new(format, None, password, None, false, None).create_codec()?;
+        Tree::of_archive(codec.as_mut(), path)
+    }
+
+    fn execute_diff(old: PathBuf, new: PathBuf, json: bool, options: DiffOptions, password: PasswordArgs) -> Result<()> {
+        let password = password.resolve(false)?;
+        let diff = Diff::between(
+            &Self::diff_tree(&old, password.clone())?,
+            &Self::diff_tree(&new, password)?,
+            options,
+        );
+
+        if json {
+            println!("{}", serde_json::to_string_pretty(&diff).map_err(|e| ZipError::Other(e.to_string()))?);
+        } else {
+            println!("{}", diff.to_text());
+        }
+
+        // 与 diff(1) 一样，有差异时以非零状态退出
+        if !diff.is_empty() {
+            return Err(ZipError::Other(format!(
+                "{:?} and {:?} differ in {} entries", old, new, diff.added.len() + diff.removed.len() + diff.changed.len()
+            )));
         }
         Ok(())
     }
 
-    /// 执行压缩操作
-    fn execute_compress(
-        target: PathBuf,
-        source: Vec<PathBuf>,
+    fn execute_backup(
+        source: PathBuf,
+        output: PathBuf,
+        name: String,
         format_opt: Option<Format>,
-        method: Option<String>,
-        password: Option<String>,
-        use_external: bool,
-        volume_size: Option<usize>,
-        debug: bool,
-        level: Option<u8>,
+        state: Option<PathBuf>,
+        full: bool,
+        password: PasswordArgs,
     ) -> Result<()> {
-        Self::validate_source_not_empty(&source)?;
-
-        let format = Self::identify_format(&format_opt, &target, false)?;
-
-        if debug {
-            Self::log_debug_info(
-                &source,
-                Some(&target),
-                true,
-                Some(format),
-                method.as_deref(),
-                password.as_ref()
-            );
+        if !source.is_dir() {
+            return Err(ZipError::Other(format!("{:?} is not a directory", source)));
         }
 
-        let codec_factory = codecs::CodecFactory::new(
-            format,
-            method.as_deref(),
-            password,
-            volume_size,
-            use_external,
-            level,
+        let state = state.unwrap_or_else(|| output.join(format!("{}.state.json", name)));
+        let options = BackupOptions {
+            name,
+            format: format_opt.unwrap_or(Format::Zip),
+            state,
+            full,
+            password: password.resolve(true)?,
+        };
+
+        let report = backup::backup(&source, &output, &options)?;
+        info!(
+            "{} backup {:?}: {} written, {} deleted, {} unchanged",
+            if report.full { "Full" } else { "Incremental" }, report.archive, report.written, report.deleted, report.unchanged
         );
+        Ok(())
+    }
 
-        let mut codec = codec_factory.create_codec()?;
+    fn execute_restore(
+        backups: PathBuf,
+        target: PathBuf,
+        name: String,
+        until: Option<u32>,
+        password: PasswordArgs,
+        limits: LimitArgs,
+    ) -> Result<()> {
+        let applied = backup::restore(&backups, &name, &target, until, password.resolve(false)?, limits.into())?;
+        info!("Restored {:?} from {} archives", target, applied.len());
+        Ok(())
+    }
 
-        // 设置压缩等级（如果有）
-        if let Some(lv) = level {
-            let (min, max) = codec.compression_level_range();
-            if lv < min as u8 || lv > max as u8 {
-                return Err(ZipError::Other(format!("压缩等级超出范围: {}-{}", min, max)));
-            }
-            codec.set_compression_level(lv);
+    fn execute_rotate(template: String, policy: RetentionPolicy, dry_run: bool) -> Result<()> {
+        // 全为 0 会删除所有匹配的压缩包，多半是漏写了参数
+        if policy.daily == 0 && policy.weekly == 0 && policy.monthly == 0 {
+            return Err(ZipError::Other("Give at least one of --daily, --weekly or --monthly".to_string()));
         }
 
-        let source_paths: Vec<&Path> = source.iter().map(|p| p.as_path()).collect();
+        let rotations = rotate::plan(&template, policy)?;
+        for rotation in &rotations {
+            if rotation.kept_by.is_empty() {
+                println!("{} {}", if dry_run { "would remove" } else { "remove" }, rotation.path.display());
+            } else {
+                println!("keep {} ({})", rotation.path.display(), rotation.kept_by.join(", "));
+            }
+        }
 
-        codec.compress(&source_paths, &target, None)
+        if !dry_run {
+            let removed = rotate::remove_unkept(&rotations)?;
+            info!("Removed {} of {} archives", removed.len(), rotations.len());
+        }
+        Ok(())
     }
 
-    fn execute_extract(
+    fn execute_watch(
         target: PathBuf,
-        source: Vec<PathBuf>,
-        format_opt: Option<Format>,
-        password: Option<String>,
-        use_external: bool,
-        files: Option<Vec<String>>,
-        debug: bool
+        source: PathBuf,
+        format: Option<Format>,
+        method: Option<String>,
+        level: Option<u8>,
+        password: PasswordArgs,
+        options: WatchOptions,
     ) -> Result<()> {
-        Self::validate_source_not_empty(&source)?;
+        if !source.is_dir() {
+            return Err(ZipError::Other(format!("{:?} is not a directory", source)));
+        }
+        let format = Self::identify_format(&format, &target, false)?;
+        let password = password.resolve(true)?;
 
-        let format = Self::identify_format(&format_opt, &source[0], true)?;
+        let make_codec = || {
+            let mut codec = codecs::CodecFactory::new(format, method.as_deref(), password.clone(), None, false, level).create_codec()?;
+            Self::apply_level(codec.as_mut(), level)?;
+            Ok(codec)
+        };
+        watch::watch(&make_codec, &source, &target, &options)
+    }
 
-        if debug {
-            Self::log_debug_info(
-                &source,
-                Some(&target),
-                false,
-                Some(format),
-                None,
-                password.as_ref()
-            );
+    fn execute_batch(job_file: PathBuf, jobs: usize, keep_going: bool, report: Option<PathBuf>, debug: bool) -> Result<()> {
+        let file = JobFile::load(&job_file)?;
+
+        // 写到标准输出的任务会和报告混在一起，并行时数据还会彼此交错
+        let to_stdout: Vec<Commands> = file.jobs.iter()
+            .filter_map(|job| Self::parse_job(job).ok())
+            .filter(Self::job_writes_stdout)
+            .collect();
+        if !to_stdout.is_empty() && report.is_none() {
+            return Err(ZipError::Other(
+                "Jobs that write to stdout ('-' or test results) need --report so the report is kept apart".to_string()
+            ));
+        }
+        // test 任务只逐行输出结果，不会弄乱别的任务的数据
+        let data_to_stdout = to_stdout.iter().filter(|command| !matches!(command, Commands::Test { .. })).count();
+        if data_to_stdout > 1 && jobs != 1 {
+            return Err(ZipError::Other(
+                "Only one job may write to stdout ('-') unless jobs run one at a time".to_string()
+            ));
         }
 
-        let codec_factory = codecs::CodecFactory::new(
-            format,
-            None,
-            password,
-            None,
-            use_external,
-            None,
-        );
+        let run_job = |job: &Job| Cli { command: Self::parse_job(job)?, debug }.execute();
+        let summary = batch::run(&file.jobs, jobs, keep_going, &run_job);
 
-        let mut codec = codec_factory.create_codec()?;
+        let json = serde_json::to_string_pretty(&summary).map_err(|e| ZipError::Other(e.to_string()))?;
+        match report {
+            Some(path) => fs::write(&path, json + "\n")?,
+            None => println!("{}", json),
+        }
 
-        let source_paths: Vec<&Path> = source.iter().map(|p| p.as_path()).collect();
+        info!("{} jobs succeeded, {} failed, {} skipped", summary.succeeded, summary.failed, summary.skipped);
+        if summary.failed > 0 {
+            return Err(ZipError::Other(format!("{} of {} jobs failed", summary.failed, summary.jobs.len())));
+        }
+        Ok(())
+    }
 
-        if let Some(parts) = files {
-            codec.extract_parts(&source_paths, &target, &parts)
-        } else {
-            codec.extract(&source_paths, &target)
+    /// 按对应子命令解析任务参数
+    fn parse_job(job: &Job) -> Result<Commands> {
+        let args = std::iter::once(job.command.as_str()).chain(job.args.iter().map(String::as_str));
+        // clap 的错误信息后面附带用法说明，报告里只保留第一行
+        JobArgs::try_parse_from(args)
+            .map(|args| args.command)
+            .map_err(|e| ZipError::Other(e.to_string().lines().next().unwrap_or_default().to_string()))
+    }
+
+    /// 任务是否写标准输出：压缩包或解压内容写到 `-`，或 test 的结果行
+    fn job_writes_stdout(command: &Commands) -> bool {
+        match command {
+            Commands::Compress { target, .. } | Commands::Extract { target, .. } | Commands::Convert { output: target, .. } => {
+                utils::is_stdio(target)
+            }
+            Commands::Test { .. } => true,
+            _ => false,
         }
     }
 
-    fn execute_script(
-        source: Vec<PathBuf>,
-        script_file: PathBuf,
-        virtual_env_dir: Option<PathBuf>,
-        unzip: bool,
+    fn execute_store(command: StoreCommands) -> Result<()> {
+        match command {
+            StoreCommands::Snapshot { store, source, compression } => {
+                if !source.is_dir() {
+                    return Err(ZipError::Other(format!("{:?} is not a directory", source)));
+                }
+                let report = Store::open_or_init(&store, compression)?.snapshot(&source)?;
+                info!(
+                    "Snapshot {}: {} files, {} bytes in {} chunks, {} new chunks ({} bytes stored)",
+                    report.id, report.files, report.size, report.chunks, report.new_chunks, report.new_bytes
+                );
+                println!("{}", report.id);
+            }
+            StoreCommands::Restore { store, target, snapshot, limits } => {
+                let id = Store::open(&store)?.restore(snapshot.as_deref(), &target, limits.into())?;
+                info!("Restored snapshot {} to {:?}", id, target);
+            }
+            StoreCommands::ListSnapshots { store, json } => {
+                let snapshots = Store::open(&store)?.list()?;
+                if json {
+                    println!("{}", serde_json::to_string_pretty(&snapshots).map_err(|e| ZipError::Other(e.to_string()))?);
+                } else {
+                    for s in &snapshots {
+                        println!("{}  {}  {} files  {} bytes  {}", s.id, s.created, s.files, s.size, s.source);
+                    }
+                }
+            }
+            StoreCommands::Prune { store, snapshot, keep_last, dry_run } => {
+                if snapshot.is_empty() && keep_last.is_none() {
+                    return Err(ZipError::Other("Name snapshots to prune with --snapshot, or use --keep-last".to_string()));
+                }
+                let report = Store::open(&store)?.prune(&snapshot, keep_last, dry_run)?;
+                let verb = if dry_run { "Would remove" } else { "Removed" };
+                info!(
+                    "{} {} snapshots and {} unused chunks ({} bytes)",
+                    verb, report.snapshots.len(), report.chunks, report.bytes
+                );
+                for id in &report.snapshots {
+                    println!("{}", id);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn execute_sign(
+        archive: PathBuf,
+        key: PathBuf,
+        manifest: bool,
+        output: Option<PathBuf>,
+        format_opt: Option<Format>,
+        password: PasswordArgs,
     ) -> Result<()> {
-        let virtual_env = virtual_env_dir.map(|dir| VirtualEnv::new(dir.as_path()));
+        let key = SigningIdentity::load(&key)?;
 
-        let script = ScriptRunner::new(
-            source.clone(),
-            virtual_env.as_ref().unwrap().get_interpreter_path().as_path(),
-            unzip,
-            &script_file,
-        )?;
+        let manifest = if manifest {
+            let mut codec = Self::manifest_codec(&archive, &format_opt, password)?;
+            Some(signature::build_manifest(codec.as_mut(), &archive)?)
+        } else {
+            None
+        };
 
-        script.run()
+        let output = output.unwrap_or_else(|| SignatureFile::default_path(&archive));
+        signature::sign(&archive, &key, manifest)?.write(&output)?;
+
+        info!("Signature written to {:?}", output);
+        println!("{}", key.public());
+        Ok(())
     }
 
-    fn execute_list(
-        source: PathBuf,
+    fn execute_verify_signature(
+        archive: PathBuf,
+        signature_path: Option<PathBuf>,
+        trusted_keys: Vec<String>,
         format_opt: Option<Format>,
-        debug: bool
+        password: PasswordArgs,
     ) -> Result<()> {
-        let format = Self::identify_format(&format_opt, &source, true)?;
+        let signature_path = signature_path.unwrap_or_else(|| SignatureFile::default_path(&archive));
+        let signed = SignatureFile::read(&signature_path)?;
+        let signer = signed.check()?;
 
-        if debug {
-            Self::log_debug_info(
-                &[source.clone()],
-                None,
-                false,
-                Some(format),
-                None,
-                None
-            );
+        // 任何人都能生成密钥并签名，只有受信任的公钥才能证明来源
+        if trusted_keys.is_empty() {
+            return Err(ZipError::Other("At least one --trusted-key is required".to_string()));
+        }
+        let trusted = trusted_keys.iter().map(|key| SignerKey::load(key)).collect::<Result<Vec<_>>>()?;
+        if !trusted.contains(&signer) {
+            return Err(ZipError::Other(format!("Signed by untrusted key {}", signer)));
         }
 
-        let contents = crate::file_tree::list_archive_contents_json(
-            &source,
-            format.into(),
-            debug
-        )?;
+        let content = &signed.content;
+        let (size, sha256) = signature::hash_file(&archive)?;
+        if size == content.archive_size && sha256 == content.archive_sha256 {
+            println!("{}: signature OK ({})", archive.display(), signer);
+            return Ok(());
+        }
 
-        println!("{}", contents);
+        // 压缩包字节变化时（如重新打包），仍可按清单核对条目内容
+        let Some(expected) = &content.manifest else {
+            return Err(ZipError::Other(format!("{:?} does not match its signature", archive)));
+        };
+
+        let mut codec = Self::manifest_codec(&archive, &format_opt, password)?;
+        let actual = signature::build_manifest(codec.as_mut(), &archive)?;
+
+        let mismatches = signature::compare_manifest(expected, &actual);
+        if !mismatches.is_empty() {
+            return Err(ZipError::Other(format!(
+                "{:?} does not match its signed manifest: {}", archive, mismatches.join(", ")
+            )));
+        }
+
+        println!("{}: archive bytes differ, contents match signed manifest ({})", archive.display(), signer);
         Ok(())
     }
 
@@ -325,21 +1961,33 @@ impl Cli {
                 format,
                 method,
                 password,
+                encryption,
+                recipients,
                 use_external,
                 volume_size,
                 level,
+                checksum,
+                checksum_embed,
+                target_template,
             } => {
-                Self::execute_compress(
-                    target,
-                    source,
+                let target = if target_template {
+                    PathBuf::from(rotate::expand_template(&target.to_string_lossy(), chrono::Local::now())?)
+                } else {
+                    target
+                };
+                let options = CompressOptions {
                     format,
                     method,
                     password,
+                    encryption,
+                    recipients,
                     use_external,
                     volume_size,
-                    self.debug,
                     level,
-                )
+                    checksum,
+                    checksum_embed,
+                };
+                Self::execute_compress(target, source, options, self.debug)
             },
 
             Commands::Extract {
@@ -347,18 +1995,30 @@ impl Cli {
                 source,
                 format,
                 password,
+                identities,
                 use_external,
-                files
+                files,
+                parallel,
+                separate,
+                limits,
+                recursive,
+                unsafe_paths,
+                salvage,
             } => {
-                Self::execute_extract(
-                    target,
-                    source,
+                let options = ExtractCommandOptions {
                     format,
                     password,
+                    identities,
                     use_external,
                     files,
-                    self.debug
-                )
+                    parallel,
+                    separate,
+                    limits,
+                    recursive,
+                    unsafe_paths,
+                    salvage,
+                };
+                Self::execute_extract(target, source, options, self.debug)
             },
 
             Commands::Script {
@@ -377,14 +2037,265 @@ impl Cli {
 
             Commands::List {
                 source,
-                format
+                format,
+                identities,
             } => {
                 Self::execute_list(
                     source,
                     format,
+                    identities,
                     self.debug
                 )
             },
+
+            Commands::Test {
+                source,
+                format,
+                password,
+                identities,
+                use_external,
+            } => {
+                Self::execute_test(
+                    source,
+                    format,
+                    password,
+                    identities,
+                    use_external,
+                )
+            },
+
+            Commands::Keygen { output, sign } => Self::execute_keygen(output, sign),
+
+            Commands::Check {
+                path,
+                manifest,
+                algorithm,
+                format,
+                password,
+            } => {
+                Self::execute_check(
+                    path,
+                    manifest,
+                    algorithm,
+                    format,
+                    password,
+                )
+            },
+
+            Commands::Salvage { source, output, password } => Self::execute_salvage(source, output, password),
+
+            Commands::Parity {
+                files,
+                output,
+                redundancy,
+                block_size,
+            } => Self::execute_parity(files, output, redundancy, block_size),
+
+            Commands::Repair { parity, check_only } => Self::execute_repair(parity, check_only),
+
+            Commands::Add { args } => Self::execute_add(args, false),
+
+            Commands::Update { args } => Self::execute_add(args, true),
+
+            Commands::Delete { archive, names, format } => Self::execute_delete(archive, names, format),
+
+            Commands::Convert {
+                source,
+                output,
+                from,
+                to,
+                method,
+                level,
+                password,
+            } => Self::execute_convert(source, output, from, to, method, level, password),
+
+            Commands::Cat { source, names, format, password } => Self::execute_cat(source, names, format, password),
+
+            Commands::Grep {
+                pattern,
+                archives,
+                ignore_case,
+                glob,
+                files_with_matches,
+                no_nested,
+                limits,
+                jobs,
+                format,
+                password,
+            } => {
+                let options = Self::grep_options(&pattern, ignore_case, &glob, files_with_matches, no_nested, limits, password)?;
+                Self::execute_grep(options, archives, jobs, format)
+            }
+
+            Commands::Diff {
+                old,
+                new,
+                json,
+                ignore_mtime,
+                ignore_mode,
+                password,
+            } => Self::execute_diff(old, new, json, DiffOptions { ignore_mtime, ignore_mode }, password),
+
+            Commands::Backup {
+                source,
+                output,
+                name,
+                format,
+                state,
+                full,
+                password,
+            } => Self::execute_backup(source, output, name, format, state, full, password),
+
+            Commands::Restore { backups, target, name, until, password, limits } => {
+                Self::execute_restore(backups, target, name, until, password, limits)
+            }
+
+            Commands::Rotate { template, daily, weekly, monthly, dry_run } => {
+                Self::execute_rotate(template, RetentionPolicy { daily, weekly, monthly }, dry_run)
+            }
+
+            Commands::Store { command } => Self::execute_store(command),
+
+            Commands::Watch {
+                target,
+                source,
+                format,
+                method,
+                level,
+                password,
+                include,
+                exclude,
+                debounce,
+                update,
+            } => {
+                let options = WatchOptions {
+                    debounce: Duration::from_millis(debounce),
+                    update,
+                    filter: PathFilter::new(&include, &exclude)?,
+                };
+                Self::execute_watch(target, source, format, method, level, password, options)
+            }
+
+            Commands::Batch { job_file, jobs, keep_going, report } => {
+                Self::execute_batch(job_file, jobs, keep_going, report, self.debug)
+            }
+
+            Commands::Sign {
+                archive,
+                key,
+                manifest,
+                output,
+                format,
+                password,
+            } => {
+                Self::execute_sign(
+                    archive,
+                    key,
+                    manifest,
+                    output,
+                    format,
+                    password,
+                )
+            },
+
+            Commands::VerifySignature {
+                archive,
+                signature,
+                trusted_key,
+                format,
+                password,
+            } => {
+                Self::execute_verify_signature(
+                    archive,
+                    signature,
+                    trusted_key,
+                    format,
+                    password,
+                )
+            },
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_fd_zero_conflicts_with_stdin_data() {
+        let cli = Cli::try_parse_from(["cazip", "extract", "out", "-", "--password-fd", "0"]).unwrap();
+        let Commands::Extract { password, .. } = cli.command else { panic!("not extract") };
+
+        assert!(password.reject_stdin_fd(&[PathBuf::from("-")]).is_err());
+        assert!(password.reject_stdin_fd(&[PathBuf::from("a.zip")]).is_ok());
+    }
+
+    #[test]
+    fn batch_jobs_writing_to_stdout_need_a_report_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let job_file = dir.path().join("jobs.json");
+        let never = dir.path().join("never-created");
+        fs::write(&job_file, format!(
+            r#"{{"jobs": [{{"command": "compress", "args": ["-", "{}"]}}, {{"command": "extract", "args": ["-", "x.zip"]}}]}}"#,
+            job_file.display()
+        )).unwrap();
+
+        let error = Cli::execute_batch(job_file.clone(), 1, false, None, false).unwrap_err();
+        assert!(error.to_string().contains("--report"), "{}", error);
+        let error = Cli::execute_batch(job_file, 2, false, Some(never.clone()), false).unwrap_err();
+        assert!(error.to_string().contains("one at a time"), "{}", error);
+        assert!(!never.exists());
+
+        // test results go to stdout too, but several test jobs may run side by side
+        let test_jobs = dir.path().join("tests.json");
+        let missing = dir.path().join("missing.zip");
+        fs::write(&test_jobs, format!(
+            r#"{{"jobs": [{{"command": "test", "args": ["{0}"]}}, {{"command": "test", "args": ["{0}"]}}]}}"#,
+            missing.display()
+        )).unwrap();
+        let error = Cli::execute_batch(test_jobs.clone(), 2, true, None, false).unwrap_err();
+        assert!(error.to_string().contains("--report"), "{}", error);
+        let error = Cli::execute_batch(test_jobs, 2, true, Some(never.clone()), false).unwrap_err();
+        assert!(error.to_string().contains("2 of 2 jobs failed"), "{}", error);
+        assert!(never.exists());
+    }
+
+    #[test]
+    fn extracts_several_archives_into_their_own_directories() {
+        use std::io::Write;
+
+        let dir = tempfile::tempdir().unwrap();
+        let archive = |name: &str, data: &[u8]| {
+            let path = dir.path().join(name);
+            let mut zip = zip::ZipWriter::new(File::create(&path).unwrap());
+            zip.start_file("x.txt", zip::write::SimpleFileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+            zip.finish().unwrap();
+            path
+        };
+        let (a, b) = (archive("a.zip", b"from a"), archive("b.zip", b"from b"));
+
+        let target = dir.path().join("out");
+        let args = [target.as_path(), &a, &b, &dir.path().join("missing.zip")];
+        let cli = Cli::try_parse_from(
+            ["cazip", "extract"].iter().map(Path::new).chain(args).chain([Path::new("--separate"), Path::new("--parallel")])
+        ).unwrap();
+
+        // One bad archive is reported without stopping the others
+        let error = cli.execute().unwrap_err();
+        assert!(error.to_string().contains("1 of 3 archives failed"), "{}", error);
+        assert_eq!(fs::read_to_string(target.join("a/x.txt")).unwrap(), "from a");
+        assert_eq!(fs::read_to_string(target.join("b/x.txt")).unwrap(), "from b");
+        assert_eq!(utils::archive_stem(Path::new("dir/c.tar.xz")), "c");
+    }
+
+    #[test]
+    fn a_missing_source_is_an_error_rather_than_stdin() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("out.zip");
+        for args in [vec!["cazip", "compress", target.to_str().unwrap()], vec!["cazip", "test"]] {
+            let error = Cli::try_parse_from(args).unwrap().execute().unwrap_err();
+            assert!(error.to_string().contains("No source files specified"), "{}", error);
         }
+        assert!(!target.exists());
     }
-}
\ No newline at end of file
+}
@@ -1,13 +1,41 @@
+use crate::codecs::limits::{ExtractLimits, LimitTracker, RatioBase};
+use crate::codecs::safe_path::{PathGuard, PathPolicy};
 use crate::codecs::{Codec, Format};
-use crate::utils::{ensure_directory_exists, is_tar_file};
+use crate::utils::{ensure_directory_exists, is_stdio};
 use crate::{Result, ZipError};
-use log::{error, info};
-use std::fs;
-use std::io::{self, BufRead, BufReader};
-use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use log::{debug, error, info};
+use sevenz_rust2::{Password, SevenZReader};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::ptr;
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+use walkdir::WalkDir;
+use zip::ZipArchive;
+
+/// How often a running tool's staging directory is measured
+const STAGING_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Total size limit on the staging directory an external tool extracts into
+#[derive(Clone, Copy)]
+struct StagingWatch<'a> {
+    dir: &'a Path,
+    max_bytes: u64,
+}
+
+impl StagingWatch<'_> {
+    fn exceeded(&self) -> bool {
+        let written: u64 = WalkDir::new(self.dir)
+            .into_iter()
+            .filter_map(|entry| entry.ok()?.metadata().ok())
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+            .sum();
+        written > self.max_bytes
+    }
+}
 
 /// External command line tools implementation
 pub struct CommandLineCodec {
@@ -15,6 +43,8 @@ pub struct CommandLineCodec {
     method: Option<String>,
     password: Option<String>,
     volume_size: Option<usize>,
+    limits: ExtractLimits,
+    path_policy: PathPolicy,
 }
 
 impl CommandLineCodec {
@@ -30,83 +60,423 @@ impl CommandLineCodec {
             method: method.map(String::from),
             password,
             volume_size,
+            limits: ExtractLimits::default(),
+            path_policy: PathPolicy::default(),
+        }
+    }
+
+    /// External tools are driven by path, so `-` is only handled by the native codecs
+    fn reject_stdio(source: &[&Path], target: &Path) -> Result<()> {
+        if is_stdio(target) || source.iter().any(|p| is_stdio(p)) {
+            return Err(ZipError::UnsupportedOperation(
+                "stdin/stdout streaming is not supported with external tools".to_string()
+            ));
         }
+        Ok(())
+    }
+
+    /// Create a staging directory inside `target` for an external tool to extract into
+    fn staging_dir(target: &Path) -> Result<tempfile::TempDir> {
+        Ok(tempfile::Builder::new().prefix(".crate-staging-").tempdir_in(target)?)
+    }
+
+    /// Check entry names of formats with a cheap index before an external tool
+    /// sees them; tarballs rely on tar's own refusal plus the staging check
+    fn prescan_paths(&self, source: &Path, target: &Path) -> Result<()> {
+        let names: Vec<String> = match self.format {
+            Format::Zip => match ZipArchive::new(File::open(source)?) {
+                Ok(archive) => archive.file_names().map(String::from).collect(),
+                Err(e) => {
+                    debug!("Skipping path pre-scan of {:?}: {}", source, e);
+                    return Ok(());
+                }
+            },
+            Format::SevenZ => {
+                let password = self.password.as_deref().map(Password::from).unwrap_or_else(Password::empty);
+                match SevenZReader::open(source, password) {
+                    Ok(archive) => archive.archive().files.iter().map(|f| f.name().to_string()).collect(),
+                    Err(e) => {
+                        debug!("Skipping path pre-scan of {:?}: {}", source, e);
+                        return Ok(());
+                    }
+                }
+            }
+            _ => return Ok(()),
+        };
+
+        let guard = PathGuard::new(target, self.path_policy)?;
+        for name in &names {
+            guard.resolve(name);
+        }
+
+        // Under sanitize the tools' own rewriting is kept; the scan only reports
+        match self.path_policy {
+            PathPolicy::Reject => guard.finish(),
+            PathPolicy::Sanitize => Ok(()),
+        }
+    }
+
+    /// Move an external tool's output from staging into `target` once it is
+    /// within limits and free of escaping symlinks
+    fn commit_staging(&self, staging: &Path, target: &Path) -> Result<()> {
+        let tracker = LimitTracker::new(self.limits);
+        let guard = PathGuard::new(staging, self.path_policy)?;
+
+        Self::check_staged(&tracker, &guard, guard.root(), guard.root())?;
+        if self.path_policy == PathPolicy::Reject {
+            guard.finish()?;
+        }
+
+        Self::merge_into(staging, target)
+    }
+
+    fn check_staged(tracker: &LimitTracker, guard: &PathGuard, root: &Path, dir: &Path) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let name = path.strip_prefix(root)?.to_string_lossy().into_owned();
+            let metadata = entry.metadata()?;
+
+            tracker.begin_entry(&name)?;
+
+            if metadata.is_symlink() {
+                if !guard.allow_link(&name, &path, &fs::read_link(&path)?) {
+                    fs::remove_file(&path)?;
+                }
+            } else if metadata.is_dir() {
+                Self::check_staged(tracker, guard, root, &path)?;
+            } else {
+                tracker.add_bytes(&name, metadata.len())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Decompress a plain `.xz` file into `target` through `xz -dc`, so its
+    /// output is limited while it streams as in the native codec
+    fn unxz(&self, source: &Path, target: &Path) -> Result<()> {
+        let name = source.file_stem().unwrap_or_default().to_string_lossy().into_owned();
+        let guard = PathGuard::new(target, self.path_policy)?;
+        let Some(path) = guard.resolve(&name) else {
+            return guard.finish();
+        };
+
+        let tracker = LimitTracker::new(self.limits);
+        tracker.begin_entry(&name)?;
+        let compressed = fs::metadata(source)?.len();
+
+        let mut cmd = Command::new("xz");
+        cmd.arg("-d");
+        cmd.arg("-c");
+        cmd.arg(source);
+
+        Self::run_command_piped(cmd, |output| {
+            let mut file = tracker.create_file(&path)?;
+            tracker.copy(&name, output, &mut file, RatioBase::Entry(compressed))?;
+            Ok(())
+        })
+    }
+
+    /// Watch for staging to grow past the total size limit while a tool runs
+    fn staging_watch<'a>(&self, staging: &'a Path) -> Option<StagingWatch<'a>> {
+        self.limits.max_total_size.map(|max_bytes| StagingWatch { dir: staging, max_bytes })
+    }
+
+    /// Move the contents of `from` into `to`, overwriting files but never
+    /// replacing a directory with a file
+    fn merge_into(from: &Path, to: &Path) -> Result<()> {
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            let dest = to.join(entry.file_name());
+
+            // Existing links in the target are replaced, never followed
+            match fs::symlink_metadata(&dest).map(|m| m.file_type()) {
+                Ok(existing) if existing.is_dir() && entry.file_type()?.is_dir() => {
+                    Self::merge_into(&entry.path(), &dest)?;
+                    continue;
+                }
+                Ok(existing) if existing.is_dir() => {
+                    return Err(ZipError::Other(format!(
+                        "Cannot extract file {:?}: a directory with that name already exists", dest
+                    )));
+                }
+                Ok(_) => fs::remove_file(&dest)?,
+                Err(_) => {}
+            }
+            fs::rename(entry.path(), &dest)?;
+        }
+        Ok(())
     }
 
     /// Run a command with logging
-    fn run_command_with_logging(mut cmd: Command) -> Result<()> {
+    fn run_command_with_logging(cmd: Command) -> Result<()> {
+        Self::run_command(cmd, None, None)
+    }
+
+    /// Run a command with logging, answering its password prompts over stdin.
+    /// `prompts` is how many times the tool asks (7z asks twice when creating).
+    fn run_command_with_password(cmd: Command, password: &str, prompts: usize, watch: Option<StagingWatch>) -> Result<()> {
+        Self::run_command(cmd, Some(format!("{}\n", password).repeat(prompts)), watch)
+    }
+
+    /// Run 7z, passing the password (if any) through its prompt
+    fn run_7z(&self, cmd: Command, prompts: usize, watch: Option<StagingWatch>) -> Result<()> {
+        match self.password {
+            Some(ref password) => Self::run_command_with_password(cmd, password, prompts, watch),
+            None => Self::run_command(cmd, None, watch),
+        }
+    }
+
+    fn run_command(mut cmd: Command, input: Option<String>, watch: Option<StagingWatch>) -> Result<()> {
         info!("Running command: {:?}", cmd);
 
+        if input.is_some() {
+            cmd.stdin(Stdio::piped());
+
+            // Tools that prompt through getpass() read /dev/tty when they have one;
+            // a new session has no controlling terminal, so they fall back to stdin
+            #[cfg(unix)]
+            unsafe {
+                use std::os::unix::process::CommandExt;
+                cmd.pre_exec(|| {
+                    libc::setsid();
+                    Ok(())
+                });
+            }
+        }
+
         let mut child = cmd
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .spawn()?;
 
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+            // The tool may exit before reading everything, e.g. on a bad archive
+            stdin.write_all(input.as_bytes()).ok();
+        }
+
         let stdout = child.stdout.take().unwrap();
         let stderr = child.stderr.take().unwrap();
 
         let stdout_thread = thread::spawn(move || {
             let reader = BufReader::new(stdout);
 
-            for line in reader.lines() {
-                if let Ok(line) = line {
-                    info!("{}", line);
-                }
+            for line in reader.lines().map_while(io::Result::ok) {
+                info!("{}", line);
             }
         });
 
         let stderr_thread = thread::spawn(move || {
             let reader = BufReader::new(stderr);
 
-            for line in reader.lines() {
-                if let Ok(line) = line {
-                    error!("{}", line);
-                }
+            for line in reader.lines().map_while(io::Result::ok) {
+                error!("{}", line);
             }
         });
 
-        let status = child.wait()?;
+        let status = Self::wait_watched(&mut child, watch);
 
+        // Killing the tool closes its output, which ends the logging threads
         stdout_thread.join().unwrap();
         stderr_thread.join().unwrap();
 
+        Self::check_status(status?)
+    }
+
+    /// Run a command, handing its stdout to `consume` and logging its stderr.
+    /// The command is stopped if `consume` fails.
+    fn run_command_piped(mut cmd: Command, consume: impl FnOnce(&mut dyn Read) -> Result<()>) -> Result<()> {
+        info!("Running command: {:?}", cmd);
+
+        let mut child = cmd
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let stderr = child.stderr.take().unwrap();
+        let stderr_thread = thread::spawn(move || {
+            let reader = BufReader::new(stderr);
+
+            for line in reader.lines().map_while(io::Result::ok) {
+                error!("{}", line);
+            }
+        });
+
+        let mut stdout = child.stdout.take().unwrap();
+        let consumed = consume(&mut stdout);
+        if consumed.is_err() {
+            child.kill().ok();
+        }
+        drop(stdout);
+
+        let status = child.wait();
+        stderr_thread.join().unwrap();
+
+        consumed?;
+        Self::check_status(status?)
+    }
+
+    /// Run zip or unzip, answering their password prompts on a pseudo-terminal
+    /// so the password never shows up in the process arguments
+    #[cfg(unix)]
+    fn run_info_zip(&self, mut cmd: Command, watch: Option<StagingWatch>) -> Result<()> {
+        use std::os::fd::{FromRawFd, OwnedFd};
+        use std::os::unix::process::CommandExt;
+
+        let Some(password) = &self.password else {
+            return Self::run_command(cmd, None, watch);
+        };
+        info!("Running command: {:?}", cmd);
+
+        let (mut master, mut slave) = (-1, -1);
+        // SAFETY: openpty only writes the two descriptors it opens
+        if unsafe { libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null(), ptr::null()) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        // SAFETY: both descriptors were just opened and nothing else owns them
+        let (terminal, slave) = unsafe { (File::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+
+        cmd.stdin(Stdio::from(slave.try_clone()?))
+            .stdout(Stdio::from(slave.try_clone()?))
+            .stderr(Stdio::from(slave));
+        // SAFETY: setsid and ioctl are async-signal-safe
+        unsafe {
+            cmd.pre_exec(|| {
+                // A new session whose controlling terminal is the pty, where getpass() reads
+                if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+
+        let mut child = cmd.spawn()?;
+        // Only the child holds the terminal side now, so reading ends when it exits
+        drop(cmd);
+
+        let answer = format!("{}\n", password);
+        let output_thread = thread::spawn(move || answer_prompts(terminal, &answer));
+        let status = Self::wait_watched(&mut child, watch);
+        output_thread.join().unwrap();
+
+        Self::check_status(status?)
+    }
+
+    #[cfg(not(unix))]
+    fn run_info_zip(&self, cmd: Command, watch: Option<StagingWatch>) -> Result<()> {
+        if self.password.is_some() {
+            return Err(ZipError::UnsupportedOperation(
+                "zip/unzip can only be given a password safely on Unix; use the native backend".to_string()
+            ));
+        }
+        Self::run_command(cmd, None, watch)
+    }
+
+    /// Wait for `child`, stopping it if staging grows past the watch's limit
+    fn wait_watched(child: &mut Child, watch: Option<StagingWatch>) -> Result<ExitStatus> {
+        let Some(watch) = watch else {
+            return Ok(child.wait()?);
+        };
+
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status);
+            }
+            if watch.exceeded() {
+                child.kill().ok();
+                child.wait()?;
+                return Err(ZipError::LimitExceeded(format!(
+                    "external tool wrote more than {} bytes; it was stopped", watch.max_bytes
+                )));
+            }
+            thread::sleep(STAGING_POLL_INTERVAL);
+        }
+    }
+
+    fn check_status(status: ExitStatus) -> Result<()> {
         if !status.success() {
             return Err(ZipError::ExternalCommand(format!(
                 "Command failed with status: {}",
                 status
             )));
         }
-
         Ok(())
     }
 }
 
+/// Log a tool's terminal output and type `answer` at each password prompt,
+/// once the tool has turned echo off
+#[cfg(unix)]
+fn answer_prompts(mut terminal: File, answer: &str) {
+    let mut pending = Vec::new();
+    let mut buffer = [0_u8; 4096];
+
+    // Reading fails with EIO once the tool has exited and closed its side
+    while let Ok(read @ 1..) = terminal.read(&mut buffer) {
+        pending.extend_from_slice(&buffer[..read]);
+
+        while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            if !line.trim().is_empty() {
+                info!("{}", line.trim_end());
+            }
+        }
+
+        let prompt = String::from_utf8_lossy(&pending).to_lowercase();
+        if prompt.contains("password") && prompt.trim_end().ends_with(':') {
+            debug!("Answering prompt {:?}", prompt.trim());
+            wait_for_echo_off(&terminal);
+            if terminal.write_all(answer.as_bytes()).is_err() {
+                break;
+            }
+            pending.clear();
+        }
+    }
+}
+
+#[cfg(unix)]
+fn wait_for_echo_off(terminal: &File) {
+    use std::os::fd::AsRawFd;
+
+    for _ in 0..200 {
+        // SAFETY: termios is plain data that tcgetattr fills in
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(terminal.as_raw_fd(), &mut termios) } != 0 || termios.c_lflag & libc::ECHO == 0 {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
 impl Codec for CommandLineCodec {
     fn extract(&mut self, source: &[&Path], target: &Path) -> Result<()> {
+        Self::reject_stdio(source, target)?;
         let start = Instant::now();
 
         ensure_directory_exists(target)?;
+        self.prescan_paths(source[0], target)?;
+
+        let staging = Self::staging_dir(target)?;
+        let (target, final_target) = (staging.path(), target);
+        let watch = self.staging_watch(target);
 
         match self.format {
             Format::Zip => {
                 let mut cmd = Command::new("unzip");
 
-                if let Some(ref pwd) = self.password {
-                    cmd.arg("-P").arg(pwd);
-                }
-
                 cmd.arg("-o");
                 cmd.arg(source[0]);
                 cmd.arg("-d").arg(target);
 
-                Self::run_command_with_logging(cmd)?;
+                self.run_info_zip(cmd, watch)?;
             }
             Format::SevenZ => {
                 let mut cmd = Command::new("7z");
                 cmd.arg("x");
 
-                if let Some(ref pwd) = self.password {
-                    cmd.arg(format!("-p{{{}}}", pwd));
+                if self.password.is_some() {
+                    cmd.arg("-p");
                 }
 
                 cmd.arg("-mmt12");
@@ -115,41 +485,33 @@ impl Codec for CommandLineCodec {
                 cmd.arg(source[0]);
                 cmd.arg(format!("-o{}", target.display()));
 
-                Self::run_command_with_logging(cmd)?;
+                self.run_7z(cmd, 1, watch)?;
             }
             Format::Xz => {
                 let source_path = source[0];
                 let filename = source_path.to_string_lossy();
 
-                if filename.ends_with(".tar.xz") || source_path.extension().map_or(false, |ext| ext == "txz") {
+                if filename.ends_with(".tar.xz") || source_path.extension().is_some_and(|ext| ext == "txz") {
                     // 直接用 tar 解包，tar 会自动识别 xz 压缩
                     let mut cmd = Command::new("tar");
                     cmd.arg("-xvf");
                     cmd.arg(source_path);
                     cmd.arg("-C").arg(target);
-                    Self::run_command_with_logging(cmd)?;
+                    Self::run_command(cmd, None, watch)?;
                 } else {
                     // 普通 .xz 文件
-                    let mut cmd = Command::new("xz");
-                    cmd.arg("-d");
-                    cmd.arg("-v");
-                    cmd.arg("-k");
-                    cmd.arg(source_path);
-                    Self::run_command_with_logging(cmd)?;
-
-                    // Get the uncompressed file path
-                    let source_stem = source_path.file_stem().unwrap_or_default();
-                    let source_dir = source_path.parent().unwrap_or(Path::new("."));
-                    let uncompressed = source_dir.join(source_stem);
-
-                    let target_file = if target.is_dir() {
-                        target.join(source_stem)
-                    } else {
-                        target.to_path_buf()
-                    };
-                    info!("Copying uncompressed file to: {:?}", target_file);
-                    fs::copy(uncompressed, target_file)?;
+                    self.unxz(source_path, target)?;
+                }
+            }
+            Format::Tar | Format::Zst => {
+                let mut cmd = Command::new("tar");
+                if self.format == Format::Zst {
+                    cmd.arg("--zstd");
                 }
+                cmd.arg("-xvf");
+                cmd.arg(source[0]);
+                cmd.arg("-C").arg(target);
+                Self::run_command(cmd, None, watch)?;
             }
             Format::Gz => {
                 return Err(ZipError::UnsupportedOperation(
@@ -158,6 +520,8 @@ impl Codec for CommandLineCodec {
             }
         }
 
+        self.commit_staging(target, final_target)?;
+
         info!(
             "Extraction completed in {:?} ms / {:?} s",
             start.elapsed().as_millis(),
@@ -168,18 +532,20 @@ impl Codec for CommandLineCodec {
     }
 
     fn extract_parts(&mut self, source: &[&Path], target: &Path, parts: &[String]) -> Result<()> {
+        Self::reject_stdio(source, target)?;
         let start = Instant::now();
 
         ensure_directory_exists(target)?;
+        self.prescan_paths(source[0], target)?;
+
+        let staging = Self::staging_dir(target)?;
+        let (target, final_target) = (staging.path(), target);
+        let watch = self.staging_watch(target);
 
         match self.format {
             Format::Zip => {
                 let mut cmd = Command::new("unzip");
 
-                if let Some(ref pwd) = self.password {
-                    cmd.arg("-P").arg(pwd);
-                }
-
                 cmd.arg("-o");
                 cmd.arg(source[0]);
                 cmd.arg("-d").arg(target);
@@ -194,14 +560,14 @@ impl Codec for CommandLineCodec {
                     cmd.arg(&part_str);
                 }
                 
-                Self::run_command_with_logging(cmd)?;
+                self.run_info_zip(cmd, watch)?;
             }
             Format::SevenZ => {
                 let mut cmd = Command::new("7z");
                 cmd.arg("x");
 
-                if let Some(ref pwd) = self.password {
-                    cmd.arg(format!("-p{{{}}}", pwd));
+                if self.password.is_some() {
+                    cmd.arg("-p");
                 }
 
                 cmd.arg("-mmt12");
@@ -214,13 +580,13 @@ impl Codec for CommandLineCodec {
                     cmd.arg(part);
                 }
                 
-                Self::run_command_with_logging(cmd)?;
+                self.run_7z(cmd, 1, watch)?;
             }
             Format::Xz => {
                 let source_path = source[0];
                 let filename = source_path.to_string_lossy();
 
-                if filename.ends_with(".tar.xz") || source_path.extension().map_or(false, |ext| ext == "txz") {
+                if filename.ends_with(".tar.xz") || source_path.extension().is_some_and(|ext| ext == "txz") {
                     // For tar.xz files
                     let mut cmd = Command::new("tar");
                     cmd.arg("-xvf");
@@ -231,31 +597,26 @@ impl Codec for CommandLineCodec {
                         cmd.arg(part);
                     }
                     
-                    Self::run_command_with_logging(cmd)?;
+                    Self::run_command(cmd, None, watch)?;
                 } else {
                     // For .xz files (single file compression)
-                    let mut cmd = Command::new("xz");
-                    cmd.arg("-d");
-                    cmd.arg("-v");
-                    cmd.arg("-k");
-                    cmd.arg(source_path);
+                    self.unxz(source_path, target)?;
+                }
+            }
+            Format::Tar | Format::Zst => {
+                let mut cmd = Command::new("tar");
+                if self.format == Format::Zst {
+                    cmd.arg("--zstd");
+                }
+                cmd.arg("-xvf");
+                cmd.arg(source[0]);
+                cmd.arg("-C").arg(target);
 
-                    Self::run_command_with_logging(cmd)?;
-
-                    // Get the uncompressed file path
-                    let source_stem = source_path.file_stem().unwrap_or_default();
-                    let source_dir = source_path.parent().unwrap_or(Path::new("."));
-                    let uncompressed = source_dir.join(source_stem);
-
-                    // 直接copy，不再判断是否tar
-                    let target_file = if target.is_dir() {
-                        target.join(source_stem)
-                    } else {
-                        target.to_path_buf()
-                    };
-                    info!("Copying uncompressed file to: {:?}", target_file);
-                    fs::copy(uncompressed, target_file)?;
+                for part in parts {
+                    cmd.arg(part);
                 }
+
+                Self::run_command(cmd, None, watch)?;
             }
             Format::Gz => {
                 return Err(ZipError::UnsupportedOperation(
@@ -264,6 +625,8 @@ impl Codec for CommandLineCodec {
             }
         }
 
+        self.commit_staging(target, final_target)?;
+
         info!(
             "Extraction completed in {:?} ms / {:?} s",
             start.elapsed().as_millis(),
@@ -274,12 +637,13 @@ impl Codec for CommandLineCodec {
     }
     
     fn compress(&mut self, source: &[&Path], target: &Path, exclude: Option<&[&Path]>) -> Result<()> {
+        Self::reject_stdio(source, target)?;
         let start = Instant::now();
 
-        if let Some(parent) = target.parent() {
-            if !parent.exists() {
-                fs::create_dir_all(parent)?;
-            }
+        if let Some(parent) = target.parent()
+            && !parent.exists()
+        {
+            fs::create_dir_all(parent)?;
         }
 
         match self.format {
@@ -287,16 +651,17 @@ impl Codec for CommandLineCodec {
                 let mut cmd = Command::new("zip");
                 cmd.arg("-r");
                 cmd.arg("-v");
+
+                // The password is typed at zip's prompt, never passed as an argument
+                if self.password.is_some() {
+                    cmd.arg("-e");
+                }
                 
                 // Set compression level if using deflate
-                if self.method.as_deref() == Some("deflated") {
+                if matches!(self.method.as_deref(), Some("deflate" | "deflated")) {
                     cmd.arg("-9");
                 }
 
-                if let Some(ref pwd) = self.password {
-                    cmd.arg("-e");
-                    cmd.arg("-P").arg(pwd);
-                }
 
                 if let Some(size_mb) = self.volume_size {
                     cmd.arg("-s").arg(format!("{}m", size_mb));
@@ -316,7 +681,7 @@ impl Codec for CommandLineCodec {
                     cmd.arg(path);
                 }
 
-                Self::run_command_with_logging(cmd)?;
+                self.run_info_zip(cmd, None)?;
             }
             Format::SevenZ => {
                 let mut cmd = Command::new("7z");
@@ -324,8 +689,8 @@ impl Codec for CommandLineCodec {
                 cmd.arg("-mmt12");
                 cmd.arg("-bb3");
 
-                if let Some(ref pwd) = self.password {
-                    cmd.arg(format!("-p{{{}}}", pwd));
+                if self.password.is_some() {
+                    cmd.arg("-p");
                 }
 
                 if let Some(size_mb) = self.volume_size {
@@ -344,7 +709,7 @@ impl Codec for CommandLineCodec {
                     cmd.arg(path);
                 }
 
-                Self::run_command_with_logging(cmd)?;
+                self.run_7z(cmd, 2, None)?;
             }
             Format::Xz => {
                 if source.len() > 1 || source[0].is_dir() {
@@ -385,23 +750,38 @@ impl Codec for CommandLineCodec {
                         fs::rename(xz_path, target)?;
                     }
                 } else {
-                    // Compress a single file
+                    // Compress a single file straight into the target
                     let mut cmd = Command::new("xz");
-                    cmd.arg("-k");
-                    cmd.arg("-f");
-                    cmd.arg("-v");
+                    cmd.arg("-c");
                     cmd.arg("-T").arg("12");
                     cmd.arg(source[0]);
 
-                    Self::run_command_with_logging(cmd)?;
+                    Self::run_command_piped(cmd, |output| {
+                        io::copy(output, &mut File::create(target)?)?;
+                        Ok(())
+                    })?;
+                }
+            }
+            Format::Tar | Format::Zst => {
+                let mut cmd = Command::new("tar");
+                if self.format == Format::Zst {
+                    cmd.arg("--zstd");
+                }
+                cmd.arg("-cvf");
+                cmd.arg(target);
 
-                    // Move the compressed file to the target path
-                    let compressed = PathBuf::from(source[0].to_string_lossy().into_owned() + ".xz");
-                    if compressed != target {
-                        info!("Renaming {:?} to {:?}", compressed, target);
-                        fs::rename(compressed, target)?;
+                if let Some(exclude_paths) = exclude {
+                    for path in exclude_paths {
+                        cmd.arg("--exclude");
+                        cmd.arg(path);
                     }
                 }
+
+                for path in source {
+                    cmd.arg(path);
+                }
+
+                Self::run_command_with_logging(cmd)?;
             }
             Format::Gz => {
                 return Err(ZipError::UnsupportedOperation(
@@ -422,5 +802,101 @@ impl Codec for CommandLineCodec {
     fn compression_level_range(&self) -> (u8, u8) {
         (0, 9)
     }
+    fn set_limits(&mut self, limits: ExtractLimits) {
+        self.limits = limits;
+    }
+
+    fn set_path_policy(&mut self, policy: PathPolicy) {
+        self.path_policy = policy;
+    }
+
     fn set_compression_level(&mut self, _level: u8) {}
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merging_never_replaces_a_directory_with_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let (staging, target) = (dir.path().join("staging"), dir.path().join("target"));
+        fs::create_dir_all(&staging).unwrap();
+        fs::create_dir_all(target.join("docs")).unwrap();
+        fs::write(target.join("docs/keep.txt"), "mine").unwrap();
+        fs::write(target.join("old.txt"), "old").unwrap();
+
+        fs::write(staging.join("old.txt"), "new").unwrap();
+        fs::write(staging.join("docs"), "a file").unwrap();
+
+        assert!(CommandLineCodec::merge_into(&staging, &target).is_err());
+        assert_eq!(fs::read_to_string(target.join("docs/keep.txt")).unwrap(), "mine");
+        assert_eq!(fs::read_to_string(target.join("old.txt")).unwrap(), "new");
+    }
+
+    #[test]
+    fn external_output_over_the_size_limit_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, archive, target) = (dir.path().join("big"), dir.path().join("big.tar"), dir.path().join("out"));
+        fs::write(&source, vec![0_u8; 4 << 20]).unwrap();
+        let mut builder = tar::Builder::new(File::create(&archive).unwrap());
+        builder.append_path_with_name(&source, "big").unwrap();
+        builder.finish().unwrap();
+
+        let mut codec = CommandLineCodec::new(Format::Tar, None, None, None);
+        codec.set_limits(ExtractLimits { max_total_size: Some(1 << 20), ..ExtractLimits::default() });
+        assert!(matches!(codec.extract(&[&archive], &target), Err(ZipError::LimitExceeded(_))));
+        assert_eq!(fs::read_dir(&target).unwrap().count(), 0);
+
+        // The tool may finish before the first poll; the watch itself sees the same overflow
+        assert!(StagingWatch { dir: dir.path(), max_bytes: 1 << 20 }.exceeded());
+        assert!(!StagingWatch { dir: dir.path(), max_bytes: 64 << 20 }.exceeded());
+    }
+
+    #[test]
+    fn plain_xz_files_stream_into_target_within_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, archive) = (dir.path().join("big"), dir.path().join("big.xz"));
+        // Text-like data compresses well, but not past the ratio limit
+        let data: Vec<u8> = (0..4_u32 << 20).map(|i| b'a' + (i.wrapping_mul(2_654_435_761) >> 27) as u8).collect();
+        fs::write(&source, &data).unwrap();
+        CommandLineCodec::new(Format::Xz, None, None, None).compress(&[&source], &archive, None).unwrap();
+        fs::remove_file(&source).unwrap();
+
+        let out = dir.path().join("out");
+        CommandLineCodec::new(Format::Xz, None, None, None).extract(&[&archive], &out).unwrap();
+        assert!(fs::read(out.join("big")).unwrap() == data);
+
+        let mut codec = CommandLineCodec::new(Format::Xz, None, None, None);
+        codec.set_limits(ExtractLimits { max_total_size: Some(1 << 20), ..ExtractLimits::default() });
+        let limited = dir.path().join("limited");
+        assert!(matches!(codec.extract(&[&archive], &limited), Err(ZipError::LimitExceeded(_))));
+        assert_eq!(fs::read_dir(&limited).unwrap().count(), 0);
+
+        // Nothing is written beside the source archive
+        assert!(!source.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn zip_passwords_are_typed_at_the_prompt() {
+        use crate::codecs::zip::{CompressionMethod, ZipCodec};
+
+        let dir = tempfile::tempdir().unwrap();
+        let (source, archive) = (dir.path().join("secret.txt"), dir.path().join("secret.zip"));
+        fs::write(&source, "classified").unwrap();
+
+        let password = Some("hunter2".to_string());
+        CommandLineCodec::new(Format::Zip, None, password.clone(), None).compress(&[&source], &archive, None).unwrap();
+
+        // Really encrypted: the native backend needs the same password
+        assert!(ZipCodec::new(CompressionMethod::Deflated, None, None).extract(&[&archive], &dir.path().join("none")).is_err());
+
+        let out = dir.path().join("out");
+        CommandLineCodec::new(Format::Zip, None, password, None).extract(&[&archive], &out).unwrap();
+        let extracted = WalkDir::new(&out).into_iter().filter_map(|e| e.ok()).find(|e| e.file_name() == "secret.txt").unwrap();
+        assert_eq!(fs::read_to_string(extracted.path()).unwrap(), "classified");
+
+        let wrong = CommandLineCodec::new(Format::Zip, None, Some("wrong".to_string()), None).extract(&[&archive], &dir.path().join("bad"));
+        assert!(wrong.is_err());
+    }
+}
@@ -0,0 +1,140 @@
+use crate::codecs::{Codec, EntryLink, EntryWriter};
+use crate::Result;
+use log::{info, warn};
+use std::io;
+use std::path::Path;
+
+/// What a conversion wrote and what the target format could not keep
+#[derive(Default)]
+pub struct ConversionReport {
+    pub written: usize,
+    /// Entries the target format cannot hold at all, with what kind they are
+    pub skipped: Vec<(String, &'static str)>,
+    /// Entries written without their modification time
+    pub lost_times: usize,
+    /// Entries written without their permissions
+    pub lost_modes: usize,
+}
+
+impl ConversionReport {
+    /// Warn about everything `format` had to drop
+    pub fn warn_losses(&self, format: &str) {
+        for (name, kind) in &self.skipped {
+            warn!("Left out {}: {} cannot store a {}", name, format, kind);
+        }
+        if self.lost_times > 0 {
+            warn!("{} entries lost their modification time; {} does not store it", self.lost_times, format);
+        }
+        if self.lost_modes > 0 {
+            warn!("{} entries lost their permissions; {} does not store them", self.lost_modes, format);
+        }
+    }
+}
+
+/// Stream every entry of `source` from `reader` into `writer` in one pass without
+/// touching the disk, leaving out entries the target format cannot hold
+pub fn convert(reader: &mut dyn Codec, source: &Path, mut writer: Box<dyn EntryWriter + '_>) -> Result<ConversionReport> {
+    let capabilities = writer.capabilities();
+    let mut report = ConversionReport::default();
+
+    reader.visit_entries(source, &mut |meta, data| {
+        let unsupported = match &meta.link {
+            Some(EntryLink::Symbolic(_)) if !capabilities.symlinks => Some("symbolic link"),
+            Some(EntryLink::Hard(_)) if !capabilities.hard_links => Some("hard link"),
+            None if meta.is_dir && !capabilities.directories => Some("directory"),
+            _ => None,
+        };
+
+        if let Some(kind) = unsupported {
+            // Entries of a solid block share one stream, so skipped data still has to be consumed
+            io::copy(data, &mut io::sink())?;
+            report.skipped.push((meta.name.clone(), kind));
+            return Ok(());
+        }
+
+        info!("Converting: {}", meta.name);
+        writer.write_entry(meta, data)?;
+
+        report.written += 1;
+        if meta.modified.is_some() && !capabilities.modified {
+            report.lost_times += 1;
+        }
+        if meta.mode.is_some() && !capabilities.mode {
+            report.lost_modes += 1;
+        }
+        Ok(())
+    })?;
+
+    writer.finish()?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codecs::gzip::GzipCodec;
+    use crate::codecs::tarball::TarCodec;
+    use crate::codecs::zip::{CompressionMethod, ZipCodec};
+    use crate::codecs::EntryMeta;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    fn entry(name: &str, is_dir: bool, link: Option<EntryLink>) -> EntryMeta {
+        EntryMeta {
+            name: name.to_string(),
+            is_dir,
+            size: None,
+            modified: Some(UNIX_EPOCH + Duration::from_secs(1_600_000_000)),
+            mode: Some(0o750),
+            link,
+        }
+    }
+
+    /// Name, time, permissions and link of a visited entry
+    type Seen = (String, Option<SystemTime>, Option<u32>, Option<EntryLink>);
+
+    fn entries(codec: &mut dyn Codec, path: &Path) -> Vec<Seen> {
+        let mut found = Vec::new();
+        codec.visit_entries(path, &mut |meta, _| {
+            found.push((meta.name.clone(), meta.modified, meta.mode.map(|m| m & 0o7777), meta.link.clone()));
+            Ok(())
+        }).unwrap();
+        found
+    }
+
+    #[test]
+    fn metadata_survives_where_the_target_can_hold_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let tar = dir.path().join("in.tar");
+        let mut tar_codec = TarCodec::new();
+
+        let mut writer = tar_codec.entry_writer(&tar).unwrap();
+        writer.write_entry(&entry("d/", true, None), &mut io::empty()).unwrap();
+        writer.write_entry(&entry("d/a.txt", false, None), &mut &b"hello"[..]).unwrap();
+        writer.write_entry(&entry("d/link", false, Some(EntryLink::Symbolic("a.txt".into()))), &mut io::empty()).unwrap();
+        writer.write_entry(&entry("d/hard", false, Some(EntryLink::Hard("d/a.txt".into()))), &mut io::empty()).unwrap();
+        writer.finish().unwrap();
+
+        let zip = dir.path().join("out.zip");
+        let mut zip_codec = ZipCodec::new(CompressionMethod::Deflated, None, None);
+        let report = convert(&mut TarCodec::new(), &tar, zip_codec.entry_writer(&zip).unwrap()).unwrap();
+        assert_eq!(report.written, 3);
+        assert_eq!(report.skipped, [("d/hard".to_string(), "hard link")]);
+
+        let converted = entries(&mut ZipCodec::new(CompressionMethod::Deflated, None, None), &zip);
+        assert_eq!(converted[1], ("d/a.txt".to_string(), Some(UNIX_EPOCH + Duration::from_secs(1_600_000_000)), Some(0o750), None));
+        assert_eq!(converted[2].3, Some(EntryLink::Symbolic("a.txt".into())));
+
+        // gzip keeps a single file with its time, but no directory or permissions
+        let single = dir.path().join("single.tar");
+        let mut writer = tar_codec.entry_writer(&single).unwrap();
+        writer.write_entry(&entry("d/", true, None), &mut io::empty()).unwrap();
+        writer.write_entry(&entry("d/a.txt", false, None), &mut &b"hello"[..]).unwrap();
+        writer.finish().unwrap();
+
+        let gz = dir.path().join("a.gz");
+        let mut gz_codec = GzipCodec::new();
+        let report = convert(&mut TarCodec::new(), &single, gz_codec.entry_writer(&gz).unwrap()).unwrap();
+        assert_eq!((report.written, report.lost_modes, report.skipped.len()), (1, 1, 1));
+        assert_eq!(entries(&mut GzipCodec::new(), &gz)[0].1, Some(UNIX_EPOCH + Duration::from_secs(1_600_000_000)));
+    }
+}
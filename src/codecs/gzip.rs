@@ -1,59 +1,215 @@
-use crate::codecs::Codec;
-use crate::utils::ensure_directory_exists;
-use crate::Result;
-use flate2::{bufread, Compression, GzBuilder};
-use std::fs::File;
+use crate::codecs::limits::{CountingReader, ExtractLimits, LimitTracker, RatioBase};
+use crate::codecs::safe_path::{PathGuard, PathPolicy};
+use crate::codecs::stream_salvage::{open_seekable, GzSalvage};
+use crate::codecs::tarball;
+use crate::codecs::{Capabilities, Codec, EntryMeta, EntryVisitor, EntryWriter};
+use crate::utils::{create_output, ensure_directory_exists, is_stdio, open_input};
+use crate::{Result, ZipError};
+use flate2::{bufread, Compression, GzBuilder, GzHeader};
 use std::io::{self, BufReader, Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
 
 /// GZip codec implementation
 pub struct GzipCodec {
     compression_level: u8,
+    limits: ExtractLimits,
+    path_policy: PathPolicy,
+    /// Decode around damaged members instead of stopping at the first error
+    salvage: bool,
 }
 
 impl GzipCodec {
     /// Create a new GZip codec
     pub fn new() -> Self {
-        Self { compression_level: 6 }
+        Self {
+            compression_level: 6,
+            limits: ExtractLimits::default(),
+            path_policy: PathPolicy::default(),
+            salvage: false,
+        }
+    }
+
+    /// Recover every member that still decodes when extracting
+    pub fn set_salvage(&mut self, salvage: bool) {
+        self.salvage = salvage;
+    }
+}
+
+impl Default for GzipCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Writes the one file a gzip stream can hold
+struct GzEntryWriter {
+    target: PathBuf,
+    level: u8,
+    /// Name of the file already written
+    written: Option<String>,
+}
+
+impl EntryWriter for GzEntryWriter {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities { modified: true, mode: false, symlinks: false, hard_links: false, directories: false }
+    }
+
+    fn write_entry(&mut self, meta: &EntryMeta, data: &mut dyn Read) -> Result<()> {
+        if let Some(first) = &self.written {
+            return Err(ZipError::UnsupportedOperation(format!(
+                "gzip holds a single file, but {} follows {}; use a tar-based format such as xz or zst", meta.name, first
+            )));
+        }
+
+        // The output is only created here so an archive without files leaves nothing behind
+        if !is_stdio(&self.target) {
+            ensure_directory_exists(self.target.parent().unwrap_or(Path::new(".")))?;
+        }
+
+        let name = meta.name.rsplit('/').next().unwrap_or(&meta.name);
+        let mtime = meta.modified.and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs() as u32);
+        let mut gz = GzBuilder::new()
+            .filename(name)
+            .mtime(mtime)
+            .write(create_output(&self.target)?, Compression::new(self.level as u32));
+
+        io::copy(data, &mut gz)?;
+        gz.finish()?.flush()?;
+        self.written = Some(meta.name.clone());
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        match self.written {
+            Some(_) => Ok(()),
+            None => Err(ZipError::Other("There is no file to put in the gzip stream".to_string())),
+        }
     }
 }
 
 impl Codec for GzipCodec {
     fn extract(&mut self, source: &[&Path], target: &Path) -> Result<()> {
-        ensure_directory_exists(target.parent().unwrap_or(Path::new(".")))?;
+        if !is_stdio(target) {
+            ensure_directory_exists(target.parent().unwrap_or(Path::new(".")))?;
+        }
 
-        let reader = BufReader::new(File::open(source[0])?);
-        let mut outfile = File::create(target)?;
-        let mut decoder = bufread::GzDecoder::new(reader);
+        let header_name = |header: Option<&GzHeader>| {
+            header.and_then(|h| h.filename()).map(|n| String::from_utf8_lossy(n).into_owned())
+        };
 
-        io::copy(&mut decoder, &mut outfile)?;
+        let mut salvaged = None;
+        let (mut decoder, stored_name, compressed): (Box<dyn Read>, _, _) = if self.salvage {
+            let file = open_seekable(source[0])?;
+            let stored_name = header_name(bufread::GzDecoder::new(BufReader::new(file.try_clone()?)).header());
+            let reader = GzSalvage::new(file)?;
+            let damage = reader.report();
+            let compressed = reader.counter();
 
-        Ok(())
+            // A damaged tarball is worth unpacking entry by entry
+            let (is_tar, reader) = tarball::sniff_tar(reader)?;
+            if is_tar && !is_stdio(target) {
+                return tarball::salvage_into(reader, &damage, "members", &compressed, target, self.limits, self.path_policy);
+            }
+            salvaged = Some(damage);
+            (Box::new(reader), stored_name, compressed)
+        } else {
+            let input = CountingReader::new(open_input(source[0])?);
+            let compressed = input.counter();
+            let decoder = bufread::MultiGzDecoder::new(BufReader::new(input));
+            let stored_name = header_name(decoder.header());
+            (Box::new(decoder), stored_name, compressed)
+        };
+
+        // Extracting into a directory: name the output after the source (or the gzip header for stdin)
+        let target = if target.is_dir() {
+            let name = if is_stdio(source[0]) {
+                stored_name.unwrap_or_else(|| "stdin".to_string())
+            } else {
+                source[0].file_stem().unwrap_or_default().to_string_lossy().into_owned()
+            };
+
+            // The header name comes from the archive, so it goes through the same checks as entry names
+            let guard = PathGuard::new(target, self.path_policy)?;
+            match guard.resolve(&name) {
+                Some(path) => path,
+                None => return guard.finish(),
+            }
+        } else {
+            target.to_path_buf()
+        };
+
+        let tracker = LimitTracker::new(self.limits);
+        let name = target.to_string_lossy().into_owned();
+
+        let result = if is_stdio(&target) {
+            let mut stdout = create_output(&target)?;
+            tracker.copy(&name, &mut decoder, &mut stdout, RatioBase::Stream(&compressed))
+                .and_then(|_| Ok(stdout.flush()?))
+        } else {
+            let mut outfile = tracker.create_file(&target)?;
+            tracker.copy(&name, &mut decoder, &mut outfile, RatioBase::Stream(&compressed))
+                .map(|_| ())
+        };
+
+        tracker.guard(result)?;
+        match salvaged {
+            Some(damage) => damage.lock().unwrap().summarise("members"),
+            None => Ok(()),
+        }
+    }
+
+    fn visit_entries(&mut self, source: &Path, visitor: &mut EntryVisitor) -> Result<()> {
+        let mut decoder = bufread::MultiGzDecoder::new(BufReader::new(open_input(source)?));
+
+        // A gzip stream holds one file, named by its header or else by the archive
+        let name = decoder.header()
+            .and_then(|h| h.filename())
+            .map(|n| String::from_utf8_lossy(n).into_owned())
+            .unwrap_or_else(|| source.file_stem().unwrap_or_default().to_string_lossy().into_owned());
+
+        // Zero means the header has no time
+        let modified = decoder.header()
+            .map(|h| h.mtime())
+            .filter(|&secs| secs > 0)
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs as u64));
+
+        let meta = EntryMeta { name, is_dir: false, size: None, modified, mode: None, link: None };
+        visitor(&meta, &mut decoder)
+    }
+
+    fn entry_writer(&mut self, target: &Path) -> Result<Box<dyn EntryWriter + '_>> {
+        Ok(Box::new(GzEntryWriter {
+            target: target.to_path_buf(),
+            level: self.compression_level,
+            written: None,
+        }))
     }
 
     fn compress(&mut self, source: &[&Path], target: &Path, _exclude: Option<&[&Path]>) -> Result<()> {
-        ensure_directory_exists(target.parent().unwrap_or(Path::new(".")))?;
+        if !is_stdio(target) {
+            ensure_directory_exists(target.parent().unwrap_or(Path::new(".")))?;
+        }
 
         // GZip only compresses a single file
         let source_file = source[0];
 
-        let f = File::create(target)?;
-        let mut s = File::open(source_file)?;
+        let f = create_output(target)?;
+        let mut s = open_input(source_file)?;
 
-        let filename = source_file
-            .file_name()
-            .and_then(|n| n.to_str())
-            .unwrap_or("unknown");
-
-        let mut gz = GzBuilder::new()
-            .filename(filename)
-            .write(f, flate2::Compression::new(self.compression_level as u32));
+        let mut builder = GzBuilder::new();
+        if !is_stdio(source_file) {
+            let filename = source_file
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or("unknown");
+            builder = builder.filename(filename);
+        }
 
-        let mut buffer = Vec::new();
-        s.read_to_end(&mut buffer)?;
+        let mut gz = builder.write(f, Compression::new(self.compression_level as u32));
 
-        gz.write_all(&buffer)?;
-        gz.finish()?;
+        io::copy(&mut s, &mut gz)?;
+        gz.finish()?.flush()?;
 
         Ok(())
     }
@@ -62,7 +218,49 @@ impl Codec for GzipCodec {
         (0, 9)
     }
 
+    fn set_limits(&mut self, limits: ExtractLimits) {
+        self.limits = limits;
+    }
+
+    fn set_path_policy(&mut self, policy: PathPolicy) {
+        self.path_policy = policy;
+    }
+
     fn set_compression_level(&mut self, level: u8) {
         self.compression_level = level;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+
+    #[test]
+    fn salvage_unpacks_the_complete_entries_of_a_cut_off_tarball() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("site.tar.gz");
+        let big: Vec<u8> = (0..256 * 1024_u32).map(|i| b'a' + (i.wrapping_mul(2_654_435_761) >> 28) as u8).collect();
+
+        let encoder = flate2::write::GzEncoder::new(File::create(&archive).unwrap(), Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+        for (name, data) in [("site/index.html", &b"<html>"[..]), ("site/big.bin", &big)] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, data).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let bytes = fs::read(&archive).unwrap();
+        fs::write(&archive, &bytes[..bytes.len() * 3 / 4]).unwrap();
+
+        let out = dir.path().join("out");
+        let mut codec = GzipCodec::new();
+        codec.set_salvage(true);
+        let error = codec.extract(&[&archive], &out).unwrap_err();
+        assert!(error.to_string().contains("site/big.bin"), "{}", error);
+        assert_eq!(fs::read_to_string(out.join("site/index.html")).unwrap(), "<html>");
+        assert!(!out.join("site/big.bin").exists());
+    }
+}
@@ -0,0 +1,350 @@
+use crate::{Result, ZipError};
+use log::{info, warn};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Output below this size is never rejected for its compression ratio, so
+/// small, highly compressible files do not trip the check
+const RATIO_GRACE_BYTES: u64 = 1024 * 1024;
+
+/// Resource limits enforced while extracting; `None` disables a limit
+#[derive(Clone, Copy, Debug)]
+pub struct ExtractLimits {
+    /// Maximum bytes written across all entries
+    pub max_total_size: Option<u64>,
+    /// Maximum uncompressed/compressed ratio of a single entry
+    pub max_ratio: Option<u64>,
+    /// Maximum number of entries
+    pub max_entries: Option<u64>,
+    /// Maximum number of path components in an entry name
+    pub max_depth: Option<usize>,
+}
+
+impl Default for ExtractLimits {
+    fn default() -> Self {
+        Self {
+            max_total_size: None,
+            max_ratio: Some(1000),
+            max_entries: Some(1_000_000),
+            max_depth: Some(256),
+        }
+    }
+}
+
+/// What an entry's output is compared against for the ratio check
+#[derive(Clone, Copy)]
+pub enum RatioBase<'a> {
+    /// Compressed size recorded for the entry (zip, 7z)
+    Entry(u64),
+    /// Running count of compressed bytes consumed from a stream (gz, xz)
+    Stream(&'a AtomicU64),
+    /// No usable compressed size
+    Unknown,
+}
+
+/// Reader that counts the bytes pulled through it into a shared counter
+pub struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R: Read> CountingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, count: Arc::new(AtomicU64::new(0)) }
+    }
+
+    /// Shared handle to the byte count
+    pub fn counter(&self) -> Arc<AtomicU64> {
+        self.count.clone()
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count.fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+/// Tracks one extraction against its limits and remembers what it created
+/// or overwrote, so that output can be undone again when a limit is hit
+pub struct LimitTracker {
+    limits: ExtractLimits,
+    total: AtomicU64,
+    entries: AtomicU64,
+    created: Mutex<Vec<PathBuf>>,
+    /// Overwritten files and the backups they were moved to
+    replaced: Mutex<Vec<(PathBuf, PathBuf)>>,
+}
+
+impl LimitTracker {
+    pub fn new(limits: ExtractLimits) -> Self {
+        Self {
+            limits,
+            total: AtomicU64::new(0),
+            entries: AtomicU64::new(0),
+            created: Mutex::new(Vec::new()),
+            replaced: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Total bytes written so far
+    pub fn total_written(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// Reject an archive whose header already declares too many entries
+    pub fn check_declared_entries(&self, count: u64) -> Result<()> {
+        match self.limits.max_entries {
+            Some(max) if count > max => Err(ZipError::LimitExceeded(format!(
+                "archive declares {} entries, limit is {}", count, max
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Register an entry about to be extracted, checking entry count and path depth
+    pub fn begin_entry(&self, name: &str) -> Result<()> {
+        let count = self.entries.fetch_add(1, Ordering::Relaxed) + 1;
+        match self.limits.max_entries {
+            Some(max) if count > max => {
+                return Err(ZipError::LimitExceeded(format!("more than {} entries", max)));
+            }
+            _ => {}
+        }
+
+        if let Some(max) = self.limits.max_depth {
+            let depth = Path::new(name)
+                .components()
+                .filter(|c| matches!(c, Component::Normal(_)))
+                .count();
+            if depth > max {
+                return Err(ZipError::LimitExceeded(format!(
+                    "{}: path depth {} exceeds limit {}", name, depth, max
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Account for bytes about to be written, checking the total-size limit
+    pub fn add_bytes(&self, name: &str, len: u64) -> Result<()> {
+        let total = self.total.fetch_add(len, Ordering::Relaxed) + len;
+        match self.limits.max_total_size {
+            Some(max) if total > max => Err(ZipError::LimitExceeded(format!(
+                "{}: total extracted size exceeds {} bytes", name, max
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Copy an entry's data, enforcing the total-size and ratio limits as bytes arrive
+    pub fn copy<R: Read + ?Sized, W: Write + ?Sized>(
+        &self,
+        name: &str,
+        reader: &mut R,
+        writer: &mut W,
+        base: RatioBase,
+    ) -> Result<u64> {
+        let stream_start = match base {
+            RatioBase::Stream(counter) => counter.load(Ordering::Relaxed),
+            _ => 0,
+        };
+
+        let mut buffer = [0_u8; 64 * 1024];
+        let mut written = 0_u64;
+
+        loop {
+            let read = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            written += read as u64;
+            self.add_bytes(name, read as u64)?;
+
+            if let (Some(max), true) = (self.limits.max_ratio, written > RATIO_GRACE_BYTES) {
+                let compressed = match base {
+                    RatioBase::Entry(size) => Some(size),
+                    RatioBase::Stream(counter) => Some(counter.load(Ordering::Relaxed) - stream_start),
+                    RatioBase::Unknown => None,
+                };
+
+                match compressed {
+                    Some(compressed) if written / compressed.max(1) > max => {
+                        return Err(ZipError::LimitExceeded(format!(
+                            "{}: compression ratio exceeds {}:1", name, max
+                        )));
+                    }
+                    _ => {}
+                }
+            }
+
+            writer.write_all(&buffer[..read])?;
+        }
+
+        Ok(written)
+    }
+
+    /// Create a directory and its parents, remembering the ones that were new
+    pub fn create_dir_all(&self, path: &Path) -> Result<()> {
+        let mut missing = Vec::new();
+        let mut current = Some(path);
+        while let Some(dir) = current {
+            if dir.as_os_str().is_empty() || dir.exists() {
+                break;
+            }
+            missing.push(dir.to_path_buf());
+            current = dir.parent();
+        }
+
+        fs::create_dir_all(path)?;
+
+        let mut created = self.created.lock().unwrap();
+        created.extend(missing.into_iter().rev());
+        Ok(())
+    }
+
+    /// Create an output file and remember it. A file it replaces is moved
+    /// to a backup beside it first, so that `cleanup` can put it back.
+    pub fn create_file(&self, path: &Path) -> Result<File> {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_file() => {
+                let dir = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+                let backup = tempfile::Builder::new()
+                    .prefix(".crate-backup-")
+                    .tempfile_in(dir)?
+                    .into_temp_path()
+                    .keep()
+                    .map_err(|e| e.error)?;
+                fs::rename(path, &backup)?;
+                self.replaced.lock().unwrap().push((path.to_path_buf(), backup));
+            }
+            Ok(_) => return Ok(File::create(path)?),
+            Err(_) => {}
+        }
+
+        let file = File::create(path)?;
+        self.created.lock().unwrap().push(path.to_path_buf());
+        Ok(file)
+    }
+
+    /// Remove everything this extraction created, newest first, and restore
+    /// the files it overwrote
+    pub fn cleanup(&self) {
+        let created = std::mem::take(&mut *self.created.lock().unwrap());
+        info!("Removing {} partially extracted paths", created.len());
+
+        for path in created.iter().rev() {
+            let removed = if path.is_dir() {
+                fs::remove_dir(path)
+            } else {
+                fs::remove_file(path)
+            };
+
+            match removed {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => warn!("Could not remove {:?}: {}", path, e),
+                _ => {}
+            }
+        }
+
+        for (path, backup) in std::mem::take(&mut *self.replaced.lock().unwrap()).iter().rev() {
+            if let Err(e) = fs::rename(backup, path) {
+                warn!("Could not restore {:?} from {:?}: {}", path, backup, e);
+            }
+        }
+    }
+
+    /// Pass an extraction result through, removing its output if it stopped on a limit
+    pub fn guard<T>(&self, result: Result<T>) -> Result<T> {
+        if let Err(ZipError::LimitExceeded(_)) = &result {
+            self.cleanup();
+        }
+        result
+    }
+}
+
+impl Drop for LimitTracker {
+    /// Extraction is over without a cleanup, so the overwritten files stay replaced
+    fn drop(&mut self) {
+        for (_, backup) in self.replaced.get_mut().unwrap().drain(..) {
+            if let Err(e) = fs::remove_file(&backup) {
+                warn!("Could not remove backup {:?}: {}", backup, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> ExtractLimits {
+        ExtractLimits { max_total_size: None, max_ratio: None, max_entries: None, max_depth: None }
+    }
+
+    #[test]
+    fn each_limit_is_enforced() {
+        let tracker = LimitTracker::new(ExtractLimits { max_entries: Some(2), max_depth: Some(2), ..limits() });
+        assert!(tracker.check_declared_entries(3).is_err());
+        tracker.begin_entry("a/b").unwrap();
+        assert!(matches!(tracker.begin_entry("a/b/c"), Err(ZipError::LimitExceeded(_))));
+        assert!(matches!(tracker.begin_entry("d"), Err(ZipError::LimitExceeded(_))));
+
+        let tracker = LimitTracker::new(ExtractLimits { max_total_size: Some(10), ..limits() });
+        let mut out = Vec::new();
+        tracker.copy("a", &mut &[0_u8; 6][..], &mut out, RatioBase::Unknown).unwrap();
+        assert!(matches!(tracker.copy("b", &mut &[0_u8; 6][..], &mut out, RatioBase::Unknown), Err(ZipError::LimitExceeded(_))));
+        assert_eq!(tracker.total_written(), 12);
+
+        // Ratios are only checked past the grace size
+        let data = vec![0_u8; 2 * RATIO_GRACE_BYTES as usize];
+        let tracker = LimitTracker::new(ExtractLimits { max_ratio: Some(100), ..limits() });
+        tracker.copy("small", &mut &data[..RATIO_GRACE_BYTES as usize], &mut io::sink(), RatioBase::Entry(1)).unwrap();
+        tracker.copy("unknown", &mut &data[..], &mut io::sink(), RatioBase::Unknown).unwrap();
+        assert!(tracker.copy("bomb", &mut &data[..], &mut io::sink(), RatioBase::Entry(1000)).is_err());
+
+        // A stream's ratio follows the compressed bytes consumed so far
+        let mut reader = CountingReader::new(&data[..]);
+        let counter = reader.counter();
+        assert!(tracker.copy("stream", &mut reader, &mut io::sink(), RatioBase::Stream(&counter)).is_ok());
+        let consumed = AtomicU64::new(0);
+        assert!(tracker.copy("stream bomb", &mut &data[..], &mut io::sink(), RatioBase::Stream(&consumed)).is_err());
+    }
+
+    #[test]
+    fn limit_errors_remove_only_new_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let existing = dir.path().join("existing");
+        fs::create_dir(&existing).unwrap();
+        fs::write(existing.join("kept.txt"), "precious").unwrap();
+
+        let tracker = LimitTracker::new(limits());
+        tracker.create_dir_all(&existing.join("new/deeper")).unwrap();
+        tracker.create_file(&existing.join("new/deeper/file")).unwrap();
+        tracker.create_file(&existing.join("kept.txt")).unwrap().write_all(b"partial").unwrap();
+
+        assert!(tracker.guard::<()>(Err(ZipError::Other("not a limit".to_string()))).is_err());
+        assert!(existing.join("new/deeper/file").exists());
+
+        assert!(tracker.guard::<()>(Err(ZipError::LimitExceeded("too big".to_string()))).is_err());
+        assert!(existing.exists());
+        assert!(!existing.join("new").exists());
+        assert_eq!(fs::read_to_string(existing.join("kept.txt")).unwrap(), "precious");
+        drop(tracker);
+
+        // A finished extraction keeps the new contents and drops the backup
+        let tracker = LimitTracker::new(limits());
+        tracker.create_file(&existing.join("kept.txt")).unwrap().write_all(b"replaced").unwrap();
+        drop(tracker);
+        assert_eq!(fs::read_to_string(existing.join("kept.txt")).unwrap(), "replaced");
+        assert_eq!(fs::read_dir(&existing).unwrap().count(), 1);
+    }
+}
@@ -1,20 +1,39 @@
 pub mod command_line;
+pub mod convert;
 pub mod gzip;
+pub mod limits;
+pub mod safe_path;
 pub mod sevenz;
+pub mod sevenz_edit;
+pub mod stream_salvage;
+pub mod tarball;
+pub mod update;
+pub mod volume;
 pub mod xz;
 pub mod zip;
+pub mod zip_edit;
+pub mod zip_salvage;
+pub mod zip_stream;
+pub mod zst;
 
 use crate::{Result, ZipError};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::path::Path;
-use std::time::Instant;
+use std::time::{Instant, SystemTime};
 use log::info;
 
 use self::command_line::CommandLineCodec;
 use self::gzip::GzipCodec;
+use self::limits::ExtractLimits;
+use self::safe_path::PathPolicy;
 use self::sevenz::SevenZCodec;
+use self::tarball::TarCodec;
 use self::xz::XzCodec;
-use self::zip::{CompressionMethod, ZipCodec};
+use self::zst::ZstCodec;
+use self::zip::{CompressionMethod, ZipCodec, ZipEncryption};
 
 /// Compression format types
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
@@ -23,6 +42,38 @@ pub enum Format {
     Gz,
     SevenZ,
     Xz,
+    Tar,
+    /// Zstandard-compressed tarball
+    Zst,
+}
+
+impl Format {
+    /// Identify a format from the leading bytes of an archive
+    pub fn from_magic(header: &[u8]) -> Option<Format> {
+        if header.starts_with(b"PK\x03\x04") || header.starts_with(b"PK\x05\x06") || header.starts_with(b"PK\x07\x08") {
+            Some(Self::Zip)
+        } else if header.starts_with(&[0x1f, 0x8b]) {
+            Some(Self::Gz)
+        } else if header.starts_with(&[b'7', b'z', 0xbc, 0xaf, 0x27, 0x1c]) {
+            Some(Self::SevenZ)
+        } else if header.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Self::Xz)
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Self::Zst)
+        } else if header.get(257..262) == Some(b"ustar") {
+            Some(Self::Tar)
+        } else {
+            None
+        }
+    }
+
+    /// Detect the format of an existing archive from its magic bytes
+    pub fn detect(path: &Path) -> Option<Format> {
+        // Long enough to reach the tar magic at offset 257
+        let mut header = [0_u8; 262];
+        let read = File::open(path).and_then(|mut f| f.read(&mut header)).ok()?;
+        Self::from_magic(&header[..read])
+    }
 }
 
 impl From<&str> for Format {
@@ -32,38 +83,204 @@ impl From<&str> for Format {
             "gz" => Self::Gz,
             "7z" => Self::SevenZ,
             "xz" => Self::Xz,
+            "tar" => Self::Tar,
+            "zst" => Self::Zst,
             _ => Self::Zip,
         }
     }
 }
 
-impl Into<&str> for Format {
-    fn into(self) -> &'static str {
-        match self {
-            Self::Zip => "zip",
-            Self::Gz => "gz",
-            Self::SevenZ => "7z",
-            Self::Xz => "xz",
+impl From<Format> for &str {
+    fn from(val: Format) -> Self {
+        match val {
+            Format::Zip => "zip",
+            Format::Gz => "gz",
+            Format::SevenZ => "7z",
+            Format::Xz => "xz",
+            Format::Tar => "tar",
+            Format::Zst => "zst",
         }
     }
 }
 
+/// An archive entry handed to an entry visitor
+pub struct EntryMeta {
+    pub name: String,
+    pub is_dir: bool,
+    /// Uncompressed size, when the format records it up front
+    pub size: Option<u64>,
+    /// Modification time, when the format records one
+    pub modified: Option<SystemTime>,
+    /// Unix permission bits, when the format records them
+    pub mode: Option<u32>,
+    /// Set for link entries
+    pub link: Option<EntryLink>,
+}
+
+/// Where a link entry points
+#[derive(Clone, Debug, PartialEq)]
+pub enum EntryLink {
+    Symbolic(String),
+    /// Another entry of the same archive
+    Hard(String),
+}
+
+/// Callback receiving each entry of an archive and a reader over its contents
+pub type EntryVisitor<'a> = dyn FnMut(&EntryMeta, &mut dyn Read) -> Result<()> + 'a;
+
+/// Entry callback that may be called from several threads at once
+pub type SharedVisitor<'a> = dyn Fn(&EntryMeta, &mut dyn Read) -> Result<()> + Sync + 'a;
+
+/// Metadata a format can store besides entry names and contents
+#[derive(Clone, Copy, Debug)]
+pub struct Capabilities {
+    pub modified: bool,
+    pub mode: bool,
+    pub symlinks: bool,
+    pub hard_links: bool,
+    pub directories: bool,
+}
+
+/// Writes a new archive one entry at a time; the counterpart of [`Codec::visit_entries`]
+pub trait EntryWriter {
+    fn capabilities(&self) -> Capabilities;
+
+    /// Write one entry, ignoring metadata the format cannot store
+    fn write_entry(&mut self, meta: &EntryMeta, data: &mut dyn Read) -> Result<()>;
+
+    /// Complete the archive
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
 /// Trait for compression/decompression operations
 pub trait Codec {
     /// Extract files from an archive
     fn extract(&mut self, source: &[&Path], target: &Path) -> Result<()>;
 
-    fn extract_parts(&mut self, source: &[&Path], target: &Path, parts: &[String]) -> Result<()> {
+    fn extract_parts(&mut self, _source: &[&Path], _target: &Path, _parts: &[String]) -> Result<()> {
         Err(ZipError::UnsupportedOperation(
             "Extracting specific parts is not supported for this format".to_string()
         ))
     }
     
+    /// Read every entry of an archive in order without writing anything to disk
+    fn visit_entries(&mut self, _source: &Path, _visitor: &mut EntryVisitor) -> Result<()> {
+        Err(ZipError::UnsupportedOperation(
+            "Reading entries is not supported by this backend".to_string()
+        ))
+    }
+
+    /// Like [`Self::visit_entries`], but reading entries on several threads
+    /// where the format allows it, so they arrive in no particular order
+    fn visit_entries_parallel(&mut self, source: &Path, visitor: &SharedVisitor) -> Result<()> {
+        self.visit_entries(source, &mut |meta, data| visitor(meta, data))
+    }
+
+    /// Write the contents of the named entries to `out` in the order named, in one
+    /// scan that holds early entries in temp files; indexed formats seek instead
+    fn cat_entries(&mut self, source: &Path, names: &[String], out: &mut dyn Write) -> Result<()> {
+        // Named entries already found but not yet printed, by position in `names`
+        let mut spooled: HashMap<usize, File> = HashMap::new();
+        let mut next = 0;
+
+        self.visit_entries(source, &mut |meta, data| {
+            let wanted: Vec<usize> = (next..names.len())
+                .filter(|i| !spooled.contains_key(i) && same_entry_name(&names[*i], &meta.name))
+                .collect();
+            if wanted.is_empty() {
+                return Ok(());
+            }
+            if meta.is_dir {
+                return Err(ZipError::Other(format!("{} is a directory", meta.name)));
+            }
+
+            if wanted == [next] {
+                io::copy(data, out)?;
+                next += 1;
+            } else {
+                let mut file = tempfile::tempfile()?;
+                io::copy(data, &mut file)?;
+                for i in wanted {
+                    spooled.insert(i, file.try_clone()?);
+                }
+            }
+
+            while let Some(mut file) = spooled.remove(&next) {
+                file.seek(SeekFrom::Start(0))?;
+                io::copy(&mut file, out)?;
+                next += 1;
+            }
+            Ok(())
+        })?;
+
+        // Entries found after one that is missing are still printed
+        let mut missing = Vec::new();
+        for (i, name) in names.iter().enumerate().skip(next) {
+            match spooled.get_mut(&i) {
+                Some(file) => {
+                    file.seek(SeekFrom::Start(0))?;
+                    io::copy(file, out)?;
+                }
+                None => missing.push(name.as_str()),
+            }
+        }
+        missing_entries(&missing)
+    }
+
+    /// Add a small file to an existing archive
+    fn append_entry(&mut self, _archive: &Path, _name: &str, _data: &[u8]) -> Result<()> {
+        Err(ZipError::UnsupportedOperation(
+            "Adding entries to an existing archive is not supported for this format".to_string()
+        ))
+    }
+
+    /// Add files and directories to an existing archive the way `compress` names them,
+    /// replacing same-named entries; with `only_changed`, unchanged files are skipped
+    fn add_entries(&mut self, _archive: &Path, _source: &[&Path], _only_changed: bool) -> Result<()> {
+        Err(ZipError::UnsupportedOperation(
+            "Adding files to an existing archive is not supported for this format".to_string()
+        ))
+    }
+
+    /// Remove entries from an existing archive; naming a directory removes everything under it
+    fn delete_entries(&mut self, _archive: &Path, _names: &[String]) -> Result<()> {
+        Err(ZipError::UnsupportedOperation(
+            "Deleting entries is not supported for this format".to_string()
+        ))
+    }
+
+    /// Start a new archive at `target` to be filled through the returned writer
+    fn entry_writer(&mut self, _target: &Path) -> Result<Box<dyn EntryWriter + '_>> {
+        Err(ZipError::UnsupportedOperation(
+            "Writing entries one at a time is not supported by this backend".to_string()
+        ))
+    }
+
     /// Compress files into an archive
     fn compress(&mut self, source: &[&Path], target: &Path, _exclude: Option<&[&Path]>) -> Result<()>;
 
     fn compression_level_range(&self) -> (u8, u8);
     fn set_compression_level(&mut self, _level: u8);
+
+    /// Set the resource limits enforced during extraction
+    fn set_limits(&mut self, limits: ExtractLimits);
+
+    /// Set how entries with unsafe paths are handled during extraction
+    fn set_path_policy(&mut self, policy: PathPolicy);
+}
+
+/// Whether a name given to `cat_entries` refers to the entry `name`; a
+/// leading `./` on either side makes no difference
+fn same_entry_name(wanted: &str, name: &str) -> bool {
+    wanted.trim_start_matches("./") == name.trim_start_matches("./")
+}
+
+/// Error naming the entries `cat_entries` could not find, if any
+fn missing_entries(missing: &[&str]) -> Result<()> {
+    if missing.is_empty() {
+        return Ok(());
+    }
+    Err(ZipError::Other(format!("Not found in the archive: {}", missing.join(", "))))
 }
 
 /// Factory for creating codec instances
@@ -74,6 +291,10 @@ pub struct CodecFactory {
     volume_size: Option<usize>,
     use_external: bool,
     level: Option<u8>,
+    limits: ExtractLimits,
+    path_policy: PathPolicy,
+    encryption: Option<ZipEncryption>,
+    salvage: bool,
 }
 
 impl CodecFactory {
@@ -93,11 +314,63 @@ impl CodecFactory {
             volume_size,
             use_external,
             level,
+            limits: ExtractLimits::default(),
+            path_policy: PathPolicy::default(),
+            encryption: None,
+            salvage: false,
         }
     }
 
+    /// Use these extraction limits instead of the defaults
+    pub fn with_limits(mut self, limits: ExtractLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Handle unsafe entry paths with this policy instead of rejecting them
+    pub fn with_path_policy(mut self, policy: PathPolicy) -> Self {
+        self.path_policy = policy;
+        self
+    }
+
+    /// Encrypt zip archives with this scheme; other formats have a fixed scheme
+    pub fn with_encryption(mut self, encryption: Option<ZipEncryption>) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    /// Recover what is still readable from damaged archives when extracting
+    pub fn with_salvage(mut self, salvage: bool) -> Self {
+        self.salvage = salvage;
+        self
+    }
+
     /// Create appropriate codec based on configuration
     pub fn create_codec(&self) -> Result<Box<dyn Codec>> {
+        let mut codec = self.create_backend()?;
+        codec.set_limits(self.limits);
+        codec.set_path_policy(self.path_policy);
+        Ok(codec)
+    }
+
+    fn create_backend(&self) -> Result<Box<dyn Codec>> {
+        if self.encryption.is_some() && (self.format != Format::Zip || self.use_external) {
+            return Err(ZipError::UnsupportedOperation(
+                "Choosing the encryption scheme is only supported for native zip archives".to_string()
+            ));
+        }
+
+        if self.salvage && (matches!(self.format, Format::SevenZ | Format::Tar | Format::Zst) || self.use_external) {
+            return Err(ZipError::UnsupportedOperation(
+                "Salvage is only supported for native zip, gz and xz archives".to_string()
+            ));
+        }
+
+        let zip_method = match (self.format, self.method.as_deref()) {
+            (Format::Zip, Some(name)) => CompressionMethod::from_name(name)?,
+            _ => CompressionMethod::default(),
+        };
+
         // If external tools are requested, use command line codec
         if self.use_external {
             return Ok(Box::new(CommandLineCodec::new(
@@ -111,11 +384,11 @@ impl CodecFactory {
         // Create native Rust codec based on format
         match self.format {
             Format::Zip => {
-                let method = self.method
-                    .as_deref()
-                    .map(CompressionMethod::from_str)
-                    .unwrap_or_default();
-                let mut codec = ZipCodec::new(method, self.password.clone());
+                let mut codec = ZipCodec::new(zip_method, self.password.clone(), self.volume_size);
+                if let Some(encryption) = self.encryption {
+                    codec.set_encryption(encryption);
+                }
+                codec.set_salvage(self.salvage);
                 if let Some(lv) = self.level {
                     codec.set_compression_level(lv);
                 }
@@ -123,15 +396,25 @@ impl CodecFactory {
             },
             Format::Gz => {
                 let mut codec = GzipCodec::new();
+                codec.set_salvage(self.salvage);
                 if let Some(lv) = self.level {
                     codec.set_compression_level(lv);
                 }
                 Ok(Box::new(codec))
             },
-            Format::SevenZ => Ok(Box::new(SevenZCodec::new(self.password.clone()))),
+            Format::SevenZ => Ok(Box::new(SevenZCodec::new(self.password.clone(), self.volume_size))),
             Format::Xz => {
                 // Use 12 threads by default
                 let mut codec = XzCodec::new(self.level.unwrap_or(6) as u32, 12);
+                codec.set_salvage(self.salvage);
+                if let Some(lv) = self.level {
+                    codec.set_compression_level(lv);
+                }
+                Ok(Box::new(codec))
+            },
+            Format::Tar => Ok(Box::new(TarCodec::new())),
+            Format::Zst => {
+                let mut codec = ZstCodec::new();
                 if let Some(lv) = self.level {
                     codec.set_compression_level(lv);
                 }
@@ -157,4 +440,61 @@ where
     );
 
     Ok(result)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sevenz_rust2::{SevenZArchiveEntry, SevenZWriter};
+    use ::zip::write::SimpleFileOptions;
+
+    const ENTRIES: [(&str, &str); 2] = [("./dot.txt", "dot "), ("plain.txt", "plain ")];
+
+    fn cat(codec: &mut dyn Codec, archive: &Path, names: &[&str]) -> Result<String> {
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        let mut out = Vec::new();
+        codec.cat_entries(archive, &names, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn cat_matches_names_with_or_without_dot_slash() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let zip_path = dir.path().join("a.zip");
+        let mut zip = ::zip::ZipWriter::new(File::create(&zip_path).unwrap());
+        for (name, data) in ENTRIES {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(data.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let sevenz_path = dir.path().join("a.7z");
+        let mut sevenz = SevenZWriter::create(&sevenz_path).unwrap();
+        for (name, data) in ENTRIES {
+            sevenz.push_archive_entry(SevenZArchiveEntry::new_file(name), Some(data.as_bytes())).unwrap();
+        }
+        sevenz.finish().unwrap();
+
+        // Tar goes through the default implementation
+        let tar_path = dir.path().join("a.tar");
+        let mut tar = tar::Builder::new(File::create(&tar_path).unwrap());
+        for (name, data) in ENTRIES {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, name, data.as_bytes()).unwrap();
+        }
+        tar.into_inner().unwrap();
+
+        let codecs: [(Box<dyn Codec>, &Path); 3] = [
+            (Box::new(ZipCodec::new(CompressionMethod::Deflated, None, None)), &zip_path),
+            (Box::new(SevenZCodec::new(None, None)), &sevenz_path),
+            (Box::new(TarCodec::new()), &tar_path),
+        ];
+        for (mut codec, archive) in codecs {
+            let printed = cat(codec.as_mut(), archive, &["dot.txt", "./plain.txt", "./dot.txt", "plain.txt"]).unwrap();
+            assert_eq!(printed, "dot plain dot plain ", "{:?}", archive);
+            assert!(cat(codec.as_mut(), archive, &["other.txt"]).is_err(), "{:?}", archive);
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use crate::{Result, ZipError};
+use log::warn;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// What to do with entries whose names would land outside the target directory
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PathPolicy {
+    /// Skip unsafe entries and fail once extraction has finished
+    #[default]
+    Reject,
+    /// Rewrite unsafe names to stay inside the target; skip what cannot be rewritten
+    Sanitize,
+}
+
+impl FromStr for PathPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reject" => Ok(Self::Reject),
+            "sanitize" | "sanitise" => Ok(Self::Sanitize),
+            _ => Err(format!("unknown path policy: {} (expected reject or sanitize)", s)),
+        }
+    }
+}
+
+/// Maps archive entry names to paths inside one extraction root and keeps a
+/// record of every entry that was refused
+pub struct PathGuard {
+    root: PathBuf,
+    policy: PathPolicy,
+    rejected: Mutex<Vec<String>>,
+}
+
+impl PathGuard {
+    /// Guard extraction into `root`, which must already exist
+    pub fn new(root: &Path, policy: PathPolicy) -> Result<Self> {
+        Ok(Self {
+            root: fs::canonicalize(root)?,
+            policy,
+            rejected: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Canonical extraction root
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Record a refused entry
+    pub fn reject(&self, name: &str, reason: &str) {
+        warn!("Rejected entry {:?}: {}", name, reason);
+        self.rejected.lock().unwrap().push(format!("{} ({})", name, reason));
+    }
+
+    /// Path an entry may be written to, or `None` if it was rejected
+    pub fn resolve(&self, name: &str) -> Option<PathBuf> {
+        let (relative, issue) = split_entry_name(name);
+
+        match (issue, self.policy) {
+            (Some(reason), PathPolicy::Reject) => {
+                self.reject(name, reason);
+                return None;
+            }
+            (Some(reason), PathPolicy::Sanitize) if !relative.as_os_str().is_empty() => {
+                warn!("Sanitized entry {:?} ({}) to {:?}", name, reason, relative);
+            }
+            _ => {}
+        }
+
+        if relative.as_os_str().is_empty() {
+            self.reject(name, "empty path");
+            return None;
+        }
+
+        let path = self.root.join(relative);
+        if !self.is_inside(&path) {
+            self.reject(name, "path passes through a symlink outside the target directory");
+            return None;
+        }
+
+        Some(path)
+    }
+
+    /// Whether a symlink at `link` pointing to `target` stays inside the root,
+    /// recording rejected links under `name`; the link's directory must exist
+    pub fn allow_link(&self, name: &str, link: &Path, target: &Path) -> bool {
+        if !self.link_stays_inside(link, target) {
+            self.reject(name, &format!("symlink to {:?} points outside the target directory", target));
+            return false;
+        }
+        true
+    }
+
+    /// Follow `target` from the link's directory the way the kernel would; a `..`
+    /// after a component that does not exist yet counts as escaping
+    fn link_stays_inside(&self, link: &Path, target: &Path) -> bool {
+        let Some(Ok(mut current)) = link.parent().map(fs::canonicalize) else {
+            return false;
+        };
+        let mut on_disk = true;
+
+        for component in target.components() {
+            match component {
+                Component::CurDir => {}
+                Component::Normal(part) => {
+                    current.push(part);
+                    if on_disk {
+                        match fs::canonicalize(&current) {
+                            Ok(real) => current = real,
+                            // A dangling link cannot be resolved, so its destination is unknown
+                            Err(_) if current.symlink_metadata().is_ok() => return false,
+                            Err(_) => on_disk = false,
+                        }
+                    }
+                }
+                Component::ParentDir if on_disk => {
+                    current.pop();
+                }
+                _ => return false,
+            }
+
+            if !current.starts_with(&self.root) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Whether writing to `path` would stay inside the root once existing
+    /// symlinks along the way are followed
+    pub fn is_inside(&self, path: &Path) -> bool {
+        let mut probe = Some(path);
+
+        while let Some(current) = probe {
+            if current.symlink_metadata().is_ok() {
+                // A dangling link cannot be resolved, so its destination is unknown
+                return fs::canonicalize(current).is_ok_and(|real| real.starts_with(&self.root));
+            }
+            probe = current.parent();
+        }
+
+        false
+    }
+
+    /// Number of entries rejected so far
+    pub fn rejected_count(&self) -> usize {
+        self.rejected.lock().unwrap().len()
+    }
+
+    /// Fail if any entry was rejected
+    pub fn finish(&self) -> Result<()> {
+        let rejected = self.rejected.lock().unwrap();
+        if rejected.is_empty() {
+            return Ok(());
+        }
+
+        Err(ZipError::UnsafePath(format!(
+            "{} entries rejected: {}",
+            rejected.len(),
+            rejected.join(", ")
+        )))
+    }
+}
+
+/// Split an entry name into a relative path, treating both `/` and `\` as
+/// separators, along with the first reason the original name was unsafe
+fn split_entry_name(name: &str) -> (PathBuf, Option<&'static str>) {
+    let mut issue = None;
+    let mut rest = name;
+
+    let bytes = name.as_bytes();
+    if bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' {
+        issue = Some("drive-prefixed path");
+        rest = &name[2..];
+    }
+
+    if rest.starts_with(['/', '\\']) {
+        issue.get_or_insert("absolute path");
+    }
+
+    let mut parts: Vec<&str> = Vec::new();
+    for part in rest.split(['/', '\\']) {
+        match part {
+            "" | "." => {}
+            ".." => {
+                issue.get_or_insert("contains `..`");
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+
+    (parts.iter().collect(), issue)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_parent_and_absolute_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let guard = PathGuard::new(dir.path(), PathPolicy::Reject).unwrap();
+
+        assert_eq!(guard.resolve("a/./b.txt"), Some(guard.root().join("a/b.txt")));
+        for name in ["../x", "a/../../x", "/etc/passwd", "\\\\server\\x", "C:\\x", "a/.."] {
+            assert_eq!(guard.resolve(name), None, "{:?} was accepted", name);
+        }
+        assert_eq!(guard.rejected_count(), 6);
+        assert!(matches!(guard.finish(), Err(ZipError::UnsafePath(_))));
+    }
+
+    #[test]
+    fn sanitize_rewrites_names_into_the_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let guard = PathGuard::new(dir.path(), PathPolicy::Sanitize).unwrap();
+
+        assert_eq!(guard.resolve("../../etc/passwd"), Some(guard.root().join("etc/passwd")));
+        assert_eq!(guard.resolve("/abs/x"), Some(guard.root().join("abs/x")));
+        assert_eq!(guard.resolve("C:\\win\\x"), Some(guard.root().join("win/x")));
+        assert_eq!(guard.resolve(".."), None);
+        assert_eq!(guard.rejected_count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_targets_are_resolved_through_existing_links() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let guard = PathGuard::new(dir.path(), PathPolicy::Reject).unwrap();
+        let root = guard.root().to_path_buf();
+        fs::create_dir_all(root.join("a/b")).unwrap();
+
+        assert!(guard.allow_link("a/b/up", &root.join("a/b/up"), Path::new("../../x")));
+        assert!(!guard.allow_link("a/out", &root.join("a/out"), Path::new("../../x")));
+        assert!(!guard.allow_link("abs", &root.join("abs"), Path::new("/etc")));
+
+        // `d -> .` is harmless on its own, but makes `d/e -> ..` point above the root
+        assert!(guard.allow_link("d", &root.join("d"), Path::new(".")));
+        symlink(".", root.join("d")).unwrap();
+        assert!(!guard.allow_link("d/e", &root.join("d/e"), Path::new("..")));
+
+        // Lexically `d/d/d/..` stays inside, but every `d` is the root itself
+        assert!(!guard.allow_link("x", &root.join("x"), Path::new("d/d/d/../..")));
+        assert!(guard.allow_link("y", &root.join("y"), Path::new("d/d/a/b")));
+
+        // What a missing component's `..` refers to depends on entries not seen yet
+        assert!(!guard.allow_link("z", &root.join("z"), Path::new("later/..")));
+
+        symlink("/", root.join("escape")).unwrap();
+        assert!(!guard.allow_link("w", &root.join("w"), Path::new("escape/tmp")));
+        assert!(!guard.is_inside(&root.join("escape/tmp")));
+        assert_eq!(guard.rejected_count(), 6);
+    }
+}
@@ -1,51 +1,370 @@
-use crate::codecs::Codec;
-use crate::utils::{ensure_directory_exists, ensure_extension};
+use crate::codecs::limits::{ExtractLimits, LimitTracker, RatioBase};
+use crate::codecs::safe_path::{PathGuard, PathPolicy};
+use crate::codecs::sevenz_edit;
+use crate::codecs::update::{is_changed, StoredFile};
+use crate::codecs::{missing_entries, same_entry_name, Capabilities, Codec, EntryLink, EntryMeta, EntryVisitor, EntryWriter, SharedVisitor};
+use crate::codecs::volume::{discover_volumes, split_numbered, volume_bytes, MultiVolumeReader};
+use crate::utils::{ensure_directory_exists, ensure_extension, is_stdio, replace_with_staged, spool_stdin, staging_file};
 use crate::Result;
 use crate::ZipError;
-use log::debug;
+use log::{debug, info};
+use rayon::prelude::*;
+use std::collections::HashSet;
 use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::path::Path;
-use sevenz_rust2::{SevenZArchiveEntry, SevenZWriter};
+use std::time::{Duration, SystemTime};
+use sevenz_rust2::{Archive, BlockDecoder, Password, SevenZArchiveEntry, SevenZReader, SevenZWriter};
+
+const FILE_ATTRIBUTE_DIRECTORY: u32 = 0x10;
+/// Marks the upper 16 bits of the attributes as a unix mode
+const FILE_ATTRIBUTE_UNIX_EXTENSION: u32 = 0x8000;
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+/// Seekable input a 7z archive can be read from
+trait ArchiveSource: Read + Seek {}
+
+impl<T: Read + Seek> ArchiveSource for T {}
 
 /// 7-Zip codec implementation
 pub struct SevenZCodec {
     password: Option<String>,
+    /// Split output into volumes of this many MB
+    volume_size: Option<usize>,
+    limits: ExtractLimits,
+    path_policy: PathPolicy,
 }
 
 impl SevenZCodec {
     /// Create a new 7-Zip codec
-    pub fn new(password: Option<String>) -> Self {
-        Self { password }
+    pub fn new(password: Option<String>, volume_size: Option<usize>) -> Self {
+        Self {
+            password,
+            volume_size,
+            limits: ExtractLimits::default(),
+            path_policy: PathPolicy::default(),
+        }
+    }
+
+    /// Open the archive bytes from a file, its split volumes or spooled stdin
+    fn open_source(source: &Path) -> Result<Box<dyn ArchiveSource>> {
+        Ok(if is_stdio(source) {
+            Box::new(spool_stdin()?)
+        } else if let Some(volumes) = discover_volumes(source) {
+            info!("Reading {} volumes", volumes.len());
+            Box::new(MultiVolumeReader::open(&volumes)?)
+        } else {
+            Box::new(File::open(source)?)
+        })
+    }
+
+    fn password(&self) -> Password {
+        self.password.as_deref().map(Password::from).unwrap_or_else(Password::empty)
+    }
+
+    /// Open an archive from a file, its split volumes or spooled stdin
+    fn open_reader(&self, source: &Path) -> Result<SevenZReader<Box<dyn ArchiveSource>>> {
+        Ok(SevenZReader::new(Self::open_source(source)?, self.password())?)
+    }
+
+    /// Call `each` for every entry, stopping at the first error it returns
+    fn for_each_entry<R: Read + Seek>(
+        archive: &mut SevenZReader<R>,
+        mut each: impl FnMut(&SevenZArchiveEntry, &mut dyn Read) -> Result<()>,
+    ) -> Result<()> {
+        // The callback can only return sevenz errors, so ours are parked here
+        let mut failure = None;
+        let result = archive.for_each_entries(|entry, data| park(each(entry, data), &mut failure));
+        settle(result, failure)
+    }
+
+    /// Hand one entry to `visitor`, with the metadata [`Codec::visit_entries`] promises
+    fn visit_entry(entry: &SevenZArchiveEntry, data: &mut dyn Read, visitor: &mut EntryVisitor) -> Result<()> {
+        let mode = unix_mode(entry);
+
+        // Like zip, a symlink's contents are its target
+        let mut target = Vec::new();
+        let is_symlink = mode.is_some_and(|mode| mode & S_IFMT == S_IFLNK);
+        if is_symlink {
+            data.read_to_end(&mut target)?;
+        }
+
+        let meta = EntryMeta {
+            name: entry.name().to_string(),
+            is_dir: entry.is_directory(),
+            size: Some(entry.size()),
+            modified: entry.has_last_modified_date.then(|| entry.last_modified_date().into()),
+            mode: mode.map(|mode| mode & 0o7777),
+            link: is_symlink.then(|| EntryLink::Symbolic(String::from_utf8_lossy(&target).into_owned())),
+        };
+
+        if is_symlink {
+            visitor(&meta, &mut target.as_slice())
+        } else {
+            visitor(&meta, data)
+        }
+    }
+
+    /// Compress `files` into `writer` under their archive names
+    fn push_files<W: Write + Seek>(writer: &mut SevenZWriter<W>, files: &[(String, &Path)]) -> Result<()> {
+        for (name, path) in files {
+            info!("Writing: {}", name);
+            writer.push_archive_entry(SevenZArchiveEntry::from_path(path, name.clone()), Some(File::open(path)?))?;
+        }
+        Ok(())
+    }
+
+    /// Write a single entry through the limit tracker
+    fn extract_entry(
+        entry: &SevenZArchiveEntry,
+        data: &mut dyn Read,
+        guard: &PathGuard,
+        tracker: &LimitTracker,
+    ) -> Result<()> {
+        tracker.begin_entry(entry.name())?;
+        let Some(outpath) = guard.resolve(entry.name()) else {
+            // Entries in a solid block share one stream, so skipped data still has to be consumed
+            io::copy(data, &mut io::sink())?;
+            return Ok(());
+        };
+
+        if entry.is_directory() {
+            info!("Creating directory: {}", entry.name());
+            tracker.create_dir_all(&outpath)?;
+        } else {
+            info!("Extracting file: {}", entry.name());
+
+            if let Some(parent) = outpath.parent() {
+                tracker.create_dir_all(parent)?;
+            }
+
+            // Solid archives only record a compressed size for the whole block
+            let base = match entry.compressed_size {
+                0 => RatioBase::Unknown,
+                size => RatioBase::Entry(size),
+            };
+
+            let mut outfile = tracker.create_file(&outpath)?;
+            tracker.copy(entry.name(), data, &mut outfile, base)?;
+        }
+
+        Ok(())
     }
 }
 
 impl Codec for SevenZCodec {
     fn extract(&mut self, source: &[&Path], target: &Path) -> Result<()> {
+        if is_stdio(target) {
+            return Err(ZipError::UnsupportedOperation(
+                "7z extraction to stdout is not supported".to_string()
+            ));
+        }
+
         ensure_directory_exists(target)?;
+        let mut archive = self.open_reader(source[0])?;
 
-        match &self.password {
-            Some(password) => {
-                return Err(ZipError::UnsupportedOperation(
-                    "Password-protected 7z extraction not implemented in native mode".to_string()
-                ));
+        let tracker = LimitTracker::new(self.limits);
+        tracker.check_declared_entries(archive.archive().files.len() as u64)?;
+        let guard = PathGuard::new(target, self.path_policy)?;
+
+        let result = Self::for_each_entry(&mut archive, |entry, data| {
+            Self::extract_entry(entry, data, &guard, &tracker)
+        });
+
+        tracker.guard(result)?;
+        guard.finish()
+    }
+
+    fn visit_entries(&mut self, source: &Path, visitor: &mut EntryVisitor) -> Result<()> {
+        let mut archive = self.open_reader(source)?;
+        Self::for_each_entry(&mut archive, |entry, data| Self::visit_entry(entry, data, visitor))
+    }
+
+    fn visit_entries_parallel(&mut self, source: &Path, visitor: &SharedVisitor) -> Result<()> {
+        // Stdin and split volumes can only be read through once
+        if is_stdio(source) || discover_volumes(source).is_some() {
+            return self.visit_entries(source, &mut |meta, data| visitor(meta, data));
+        }
+
+        let password = self.password();
+        let archive = Archive::read(&mut File::open(source)?, password.as_slice())?;
+
+        // Directories and empty files belong to no block
+        for (index, entry) in archive.files.iter().enumerate() {
+            if archive.stream_map.file_folder_index[index].is_none() {
+                Self::visit_entry(entry, &mut io::empty(), &mut |meta, data| visitor(meta, data))?;
             }
-            None => {
+        }
+
+        // Blocks are compressed independently, so each is decoded on its own thread
+        (0..archive.folders.len()).into_par_iter().try_for_each(|block| {
+            let mut input = File::open(source)?;
+            let mut failure = None;
+            let result = BlockDecoder::new(block, &archive, password.as_slice(), &mut input)
+                .for_each_entries(&mut |entry, data| {
+                    park(Self::visit_entry(entry, data, &mut |meta, data| visitor(meta, data)), &mut failure)
+                });
+            settle(result, failure)
+        })
+    }
+
+    fn cat_entries(&mut self, source: &Path, names: &[String], out: &mut dyn Write) -> Result<()> {
+        let password = self.password();
+        let mut input = Self::open_source(source)?;
+        let archive = Archive::read(&mut input, password.as_slice())?;
+        let mut missing = Vec::new();
+
+        for name in names {
+            let Some(index) = archive.files.iter().rposition(|f| same_entry_name(name, f.name())) else {
+                missing.push(name.as_str());
+                continue;
+            };
+            let file = &archive.files[index];
+            if file.is_directory() {
+                return Err(ZipError::Other(format!("{} is a directory", name)));
+            }
+            // Empty files have no block to decode
+            let Some(block) = archive.stream_map.file_folder_index[index] else { continue };
+
+            // Only the entry's block is decoded, and a solid one only up to the entry
+            BlockDecoder::new(block, &archive, password.as_slice(), &mut input).for_each_entries(&mut |entry, data| {
+                if !std::ptr::eq(entry, file) {
+                    io::copy(data, &mut io::sink())?;
+                    return Ok(true);
+                }
+                io::copy(data, out)?;
+                Ok(false)
+            })?;
+        }
+
+        missing_entries(&missing)
+    }
+
+    fn entry_writer(&mut self, target: &Path) -> Result<Box<dyn EntryWriter + '_>> {
+        if self.volume_size.is_some() {
+            return Err(ZipError::UnsupportedOperation(
+                "Converting into split 7z volumes is not supported".to_string()
+            ));
+        }
+
+        // As in `compress`, stdout gets a copy once the start header is written
+        let (output, stdout) = if is_stdio(target) {
+            (tempfile::tempfile()?, Some(io::stdout()))
+        } else {
+            ensure_directory_exists(target.parent().unwrap_or(Path::new(".")))?;
+            (File::create(target)?, None)
+        };
+
+        Ok(Box::new(SevenZEntryWriter { writer: SevenZWriter::new(output)?, stdout }))
+    }
+
+    fn add_entries(&mut self, archive: &Path, source: &[&Path], only_changed: bool) -> Result<()> {
+        if is_stdio(archive) || discover_volumes(archive).is_some() {
+            return Err(ZipError::UnsupportedOperation(
+                "Only a single 7z file can be edited in place, not stdin or split volumes".to_string()
+            ));
+        }
+
+        let existing = Archive::open(archive).map_err(|e| match e {
+            sevenz_rust2::Error::PasswordRequired => ZipError::UnsupportedOperation(
+                "7z archives with encrypted headers cannot be edited".to_string()
+            ),
+            e => e.into(),
+        })?;
+
+        let mut files = Vec::new();
+        for src in source {
+            if !src.is_file() {
                 return Err(ZipError::UnsupportedOperation(
-                    "7z extraction not implemented in native mode".to_string()
+                    "Directory compression with 7z not yet implemented".to_string()
                 ));
             }
+            let name = src.file_name().and_then(|n| n.to_str()).unwrap_or("unknown").to_string();
+
+            let changed = match existing.files.iter().rposition(|entry| entry.name() == name) {
+                Some(index) if only_changed => {
+                    let entry = &existing.files[index];
+                    is_changed(src, &stored_file(entry), || Ok(entry.has_crc.then(|| format!("{:08x}", entry.crc))))?
+                }
+                _ => true,
+            };
+            if changed {
+                files.push((name, *src));
+            }
+        }
+
+        if files.is_empty() {
+            info!("{:?} is up to date", archive);
+            return Ok(());
+        }
+
+        let replaced: HashSet<&str> = files.iter()
+            .map(|(name, _)| name.as_str())
+            .filter(|name| existing.files.iter().any(|entry| entry.name() == *name))
+            .collect();
+
+        if replaced.is_empty() {
+            // The new files go into an archive of their own, whose data then moves over as is
+            let mut writer = SevenZWriter::new(tempfile::tempfile()?)?;
+            Self::push_files(&mut writer, &files)?;
+            sevenz_edit::append(archive, &existing, &mut writer.finish()?)?;
+
+            info!("Appended {} entries to {:?}", files.len(), archive);
+            return Ok(());
+        }
+
+        // Replaced entries may share a solid block with others, so the archive is rebuilt
+        if sevenz_edit::is_encrypted(&existing) {
+            return Err(ZipError::UnsupportedOperation(
+                "Entries of an encrypted 7z archive cannot be replaced".to_string()
+            ));
         }
+
+        let mut writer = SevenZWriter::new(staging_file(archive)?)?;
+        let mut reader = self.open_reader(archive)?;
+        Self::for_each_entry(&mut reader, |entry, data| {
+            if replaced.contains(entry.name()) {
+                io::copy(data, &mut io::sink())?;
+            } else {
+                writer.push_archive_entry(entry.clone(), entry.has_stream().then_some(data))?;
+            }
+            Ok(())
+        })?;
+        Self::push_files(&mut writer, &files)?;
+        replace_with_staged(writer.finish()?, archive)?;
+
+        info!("Added {} and replaced {} entries in {:?}", files.len() - replaced.len(), replaced.len(), archive);
+        Ok(())
     }
 
     fn compress(&mut self, source: &[&Path], target: &Path, _exclude: Option<&[&Path]>) -> Result<()> {
-        let target = ensure_extension(target, "7z");
-        ensure_directory_exists(target.parent().unwrap_or(Path::new(".")))?;
+        let split_size = self.volume_size.map(volume_bytes).transpose()?;
+        if split_size.is_some() && is_stdio(target) {
+            return Err(ZipError::UnsupportedOperation(
+                "Split volumes cannot be written to stdout".to_string()
+            ));
+        }
 
-        let mut sz_writer = SevenZWriter::create(target.as_path())?;
+        // 7z rewrites its start header on finish, so stdout and split output go through a temp file
+        let mut sz_writer = if is_stdio(target) || split_size.is_some() {
+            SevenZWriter::new(tempfile::tempfile()?)?
+        } else {
+            let target = ensure_extension(target, "7z");
+            ensure_directory_exists(target.parent().unwrap_or(Path::new(".")))?;
+            SevenZWriter::new(File::create(target)?)?
+        };
 
         for src in source {
             debug!("Writing {:?}", src);
 
+            if is_stdio(src) {
+                sz_writer.push_archive_entry(SevenZArchiveEntry::new_file("-"), Some(io::stdin().lock()))?;
+                continue;
+            }
+
             let name = if src.is_file() {
                 src.file_name()
                     .and_then(|n| n.to_str())
@@ -68,7 +387,22 @@ impl Codec for SevenZCodec {
             }
         }
 
-        sz_writer.finish()?;
+        let mut written = sz_writer.finish()?;
+
+        if is_stdio(target) {
+            written.seek(SeekFrom::Start(0))?;
+            let mut stdout = io::stdout().lock();
+            io::copy(&mut written, &mut stdout)?;
+            stdout.flush()?;
+        } else if let Some(size) = split_size {
+            let target = ensure_extension(target, "7z");
+            ensure_directory_exists(target.parent().unwrap_or(Path::new(".")))?;
+
+            written.seek(SeekFrom::Start(0))?;
+            let volumes = split_numbered(&mut written, &target, size)?;
+            info!("Archive split into {} volumes", volumes.len());
+        }
+
         Ok(())
     }
 
@@ -76,5 +410,106 @@ impl Codec for SevenZCodec {
         (0, 0)
     }
 
+    fn set_limits(&mut self, limits: ExtractLimits) {
+        self.limits = limits;
+    }
+
+    fn set_path_policy(&mut self, policy: PathPolicy) {
+        self.path_policy = policy;
+    }
+
     fn set_compression_level(&mut self, _level: u8) {}
-}
\ No newline at end of file
+}
+
+/// Writes entries into a new 7z archive
+struct SevenZEntryWriter {
+    writer: SevenZWriter<File>,
+    stdout: Option<io::Stdout>,
+}
+
+impl EntryWriter for SevenZEntryWriter {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities { modified: true, mode: true, symlinks: true, hard_links: false, directories: true }
+    }
+
+    fn write_entry(&mut self, meta: &EntryMeta, data: &mut dyn Read) -> Result<()> {
+        let name = meta.name.trim_end_matches('/');
+        let mut entry = if meta.is_dir {
+            SevenZArchiveEntry::new_folder(name)
+        } else {
+            SevenZArchiveEntry::new_file(name)
+        };
+
+        if let Some(modified) = meta.modified.and_then(|t| t.try_into().ok()) {
+            entry.last_modified_date = modified;
+            entry.has_last_modified_date = true;
+        }
+
+        let file_type = match &meta.link {
+            Some(EntryLink::Symbolic(_)) => S_IFLNK,
+            Some(EntryLink::Hard(_)) => {
+                return Err(ZipError::UnsupportedOperation(format!("{} is a hard link, which 7z cannot store", meta.name)));
+            }
+            None if meta.is_dir => S_IFDIR,
+            None => S_IFREG,
+        };
+        if meta.mode.is_some() || file_type == S_IFLNK {
+            let mode = file_type | (meta.mode.unwrap_or(0o777) & 0o7777);
+            entry.has_windows_attributes = true;
+            entry.windows_attributes = (mode << 16) | FILE_ATTRIBUTE_UNIX_EXTENSION
+                | if meta.is_dir { FILE_ATTRIBUTE_DIRECTORY } else { 0 };
+        }
+
+        match &meta.link {
+            Some(EntryLink::Symbolic(target)) => self.writer.push_archive_entry(entry, Some(target.as_bytes()))?,
+            _ if meta.is_dir => self.writer.push_archive_entry::<&[u8]>(entry, None)?,
+            _ => self.writer.push_archive_entry(entry, Some(data))?,
+        };
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        let mut written = self.writer.finish()?;
+        if let Some(stdout) = self.stdout {
+            written.seek(SeekFrom::Start(0))?;
+            let mut stdout = stdout.lock();
+            io::copy(&mut written, &mut stdout)?;
+            stdout.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Turn the result of a callback into what a sevenz callback may return,
+/// parking our own error in `failure`
+fn park(result: Result<()>, failure: &mut Option<ZipError>) -> std::result::Result<bool, sevenz_rust2::Error> {
+    result.map(|_| true).map_err(|e| {
+        let message = e.to_string();
+        *failure = Some(e);
+        sevenz_rust2::Error::other(message)
+    })
+}
+
+/// The outcome of a sevenz walk, preferring a parked error over the one it was wrapped in
+fn settle<T>(result: std::result::Result<T, sevenz_rust2::Error>, failure: Option<ZipError>) -> Result<()> {
+    match (result, failure) {
+        (_, Some(e)) => Err(e),
+        (result, None) => result.map(|_| ()).map_err(ZipError::from),
+    }
+}
+
+/// Unix mode bits p7zip keeps in the upper half of the attributes
+fn unix_mode(entry: &SevenZArchiveEntry) -> Option<u32> {
+    let attributes = entry.windows_attributes();
+    (entry.has_windows_attributes && attributes & FILE_ATTRIBUTE_UNIX_EXTENSION != 0).then_some(attributes >> 16)
+}
+
+/// What a 7z entry records about its file; times are kept in 100 ns steps
+fn stored_file(entry: &SevenZArchiveEntry) -> StoredFile {
+    StoredFile {
+        size: entry.size(),
+        modified: entry.has_last_modified_date.then(|| SystemTime::from(entry.last_modified_date())),
+        time_step: Duration::from_nanos(100),
+    }
+}
+
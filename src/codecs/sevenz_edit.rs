@@ -0,0 +1,393 @@
+use crate::utils::{replace_with_staged, staging_file};
+use crate::Result;
+use sevenz_rust2::{Archive, SevenZArchiveEntry};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const SIGNATURE_HEADER_SIZE: u64 = 32;
+
+const K_END: u8 = 0x00;
+const K_HEADER: u8 = 0x01;
+const K_MAIN_STREAMS_INFO: u8 = 0x04;
+const K_FILES_INFO: u8 = 0x05;
+const K_PACK_INFO: u8 = 0x06;
+const K_UNPACK_INFO: u8 = 0x07;
+const K_SUB_STREAMS_INFO: u8 = 0x08;
+const K_SIZE: u8 = 0x09;
+const K_CRC: u8 = 0x0A;
+const K_FOLDER: u8 = 0x0B;
+const K_CODERS_UNPACK_SIZE: u8 = 0x0C;
+const K_NUM_UNPACK_STREAM: u8 = 0x0D;
+const K_EMPTY_STREAM: u8 = 0x0E;
+const K_EMPTY_FILE: u8 = 0x0F;
+const K_ANTI: u8 = 0x10;
+const K_NAME: u8 = 0x11;
+const K_C_TIME: u8 = 0x12;
+const K_A_TIME: u8 = 0x13;
+const K_M_TIME: u8 = 0x14;
+const K_WIN_ATTRIBUTES: u8 = 0x15;
+
+/// Method id of the AES coder
+pub(super) const AES_METHOD: &[u8] = &[0x06, 0xf1, 0x07, 0x01];
+
+/// Whether any data in the archive is encrypted
+pub(super) fn is_encrypted(archive: &Archive) -> bool {
+    archive.folders.iter()
+        .flat_map(|folder| &folder.coders)
+        .any(|coder| coder.decompression_method_id() == AES_METHOD)
+}
+
+/// Append the entries of the 7z archive in `staged` to the one at `target`,
+/// whose header has already been read into `existing`
+pub(super) fn append(target: &Path, existing: &Archive, staged: &mut File) -> Result<()> {
+    let added = Archive::read(staged, &[])?;
+    let mut output = staging_file(target)?;
+    let file = output.as_file_mut();
+
+    // Everything up to the old header: signature header and packed streams
+    let streams_end = SIGNATURE_HEADER_SIZE + existing.pack_pos + existing.pack_sizes.iter().sum::<u64>();
+    copy_exact(&mut File::open(target)?, file, streams_end)?;
+
+    staged.seek(SeekFrom::Start(SIGNATURE_HEADER_SIZE + added.pack_pos))?;
+    copy_exact(staged, file, added.pack_sizes.iter().sum())?;
+
+    let header = encode_header(&[existing, &added], existing.pack_pos);
+    let header_pos = file.stream_position()?;
+    file.write_all(&header)?;
+
+    // Start header: offset, size and CRC of the header, then the CRC of those 20 bytes
+    let mut start = Vec::with_capacity(20);
+    start.extend_from_slice(&(header_pos - SIGNATURE_HEADER_SIZE).to_le_bytes());
+    start.extend_from_slice(&(header.len() as u64).to_le_bytes());
+    start.extend_from_slice(&crc32fast::hash(&header).to_le_bytes());
+
+    file.seek(SeekFrom::Start(8))?;
+    file.write_all(&crc32fast::hash(&start).to_le_bytes())?;
+    file.write_all(&start)?;
+    file.sync_all()?;
+
+    replace_with_staged(output, target)
+}
+
+fn copy_exact(from: &mut File, to: &mut File, len: u64) -> Result<()> {
+    if io::copy(&mut from.take(len), to)? != len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(())
+}
+
+/// A stream within a folder: size and CRC if recorded
+type Stream = (u64, Option<u32>);
+
+/// Plain (not compressed) header for the streams and files of `parts`,
+/// whose packed streams are stored one after another from `pack_pos`
+fn encode_header(parts: &[&Archive], pack_pos: u64) -> Vec<u8> {
+    let mut packs = Vec::new();
+    let mut folders = Vec::new();
+    let mut files = Vec::new();
+
+    for archive in parts {
+        packs.extend(archive.pack_sizes.iter().enumerate().map(|(i, &size)| {
+            (size, archive.pack_crcs_defined.contains(i).then(|| archive.pack_crcs[i] as u32))
+        }));
+        folders.extend(archive.folders.iter().zip(sub_streams(archive)));
+        files.extend(&archive.files);
+    }
+
+    let mut out = Vec::new();
+    out.push(K_HEADER);
+    out.push(K_MAIN_STREAMS_INFO);
+
+    if !packs.is_empty() {
+        out.push(K_PACK_INFO);
+        write_number(&mut out, pack_pos);
+        write_number(&mut out, packs.len() as u64);
+        out.push(K_SIZE);
+        for (size, _) in &packs {
+            write_number(&mut out, *size);
+        }
+        let crcs: Vec<_> = packs.iter().map(|(_, crc)| *crc).collect();
+        if crcs.iter().any(Option::is_some) {
+            write_crcs(&mut out, &crcs);
+        }
+        out.push(K_END);
+    }
+
+    if !folders.is_empty() {
+        out.push(K_UNPACK_INFO);
+        out.push(K_FOLDER);
+        write_number(&mut out, folders.len() as u64);
+        // Folders are stored inline, not in an additional stream
+        out.push(0);
+        for (folder, _) in &folders {
+            write_number(&mut out, folder.coders.len() as u64);
+
+            for coder in &folder.coders {
+                let id = coder.decompression_method_id();
+                let simple = coder.num_in_streams == 1 && coder.num_out_streams == 1;
+
+                let mut flags = id.len() as u8;
+                if !simple {
+                    flags |= 0x10;
+                }
+                if !coder.properties.is_empty() {
+                    flags |= 0x20;
+                }
+                out.push(flags);
+                out.extend_from_slice(id);
+
+                if !simple {
+                    write_number(&mut out, coder.num_in_streams);
+                    write_number(&mut out, coder.num_out_streams);
+                }
+                if !coder.properties.is_empty() {
+                    write_number(&mut out, coder.properties.len() as u64);
+                    out.extend_from_slice(&coder.properties);
+                }
+            }
+
+            for pair in &folder.bind_pairs {
+                write_number(&mut out, pair.in_index);
+                write_number(&mut out, pair.out_index);
+            }
+            // A single packed stream is implied by the bind pairs
+            if folder.packed_streams.len() > 1 {
+                for &index in &folder.packed_streams {
+                    write_number(&mut out, index);
+                }
+            }
+        }
+        out.push(K_CODERS_UNPACK_SIZE);
+        for (folder, _) in &folders {
+            for &size in &folder.unpack_sizes {
+                write_number(&mut out, size);
+            }
+        }
+        // Folder CRCs are left out; like 7-Zip, the CRC of every stream goes below instead
+        out.push(K_END);
+
+        out.push(K_SUB_STREAMS_INFO);
+        out.push(K_NUM_UNPACK_STREAM);
+        for (_, streams) in &folders {
+            write_number(&mut out, streams.len() as u64);
+        }
+        // The last size in each folder follows from the folder's size
+        out.push(K_SIZE);
+        for (_, streams) in &folders {
+            for (size, _) in streams.iter().take(streams.len().saturating_sub(1)) {
+                write_number(&mut out, *size);
+            }
+        }
+        let digests: Vec<_> = folders.iter().flat_map(|(_, streams)| streams.iter().map(|(_, crc)| *crc)).collect();
+        if !digests.is_empty() {
+            write_crcs(&mut out, &digests);
+        }
+        out.push(K_END);
+    }
+    out.push(K_END);
+
+    write_files(&mut out, &files);
+    out.push(K_END);
+    out
+}
+
+/// Streams of each folder of `archive`, in folder order
+fn sub_streams(archive: &Archive) -> Vec<Vec<Stream>> {
+    let Some(info) = &archive.sub_streams_info else {
+        // Without sub-stream information every folder holds a single stream
+        return archive.folders.iter()
+            .map(|folder| vec![(folder.get_unpack_size(), folder.has_crc.then_some(folder.crc as u32))])
+            .collect();
+    };
+
+    let mut next = 0;
+    archive.folders.iter().map(|folder| {
+        let streams = (next..next + folder.num_unpack_sub_streams)
+            .map(|i| (info.unpack_sizes[i], info.has_crc.contains(i).then(|| info.crcs[i] as u32)))
+            .collect();
+        next += folder.num_unpack_sub_streams;
+        streams
+    }).collect()
+}
+
+fn write_files(out: &mut Vec<u8>, files: &[&SevenZArchiveEntry]) {
+    out.push(K_FILES_INFO);
+    write_number(out, files.len() as u64);
+
+    let empty: Vec<_> = files.iter().filter(|f| !f.has_stream).collect();
+    if !empty.is_empty() {
+        write_property(out, K_EMPTY_STREAM, &bits(files.iter().map(|f| !f.has_stream)));
+        // These two describe only the entries without a stream
+        if empty.iter().any(|f| !f.is_directory) {
+            write_property(out, K_EMPTY_FILE, &bits(empty.iter().map(|f| !f.is_directory)));
+        }
+        if empty.iter().any(|f| f.is_anti_item) {
+            write_property(out, K_ANTI, &bits(empty.iter().map(|f| f.is_anti_item)));
+        }
+    }
+
+    // UTF-16 names, each terminated by a zero
+    let mut names = vec![0];
+    for file in files {
+        names.extend(file.name.encode_utf16().chain([0]).flat_map(u16::to_le_bytes));
+    }
+    write_property(out, K_NAME, &names);
+
+    write_times(out, K_C_TIME, files, |f| f.has_creation_date.then(|| f.creation_date.to_raw()));
+    write_times(out, K_A_TIME, files, |f| f.has_access_date.then(|| f.access_date.to_raw()));
+    write_times(out, K_M_TIME, files, |f| f.has_last_modified_date.then(|| f.last_modified_date.to_raw()));
+
+    let attributes: Vec<_> = files.iter().map(|f| f.has_windows_attributes.then_some(f.windows_attributes)).collect();
+    if attributes.iter().any(Option::is_some) {
+        let mut data = defined(&attributes);
+        data.push(0);
+        for value in attributes.iter().flatten() {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        write_property(out, K_WIN_ATTRIBUTES, &data);
+    }
+
+    out.push(K_END);
+}
+
+fn write_times(out: &mut Vec<u8>, id: u8, files: &[&SevenZArchiveEntry], time: impl Fn(&SevenZArchiveEntry) -> Option<u64>) {
+    let times: Vec<_> = files.iter().map(|f| time(f)).collect();
+    if times.iter().all(Option::is_none) {
+        return;
+    }
+
+    let mut data = defined(&times);
+    // Stored inline, not in an additional stream
+    data.push(0);
+    for value in times.iter().flatten() {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    write_property(out, id, &data);
+}
+
+fn write_property(out: &mut Vec<u8>, id: u8, data: &[u8]) {
+    out.push(id);
+    write_number(out, data.len() as u64);
+    out.extend_from_slice(data);
+}
+
+/// CRC list: which are defined, then the defined values
+fn write_crcs(out: &mut Vec<u8>, crcs: &[Option<u32>]) {
+    out.push(K_CRC);
+    out.extend(defined(crcs));
+    for crc in crcs.iter().flatten() {
+        out.extend_from_slice(&crc.to_le_bytes());
+    }
+}
+
+/// "All defined" marker, or a zero followed by a bit per value
+fn defined<T>(values: &[Option<T>]) -> Vec<u8> {
+    if values.iter().all(Option::is_some) {
+        vec![1]
+    } else {
+        let mut data = vec![0];
+        data.extend(bits(values.iter().map(Option::is_some)));
+        data
+    }
+}
+
+/// Bit vector, most significant bit first
+fn bits(values: impl Iterator<Item = bool>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (i, set) in values.enumerate() {
+        if i % 8 == 0 {
+            bytes.push(0);
+        }
+        if set {
+            *bytes.last_mut().unwrap() |= 0x80 >> (i % 8);
+        }
+    }
+    bytes
+}
+
+/// 7z variable-length number: leading one bits in the first byte count the extra bytes
+fn write_number(out: &mut Vec<u8>, value: u64) {
+    let extra = (1..=8).find(|&n| n == 8 || value < 1 << (7 * (n + 1))).unwrap_or(8);
+    let extra = if value < 0x80 { 0 } else { extra };
+
+    let high = if extra == 8 { 0 } else { (value >> (8 * extra)) as u8 };
+    let mask = !(0xff_u16 >> extra) as u8;
+    out.push(mask | high);
+    out.extend_from_slice(&value.to_le_bytes()[..extra]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_use_the_7z_encoding() {
+        let encode = |value| {
+            let mut out = Vec::new();
+            write_number(&mut out, value);
+            out
+        };
+
+        assert_eq!(encode(0x7f), [0x7f]);
+        assert_eq!(encode(0x80), [0x80, 0x80]);
+        assert_eq!(encode(0x3fff), [0xbf, 0xff]);
+        assert_eq!(encode(0x4000), [0xc0, 0x00, 0x40]);
+        assert_eq!(encode(u64::MAX), [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn appended_archive_reads_back() {
+        use sevenz_rust2::{SeqReader, SevenZReader, SevenZWriter, SourceReader};
+
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("a.7z");
+
+        // A solid block of two files, a directory and an empty file
+        let mut writer = SevenZWriter::create(&target).unwrap();
+        let solid: Vec<&[u8]> = vec![b"first file", b"second file"];
+        writer.push_archive_entries(
+            vec![SevenZArchiveEntry::new_file("one.txt"), SevenZArchiveEntry::new_file("two.txt")],
+            SeqReader::new(solid.into_iter().map(SourceReader::new).collect()),
+        ).unwrap();
+        writer.push_archive_entry::<&[u8]>(SevenZArchiveEntry::new_folder("dir"), None).unwrap();
+        writer.push_archive_entry::<&[u8]>(SevenZArchiveEntry::new_file("empty.txt"), None).unwrap();
+        writer.finish().unwrap();
+
+        let mut scratch = SevenZWriter::new(tempfile::tempfile().unwrap()).unwrap();
+        scratch.push_archive_entry(SevenZArchiveEntry::new_file("dir/three.txt"), Some(&b"third file"[..])).unwrap();
+        scratch.push_archive_entry::<&[u8]>(SevenZArchiveEntry::new_file("empty2.txt"), None).unwrap();
+        let mut staged = scratch.finish().unwrap();
+
+        let existing = Archive::open(&target).unwrap();
+        assert!(existing.folders.iter().any(|folder| folder.num_unpack_sub_streams == 2));
+        append(&target, &existing, &mut staged).unwrap();
+
+        let mut entries = Vec::new();
+        let mut reader = SevenZReader::open(&target, sevenz_rust2::Password::empty()).unwrap();
+        reader.for_each_entries(|entry, data| {
+            let mut contents = String::new();
+            data.read_to_string(&mut contents)?;
+            entries.push((entry.name().to_string(), entry.is_directory(), contents));
+            Ok(true)
+        }).unwrap();
+
+        // The reader visits entries with data first
+        entries.sort();
+        let expected = [
+            ("dir", true, ""),
+            ("dir/three.txt", false, "third file"),
+            ("empty.txt", false, ""),
+            ("empty2.txt", false, ""),
+            ("one.txt", false, "first file"),
+            ("two.txt", false, "second file"),
+        ].map(|(name, dir, contents)| (name.to_string(), dir, contents.to_string()));
+        assert_eq!(entries, expected);
+
+        // Every stream kept its CRC, which the reader checked above
+        let archive = Archive::open(&target).unwrap();
+        let info = archive.sub_streams_info.as_ref().unwrap();
+        assert_eq!(info.unpack_sizes.len(), 3);
+        assert!((0..3).all(|i| info.has_crc.contains(i)));
+    }
+}
@@ -0,0 +1,737 @@
+use crate::codecs::zip_salvage::read_up_to;
+use crate::utils::{is_stdio, spool_stdin};
+use crate::{Result, ZipError};
+use flate2::bufread::GzDecoder;
+use log::{info, warn};
+use lzma_rust2::LZMA2Reader;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Take};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+const GZIP_MAGIC: [u8; 3] = [0x1f, 0x8b, 0x08];
+const XZ_MAGIC: [u8; 6] = [0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const XZ_STREAM_HEADER_LEN: usize = 12;
+const XZ_MAX_BLOCK_HEADER: usize = 1024;
+const LZMA2_FILTER: u64 = 0x21;
+const SCAN_CHUNK: usize = 64 * 1024;
+
+/// What a salvage pass over a compressed stream found
+#[derive(Debug, Default, Clone)]
+pub struct StreamReport {
+    /// gzip members or xz blocks that decoded and passed their check
+    pub intact: u64,
+    /// Bytes handed out, zero fill included
+    pub written: u64,
+    pub zero_filled: u64,
+    /// One line per damaged region of the input
+    pub damage: Vec<String>,
+    /// Output that is zero fill or failed its check; an empty range marks a
+    /// gap of unknown length at that offset
+    pub suspect: Vec<Range<u64>>,
+}
+
+impl StreamReport {
+    /// Whether any output in `range` may be wrong or missing
+    pub fn is_suspect(&self, range: &Range<u64>) -> bool {
+        self.suspect.iter().any(|gap| {
+            if gap.is_empty() {
+                range.start < gap.start && gap.start < range.end
+            } else {
+                range.start < gap.end && gap.start < range.end
+            }
+        })
+    }
+
+    /// Log what was recovered and fail if any of the input was damaged
+    pub fn summarise(&self, units: &str) -> Result<()> {
+        info!(
+            "Recovered {} bytes ({} zero-filled) from {} intact {}",
+            self.written, self.zero_filled, self.intact, units
+        );
+
+        if self.damage.is_empty() {
+            return Ok(());
+        }
+        Err(ZipError::Other(format!(
+            "{} damaged regions in the input: {}", self.damage.len(), self.damage.join("; ")
+        )))
+    }
+
+    fn damaged(&mut self, message: String) {
+        warn!("{}", message);
+        self.damage.push(message);
+    }
+}
+
+/// Report shared between a salvage reader and whoever consumes its output
+pub type SharedReport = Arc<Mutex<StreamReport>>;
+
+/// Open a source for salvage, spooling stdin since damage means seeking back and forth
+pub fn open_seekable(path: &Path) -> Result<File> {
+    if is_stdio(path) {
+        spool_stdin()
+    } else {
+        Ok(File::open(path)?)
+    }
+}
+
+/// Reader over every gzip member that can still be decoded
+pub struct GzSalvage {
+    file: File,
+    len: u64,
+    /// Member being decoded, with its input and output start offsets
+    member: Option<(GzDecoder<BufReader<File>>, u64, u64)>,
+    /// Where the next member starts or, after damage, where to look for one
+    next: u64,
+    resync: bool,
+    consumed: Arc<AtomicU64>,
+    report: SharedReport,
+}
+
+impl GzSalvage {
+    pub fn new(file: File) -> io::Result<Self> {
+        Ok(Self {
+            len: file.metadata()?.len(),
+            file,
+            member: None,
+            next: 0,
+            resync: false,
+            consumed: Arc::new(AtomicU64::new(0)),
+            report: SharedReport::default(),
+        })
+    }
+
+    pub fn report(&self) -> SharedReport {
+        self.report.clone()
+    }
+
+    /// Furthest input offset read so far, for the compression ratio limit
+    pub fn counter(&self) -> Arc<AtomicU64> {
+        self.consumed.clone()
+    }
+
+    /// Position a decoder on the next member; false once there is none
+    fn next_member(&mut self) -> io::Result<bool> {
+        let mut from = self.next;
+
+        while from < self.len {
+            let at = if self.resync {
+                match find_bytes(&mut self.file, from, &GZIP_MAGIC)? {
+                    Some(at) => at,
+                    None => break,
+                }
+            } else {
+                from
+            };
+
+            // Members found by scanning could be chance matches inside compressed data
+            if !self.resync || self.decodes_from(at)? {
+                self.file.seek(SeekFrom::Start(at))?;
+                let decoder = GzDecoder::new(BufReader::new(self.file.try_clone()?));
+                self.member = Some((decoder, at, self.report.lock().unwrap().written));
+                return Ok(true);
+            }
+            from = at + 1;
+        }
+
+        Ok(false)
+    }
+
+    /// Whether a member at `at` decodes to its end or to the end of the file
+    fn decodes_from(&self, at: u64) -> io::Result<bool> {
+        let mut file = self.file.try_clone()?;
+        file.seek(SeekFrom::Start(at))?;
+        let mut decoder = GzDecoder::new(BufReader::new(file));
+
+        match io::copy(&mut decoder, &mut io::sink()) {
+            Ok(_) => Ok(true),
+            // A member cut off by the end of the file is still worth reading
+            Err(_) => Ok(decoder.get_mut().stream_position()? >= self.len),
+        }
+    }
+}
+
+impl Read for GzSalvage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            if self.member.is_none() && !self.next_member()? {
+                return Ok(0);
+            }
+            let (decoder, start, output_start) = self.member.as_mut().unwrap();
+            let (start, output_start) = (*start, *output_start);
+
+            match decoder.read(buf) {
+                Ok(0) => {
+                    self.next = decoder.get_mut().stream_position()?;
+                    self.resync = false;
+                    self.member = None;
+                    self.report.lock().unwrap().intact += 1;
+                }
+                Ok(n) => {
+                    self.consumed.fetch_max(decoder.get_mut().stream_position()?, Ordering::Relaxed);
+                    self.report.lock().unwrap().written += n as u64;
+                    return Ok(n);
+                }
+                Err(e) => {
+                    // flate2 calls running out of input corrupt, but the output up to the cut is exact
+                    let e = if decoder.get_mut().stream_position()? >= self.len {
+                        io::Error::new(io::ErrorKind::UnexpectedEof, "member is cut off")
+                    } else {
+                        e
+                    };
+                    self.member = None;
+                    self.next = start + 1;
+                    self.resync = true;
+
+                    let mut report = self.report.lock().unwrap();
+                    let written = report.written;
+                    report.suspect.push(suspect_from(&e, output_start, written)..written);
+                    report.damaged(format!("gzip member at offset {} is damaged: {}", start, e));
+                }
+            }
+        }
+    }
+}
+
+/// Reader over every xz block that can still be decoded
+pub struct XzSalvage {
+    file: File,
+    len: u64,
+    /// Where the next stream header, block header or index is expected
+    next: u64,
+    /// Scan for the next header from `next` before reading one
+    resync: bool,
+    /// Check type of the current stream; `None` between streams
+    check: Option<u8>,
+    block: Option<Block>,
+    /// Zeros still owed for the lost part of a damaged block
+    zero_fill: u64,
+    consumed: Arc<AtomicU64>,
+    report: SharedReport,
+}
+
+struct Block {
+    decoder: LZMA2Reader<Take<BufReader<File>>>,
+    start: u64,
+    data_start: u64,
+    header: BlockHeader,
+    output_start: u64,
+    produced: u64,
+    check: Check,
+}
+
+impl XzSalvage {
+    pub fn new(file: File) -> io::Result<Self> {
+        Ok(Self {
+            len: file.metadata()?.len(),
+            file,
+            next: 0,
+            resync: false,
+            check: None,
+            block: None,
+            zero_fill: 0,
+            consumed: Arc::new(AtomicU64::new(0)),
+            report: SharedReport::default(),
+        })
+    }
+
+    pub fn report(&self) -> SharedReport {
+        self.report.clone()
+    }
+
+    /// Furthest input offset read so far, for the compression ratio limit
+    pub fn counter(&self) -> Arc<AtomicU64> {
+        self.consumed.clone()
+    }
+
+    /// Walk stream headers, indexes and padding up to the next block and
+    /// start decoding it; false once nothing is left
+    fn next_block(&mut self) -> io::Result<bool> {
+        loop {
+            if self.resync {
+                self.resync = false;
+                match self.scan(self.next)? {
+                    Some(at) => self.next = at,
+                    None => return Ok(false),
+                }
+            }
+            if self.next >= self.len {
+                return Ok(false);
+            }
+
+            let mut head = vec![0_u8; XZ_MAX_BLOCK_HEADER];
+            self.file.seek(SeekFrom::Start(self.next))?;
+            let read = read_up_to(&mut self.file, &mut head)?;
+            head.truncate(read);
+
+            if let Some(check) = stream_header_check(&head) {
+                self.check = Some(check);
+                self.next += XZ_STREAM_HEADER_LEN as u64;
+                continue;
+            }
+
+            if self.check.is_none() {
+                // Stream padding is a multiple of four zero bytes
+                if head.len() >= 4 && head[..4] == [0; 4] {
+                    self.next += 4;
+                    continue;
+                }
+                self.damaged_input(format!("no xz stream header at offset {}", self.next));
+                continue;
+            }
+
+            // An index closes the stream; another one may follow
+            if head[0] == 0 {
+                self.check = None;
+                match self.scan(self.next)? {
+                    Some(at) => self.next = at,
+                    None => return Ok(false),
+                }
+                continue;
+            }
+
+            match parse_block_header(&head) {
+                Some(header) if header.dict_size.is_some() => {
+                    self.start_block(header)?;
+                    return Ok(true);
+                }
+                Some(header) => {
+                    let message = format!("xz block at offset {} uses filters that cannot be decoded here", self.next);
+                    match header.compressed {
+                        Some(compressed) => {
+                            let mut report = self.report.lock().unwrap();
+                            let written = report.written;
+                            report.suspect.push(written..written);
+                            report.damaged(message);
+                            self.next = self.block_end(self.next, header.size + compressed);
+                        }
+                        None => self.damaged_input(message),
+                    }
+                }
+                None => self.damaged_input(format!("xz block header at offset {} is damaged", self.next)),
+            }
+        }
+    }
+
+    /// Record damage at `next` and look for the next header after it
+    fn damaged_input(&mut self, message: String) {
+        let mut report = self.report.lock().unwrap();
+        let written = report.written;
+        report.suspect.push(written..written);
+        report.damaged(message);
+
+        self.next += 1;
+        self.resync = true;
+    }
+
+    fn start_block(&mut self, header: BlockHeader) -> io::Result<()> {
+        let start = self.next;
+        let data_start = start + header.size;
+
+        // The window never needs to be larger than the block itself
+        let dict_size = header.dict_size.unwrap_or_default();
+        let dict_size = header.uncompressed.map_or(dict_size, |size| size.max(4096).min(dict_size as u64) as u32);
+
+        self.file.seek(SeekFrom::Start(data_start))?;
+        let input = BufReader::new(self.file.try_clone()?).take(header.compressed.unwrap_or(u64::MAX));
+
+        self.block = Some(Block {
+            decoder: LZMA2Reader::new(input, dict_size, None),
+            start,
+            data_start,
+            header,
+            output_start: self.report.lock().unwrap().written,
+            produced: 0,
+            check: Check::new(self.check.unwrap_or_default()),
+        });
+        Ok(())
+    }
+
+    /// Offset after a block of `size` bytes (header and data) starting at `start`
+    fn block_end(&self, start: u64, size: u64) -> u64 {
+        start + size.next_multiple_of(4) + check_size(self.check.unwrap_or_default())
+    }
+
+    /// Read the block's padding and check once its data has decoded
+    fn end_block(&mut self, block: Block) -> io::Result<()> {
+        let mut input = block.decoder.into_inner().into_inner();
+        let data_end = input.stream_position()?;
+        let check_start = block.start + (data_end - block.start).next_multiple_of(4);
+        self.next = self.block_end(block.start, data_end - block.start);
+
+        let mut stored = vec![0_u8; check_size(self.check.unwrap_or_default()) as usize];
+        input.seek(SeekFrom::Start(check_start))?;
+        let read = read_up_to(&mut input, &mut stored)?;
+
+        let problem = if read < stored.len() {
+            Some("its check is cut off")
+        } else if block.header.compressed.is_some_and(|size| size != data_end - block.data_start)
+            || block.header.uncompressed.is_some_and(|size| size != block.produced)
+        {
+            Some("its sizes do not match its header")
+        } else if block.check.finish().is_some_and(|actual| actual != stored) {
+            Some("its check does not match")
+        } else {
+            None
+        };
+
+        let mut report = self.report.lock().unwrap();
+        match problem {
+            None => report.intact += 1,
+            Some(problem) => {
+                report.suspect.push(block.output_start..block.output_start + block.produced);
+                report.damaged(format!("xz block at offset {} decoded but {}", block.start, problem));
+            }
+        }
+        Ok(())
+    }
+
+    /// Note a block that failed to decode and work out where to carry on
+    fn lose_block(&mut self, block: Block, error: io::Error) {
+        let what = if error.kind() == io::ErrorKind::UnexpectedEof { "is cut off".to_string() } else { format!("is damaged: {}", error) };
+        let mut report = self.report.lock().unwrap();
+        let written = report.written;
+        let from = suspect_from(&error, block.output_start, written);
+
+        match (block.header.compressed, block.header.uncompressed) {
+            // Known sizes: skip exactly this block and keep later offsets intact
+            (Some(compressed), Some(uncompressed)) if block.produced <= uncompressed => {
+                self.next = self.block_end(block.start, block.header.size + compressed);
+                if self.next <= self.len {
+                    self.zero_fill = uncompressed - block.produced;
+                }
+                report.suspect.push(from..written + self.zero_fill);
+                let filled = match self.zero_fill {
+                    0 => String::new(),
+                    n => format!("; {} bytes replaced with zeros", n),
+                };
+                report.damaged(format!("xz block at offset {} {}{}", block.start, what, filled));
+            }
+            _ => {
+                report.suspect.push(from..written);
+                report.damaged(format!("xz block at offset {} {}", block.start, what));
+                self.next = block.data_start;
+                self.resync = true;
+            }
+        }
+    }
+
+    /// Offset of the next stream header, or block header while inside a stream, at or after `from`
+    fn scan(&mut self, from: u64) -> io::Result<Option<u64>> {
+        let mut buffer = vec![0_u8; SCAN_CHUNK + XZ_MAX_BLOCK_HEADER];
+        let mut base = from;
+
+        loop {
+            self.file.seek(SeekFrom::Start(base))?;
+            let read = read_up_to(&mut self.file, &mut buffer)?;
+            let last = if read < buffer.len() { read } else { SCAN_CHUNK };
+
+            for i in 0..last {
+                let rest = &buffer[i..read];
+                let block = self.check.is_some() && parse_block_header(rest).is_some_and(|h| h.dict_size.is_some());
+                if block || stream_header_check(rest).is_some() {
+                    return Ok(Some(base + i as u64));
+                }
+            }
+
+            if read < buffer.len() {
+                return Ok(None);
+            }
+            base += SCAN_CHUNK as u64;
+        }
+    }
+}
+
+impl Read for XzSalvage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        loop {
+            if self.zero_fill > 0 {
+                let n = buf.len().min(self.zero_fill.try_into().unwrap_or(usize::MAX));
+                buf[..n].fill(0);
+                self.zero_fill -= n as u64;
+
+                let mut report = self.report.lock().unwrap();
+                report.written += n as u64;
+                report.zero_filled += n as u64;
+                return Ok(n);
+            }
+
+            let Some(block) = self.block.as_mut() else {
+                if !self.next_block()? {
+                    return Ok(0);
+                }
+                continue;
+            };
+
+            match block.decoder.read(buf) {
+                Ok(0) => {
+                    let block = self.block.take().unwrap();
+                    self.end_block(block)?;
+                }
+                Ok(n) => {
+                    block.produced += n as u64;
+                    block.check.update(&buf[..n]);
+                    self.consumed.fetch_max(block.decoder.get_mut().get_mut().stream_position()?, Ordering::Relaxed);
+                    self.report.lock().unwrap().written += n as u64;
+                    return Ok(n);
+                }
+                Err(e) => {
+                    let block = self.block.take().unwrap();
+                    self.lose_block(block, e);
+                }
+            }
+        }
+    }
+}
+
+/// Where output of a unit that started at `output_start` stops being trustworthy
+/// after it fails with `error`; corrupt units may emit garbage before failing
+fn suspect_from(error: &io::Error, output_start: u64, written: u64) -> u64 {
+    if error.kind() == io::ErrorKind::UnexpectedEof { written } else { output_start }
+}
+
+/// Check type of a valid xz stream header at the start of `bytes`
+fn stream_header_check(bytes: &[u8]) -> Option<u8> {
+    let header = bytes.get(..XZ_STREAM_HEADER_LEN)?;
+    let stored = u32::from_le_bytes(header[8..12].try_into().unwrap());
+
+    (header.starts_with(&XZ_MAGIC) && header[6] == 0 && header[7] & 0xf0 == 0 && crc32fast::hash(&header[6..8]) == stored)
+        .then_some(header[7])
+}
+
+struct BlockHeader {
+    size: u64,
+    compressed: Option<u64>,
+    uncompressed: Option<u64>,
+    /// Dictionary size of a lone LZMA2 filter; `None` for other filter chains
+    dict_size: Option<u32>,
+}
+
+/// Parse a block header at the start of `bytes`, verifying its CRC32
+fn parse_block_header(bytes: &[u8]) -> Option<BlockHeader> {
+    let size = (*bytes.first()? as usize + 1) * 4;
+    if bytes[0] == 0 || bytes.len() < size {
+        return None;
+    }
+
+    let flags = bytes[1];
+    if flags & 0x3c != 0 {
+        return None;
+    }
+
+    let (body, stored) = bytes[..size].split_at(size - 4);
+    let mut pos = 2;
+    let compressed = if flags & 0x40 != 0 { Some(read_varint(body, &mut pos)?) } else { None };
+    let uncompressed = if flags & 0x80 != 0 { Some(read_varint(body, &mut pos)?) } else { None };
+
+    let mut filters = Vec::new();
+    for _ in 0..=(flags & 0x03) {
+        let id = read_varint(body, &mut pos)?;
+        let props_len = read_varint(body, &mut pos)? as usize;
+        filters.push((id, body.get(pos..pos.checked_add(props_len)?)?));
+        pos += props_len;
+    }
+
+    // Header padding is zero, and the CRC covers everything before it
+    if body.get(pos..)?.iter().any(|&b| b != 0) || crc32fast::hash(body) != u32::from_le_bytes(stored.try_into().unwrap()) {
+        return None;
+    }
+
+    let dict_size = match filters[..] {
+        [(LZMA2_FILTER, &[props])] if props <= 40 => Some(match props {
+            40 => u32::MAX,
+            _ => (2 | (props as u32 & 1)) << (props / 2 + 11),
+        }),
+        _ => None,
+    };
+
+    Some(BlockHeader { size: size as u64, compressed, uncompressed, dict_size })
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..63).step_by(7) {
+        let byte = *bytes.get(*pos)?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn check_size(check: u8) -> u64 {
+    match check {
+        0 => 0,
+        1..=3 => 4,
+        4..=6 => 8,
+        7..=9 => 16,
+        10..=12 => 32,
+        _ => 64,
+    }
+}
+
+/// Running block check; `None` for no check or one this reader does not know
+enum Check {
+    None,
+    Crc32(crc32fast::Hasher),
+    Crc64(u64),
+    Sha256(Sha256),
+}
+
+impl Check {
+    fn new(check: u8) -> Self {
+        match check {
+            1 => Self::Crc32(crc32fast::Hasher::new()),
+            4 => Self::Crc64(0),
+            10 => Self::Sha256(Sha256::new()),
+            _ => Self::None,
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::None => {}
+            Self::Crc32(hasher) => hasher.update(data),
+            Self::Crc64(crc) => *crc = crc64(*crc, data),
+            Self::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Check value as stored in the file
+    fn finish(self) -> Option<Vec<u8>> {
+        match self {
+            Self::None => None,
+            Self::Crc32(hasher) => Some(hasher.finalize().to_le_bytes().to_vec()),
+            Self::Crc64(crc) => Some(crc.to_le_bytes().to_vec()),
+            Self::Sha256(hasher) => Some(hasher.finalize().to_vec()),
+        }
+    }
+}
+
+/// CRC-64/XZ (ECMA-182 polynomial, reflected)
+const CRC64_TABLE: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xc96c_5795_d787_0f42 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc64(crc: u64, data: &[u8]) -> u64 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc = CRC64_TABLE[((crc ^ byte as u64) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+/// Offset of the next occurrence of `needle` at or after `from`
+fn find_bytes(file: &mut File, from: u64, needle: &[u8]) -> io::Result<Option<u64>> {
+    let mut buffer = vec![0_u8; SCAN_CHUNK + needle.len() - 1];
+    let mut base = from;
+
+    loop {
+        file.seek(SeekFrom::Start(base))?;
+        let read = read_up_to(file, &mut buffer)?;
+        if read < needle.len() {
+            return Ok(None);
+        }
+
+        if let Some(at) = buffer[..read].windows(needle.len()).position(|w| w == needle) {
+            return Ok(Some(base + at as u64));
+        }
+        base += (read - needle.len() + 1) as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use xz2::stream::{Check as XzCheck, MtStreamBuilder};
+    use xz2::write::XzEncoder;
+
+    #[test]
+    fn xz_damage_is_zero_filled_and_later_blocks_survive() {
+        const BLOCK: usize = 64 * 1024;
+        let mut seed = 1_u32;
+        let data: Vec<u8> = (0..3 * BLOCK).map(|_| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            b'a' + (seed >> 16) as u8 % 16
+        }).collect();
+
+        let stream = MtStreamBuilder::new().threads(2).block_size(BLOCK as u64).preset(6).check(XzCheck::Crc64).encoder().unwrap();
+        let mut encoder = XzEncoder::new_stream(Vec::new(), stream);
+        encoder.write_all(&data).unwrap();
+        let mut bytes = encoder.finish().unwrap();
+
+        // Flip a byte in the middle block's compressed data
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0x55;
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&bytes).unwrap();
+
+        let mut reader = XzSalvage::new(file).unwrap();
+        let mut recovered = Vec::new();
+        reader.read_to_end(&mut recovered).unwrap();
+
+        let report = reader.report().lock().unwrap().clone();
+        assert_eq!(recovered.len(), data.len());
+        assert_eq!(recovered[..BLOCK], data[..BLOCK]);
+        assert_eq!(recovered[2 * BLOCK..], data[2 * BLOCK..]);
+        assert_eq!((report.intact, report.damage.len()), (2, 1));
+        assert!(report.is_suspect(&(BLOCK as u64..2 * BLOCK as u64)));
+        assert!(!report.is_suspect(&(2 * BLOCK as u64..3 * BLOCK as u64)));
+    }
+
+    #[test]
+    fn gzip_members_after_damage_survive() {
+        let members: Vec<Vec<u8>> = (0..3_u8).map(|m| (0..64 * 1024_u32).map(|i| b'a' + ((i.wrapping_mul(2_654_435_761) >> 28) as u8 ^ m)).collect()).collect();
+        let mut bytes = Vec::new();
+        let mut offsets = Vec::new();
+        for member in &members {
+            offsets.push(bytes.len());
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(member).unwrap();
+            bytes.extend(encoder.finish().unwrap());
+        }
+
+        // Flip a byte in the middle member's compressed data
+        bytes[(offsets[1] + offsets[2]) / 2] ^= 0x55;
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&bytes).unwrap();
+
+        let mut reader = GzSalvage::new(file).unwrap();
+        let mut recovered = Vec::new();
+        reader.read_to_end(&mut recovered).unwrap();
+
+        let report = reader.report().lock().unwrap().clone();
+        assert!(recovered.starts_with(&members[0]) && recovered.ends_with(&members[2]));
+        assert_eq!((report.intact, report.damage.len()), (2, 1));
+        assert!(report.damage[0].contains(&format!("offset {}", offsets[1])));
+        assert!(!report.is_suspect(&(0..members[0].len() as u64)));
+        assert!(report.summarise("members").is_err());
+    }
+}
@@ -0,0 +1,565 @@
+use crate::checksum::Checksum;
+use crate::codecs::limits::{CountingReader, ExtractLimits, LimitTracker, RatioBase};
+use crate::codecs::safe_path::{PathGuard, PathPolicy};
+use crate::codecs::stream_salvage::SharedReport;
+use crate::codecs::zip_salvage::{read_up_to, SalvageReport};
+use crate::codecs::update::{is_changed, source_entries, DirNaming, StoredFile};
+use crate::codecs::{Capabilities, Codec, EntryLink, EntryMeta, EntryVisitor, EntryWriter};
+use crate::utils::{create_output, ensure_directory_exists, is_stdio, open_input, replace_with_staged, staging_file};
+use crate::{Result, ZipError};
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, UNIX_EPOCH};
+use tar::{Archive, Builder, Entry, EntryType, Header};
+
+/// Uncompressed tar codec
+#[derive(Default)]
+pub struct TarCodec {
+    limits: ExtractLimits,
+    path_policy: PathPolicy,
+}
+
+impl TarCodec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Codec for TarCodec {
+    fn extract(&mut self, source: &[&Path], target: &Path) -> Result<()> {
+        if is_stdio(target) {
+            return Err(ZipError::UnsupportedOperation(
+                "tar extraction to stdout is not supported".to_string()
+            ));
+        }
+
+        ensure_directory_exists(target)?;
+        let input = CountingReader::new(open_input(source[0])?);
+        let consumed = input.counter();
+        let mut archive = Archive::new(input);
+
+        let tracker = LimitTracker::new(self.limits);
+        let guard = PathGuard::new(target, self.path_policy)?;
+        tracker.guard(unpack_entries(&mut archive, &guard, &tracker, &consumed))?;
+        guard.finish()
+    }
+
+    fn visit_entries(&mut self, source: &Path, visitor: &mut EntryVisitor) -> Result<()> {
+        let mut archive = Archive::new(open_input(source)?);
+
+        for entry_result in archive.entries()? {
+            let mut entry = entry_result?;
+            visitor(&entry_meta(&entry)?, &mut entry)?;
+        }
+
+        Ok(())
+    }
+
+    fn add_entries(&mut self, archive: &Path, source: &[&Path], only_changed: bool) -> Result<()> {
+        reject_stdio(archive)?;
+        let plan = plan_additions(
+            &mut Archive::new(BufReader::new(File::open(archive)?)),
+            source_entries(source, DirNaming::UnderDirName)?,
+            only_changed,
+        )?;
+
+        if plan.files.is_empty() {
+            info!("{:?} is up to date", archive);
+            return Ok(());
+        }
+
+        if !plan.replaced.is_empty() {
+            let mut builder = Builder::new(BufWriter::new(staging_file(archive)?));
+            rewrite(&mut Archive::new(BufReader::new(File::open(archive)?)), &mut builder, &plan)?;
+            let staging = builder.into_inner()?.into_inner().map_err(|e| ZipError::Io(e.into_error()))?;
+            replace_with_staged(staging, archive)?;
+
+            info!("Rewrote {:?}, replacing {} entries", archive, plan.replaced.len());
+            return Ok(());
+        }
+
+        // Write the new entries aside first so a file that cannot be read leaves the archive untouched
+        let mut staged = Builder::new(tempfile::tempfile()?);
+        append_files(&mut staged, &plan.files)?;
+        let mut staged = staged.into_inner()?;
+        staged.seek(SeekFrom::Start(0))?;
+
+        // They take the place of the end-of-archive blocks
+        let mut file = OpenOptions::new().write(true).open(archive)?;
+        file.set_len(plan.end)?;
+        file.seek(SeekFrom::End(0))?;
+        io::copy(&mut staged, &mut file)?;
+
+        info!("Appended {} entries to {:?}", plan.files.len(), archive);
+        Ok(())
+    }
+
+    fn entry_writer(&mut self, target: &Path) -> Result<Box<dyn EntryWriter + '_>> {
+        let output = BufWriter::new(create_target(target)?);
+        Ok(Box::new(TarEntryWriter::new(output, |mut output| Ok(output.flush()?))))
+    }
+
+    fn compress(&mut self, source: &[&Path], target: &Path, _exclude: Option<&[&Path]>) -> Result<()> {
+        if source.iter().any(|p| is_stdio(p)) {
+            return Err(ZipError::UnsupportedOperation(
+                "stdin has no name to store in a tar header".to_string()
+            ));
+        }
+        let mut builder = Builder::new(BufWriter::new(create_target(target)?));
+        append_files(&mut builder, &source_entries(source, DirNaming::UnderDirName)?)?;
+        builder.into_inner()?.flush()?;
+
+        Ok(())
+    }
+
+    fn compression_level_range(&self) -> (u8, u8) {
+        (0, 0)
+    }
+
+    fn set_compression_level(&mut self, _level: u8) {}
+
+    fn set_limits(&mut self, limits: ExtractLimits) {
+        self.limits = limits;
+    }
+
+    fn set_path_policy(&mut self, policy: PathPolicy) {
+        self.path_policy = policy;
+    }
+}
+
+/// Writes entries into a tar stream, which `finish` completes once the end marker is written
+pub struct TarEntryWriter<W: Write> {
+    builder: Builder<W>,
+    finish: fn(W) -> Result<()>,
+}
+
+impl<W: Write> TarEntryWriter<W> {
+    pub fn new(inner: W, finish: fn(W) -> Result<()>) -> Self {
+        Self { builder: Builder::new(inner), finish }
+    }
+}
+
+impl<W: Write> EntryWriter for TarEntryWriter<W> {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities { modified: true, mode: true, symlinks: true, hard_links: true, directories: true }
+    }
+
+    fn write_entry(&mut self, meta: &EntryMeta, data: &mut dyn Read) -> Result<()> {
+        let mut header = Header::new_gnu();
+        let mtime = meta.modified.and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs());
+        header.set_mtime(mtime);
+        header.set_mode(meta.mode.unwrap_or(if meta.is_dir { 0o755 } else { 0o644 }) & 0o7777);
+        header.set_size(0);
+
+        match &meta.link {
+            Some(EntryLink::Symbolic(target)) => {
+                header.set_entry_type(EntryType::Symlink);
+                self.builder.append_link(&mut header, &meta.name, target)?;
+            }
+            Some(EntryLink::Hard(target)) => {
+                header.set_entry_type(EntryType::Link);
+                self.builder.append_link(&mut header, &meta.name, target)?;
+            }
+            None if meta.is_dir => {
+                header.set_entry_type(EntryType::Directory);
+                self.builder.append_data(&mut header, &meta.name, io::empty())?;
+            }
+            None => {
+                // The size goes in the header ahead of the data, so an unknown one means spooling first
+                let mut spooled;
+                let (size, data): (u64, &mut dyn Read) = match meta.size {
+                    Some(size) => (size, data),
+                    None => {
+                        spooled = tempfile::tempfile()?;
+                        let size = io::copy(data, &mut spooled)?;
+                        spooled.seek(SeekFrom::Start(0))?;
+                        (size, &mut spooled)
+                    }
+                };
+                header.set_size(size);
+                self.builder.append_data(&mut header, &meta.name, data.take(size))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        (self.finish)(self.builder.into_inner()?)
+    }
+}
+
+/// Output for a new archive, creating the directory it goes in
+pub fn create_target(target: &Path) -> Result<Box<dyn Write>> {
+    if !is_stdio(target) {
+        ensure_directory_exists(target.parent().unwrap_or(Path::new(".")))?;
+    }
+    create_output(target)
+}
+
+/// Metadata of a tar entry as handed to entry visitors
+pub fn entry_meta<R: Read>(entry: &Entry<R>) -> Result<EntryMeta> {
+    let header = entry.header();
+    let entry_type = header.entry_type();
+    let link_name = || -> Result<String> {
+        Ok(entry.link_name()?.unwrap_or_default().to_string_lossy().into_owned())
+    };
+
+    let link = if entry_type.is_symlink() {
+        Some(EntryLink::Symbolic(link_name()?))
+    } else if entry_type.is_hard_link() {
+        Some(EntryLink::Hard(link_name()?))
+    } else {
+        None
+    };
+
+    Ok(EntryMeta {
+        name: entry.path()?.to_string_lossy().into_owned(),
+        is_dir: entry_type.is_dir(),
+        size: header.size().ok(),
+        modified: header.mtime().ok().map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+        mode: header.mode().ok(),
+        link,
+    })
+}
+
+/// Unpack every entry of a tar stream into the root of `guard`, checking names
+/// and link targets with `guard` and writing data through `tracker`'s limits
+pub fn unpack_entries<R: Read>(
+    archive: &mut Archive<R>,
+    guard: &PathGuard,
+    tracker: &LimitTracker,
+    compressed: &AtomicU64,
+) -> Result<()> {
+    for entry_result in archive.entries()? {
+        let mut entry = entry_result?;
+        let entry_path = entry.path()?.to_string_lossy().into_owned();
+
+        tracker.begin_entry(&entry_path)?;
+        info!("Extracting: {:?}", entry_path);
+
+        let outpath = match guard.resolve(&entry_path) {
+            Some(path) => path,
+            None => continue,
+        };
+        unpack_entry(&mut entry, &entry_path, &outpath, guard, tracker, compressed)?;
+    }
+
+    Ok(())
+}
+
+/// Unpack what can still be read from a damaged tar stream, resyncing after
+/// corrupt headers and removing entries that overlap output `damage` marks suspect
+pub fn salvage_entries<R: Read>(
+    reader: R,
+    damage: &SharedReport,
+    guard: &PathGuard,
+    tracker: &LimitTracker,
+    compressed: &AtomicU64,
+) -> Result<SalvageReport> {
+    let mut input = CountingReader::new(reader);
+    let position = input.counter();
+    let mut report = SalvageReport::default();
+    // Extracted entries with their output path and extent in the tar stream
+    let mut unpacked = Vec::new();
+    // Bytes read ahead while looking for a header, starting with that header
+    let mut pending = Vec::new();
+
+    loop {
+        let base = position.load(Ordering::Relaxed) - pending.len() as u64;
+        let mut archive = Archive::new(Cursor::new(std::mem::take(&mut pending)).chain(&mut input));
+        // Zero-filled gaps look like the end-of-archive marker
+        archive.set_ignore_zeros(true);
+
+        let mut broken = false;
+        for entry_result in archive.entries()? {
+            let mut entry = match entry_result {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("Damaged tar header before offset {}: {}", position.load(Ordering::Relaxed), e);
+                    broken = true;
+                    break;
+                }
+            };
+            let entry_path = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+            let extent = base + entry.raw_header_position()..base + entry.raw_file_position() + entry.size();
+
+            tracker.begin_entry(&entry_path)?;
+            info!("Extracting: {:?}", entry_path);
+
+            let outpath = match guard.resolve(&entry_path) {
+                Some(path) => path,
+                None => continue,
+            };
+            let is_file = entry.header().entry_type().is_file();
+
+            match unpack_entry(&mut entry, &entry_path, &outpath, guard, tracker, compressed) {
+                Ok(()) => unpacked.push((entry_path, outpath, extent, is_file)),
+                Err(e @ ZipError::LimitExceeded(_)) => return Err(e),
+                Err(e) => {
+                    warn!("Lost {:?}: {}", entry_path, e);
+                    if is_file {
+                        fs::remove_file(&outpath).ok();
+                    }
+                    report.lost.push(entry_path);
+                }
+            }
+        }
+
+        if !broken {
+            break;
+        }
+
+        let (unread, _) = archive.into_inner().into_inner();
+        let unread = unread.get_ref()[unread.position() as usize..].to_vec();
+        match find_header(unread, &mut input)? {
+            Some(found) => pending = found,
+            None => break,
+        }
+    }
+
+    // Damage is only noticed some way into a unit, after entries decoded from it looked fine
+    let damage = damage.lock().unwrap();
+    for (entry_path, outpath, extent, is_file) in unpacked {
+        if !damage.is_suspect(&extent) {
+            report.recovered.push(entry_path);
+            continue;
+        }
+
+        warn!("Lost {:?}: overlaps damaged data", entry_path);
+        if is_file {
+            fs::remove_file(&outpath).ok();
+        }
+        report.lost.push(entry_path);
+    }
+
+    Ok(report)
+}
+
+/// Unpack what can still be read from a damaged tarball into `target` and
+/// fail if anything was lost; `units` names what `damage` counts
+pub fn salvage_into<R: Read>(
+    reader: R,
+    damage: &SharedReport,
+    units: &str,
+    compressed: &AtomicU64,
+    target: &Path,
+    limits: ExtractLimits,
+    policy: PathPolicy,
+) -> Result<()> {
+    ensure_directory_exists(target)?;
+    let tracker = LimitTracker::new(limits);
+    let guard = PathGuard::new(target, policy)?;
+    let report = tracker.guard(salvage_entries(reader, damage, &guard, &tracker, compressed))?;
+    guard.finish()?;
+
+    info!("Salvaged {} entries, {} lost", report.recovered.len(), report.lost.len());
+    let summary = damage.lock().unwrap().summarise(units);
+    if !report.lost.is_empty() {
+        return Err(ZipError::Other(format!(
+            "{} entries could not be recovered: {}", report.lost.len(), report.lost.join(", ")
+        )));
+    }
+    summary
+}
+
+/// A stream with its first block read back in front of it
+pub type Sniffed<R> = io::Chain<Cursor<Vec<u8>>, R>;
+
+/// Read the first block of a decompressed stream to tell whether it holds a
+/// tarball, handing back a reader that still starts at the first byte
+pub fn sniff_tar<R: Read>(mut reader: R) -> io::Result<(bool, Sniffed<R>)> {
+    let mut block = vec![0_u8; 512];
+    let read = read_up_to(&mut reader, &mut block)?;
+    block.truncate(read);
+    Ok((read == 512 && is_header(&block), Cursor::new(block).chain(reader)))
+}
+
+/// Read ahead to the next valid ustar header, returning it and everything
+/// read after it; `window` holds bytes already read
+fn find_header<R: Read>(mut window: Vec<u8>, input: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut chunk = vec![0_u8; 64 * 1024];
+
+    loop {
+        let mut keep_from = window.len().saturating_sub(511);
+        let mut at = 0;
+
+        while let Some(found) = window[at..].windows(5).position(|w| w == b"ustar") {
+            let magic = at + found;
+            at = magic + 1;
+            let Some(start) = magic.checked_sub(257) else { continue };
+
+            if window.len() < start + 512 {
+                keep_from = keep_from.min(start);
+                break;
+            }
+            if is_header(&window[start..start + 512]) {
+                return Ok(Some(window.split_off(start)));
+            }
+        }
+
+        window.drain(..keep_from);
+        let read = input.read(&mut chunk)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        window.extend_from_slice(&chunk[..read]);
+    }
+}
+
+fn is_header(block: &[u8]) -> bool {
+    let sum = block[..148].iter().chain(&block[156..]).map(|&b| b as u32).sum::<u32>() + 8 * b' ' as u32;
+    Header::from_byte_slice(block).cksum().is_ok_and(|stored| stored == sum)
+}
+
+/// Write one entry to `outpath`, which `guard` has already resolved
+fn unpack_entry<R: Read>(
+    entry: &mut Entry<R>,
+    entry_path: &str,
+    outpath: &Path,
+    guard: &PathGuard,
+    tracker: &LimitTracker,
+    compressed: &AtomicU64,
+) -> Result<()> {
+    let entry_type = entry.header().entry_type();
+
+    if entry_type.is_dir() {
+        tracker.create_dir_all(outpath)?;
+    } else if entry_type.is_file() {
+        if let Some(parent) = outpath.parent() {
+            tracker.create_dir_all(parent)?;
+        }
+
+        let mut outfile = tracker.create_file(outpath)?;
+        let written = tracker.copy(entry_path, &mut *entry, &mut outfile, RatioBase::Stream(compressed))?;
+        if written < entry.size() {
+            return Err(ZipError::Other(format!(
+                "{:?} is cut off after {} of {} bytes", entry_path, written, entry.size()
+            )));
+        }
+
+        #[cfg(unix)]
+        if let Ok(mode) = entry.header().mode() {
+            use std::os::unix::fs::PermissionsExt;
+            outfile.set_permissions(std::fs::Permissions::from_mode(mode & 0o777))?;
+        }
+
+        if let Ok(mtime) = entry.header().mtime() {
+            outfile.set_modified(UNIX_EPOCH + Duration::from_secs(mtime)).ok();
+        }
+    } else if entry_type.is_hard_link() {
+        // Hard link targets name another entry, so they get the same checks
+        let link_name = entry.link_name()?.unwrap_or_default().to_string_lossy().into_owned();
+        let Some(original) = guard.resolve(&link_name) else { return Ok(()) };
+
+        if let Some(parent) = outpath.parent() {
+            tracker.create_dir_all(parent)?;
+        }
+        fs::hard_link(&original, outpath)
+            .map_err(|e| ZipError::Other(format!("Error extracting {:?}: {}", entry_path, e)))?;
+    } else {
+        if let Some(parent) = outpath.parent() {
+            tracker.create_dir_all(parent)?;
+        }
+
+        // Checked once the parent exists, so it can be resolved on disk
+        if entry_type.is_symlink() {
+            let link_target = entry.link_name()?.unwrap_or_default().into_owned();
+            if !guard.allow_link(entry_path, outpath, &link_target) {
+                return Ok(());
+            }
+        }
+        if let Err(e) = entry.unpack(outpath) {
+            return Err(ZipError::Other(format!("Error extracting {:?}: {}", entry_path, e)));
+        }
+    }
+
+    Ok(())
+}
+
+/// What adding files to an existing tarball involves
+pub struct Additions {
+    /// Files still to be written once unchanged ones are left out
+    pub files: Vec<(String, PathBuf)>,
+    /// Existing entries the new files take the place of
+    pub replaced: HashSet<String>,
+    /// Offset just past the last entry, where appended entries start
+    pub end: u64,
+}
+
+/// Read through `archive` to see which of `files` replace existing entries,
+/// dropping unchanged files when `only_changed`; directories are kept as they are
+pub fn plan_additions<R: Read>(archive: &mut Archive<R>, files: Vec<(String, PathBuf)>, only_changed: bool) -> Result<Additions> {
+    // Whether each existing name is kept; the last copy of a name is the one that counts
+    let mut keep = HashMap::new();
+    let mut end = 0;
+
+    for entry_result in archive.entries()? {
+        let mut entry = entry_result?;
+        end = entry.raw_file_position() + entry.header().entry_size()?.div_ceil(512) * 512;
+
+        let name = entry.path()?.to_string_lossy().trim_end_matches('/').to_string();
+        let Some((_, path)) = files.iter().find(|(new, _)| *new == name) else { continue };
+
+        let unchanged = if path.is_dir() {
+            entry.header().entry_type().is_dir()
+        } else {
+            let stored = StoredFile {
+                size: entry.header().size()?,
+                modified: Some(UNIX_EPOCH + Duration::from_secs(entry.header().mtime()?)),
+                time_step: Duration::from_secs(1),
+            };
+            only_changed && !is_changed(path, &stored, || Ok(Some(Checksum::Crc32.digest(&mut entry)?)))?
+        };
+        keep.insert(name, unchanged);
+    }
+
+    let files = files.into_iter().filter(|(name, _)| keep.get(name) != Some(&true)).collect();
+    let replaced = keep.into_iter().filter(|(_, unchanged)| !unchanged).map(|(name, _)| name).collect();
+    Ok(Additions { files, replaced, end })
+}
+
+/// Copy `archive` into `builder` without the entries `plan` replaces, then add its files
+pub fn rewrite<R: Read, W: Write>(archive: &mut Archive<R>, builder: &mut Builder<W>, plan: &Additions) -> Result<()> {
+    for entry_result in archive.entries()? {
+        let mut entry = entry_result?;
+        let path = entry.path()?.into_owned();
+        if plan.replaced.contains(path.to_string_lossy().trim_end_matches('/')) {
+            continue;
+        }
+
+        // The builder writes long names and link targets as extension records again
+        let mut header = entry.header().clone();
+        let entry_type = header.entry_type();
+        if entry_type.is_symlink() || entry_type.is_hard_link() {
+            let target = entry.link_name()?.unwrap_or_default().into_owned();
+            builder.append_link(&mut header, &path, target)?;
+        } else {
+            builder.append_data(&mut header, &path, &mut entry)?;
+        }
+    }
+
+    append_files(builder, &plan.files)
+}
+
+/// Write `files` to `builder` under their archive names
+pub fn append_files<W: Write>(builder: &mut Builder<W>, files: &[(String, PathBuf)]) -> Result<()> {
+    for (name, path) in files {
+        info!("Writing: {}", name);
+        builder.append_path_with_name(path, name)?;
+    }
+    Ok(())
+}
+
+/// Archives are edited in place, which a stream cannot be
+pub fn reject_stdio(archive: &Path) -> Result<()> {
+    if is_stdio(archive) {
+        return Err(ZipError::UnsupportedOperation(
+            "Only an archive file can be edited in place, not stdin".to_string()
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,169 @@
+use crate::checksum::Checksum;
+use crate::Result;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use walkdir::WalkDir;
+
+/// How `compress` names what it finds in a source directory
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DirNaming {
+    /// `dir`, `dir/a.txt`, …: the directory's own name prefixes everything (tar)
+    UnderDirName,
+    /// `a.txt`, …: paths relative to the directory, which has no entry itself (zip)
+    Relative,
+}
+
+/// Entry names and paths for `source` as `compress` names them, `/`-separated
+/// and without a trailing slash on directories
+pub fn source_entries(source: &[&Path], naming: DirNaming) -> Result<Vec<(String, PathBuf)>> {
+    let mut entries = Vec::new();
+
+    for item in source {
+        let name = item.file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid source path"))?
+            .to_string_lossy()
+            .into_owned();
+
+        for entry in WalkDir::new(item).follow_links(true) {
+            let entry = entry.map_err(io::Error::from)?;
+            let relative = entry.path().strip_prefix(item)?;
+
+            let entry_name = if relative.as_os_str().is_empty() {
+                if naming == DirNaming::Relative && entry.file_type().is_dir() {
+                    continue;
+                }
+                name.clone()
+            } else {
+                let parts: Vec<_> = relative.components().map(|c| c.as_os_str().to_string_lossy()).collect();
+                match naming {
+                    DirNaming::UnderDirName => format!("{}/{}", name, parts.join("/")),
+                    DirNaming::Relative => parts.join("/"),
+                }
+            };
+            entries.push((entry_name, entry.into_path()));
+        }
+    }
+
+    Ok(entries)
+}
+
+/// What an archive entry records about the file it was made from
+pub struct StoredFile {
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    /// Granularity the format keeps modification times in
+    pub time_step: Duration,
+}
+
+/// Whether the file at `path` differs from `stored` in size, or is newer with a
+/// CRC-32 other than `stored_crc`'s (asked only then); directories never do
+pub fn is_changed(path: &Path, stored: &StoredFile, stored_crc: impl FnOnce() -> Result<Option<String>>) -> Result<bool> {
+    let metadata = fs::metadata(path)?;
+    if metadata.is_dir() {
+        return Ok(false);
+    }
+    if stored.size != metadata.len() {
+        return Ok(true);
+    }
+    if let Some(modified) = stored.modified
+        && metadata.modified()? < modified + stored.time_step
+    {
+        return Ok(false);
+    }
+
+    // A touched file with the same contents is not worth rewriting the archive for
+    match stored_crc()? {
+        Some(crc) => Ok(Checksum::Crc32.digest(&mut File::open(path)?)? != crc),
+        None => Ok(true),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codecs::tarball::TarCodec;
+    use crate::codecs::zip::{CompressionMethod, ZipCodec};
+    use crate::codecs::Codec;
+    use std::io::{Read, Write};
+    use zip::write::SimpleFileOptions;
+
+    fn source_dir(root: &Path) -> PathBuf {
+        let src = root.join("src");
+        fs::create_dir_all(src.join("d")).unwrap();
+        fs::write(src.join("a.txt"), "new contents").unwrap();
+        fs::write(src.join("b.txt"), "kept").unwrap();
+        fs::write(src.join("d/c.txt"), "c").unwrap();
+        src
+    }
+
+    #[test]
+    fn names_follow_compress() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = source_dir(dir.path());
+        let file = src.join("b.txt");
+
+        let names = |naming| {
+            let mut names: Vec<String> = source_entries(&[&src, &file], naming).unwrap().into_iter().map(|(name, _)| name).collect();
+            names.sort();
+            names
+        };
+        assert_eq!(names(DirNaming::UnderDirName), ["b.txt", "src", "src/a.txt", "src/b.txt", "src/d", "src/d/c.txt"]);
+        assert_eq!(names(DirNaming::Relative), ["a.txt", "b.txt", "b.txt", "d", "d/c.txt"]);
+    }
+
+    #[test]
+    fn updates_copy_untouched_entries_raw() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = source_dir(dir.path());
+
+        // Entries stored uncompressed, which the deflating codec would never write itself
+        let zip_path = dir.path().join("a.zip");
+        let mut writer = zip::ZipWriter::new(File::create(&zip_path).unwrap());
+        let stored = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        for (name, data) in [("a.txt", "old"), ("b.txt", "kept"), ("only-in-archive", "x")] {
+            writer.start_file(name, stored).unwrap();
+            writer.write_all(data.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let mut codec = ZipCodec::new(CompressionMethod::Deflated, None, None);
+        codec.add_entries(&zip_path, &[&src], true).unwrap();
+
+        let mut archive = zip::ZipArchive::new(File::open(&zip_path).unwrap()).unwrap();
+        for (name, method) in [("b.txt", zip::CompressionMethod::Stored), ("only-in-archive", zip::CompressionMethod::Stored), ("a.txt", zip::CompressionMethod::Deflated)] {
+            assert_eq!(archive.by_name(name).unwrap().compression(), method, "{}", name);
+        }
+        let mut contents = String::new();
+        archive.by_name("a.txt").unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "new contents");
+        assert!(archive.by_name("d/").is_ok() && archive.by_name("d/c.txt").is_ok());
+
+        // Tar entries keep header fields a rewrite from disk would not produce
+        let tar_path = dir.path().join("a.tar");
+        let mut builder = tar::Builder::new(File::create(&tar_path).unwrap());
+        for (name, data) in [("src/a.txt", "old"), ("src/b.txt", "kept")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o600);
+            header.set_username("keeper").unwrap();
+            header.set_cksum();
+            builder.append_data(&mut header, name, data.as_bytes()).unwrap();
+        }
+        builder.into_inner().unwrap();
+
+        TarCodec::new().add_entries(&tar_path, &[&src], true).unwrap();
+
+        let mut archive = tar::Archive::new(File::open(&tar_path).unwrap());
+        let mut users = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let entry = entry.unwrap();
+            let name = entry.path().unwrap().to_string_lossy().into_owned();
+            users.push((name, entry.header().username().unwrap().unwrap_or_default().to_string()));
+        }
+        assert!(users.contains(&("src/b.txt".to_string(), "keeper".to_string())));
+        assert!(users.iter().any(|(name, user)| name == "src/a.txt" && user != "keeper"));
+        assert_eq!(users.iter().filter(|(name, _)| name.starts_with("src/a.txt")).count(), 1);
+    }
+}
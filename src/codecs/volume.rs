@@ -0,0 +1,557 @@
+use crate::{Result, ZipError};
+use log::info;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Signature at the start of the first volume of a spanned zip
+const SPLIT_SIGNATURE: u32 = 0x08074b50;
+const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const END_SIGNATURE: u32 = 0x06054b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const END_RECORD_LEN: usize = 22;
+
+/// Convert a `--volume-size` value in MB to bytes
+pub fn volume_bytes(size_mb: usize) -> Result<u64> {
+    if size_mb == 0 {
+        return Err(ZipError::Other("Volume size must be at least 1 MB".to_string()));
+    }
+    Ok(size_mb as u64 * 1024 * 1024)
+}
+
+/// Find every volume of a spanned zip (`a.z01`, …, `a.zip`) or numbered split
+/// (`a.7z.001`, …) given one of them; `None` for a plain archive.
+pub fn discover_volumes(path: &Path) -> Option<Vec<PathBuf>> {
+    let ext = path.extension()?.to_string_lossy().to_lowercase();
+
+    if ext.len() == 3 && ext.chars().all(|c| c.is_ascii_digit()) {
+        let volumes = numbered_volumes(&path.with_extension(""));
+        return (!volumes.is_empty()).then_some(volumes);
+    }
+
+    let is_zip_part = ext.len() == 3 && ext.starts_with('z') && ext[1..].chars().all(|c| c.is_ascii_digit());
+    if ext == "zip" || is_zip_part {
+        let last = path.with_extension("zip");
+        let mut volumes: Vec<PathBuf> = (1..)
+            .map(|n| path.with_extension(format!("z{:02}", n)))
+            .take_while(|p| p.exists())
+            .collect();
+
+        if volumes.is_empty() || !last.exists() {
+            return None;
+        }
+
+        volumes.push(last);
+        return Some(volumes);
+    }
+
+    None
+}
+
+fn numbered_volumes(base: &Path) -> Vec<PathBuf> {
+    (1..)
+        .map(|n| numbered_volume_path(base, n))
+        .take_while(|p| p.exists())
+        .collect()
+}
+
+fn numbered_volume_path(base: &Path, number: usize) -> PathBuf {
+    let mut name = base.as_os_str().to_os_string();
+    name.push(format!(".{:03}", number));
+    PathBuf::from(name)
+}
+
+/// Split a stream into numbered volumes `<target>.001`, `<target>.002`, …
+pub fn split_numbered<R: Read>(source: &mut R, target: &Path, volume_size: u64) -> Result<Vec<PathBuf>> {
+    let mut volumes = Vec::new();
+
+    loop {
+        let path = numbered_volume_path(target, volumes.len() + 1);
+        let mut output = File::create(&path)?;
+        let copied = io::copy(&mut source.take(volume_size), &mut output)?;
+
+        if copied == 0 && !volumes.is_empty() {
+            drop(output);
+            fs::remove_file(&path)?;
+            break;
+        }
+
+        info!("Writing volume: {:?} ({} bytes)", path, copied);
+        volumes.push(path);
+
+        if copied < volume_size {
+            break;
+        }
+    }
+
+    Ok(volumes)
+}
+
+/// Read a set of volumes as one contiguous stream
+pub struct MultiVolumeReader {
+    files: Vec<File>,
+    /// Offset of each volume within the combined stream
+    starts: Vec<u64>,
+    len: u64,
+    pos: u64,
+}
+
+impl MultiVolumeReader {
+    /// Open all volumes in order
+    pub fn open(paths: &[PathBuf]) -> Result<Self> {
+        let mut files = Vec::with_capacity(paths.len());
+        let mut starts = Vec::with_capacity(paths.len());
+        let mut len = 0;
+
+        for path in paths {
+            let file = File::open(path)?;
+            starts.push(len);
+            len += file.metadata()?.len();
+            files.push(file);
+        }
+
+        Ok(Self { files, starts, len, pos: 0 })
+    }
+
+    /// Total length of all volumes
+    pub fn total_len(&self) -> u64 {
+        self.len
+    }
+
+    /// Offset of a volume within the combined stream
+    pub fn volume_start(&self, index: usize) -> Option<u64> {
+        self.starts.get(index).copied()
+    }
+
+    pub fn volume_count(&self) -> usize {
+        self.files.len()
+    }
+}
+
+impl Read for MultiVolumeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let index = self.starts.partition_point(|&start| start <= self.pos) - 1;
+        let file = &mut self.files[index];
+        file.seek(SeekFrom::Start(self.pos - self.starts[index]))?;
+
+        let read = file.read(buf)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for MultiVolumeReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => offset as i128,
+            SeekFrom::End(offset) => self.len as i128 + offset as i128,
+            SeekFrom::Current(offset) => self.pos as i128 + offset as i128,
+        };
+
+        if new_pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before start of volume set"));
+        }
+
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+/// Writes a byte stream across fixed-size volumes
+struct VolumeWriter {
+    base: PathBuf,
+    volume_size: u64,
+    volumes: Vec<PathBuf>,
+    current: Option<File>,
+    written: u64,
+}
+
+impl VolumeWriter {
+    fn new(base: &Path, volume_size: u64) -> Self {
+        Self {
+            base: base.to_path_buf(),
+            volume_size,
+            volumes: Vec::new(),
+            current: None,
+            written: 0,
+        }
+    }
+
+    /// Index of the current volume and the offset within it
+    fn position(&mut self) -> Result<(u16, u64)> {
+        if self.current.is_none() {
+            self.next_volume()?;
+        }
+        Ok(((self.volumes.len() - 1) as u16, self.written))
+    }
+
+    fn next_volume(&mut self) -> Result<()> {
+        if let Some(mut file) = self.current.take() {
+            file.flush()?;
+        }
+
+        let path = self.base.with_extension(format!("z{:02}", self.volumes.len() + 1));
+        info!("Writing volume: {:?}", path);
+
+        self.current = Some(File::create(&path)?);
+        self.volumes.push(path);
+        self.written = 0;
+        Ok(())
+    }
+
+    /// Start a new volume unless `len` bytes still fit in the current one
+    fn reserve(&mut self, len: u64) -> Result<()> {
+        if self.current.is_none() || (self.written > 0 && self.written + len > self.volume_size) {
+            self.next_volume()?;
+        }
+        Ok(())
+    }
+
+    /// Write bytes that may span volume boundaries
+    fn write_spanning(&mut self, mut buf: &[u8]) -> Result<()> {
+        while !buf.is_empty() {
+            if self.current.is_none() || self.written >= self.volume_size {
+                self.next_volume()?;
+            }
+
+            let room = (self.volume_size - self.written).min(buf.len() as u64) as usize;
+            self.current.as_mut().unwrap().write_all(&buf[..room])?;
+            self.written += room as u64;
+            buf = &buf[room..];
+        }
+        Ok(())
+    }
+
+    /// Write bytes that must stay within a single volume
+    fn write_whole(&mut self, buf: &[u8]) -> Result<()> {
+        self.reserve(buf.len() as u64)?;
+        self.current.as_mut().unwrap().write_all(buf)?;
+        self.written += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Close the last volume and give it the final `.zip` name
+    fn finish(mut self) -> Result<Vec<PathBuf>> {
+        if let Some(mut file) = self.current.take() {
+            file.flush()?;
+        }
+
+        if let Some(last) = self.volumes.last_mut() {
+            let final_path = self.base.with_extension("zip");
+            fs::rename(&*last, &final_path)?;
+            *last = final_path;
+        }
+
+        Ok(self.volumes)
+    }
+}
+
+/// End of central directory fields needed to walk and patch the central directory
+struct EndRecord {
+    cd_disk: u16,
+    entries: u16,
+    cd_size: u32,
+    cd_offset: u32,
+    comment: Vec<u8>,
+}
+
+fn read_end_record<R: Read + Seek>(reader: &mut R, len: u64) -> Result<EndRecord> {
+    let tail_len = len.min(END_RECORD_LEN as u64 + u16::MAX as u64);
+    reader.seek(SeekFrom::Start(len - tail_len))?;
+
+    let mut tail = vec![0_u8; tail_len as usize];
+    reader.read_exact(&mut tail)?;
+
+    let pos = (0..=tail.len().saturating_sub(END_RECORD_LEN))
+        .rev()
+        .find(|&i| u32_at(&tail, i).is_ok_and(|signature| signature == END_SIGNATURE))
+        .ok_or_else(|| ZipError::Other("End of central directory not found".to_string()))?;
+
+    if pos >= 20 && u32_at(&tail, pos - 20)? == ZIP64_LOCATOR_SIGNATURE {
+        return Err(ZipError::UnsupportedOperation(
+            "Split ZIP64 archives are not supported natively".to_string()
+        ));
+    }
+
+    let comment_len = u16_at(&tail, pos + 20)? as usize;
+    Ok(EndRecord {
+        cd_disk: u16_at(&tail, pos + 6)?,
+        entries: u16_at(&tail, pos + 10)?,
+        cd_size: u32_at(&tail, pos + 12)?,
+        cd_offset: u32_at(&tail, pos + 16)?,
+        comment: tail[pos + END_RECORD_LEN..(pos + END_RECORD_LEN + comment_len).min(tail.len())].to_vec(),
+    })
+}
+
+/// Split central directory records, returning (record start, record length) pairs
+fn central_records(cd: &[u8], entries: u16) -> Result<Vec<(usize, usize)>> {
+    let mut records = Vec::with_capacity(entries as usize);
+    let mut pos = 0;
+
+    for _ in 0..entries {
+        if pos + 46 > cd.len() || u32_at(cd, pos)? != CENTRAL_HEADER_SIGNATURE {
+            return Err(ZipError::Other("Corrupt central directory".to_string()));
+        }
+
+        let len = 46 + u16_at(cd, pos + 28)? as usize + u16_at(cd, pos + 30)? as usize + u16_at(cd, pos + 32)? as usize;
+        records.push((pos, len));
+        pos += len;
+    }
+
+    Ok(records)
+}
+
+fn end_record_bytes(disk: u16, entries: u16, cd_size: u32, cd_offset: u32, comment: &[u8]) -> Vec<u8> {
+    let mut end = Vec::with_capacity(END_RECORD_LEN + comment.len());
+    end.extend_from_slice(&END_SIGNATURE.to_le_bytes());
+    end.extend_from_slice(&disk.to_le_bytes());
+    end.extend_from_slice(&disk.to_le_bytes());
+    end.extend_from_slice(&entries.to_le_bytes());
+    end.extend_from_slice(&entries.to_le_bytes());
+    end.extend_from_slice(&cd_size.to_le_bytes());
+    end.extend_from_slice(&cd_offset.to_le_bytes());
+    end.extend_from_slice(&(comment.len() as u16).to_le_bytes());
+    end.extend_from_slice(comment);
+    end
+}
+
+/// Split a single-disk zip into a spanned set `target.z01`, …, `target.zip`;
+/// archives that fit into one volume are written unsplit.
+pub fn split_zip(source: &Path, target: &Path, volume_size: u64) -> Result<Vec<PathBuf>> {
+    let mut input = File::open(source)?;
+    let len = input.metadata()?.len();
+    let final_path = target.with_extension("zip");
+
+    if len <= volume_size {
+        fs::copy(source, &final_path)?;
+        return Ok(vec![final_path]);
+    }
+
+    let end = read_end_record(&mut input, len)?;
+    let mut cd = vec![0_u8; end.cd_size as usize];
+    input.seek(SeekFrom::Start(end.cd_offset as u64))?;
+    input.read_exact(&mut cd)?;
+
+    let records = central_records(&cd, end.entries)?;
+
+    // Entries in the order their data appears in the file
+    let offsets = records
+        .iter()
+        .map(|&(record, _)| Ok(u32_at(&cd, record + 42)? as u64))
+        .collect::<Result<Vec<u64>>>()?;
+    let mut order: Vec<usize> = (0..records.len()).collect();
+    order.sort_by_key(|&i| offsets[i]);
+
+    let mut writer = VolumeWriter::new(target, volume_size);
+    writer.write_whole(&SPLIT_SIGNATURE.to_le_bytes())?;
+
+    for (n, &i) in order.iter().enumerate() {
+        let record = records[i].0;
+        let offset = offsets[i];
+        let next = order
+            .get(n + 1)
+            .map(|&j| offsets[j])
+            .unwrap_or(end.cd_offset as u64);
+
+        let mut header = [0_u8; 30];
+        input.seek(SeekFrom::Start(offset))?;
+        input.read_exact(&mut header)?;
+        if u32_at(&header, 0)? != LOCAL_HEADER_SIGNATURE {
+            return Err(ZipError::Other(format!("Corrupt local header at offset {}", offset)));
+        }
+
+        let header_len = 30 + u16_at(&header, 26)? as u64 + u16_at(&header, 28)? as u64;
+        let mut full_header = vec![0_u8; header_len as usize];
+        input.seek(SeekFrom::Start(offset))?;
+        input.read_exact(&mut full_header)?;
+
+        writer.reserve(header_len)?;
+        let (disk, disk_offset) = writer.position()?;
+        cd[record + 34..record + 36].copy_from_slice(&disk.to_le_bytes());
+        cd[record + 42..record + 46].copy_from_slice(&(disk_offset as u32).to_le_bytes());
+        writer.write_whole(&full_header)?;
+
+        let data_len = next
+            .checked_sub(offset)
+            .and_then(|len| len.checked_sub(header_len))
+            .ok_or_else(|| ZipError::Other(format!("Overlapping entries at offset {}", offset)))?;
+        let mut data = (&mut input).take(data_len);
+        let mut buffer = vec![0_u8; 64 * 1024];
+        loop {
+            let read = data.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            writer.write_spanning(&buffer[..read])?;
+        }
+    }
+
+    // The central directory and end record go together into the last volume
+    writer.reserve(cd.len() as u64 + END_RECORD_LEN as u64 + end.comment.len() as u64)?;
+    let (cd_disk, cd_offset) = writer.position()?;
+    writer.write_whole(&cd)?;
+    writer.write_whole(&end_record_bytes(cd_disk, end.entries, cd.len() as u32, cd_offset as u32, &end.comment))?;
+
+    writer.finish()
+}
+
+/// Join a volume set back into one single-disk archive, rewriting a spanned
+/// zip's disk numbers and offsets.
+pub fn join_volumes(volumes: &[PathBuf], output: &mut File) -> Result<()> {
+    let mut reader = MultiVolumeReader::open(volumes)?;
+
+    let mut signature = [0_u8; 4];
+    reader.read_exact(&mut signature)?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    if u32::from_le_bytes(signature) != SPLIT_SIGNATURE {
+        io::copy(&mut reader, output)?;
+        output.seek(SeekFrom::Start(0))?;
+        return Ok(());
+    }
+
+    let len = reader.total_len();
+    let end = read_end_record(&mut reader, len)?;
+
+    let starts: Vec<u64> = (0..reader.volume_count())
+        .filter_map(|i| reader.volume_start(i))
+        .collect();
+
+    let cd_start = joined_offset(&starts, end.cd_disk, end.cd_offset)?;
+    let mut cd = vec![0_u8; end.cd_size as usize];
+    reader.seek(SeekFrom::Start(cd_start + 4))?;
+    reader.read_exact(&mut cd)?;
+
+    for (record, _) in central_records(&cd, end.entries)? {
+        let offset = joined_offset(&starts, u16_at(&cd, record + 34)?, u32_at(&cd, record + 42)?)?;
+        cd[record + 34..record + 36].copy_from_slice(&0_u16.to_le_bytes());
+        cd[record + 42..record + 46].copy_from_slice(&(offset as u32).to_le_bytes());
+    }
+
+    reader.seek(SeekFrom::Start(4))?;
+    io::copy(&mut (&mut reader).take(cd_start), output)?;
+    output.write_all(&cd)?;
+    output.write_all(&end_record_bytes(0, end.entries, end.cd_size, cd_start as u32, &end.comment))?;
+
+    info!("Joined {} volumes", volumes.len());
+    output.flush()?;
+    output.seek(SeekFrom::Start(0))?;
+    Ok(())
+}
+
+/// Offset in the joined file of a (disk, offset) pair; offsets on the first
+/// disk include the split signature, which joining drops
+fn joined_offset(starts: &[u64], disk: u16, offset: u32) -> Result<u64> {
+    let start = starts.get(disk as usize).ok_or_else(|| ZipError::Other(format!(
+        "Archive references volume {} but only {} were found",
+        disk + 1,
+        starts.len()
+    )))?;
+
+    (start + offset as u64)
+        .checked_sub(4)
+        .ok_or_else(|| ZipError::Other(format!("Offset {} points into the split signature", offset)))
+}
+
+fn u16_at(buf: &[u8], pos: usize) -> Result<u16> {
+    Ok(u16::from_le_bytes(field(buf, pos)?))
+}
+
+fn u32_at(buf: &[u8], pos: usize) -> Result<u32> {
+    Ok(u32::from_le_bytes(field(buf, pos)?))
+}
+
+fn field<const N: usize>(buf: &[u8], pos: usize) -> Result<[u8; N]> {
+    buf.get(pos..pos + N)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| ZipError::Other(format!("Truncated zip record at offset {}", pos)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use zip::write::SimpleFileOptions;
+
+    fn sample_zip(path: &Path) -> Vec<(String, Vec<u8>)> {
+        let contents: Vec<(String, Vec<u8>)> = (0..4_u8)
+            .map(|n| (format!("dir/file{}.bin", n), (0..3000).map(|i| (i as u8).wrapping_mul(n + 7)).collect()))
+            .collect();
+
+        let mut writer = zip::ZipWriter::new(File::create(path).unwrap());
+        let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        for (name, data) in &contents {
+            writer.start_file(name.as_str(), options).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.set_comment("volume test");
+        writer.finish().unwrap();
+        contents
+    }
+
+    fn read_all(file: impl Read + Seek) -> Vec<(String, Vec<u8>)> {
+        let mut archive = zip::ZipArchive::new(file).unwrap();
+        (0..archive.len())
+            .map(|i| {
+                let mut entry = archive.by_index(i).unwrap();
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data).unwrap();
+                (entry.name().to_string(), data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn split_and_join_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("in.zip");
+        let contents = sample_zip(&source);
+
+        let volumes = split_zip(&source, &dir.path().join("out"), 4096).unwrap();
+        assert!(volumes.len() > 2);
+        assert!(volumes.iter().all(|v| fs::metadata(v).unwrap().len() <= 4096));
+        assert_eq!(discover_volumes(&volumes[0]).unwrap(), volumes);
+
+        let mut joined = tempfile::tempfile().unwrap();
+        join_volumes(&volumes, &mut joined).unwrap();
+        assert_eq!(read_all(joined), contents);
+
+        // Numbered splits are plain concatenations
+        let bytes = fs::read(&source).unwrap();
+        let parts = split_numbered(&mut Cursor::new(&bytes), &dir.path().join("raw.zip"), 5000).unwrap();
+        assert_eq!(parts.len(), bytes.len().div_ceil(5000));
+        let mut joined = tempfile::tempfile().unwrap();
+        join_volumes(&parts, &mut joined).unwrap();
+        assert_eq!(read_all(joined), contents);
+    }
+
+    #[test]
+    fn malformed_volumes_are_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("in.zip");
+        sample_zip(&source);
+        let volumes = split_zip(&source, &dir.path().join("out"), 4096).unwrap();
+
+        // Point the first entry's local header into the split signature
+        let last = volumes.last().unwrap();
+        let mut bytes = fs::read(last).unwrap();
+        let cd = bytes.windows(4).position(|w| w == CENTRAL_HEADER_SIGNATURE.to_le_bytes()).unwrap();
+        bytes[cd + 34..cd + 36].copy_from_slice(&0_u16.to_le_bytes());
+        bytes[cd + 42..cd + 46].copy_from_slice(&1_u32.to_le_bytes());
+        fs::write(last, &bytes).unwrap();
+        assert!(join_volumes(&volumes, &mut tempfile::tempfile().unwrap()).is_err());
+
+        // A last volume too short to hold an end record
+        fs::write(last, SPLIT_SIGNATURE.to_le_bytes()).unwrap();
+        assert!(join_volumes(&volumes, &mut tempfile::tempfile().unwrap()).is_err());
+
+        let short = dir.path().join("short.zip");
+        fs::write(&short, b"PK\x05\x06").unwrap();
+        assert!(split_zip(&short, &dir.path().join("split"), 1).is_err());
+    }
+}
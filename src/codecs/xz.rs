@@ -1,10 +1,16 @@
-use crate::codecs::Codec;
-use crate::utils::ensure_directory_exists;
+use crate::codecs::limits::{CountingReader, ExtractLimits, LimitTracker, RatioBase};
+use crate::codecs::safe_path::{PathGuard, PathPolicy};
+use crate::codecs::stream_salvage::{open_seekable, XzSalvage};
+use crate::codecs::tarball::{self, TarEntryWriter};
+use crate::codecs::update::{source_entries, DirNaming};
+use crate::codecs::{Codec, EntryMeta, EntryVisitor, EntryWriter};
+use crate::utils::{create_output, ensure_directory_exists, is_stdio, open_input, replace_with_staged, staging_file};
 use crate::{Result, ZipError};
 use log::info;
-use std::fs::{self, File};
-use std::io;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::path::Path;
+use std::sync::atomic::AtomicU64;
 use std::time::Instant;
 use tar::{Archive, Builder};
 use xz2::read::XzDecoder;
@@ -14,6 +20,10 @@ use xz2::write::XzEncoder;
 pub struct XzCodec {
     compression_level: u32,
     threads: u32,
+    limits: ExtractLimits,
+    path_policy: PathPolicy,
+    /// Decode around damaged blocks instead of stopping at the first error
+    salvage: bool,
 }
 
 impl XzCodec {
@@ -22,30 +32,98 @@ impl XzCodec {
         Self {
             compression_level: level.clamp(0, 9),
             threads,
+            limits: ExtractLimits::default(),
+            path_policy: PathPolicy::default(),
+            salvage: false,
         }
     }
+
+    /// Recover every block and tar entry that still decodes when extracting
+    pub fn set_salvage(&mut self, salvage: bool) {
+        self.salvage = salvage;
+    }
+
+    fn extract_salvaged(&self, source: &Path, target: &Path) -> Result<()> {
+        let mut reader = XzSalvage::new(open_seekable(source)?)?;
+        let damage = reader.report();
+        let compressed = reader.counter();
+        let tracker = LimitTracker::new(self.limits);
+
+        if is_stdio(target) {
+            let mut stdout = create_output(target)?;
+            tracker.copy("stdin", &mut reader, &mut stdout, RatioBase::Stream(&compressed))?;
+            stdout.flush()?;
+            return damage.lock().unwrap().summarise("blocks");
+        }
+
+        let (is_tar, reader) = tarball::sniff_tar(reader)?;
+        if is_tar {
+            return tarball::salvage_into(reader, &damage, "blocks", &compressed, target, self.limits, self.path_policy);
+        }
+
+        ensure_directory_exists(target)?;
+        let guard = PathGuard::new(target, self.path_policy)?;
+        tracker.guard(unpack_single(reader, source, &guard, &tracker, &compressed))?;
+        guard.finish()?;
+        damage.lock().unwrap().summarise("blocks")
+    }
+}
+
+/// Name of the one file a plain xz stream holds, taken from the archive
+fn single_name(source: &Path) -> String {
+    if is_stdio(source) {
+        "stdin".to_string()
+    } else {
+        source.file_stem().unwrap_or_default().to_string_lossy().into_owned()
+    }
+}
+
+/// Write out a plain xz stream, such as one compressed from stdin
+fn unpack_single<R: Read>(mut data: R, source: &Path, guard: &PathGuard, tracker: &LimitTracker, compressed: &AtomicU64) -> Result<()> {
+    let name = single_name(source);
+    tracker.begin_entry(&name)?;
+    let Some(path) = guard.resolve(&name) else {
+        return Ok(());
+    };
+
+    info!("Extracting: {:?}", name);
+    let mut outfile = tracker.create_file(&path)?;
+    tracker.copy(&name, &mut data, &mut outfile, RatioBase::Stream(compressed))?;
+    Ok(())
 }
 
 impl Codec for XzCodec {
     fn extract(&mut self, source: &[&Path], target: &Path) -> Result<()> {
-        ensure_directory_exists(target)?;
+        if self.salvage {
+            return self.extract_salvaged(source[0], target);
+        }
 
-        let tar_xz = File::open(source[0])?;
-        let tar = XzDecoder::new(tar_xz);
-        let mut archive = Archive::new(tar);
+        let tar_xz = CountingReader::new(open_input(source[0])?);
+        let compressed = tar_xz.counter();
+        let tracker = LimitTracker::new(self.limits);
 
-        let time_start = Instant::now();
+        // Single-stream mode: decompress as-is without unpacking a tarball
+        if is_stdio(target) {
+            let mut decoder = XzDecoder::new_multi_decoder(tar_xz);
+            let mut stdout = create_output(target)?;
+            tracker.copy("stdin", &mut decoder, &mut stdout, RatioBase::Stream(&compressed))?;
+            stdout.flush()?;
+            return Ok(());
+        }
 
-        for entry_result in archive.entries()? {
-            let mut entry = entry_result?;
-            let entry_path = entry.path()?.to_owned().to_str().unwrap().to_string();
+        ensure_directory_exists(target)?;
 
-            info!("Extracting: {:?}", entry_path);
+        let (is_tar, decoded) = tarball::sniff_tar(XzDecoder::new(tar_xz))?;
 
-            if let Err(e) = entry.unpack_in(target) {
-                return Err(ZipError::Other(format!("Error extracting {:?}: {}", entry_path, e)));
-            }
+        let time_start = Instant::now();
+
+        let guard = PathGuard::new(target, self.path_policy)?;
+        if is_tar {
+            tracker.guard(tarball::unpack_entries(&mut Archive::new(decoded), &guard, &tracker, &compressed))?;
+        } else {
+            tracker.guard(unpack_single(decoded, source[0], &guard, &tracker, &compressed))?;
         }
+        guard.finish()?;
 
         info!("Extraction process completed");
         info!(
@@ -57,12 +135,78 @@ impl Codec for XzCodec {
         Ok(())
     }
 
+    fn visit_entries(&mut self, source: &Path, visitor: &mut EntryVisitor) -> Result<()> {
+        let (is_tar, mut decoded) = tarball::sniff_tar(XzDecoder::new(open_input(source)?))?;
+        if !is_tar {
+            let meta = EntryMeta { name: single_name(source), is_dir: false, size: None, modified: None, mode: None, link: None };
+            return visitor(&meta, &mut decoded);
+        }
+        let mut archive = Archive::new(decoded);
+
+        for entry_result in archive.entries()? {
+            let mut entry = entry_result?;
+            visitor(&tarball::entry_meta(&entry)?, &mut entry)?;
+        }
+
+        Ok(())
+    }
+
+    fn add_entries(&mut self, archive: &Path, source: &[&Path], only_changed: bool) -> Result<()> {
+        tarball::reject_stdio(archive)?;
+        let plan = tarball::plan_additions(
+            &mut Archive::new(XzDecoder::new(BufReader::new(File::open(archive)?))),
+            source_entries(source, DirNaming::UnderDirName)?,
+            only_changed,
+        )?;
+
+        if plan.files.is_empty() {
+            info!("{:?} is up to date", archive);
+            return Ok(());
+        }
+
+        // The tar end marker sits inside the compressed stream, so the tarball is re-encoded
+        // entry by entry into its replacement without touching the disk in between
+        let encoder = XzEncoder::new(BufWriter::new(staging_file(archive)?), self.compression_level);
+        let mut builder = Builder::new(encoder);
+        tarball::rewrite(&mut Archive::new(XzDecoder::new(BufReader::new(File::open(archive)?))), &mut builder, &plan)?;
+
+        let staging = builder.into_inner()?
+            .finish()?
+            .into_inner()
+            .map_err(|e| ZipError::Io(e.into_error()))?;
+        replace_with_staged(staging, archive)?;
+
+        info!("Added {} and replaced {} entries in {:?}", plan.files.len().saturating_sub(plan.replaced.len()), plan.replaced.len(), archive);
+        Ok(())
+    }
+
+    fn entry_writer(&mut self, target: &Path) -> Result<Box<dyn EntryWriter + '_>> {
+        let encoder = XzEncoder::new(BufWriter::new(tarball::create_target(target)?), self.compression_level);
+        Ok(Box::new(TarEntryWriter::new(encoder, |encoder| Ok(encoder.finish()?.flush()?))))
+    }
+
     fn compress(&mut self, source: &[&Path], target: &Path, _exclude: Option<&[&Path]>) -> Result<()> {
-        ensure_directory_exists(target.parent().unwrap_or(Path::new(".")))?;
+        if !is_stdio(target) {
+            ensure_directory_exists(target.parent().unwrap_or(Path::new(".")))?;
+        }
 
-        let target_file = File::create(target)?;
+        let target_file = create_output(target)?;
         info!("Creating target file: {:?}", target);
 
+        // stdin has no name or metadata to put in a tar header, so it becomes a plain .xz stream
+        if source.iter().any(|p| is_stdio(p)) {
+            if source.len() > 1 {
+                return Err(ZipError::UnsupportedOperation(
+                    "stdin cannot be combined with other sources for xz".to_string()
+                ));
+            }
+
+            let mut encoder = XzEncoder::new(target_file, self.compression_level);
+            io::copy(&mut io::stdin().lock(), &mut encoder)?;
+            encoder.finish()?.flush()?;
+            return Ok(());
+        }
+
         // Use simple XzEncoder with specified compression level
         let xz_encoder = XzEncoder::new(target_file, self.compression_level);
         let mut builder = Builder::new(xz_encoder);
@@ -87,7 +231,7 @@ impl Codec for XzCodec {
         }
 
         let finished = builder.into_inner()?;
-        finished.finish()?;
+        finished.finish()?.flush()?;
 
         info!("Compression completed");
         info!(
@@ -103,7 +247,51 @@ impl Codec for XzCodec {
         (0, 9)
     }
 
+    fn set_limits(&mut self, limits: ExtractLimits) {
+        self.limits = limits;
+    }
+
+    fn set_path_policy(&mut self, policy: PathPolicy) {
+        self.path_policy = policy;
+    }
+
     fn set_compression_level(&mut self, level: u8) {
         self.compression_level = level as u32;
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn plain_streams_extract_to_a_single_file() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // What compressing stdin writes: a bare xz stream with no tar inside
+        let dump = dir.path().join("dump.xz");
+        let mut encoder = XzEncoder::new(File::create(&dump).unwrap(), 6);
+        encoder.write_all(b"CREATE TABLE t (id int);\n").unwrap();
+        encoder.finish().unwrap();
+
+        let out = dir.path().join("out");
+        XzCodec::new(6, 1).extract(&[&dump], &out).unwrap();
+        assert_eq!(fs::read_to_string(out.join("dump")).unwrap(), "CREATE TABLE t (id int);\n");
+
+        let mut names = Vec::new();
+        XzCodec::new(6, 1).visit_entries(&dump, &mut |meta, _| {
+            names.push(meta.name.clone());
+            Ok(())
+        }).unwrap();
+        assert_eq!(names, ["dump"]);
+
+        // Tarballs are still unpacked entry by entry
+        let src = dir.path().join("site");
+        fs::create_dir(&src).unwrap();
+        fs::write(src.join("index.html"), "<html>").unwrap();
+        let tar_xz = dir.path().join("site.tar.xz");
+        XzCodec::new(6, 1).compress(&[&src], &tar_xz, None).unwrap();
+        XzCodec::new(6, 1).extract(&[&tar_xz], &out).unwrap();
+        assert_eq!(fs::read_to_string(out.join("site/index.html")).unwrap(), "<html>");
+    }
+}
@@ -1,40 +1,47 @@
-use crate::codecs::Codec;
-use crate::utils::ensure_directory_exists;
-use crate::Result;
-use log::{info};
+use crate::codecs::{missing_entries, same_entry_name, Capabilities, Codec, EntryLink, EntryMeta, EntryVisitor, EntryWriter, SharedVisitor};
+use crate::codecs::limits::{ExtractLimits, LimitTracker, RatioBase};
+use crate::codecs::safe_path::{PathGuard, PathPolicy};
+use crate::codecs::volume::{discover_volumes, join_volumes, split_zip, volume_bytes};
+use crate::codecs::update::{is_changed, source_entries, DirNaming, StoredFile};
+use crate::codecs::zip_edit::{Rewriter, ZipSource};
+use crate::codecs::zip_salvage::{salvage, SalvageReport};
+use crate::codecs::zip_stream::{ZipStreamWriter, FILE_MODE, STDIN_MODE};
+use crate::utils::{create_output, ensure_directory_exists, is_stdio, spool_stdin};
+use crate::{Result, ZipError};
+use chrono::{Datelike, Local, TimeZone, Timelike};
+use log::{info, warn};
 use rayon::prelude::*;
 use std::fs::{self, File};
 use std::io::{self, Read, Seek, Write};
-use std::path::{Path};
-use std::time::Instant;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
 use sync_file::SyncFile;
 use walkdir::{DirEntry, WalkDir};
 use zip::write::{FileOptions, SimpleFileOptions};
+use zip::unstable::write::FileOptionsExt;
 use zip::{AesMode, ZipArchive, ZipWriter};
 use zip::read::ZipFile;
 
 /// Zip compression methods
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Default)]
 pub enum CompressionMethod {
     Deflated,
     Bzip2,
+    #[default]
     Zstd,
 }
 
-impl Default for CompressionMethod {
-    fn default() -> Self {
-        Self::Zstd
-    }
-}
-
 impl CompressionMethod {
-    /// Create from string
-    pub fn from_str(s: &str) -> Self {
+    /// Create from a method name
+    pub fn from_name(s: &str) -> Result<Self> {
         match s.to_lowercase().as_str() {
-            "deflated" => Self::Deflated,
-            "bzip2" => Self::Bzip2,
-            "zstd" => Self::Zstd,
-            _ => Self::default(),
+            "deflate" | "deflated" => Ok(Self::Deflated),
+            "bzip2" => Ok(Self::Bzip2),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(ZipError::Other(format!(
+                "Unknown zip compression method: {} (expected deflate, bzip2 or zstd)", s
+            ))),
         }
     }
 
@@ -55,24 +62,246 @@ impl CompressionMethod {
             Self::Zstd => zip::CompressionMethod::Zstd,
         }
     }
+
+    /// Method id as stored in zip headers
+    pub fn header_id(&self) -> u16 {
+        match self {
+            Self::Deflated => 8,
+            Self::Bzip2 => 12,
+            Self::Zstd => 93,
+        }
+    }
+}
+
+/// Zip encryption schemes
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ZipEncryption {
+    Aes128,
+    Aes192,
+    #[default]
+    Aes256,
+    /// Legacy PKWARE encryption, readable by old tools but trivially broken
+    ZipCrypto,
+}
+
+impl FromStr for ZipEncryption {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "aes128" => Ok(Self::Aes128),
+            "aes192" => Ok(Self::Aes192),
+            "aes256" | "aes" => Ok(Self::Aes256),
+            "zipcrypto" => Ok(Self::ZipCrypto),
+            _ => Err(format!("unknown zip encryption: {} (expected aes128, aes192, aes256 or zipcrypto)", s)),
+        }
+    }
+}
+
+impl ZipEncryption {
+    /// Convert to string
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Aes128 => "aes128",
+            Self::Aes192 => "aes192",
+            Self::Aes256 => "aes256",
+            Self::ZipCrypto => "zipcrypto",
+        }
+    }
+
+    /// Scheme used by an archive entry, or `None` if it is not encrypted
+    pub fn of_entry<R: Read + Seek>(archive: &mut ZipArchive<R>, index: usize) -> Result<Option<Self>> {
+        if let Some(aes) = archive.get_aes_verification_key_and_salt(index)? {
+            return Ok(Some(match aes.aes_mode {
+                AesMode::Aes128 => Self::Aes128,
+                AesMode::Aes192 => Self::Aes192,
+                AesMode::Aes256 => Self::Aes256,
+            }));
+        }
+
+        let encrypted = archive.by_index_raw(index)?.encrypted();
+        Ok(encrypted.then_some(Self::ZipCrypto))
+    }
+
+    /// Apply this scheme with `password` to file options
+    fn apply<'k>(&self, options: FileOptions<'k, ()>, password: &'k str) -> FileOptions<'k, ()> {
+        match self {
+            Self::Aes128 => options.with_aes_encryption(AesMode::Aes128, password),
+            Self::Aes192 => options.with_aes_encryption(AesMode::Aes192, password),
+            Self::Aes256 => options.with_aes_encryption(AesMode::Aes256, password),
+            Self::ZipCrypto => options.with_deprecated_encryption(password.as_bytes()),
+        }
+    }
 }
 
 /// ZIP format implementation
 pub struct ZipCodec {
     method: CompressionMethod,
     password: Option<String>,
+    encryption: ZipEncryption,
     compression_level: u8,
+    /// Split output into volumes of this many MB
+    volume_size: Option<usize>,
+    limits: ExtractLimits,
+    path_policy: PathPolicy,
+    /// Rebuild the archive from its local headers before extracting
+    salvage: bool,
 }
 
 impl ZipCodec {
     /// Create a new ZIP codec
-    pub fn new(method: CompressionMethod, password: Option<String>) -> Self {
-        Self { method, password, compression_level: 6 }
+    pub fn new(method: CompressionMethod, password: Option<String>, volume_size: Option<usize>) -> Self {
+        Self {
+            method,
+            password,
+            encryption: ZipEncryption::default(),
+            compression_level: 6,
+            volume_size,
+            limits: ExtractLimits::default(),
+            path_policy: PathPolicy::default(),
+            salvage: false,
+        }
+    }
+
+    /// Open the raw archive bytes from a file, its split volumes or stdin
+    fn open_source(source: &Path) -> Result<File> {
+        // The central directory sits at the end, so stdin has to be spooled first
+        if is_stdio(source) {
+            spool_stdin()
+        } else if let Some(volumes) = discover_volumes(source) {
+            info!("Joining {} volumes", volumes.len());
+            let mut joined = tempfile::tempfile()?;
+            join_volumes(&volumes, &mut joined)?;
+            Ok(joined)
+        } else {
+            Ok(File::open(source)?)
+        }
+    }
+
+    fn open_archive(source: &Path) -> Result<ZipArchive<SyncFile>> {
+        Ok(ZipArchive::new(SyncFile::from(Self::open_source(source)?))?)
+    }
+
+    /// Rebuild a damaged archive from its local headers into `output`
+    pub fn salvage_to<W: Write>(&self, source: &Path, output: W) -> Result<SalvageReport> {
+        let mut file = Self::open_source(source)?;
+        salvage(&mut file, output, self.password.as_deref())
+    }
+
+    /// Fail once everything recoverable is out if salvage had to drop entries
+    fn check_salvaged(report: Option<SalvageReport>) -> Result<()> {
+        match report {
+            Some(report) if !report.lost.is_empty() => Err(ZipError::Other(format!(
+                "{} entries could not be recovered: {}", report.lost.len(), report.lost.join(", ")
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Extract from an archive rebuilt by [`Self::salvage_to`] instead of trusting its central directory
+    pub fn set_salvage(&mut self, salvage: bool) {
+        self.salvage = salvage;
+    }
+
+    /// Use this encryption scheme when a password is set
+    pub fn set_encryption(&mut self, encryption: ZipEncryption) {
+        self.encryption = encryption;
+    }
+
+    /// Open entry `index`, decrypting it with the password when it is encrypted
+    fn open_entry<'a, R: Read + Seek>(&self, archive: &'a mut ZipArchive<R>, index: usize) -> Result<ZipFile<'a>> {
+        let scheme = ZipEncryption::of_entry(archive, index)?;
+
+        match (scheme, &self.password) {
+            (None, _) => Ok(archive.by_index(index)?),
+            (Some(scheme), Some(password)) => {
+                info!("Entry {} is encrypted with {}", index, scheme.as_str());
+                Ok(archive.by_index_decrypt(index, password.as_bytes())?)
+            }
+            (Some(scheme), None) => {
+                let name = archive.name_for_index(index).unwrap_or_default().to_string();
+                Err(ZipError::Other(format!(
+                    "{} is encrypted with {}; a password is required", name, scheme.as_str()
+                )))
+            }
+        }
+    }
+
+    /// Hand entry `index` to `visitor`
+    fn visit_entry<R: Read + Seek>(&self, archive: &mut ZipArchive<R>, index: usize, visitor: &mut EntryVisitor) -> Result<()> {
+        let mut file = self.open_entry(archive, index)?;
+
+        // A symlink's contents are its target
+        let mut target = Vec::new();
+        if file.is_symlink() {
+            file.read_to_end(&mut target)?;
+        }
+
+        let meta = EntryMeta {
+            name: file.name().to_string(),
+            is_dir: file.is_dir(),
+            size: Some(file.size()),
+            modified: file.last_modified().and_then(from_zip_time),
+            mode: file.unix_mode(),
+            link: file.is_symlink().then(|| EntryLink::Symbolic(String::from_utf8_lossy(&target).into_owned())),
+        };
+
+        if meta.link.is_some() {
+            visitor(&meta, &mut target.as_slice())
+        } else {
+            visitor(&meta, &mut file)
+        }
+    }
+
+    /// Options for newly compressed entries: method, level and encryption
+    fn file_options(&self) -> FileOptions<'_, ()> {
+        let mut options = SimpleFileOptions::default()
+            .compression_method(self.method.to_zip_method())
+            .unix_permissions(FILE_MODE)
+            .compression_level(Some(self.compression_level as i64));
+
+        if let Some(password) = &self.password {
+            if self.encryption == ZipEncryption::ZipCrypto {
+                warn!("ZipCrypto is insecure and can be broken in minutes; use it only for tools that cannot read AES zip archives");
+                if !matches!(self.method, CompressionMethod::Deflated) {
+                    warn!("Tools limited to ZipCrypto usually only read deflate; consider --method deflate");
+                }
+            }
+            options = self.encryption.apply(options, password);
+        }
+        options
+    }
+
+    /// Compress `files` into a standalone archive whose entries can then be copied raw
+    fn stage_files(&self, files: &[(String, PathBuf)]) -> Result<ZipSource> {
+        let mut writer = ZipWriter::new(tempfile::tempfile()?);
+
+        for (name, path) in files {
+            let metadata = fs::metadata(path)?;
+            let mut options = self.file_options();
+            if let Some(modified) = metadata.modified().ok().and_then(zip_time) {
+                options = options.last_modified_time(modified);
+            }
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                options = options.unix_permissions(metadata.permissions().mode() & 0o7777);
+            }
+
+            if metadata.is_dir() {
+                info!("Writing dir: {}", name);
+                writer.add_directory(name.as_str(), options)?;
+            } else {
+                Self::zip_file(&mut writer, &mut File::open(path)?, name.clone(), options, metadata.len())?;
+            }
+        }
+
+        ZipSource::from_file(writer.finish()?)
     }
 
     /// Add a file to the zip archive
-    fn zip_file<F: Read + Seek>(
-        writer: &mut ZipWriter<File>,
+    fn zip_file<W: Write + Seek, F: Read + ?Sized>(
+        writer: &mut ZipWriter<W>,
         reader: &mut F,
         filename: String,
         base_options: FileOptions<()>,
@@ -113,10 +342,10 @@ impl ZipCodec {
     }
 
     /// Add a directory to the zip archive
-    fn zip_dir(
+    fn zip_dir<W: Write + Seek>(
         it: &mut dyn Iterator<Item = DirEntry>,
         prefix: String,
-        writer: &mut ZipWriter<File>,
+        writer: &mut ZipWriter<W>,
         options: FileOptions<()>,
     ) -> Result<()> {
         for entry in it {
@@ -137,57 +366,146 @@ impl ZipCodec {
 
         Ok(())
     }
+
+    /// Write the archive to a non-seekable target (stdout) using data descriptors
+    fn compress_stream(&self, source: &[&Path], target: &Path) -> Result<()> {
+        if self.password.is_some() {
+            return Err(ZipError::UnsupportedOperation(
+                "Encrypted zip output cannot be streamed to stdout".to_string()
+            ));
+        }
+
+        let mut writer = ZipStreamWriter::new(create_output(target)?, self.method, self.compression_level)?;
+        info!("Streaming zip writer created");
+
+        for item in source {
+            if is_stdio(item) {
+                info!("Writing file: - (stdin)");
+                writer.add_file("-", &mut io::stdin().lock(), None, STDIN_MODE)?;
+            } else if item.is_file() {
+                let mut f = File::open(item)?;
+                let filename = item
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .unwrap_or("unknown")
+                    .to_string();
+
+                info!("Writing file: {}", filename);
+                let modified = f.metadata()?.modified().ok();
+                writer.add_file(&filename, &mut f, modified, FILE_MODE)?;
+            } else {
+                let prefix = item.to_str().unwrap_or("").to_string();
+
+                for entry in WalkDir::new(item).follow_links(true).into_iter().filter_map(|e| e.ok()) {
+                    let path = entry.path();
+                    let outpath = path.strip_prefix(&prefix)?;
+                    let path_as_string = outpath.to_str().map(|e| e.to_owned()).unwrap_or_default();
+                    let modified = entry.metadata().ok().and_then(|m| m.modified().ok());
+
+                    if path.is_file() {
+                        info!("Writing file: {}", path_as_string);
+                        writer.add_file(&path_as_string, &mut File::open(path)?, modified, FILE_MODE)?;
+                    } else if !outpath.as_os_str().is_empty() {
+                        info!("Writing dir: {}", path_as_string);
+                        writer.add_directory(&format!("{}/", path_as_string), modified, FILE_MODE)?;
+                    }
+                }
+            }
+        }
+
+        writer.finish()?.flush()?;
+        Ok(())
+    }
+
+    /// Write the contents of every file entry to stdout, in archive order
+    fn extract_to_stdout<R: Read + Seek>(&self, archive: &mut ZipArchive<R>, tracker: &LimitTracker) -> Result<()> {
+        let mut stdout = io::stdout().lock();
+
+        for i in 0..archive.len() {
+            let mut file = self.open_entry(archive, i)?;
+
+            let file_name = file.name().to_string();
+            tracker.begin_entry(&file_name)?;
+
+            if file.is_dir() {
+                continue;
+            }
+
+            info!("Extracting file: {} -> stdout", file_name);
+            let compressed = RatioBase::Entry(file.compressed_size());
+            tracker.copy(&file_name, &mut file, &mut stdout, compressed)?;
+        }
+
+        stdout.flush()?;
+        Ok(())
+    }
 }
 
 impl Codec for ZipCodec {
     fn extract(&mut self, source: &[&Path], target: &Path) -> Result<()> {
-        ensure_directory_exists(target)?;
-
         let start = Instant::now();
-        
-        let archive = ZipArchive::new(SyncFile::open(source[0])?)?;
+
+        let mut salvaged = None;
+        let mut archive = if self.salvage {
+            let mut rebuilt = tempfile::tempfile()?;
+            let report = self.salvage_to(source[0], &mut rebuilt)?;
+            info!("Salvaged {} entries, {} lost", report.recovered.len() + report.unverified.len(), report.lost.len());
+            salvaged = Some(report);
+            ZipArchive::new(SyncFile::from(rebuilt))?
+        } else {
+            Self::open_archive(source[0])?
+        };
+
+        let tracker = LimitTracker::new(self.limits);
+        tracker.check_declared_entries(archive.len() as u64)?;
+
+        if is_stdio(target) {
+            self.extract_to_stdout(&mut archive, &tracker)?;
+            return Self::check_salvaged(salvaged);
+        }
+
+        ensure_directory_exists(target)?;
+        let guard = PathGuard::new(target, self.path_policy)?;
         
         let total_files = archive.len();
         info!("Archive contains {} files", total_files);
 
-        (0..archive.len())
+        let result = (0..archive.len())
             .into_par_iter()
             .try_for_each_with(archive, |archive, i| {
-                let mut file;
-                
-                if let Some(ref password) = self.password {
-                    file = archive.by_index_decrypt(i, password.as_bytes())?;
-                } else {
-                    file = archive.by_index(i)?;
-                }
+                let mut file = self.open_entry(archive, i)?;
                 
                 let file_name = file.name().to_string();
-                let filepath = match file.enclosed_name() {
+                tracker.begin_entry(&file_name)?;
+
+                let outpath = match guard.resolve(&file_name) {
                     Some(path) => path,
                     None => return Ok(()),
                 };
 
-                let outpath = target.join(filepath);
-
                 if file.name().ends_with('/') {
                     info!("Creating directory: {}", file_name);
-                    fs::create_dir_all(&outpath)?;
+                    tracker.create_dir_all(&outpath)?;
                 } else {
                     info!("Extracting file: {}", file_name);
                     
-                    if let Some(p) = outpath.parent() {
-                        if !p.exists() {
-                            fs::create_dir_all(p)?;
-                        }
+                    if let Some(p) = outpath.parent()
+                        && !p.exists()
+                    {
+                        tracker.create_dir_all(p)?;
                     }
 
-                    let mut outfile = File::create(&outpath)?;
-                    let bytes_copied = io::copy(&mut file, &mut outfile)?;
+                    let mut outfile = tracker.create_file(&outpath)?;
+                    let compressed = RatioBase::Entry(file.compressed_size());
+                    let bytes_copied = tracker.copy(&file_name, &mut file, &mut outfile, compressed)?;
                     info!("File extracted: {} ({} bytes)", file_name, bytes_copied);
                 }
 
                 Ok(()) as Result<()>
-            })?;
+            });
+
+        tracker.guard(result)?;
+        guard.finish()?;
 
         let elapsed = start.elapsed();
         info!(
@@ -195,34 +513,118 @@ impl Codec for ZipCodec {
             elapsed.as_millis(),
             elapsed.as_secs()
         );
+        Self::check_salvaged(salvaged)
+    }
+
+    fn visit_entries(&mut self, source: &Path, visitor: &mut EntryVisitor) -> Result<()> {
+        let mut archive = Self::open_archive(source)?;
+
+        for i in 0..archive.len() {
+            self.visit_entry(&mut archive, i, visitor)?;
+        }
+
         Ok(())
     }
 
+    fn visit_entries_parallel(&mut self, source: &Path, visitor: &SharedVisitor) -> Result<()> {
+        let archive = Self::open_archive(source)?;
+
+        // Like extract, every thread reads its own clone of the archive
+        (0..archive.len())
+            .into_par_iter()
+            .try_for_each_with(archive, |archive, i| {
+                self.visit_entry(archive, i, &mut |meta, data| visitor(meta, data))
+            })
+    }
+
+    fn cat_entries(&mut self, source: &Path, names: &[String], out: &mut dyn Write) -> Result<()> {
+        let mut archive = Self::open_archive(source)?;
+        let mut missing = Vec::new();
+
+        for name in names {
+            let index = archive.index_for_name(name).or_else(|| {
+                (0..archive.len()).find(|&i| archive.name_for_index(i).is_some_and(|entry| same_entry_name(name, entry)))
+            });
+            let Some(index) = index else {
+                missing.push(name.as_str());
+                continue;
+            };
+
+            let mut file = self.open_entry(&mut archive, index)?;
+            if file.is_dir() {
+                return Err(ZipError::Other(format!("{} is a directory", name)));
+            }
+            io::copy(&mut file, out)?;
+        }
+
+        missing_entries(&missing)
+    }
+
+    fn entry_writer(&mut self, target: &Path) -> Result<Box<dyn EntryWriter + '_>> {
+        // The zip writer seeks back to patch headers, so stdout gets a finished copy
+        let (output, stdout) = if is_stdio(target) {
+            (tempfile::tempfile()?, Some(create_output(target)?))
+        } else {
+            ensure_directory_exists(target.parent().unwrap_or(Path::new(".")))?;
+            (File::create(target)?, None)
+        };
+
+        Ok(Box::new(ZipEntryWriter {
+            writer: ZipWriter::new(output),
+            options: self.file_options(),
+            stdout,
+        }))
+    }
+
     fn compress(&mut self, source: &[&Path], target: &Path, _exclude: Option<&[&Path]>) -> Result<()> {
         let start = Instant::now();
 
-        if let Some(p) = target.parent() {
-            if !p.exists() {
-                fs::create_dir_all(p)?;
+        if is_stdio(target) {
+            if self.volume_size.is_some() {
+                return Err(ZipError::UnsupportedOperation(
+                    "Split volumes cannot be written to stdout".to_string()
+                ));
             }
-        }
 
-        let zip_method = self.method.to_zip_method();
+            self.compress_stream(source, target)?;
 
-        let mut options = SimpleFileOptions::default()
-            .compression_method(zip_method)
-            .unix_permissions(0o755)
-            .compression_level(Some(self.compression_level as i64));
+            info!(
+                "Compression completed in {:?} ms / {:?} s",
+                start.elapsed().as_millis(),
+                start.elapsed().as_secs()
+            );
+            return Ok(());
+        }
 
-        if let Some(password) = &self.password {
-            options = options.with_aes_encryption(AesMode::Aes256, password);
+        if let Some(p) = target.parent()
+            && !p.exists()
+        {
+            fs::create_dir_all(p)?;
         }
 
-        let mut writer = ZipWriter::new(File::create(target)?);
+        let options = self.file_options();
+
+        // Split archives are written whole first, then cut into volumes
+        let split_size = self.volume_size.map(volume_bytes).transpose()?;
+        let staging = match split_size {
+            Some(_) => Some(tempfile::NamedTempFile::new()?),
+            None => None,
+        };
+
+        let output = match staging {
+            Some(ref staging) => staging.reopen()?,
+            None => File::create(target)?,
+        };
+
+        let mut writer = ZipWriter::new(output);
         info!("Zip writer created");
 
         for item in source {
-            if item.is_file() {
+            if is_stdio(item) {
+                // Unknown length: write as a large file and copy until EOF
+                let options = options.unix_permissions(STDIN_MODE);
+                Self::zip_file(&mut writer, &mut io::stdin().lock(), "-".to_string(), options, u64::MAX)?;
+            } else if item.is_file() {
                 let mut f = File::open(item)?;
                 let filename = item
                     .file_name()
@@ -246,6 +648,11 @@ impl Codec for ZipCodec {
 
         writer.finish()?;
 
+        if let (Some(size), Some(staging)) = (split_size, staging) {
+            let volumes = split_zip(staging.path(), target, size)?;
+            info!("Archive split into {} volumes", volumes.len());
+        }
+
         info!(
             "Compression completed in {:?} ms / {:?} s",
             start.elapsed().as_millis(),
@@ -255,6 +662,107 @@ impl Codec for ZipCodec {
         Ok(())
     }
 
+    fn append_entry(&mut self, archive: &Path, name: &str, data: &[u8]) -> Result<()> {
+        let file = fs::OpenOptions::new().read(true).write(true).open(archive)?;
+        let mut writer = ZipWriter::new_append(file)?;
+
+        let mut options = SimpleFileOptions::default().compression_method(self.method.to_zip_method());
+        if let Some(password) = &self.password {
+            options = self.encryption.apply(options, password);
+        }
+
+        writer.start_file(name, options)?;
+        writer.write_all(data)?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    fn add_entries(&mut self, archive: &Path, source: &[&Path], only_changed: bool) -> Result<()> {
+        let mut existing = ZipSource::open(archive)?;
+
+        let mut files = Vec::new();
+        for (mut name, path) in source_entries(source, DirNaming::Relative)? {
+            if path.is_dir() {
+                name.push('/');
+            }
+            let unchanged = match existing.archive.index_for_name(&name) {
+                Some(index) if only_changed => {
+                    let entry = existing.archive.by_index_raw(index)?;
+                    let stored = StoredFile {
+                        size: entry.size(),
+                        modified: entry.last_modified().and_then(from_zip_time),
+                        time_step: Duration::from_secs(2),
+                    };
+                    !is_changed(&path, &stored, || Ok(Some(format!("{:08x}", entry.crc32()))))?
+                }
+                _ => false,
+            };
+            if !unchanged {
+                files.push((name, path));
+            }
+        }
+
+        if files.is_empty() {
+            info!("{:?} is up to date", archive);
+            return Ok(());
+        }
+
+        let mut staged = self.stage_files(&files)?;
+        let mut rewriter = Rewriter::new(archive)?;
+        let mut replaced = vec![false; staged.len()];
+
+        // Replaced entries keep their place; new ones go at the end
+        for index in 0..existing.len() {
+            match staged.archive.index_for_name(existing.name(index)) {
+                Some(new) => {
+                    replaced[new] = true;
+                    rewriter.copy(&mut staged, new)?;
+                }
+                None => rewriter.copy(&mut existing, index)?,
+            }
+        }
+        for new in (0..staged.len()).filter(|&new| !replaced[new]) {
+            rewriter.copy(&mut staged, new)?;
+        }
+        rewriter.finish()?;
+
+        let replaced = replaced.iter().filter(|&&r| r).count();
+        info!("Added {} and replaced {} entries in {:?}", staged.len() - replaced, replaced, archive);
+        Ok(())
+    }
+
+    fn delete_entries(&mut self, archive: &Path, names: &[String]) -> Result<()> {
+        let mut existing = ZipSource::open(archive)?;
+
+        let matches = |entry: &str, name: &str| {
+            let name = name.trim_end_matches('/');
+            entry.trim_end_matches('/') == name || entry.strip_prefix(name).is_some_and(|rest| rest.starts_with('/'))
+        };
+
+        let unmatched: Vec<&str> = names.iter()
+            .filter(|name| !(0..existing.len()).any(|i| matches(existing.name(i), name)))
+            .map(String::as_str)
+            .collect();
+        if !unmatched.is_empty() {
+            return Err(ZipError::Other(format!("No entries match: {}", unmatched.join(", "))));
+        }
+
+        let mut rewriter = Rewriter::new(archive)?;
+        let mut deleted = 0;
+        for index in 0..existing.len() {
+            if names.iter().any(|name| matches(existing.name(index), name)) {
+                info!("Deleting: {}", existing.name(index));
+                deleted += 1;
+            } else {
+                rewriter.copy(&mut existing, index)?;
+            }
+        }
+        rewriter.finish()?;
+
+        info!("Deleted {} entries from {:?}", deleted, archive);
+        Ok(())
+    }
+
     fn compression_level_range(&self) -> (u8, u8) {
         (0, 9)
     }
@@ -262,4 +770,123 @@ impl Codec for ZipCodec {
     fn set_compression_level(&mut self, level: u8) {
         self.compression_level = level;
     }
+
+    fn set_limits(&mut self, limits: ExtractLimits) {
+        self.limits = limits;
+    }
+
+    fn set_path_policy(&mut self, policy: PathPolicy) {
+        self.path_policy = policy;
+    }
+}
+
+/// Writes entries into a new zip archive with the codec's method, level and encryption
+struct ZipEntryWriter<'a> {
+    writer: ZipWriter<File>,
+    options: FileOptions<'a, ()>,
+    /// Where the archive is copied once finished, when writing to stdout
+    stdout: Option<Box<dyn Write>>,
+}
+
+impl EntryWriter for ZipEntryWriter<'_> {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities { modified: true, mode: true, symlinks: true, hard_links: false, directories: true }
+    }
+
+    fn write_entry(&mut self, meta: &EntryMeta, data: &mut dyn Read) -> Result<()> {
+        let mut options = self.options;
+        if let Some(modified) = meta.modified.and_then(zip_time) {
+            options = options.last_modified_time(modified);
+        }
+        if let Some(mode) = meta.mode {
+            options = options.unix_permissions(mode & 0o7777);
+        }
+
+        match &meta.link {
+            Some(EntryLink::Symbolic(target)) => self.writer.add_symlink(meta.name.as_str(), target, options)?,
+            Some(EntryLink::Hard(_)) => {
+                return Err(ZipError::UnsupportedOperation(format!("{} is a hard link, which zip cannot store", meta.name)));
+            }
+            None if meta.is_dir => self.writer.add_directory(meta.name.as_str(), options)?,
+            None => ZipCodec::zip_file(&mut self.writer, data, meta.name.clone(), options, meta.size.unwrap_or(u64::MAX))?,
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        let mut written = self.writer.finish()?;
+        if let Some(mut stdout) = self.stdout {
+            written.seek(io::SeekFrom::Start(0))?;
+            io::copy(&mut written, &mut stdout)?;
+            stdout.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Modification time of a zip timestamp, which is in local time
+fn from_zip_time(time: zip::DateTime) -> Option<SystemTime> {
+    Local.with_ymd_and_hms(
+        time.year() as i32,
+        time.month() as u32,
+        time.day() as u32,
+        time.hour() as u32,
+        time.minute() as u32,
+        time.second() as u32,
+    ).earliest().map(SystemTime::from)
+}
+
+/// Zip timestamp for a modification time, in local time like other zip tools
+fn zip_time(modified: SystemTime) -> Option<zip::DateTime> {
+    let local: chrono::DateTime<Local> = modified.into();
+    zip::DateTime::from_date_and_time(
+        local.year().try_into().ok()?,
+        local.month() as u8,
+        local.day() as u8,
+        local.hour() as u8,
+        local.minute() as u8,
+        // Zip times count in two-second steps
+        local.second() as u8 & !1,
+    ).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_encryption_round_trips_and_is_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("secret.txt");
+        fs::write(&src, "top secret").unwrap();
+
+        for encryption in [ZipEncryption::Aes128, ZipEncryption::Aes192, ZipEncryption::Aes256, ZipEncryption::ZipCrypto] {
+            let archive = dir.path().join(format!("{}.zip", encryption.as_str()));
+            let mut codec = ZipCodec::new(CompressionMethod::Deflated, Some("hunter2".to_string()), None);
+            codec.set_encryption(encryption);
+            codec.compress(&[&src], &archive, None).unwrap();
+
+            let mut zip = ZipArchive::new(File::open(&archive).unwrap()).unwrap();
+            assert_eq!(ZipEncryption::of_entry(&mut zip, 0).unwrap(), Some(encryption));
+
+            let out = dir.path().join(encryption.as_str());
+            codec.extract(&[&archive], &out).unwrap();
+            assert_eq!(fs::read_to_string(out.join("secret.txt")).unwrap(), "top secret");
+
+            let mut wrong = ZipCodec::new(CompressionMethod::Deflated, Some("wrong".to_string()), None);
+            assert!(wrong.extract(&[&archive], &dir.path().join("wrong")).is_err(), "{}", encryption.as_str());
+        }
+    }
+
+    #[test]
+    fn method_names_match_the_help_and_unknown_ones_are_refused() {
+        for name in ["deflate", "Deflated"] {
+            assert!(matches!(CompressionMethod::from_name(name), Ok(CompressionMethod::Deflated)));
+        }
+        assert!(matches!(CompressionMethod::from_name("zstd"), Ok(CompressionMethod::Zstd)));
+        assert!(CompressionMethod::from_name("lzma").is_err());
+
+        let factory = crate::codecs::CodecFactory::new(crate::codecs::Format::Zip, Some("lzma"), None, None, false, None);
+        assert!(factory.create_codec().is_err());
+    }
 }
@@ -0,0 +1,122 @@
+use crate::codecs::volume::discover_volumes;
+use crate::codecs::zip::CompressionMethod;
+use crate::codecs::zip_salvage::{central_extra, extra_field, ZIP64_EXTRA};
+use crate::codecs::zip_stream::{CentralEntry, ZipStreamWriter, DATA_DESCRIPTOR_SIGNATURE, FLAG_DATA_DESCRIPTOR, LOCAL_HEADER_SIGNATURE};
+use crate::utils::{is_stdio, replace_with_staged, staging_file};
+use crate::{Result, ZipError};
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+use zip::ZipArchive;
+
+/// An archive whose entries can be copied without decompressing them
+pub(super) struct ZipSource {
+    file: File,
+    pub archive: ZipArchive<File>,
+}
+
+impl ZipSource {
+    /// Open an archive that is about to be rewritten
+    pub fn open(path: &Path) -> Result<Self> {
+        if is_stdio(path) || discover_volumes(path).is_some() {
+            return Err(ZipError::UnsupportedOperation(
+                "Only a single zip file can be edited in place, not stdin or split volumes".to_string()
+            ));
+        }
+        Self::from_file(File::open(path)?)
+    }
+
+    pub fn from_file(file: File) -> Result<Self> {
+        Ok(Self { archive: ZipArchive::new(file.try_clone()?)?, file })
+    }
+
+    pub fn len(&self) -> usize {
+        self.archive.len()
+    }
+
+    pub fn name(&self, index: usize) -> &str {
+        self.archive.name_for_index(index).unwrap_or_default()
+    }
+}
+
+/// Writer for the replacement of an archive
+pub(super) struct Rewriter {
+    target: PathBuf,
+    writer: ZipStreamWriter<BufWriter<NamedTempFile>>,
+}
+
+impl Rewriter {
+    pub fn new(target: &Path) -> Result<Self> {
+        let staging = staging_file(target)?;
+
+        Ok(Self {
+            target: target.to_path_buf(),
+            // The method only applies to compressed entries, and every entry here is copied raw
+            writer: ZipStreamWriter::new(BufWriter::new(staging), CompressionMethod::Deflated, 6)?,
+        })
+    }
+
+    /// Copy entry `index` of `source` as it is stored
+    pub fn copy(&mut self, source: &mut ZipSource, index: usize) -> Result<()> {
+        let entry = source.archive.by_index_raw(index)?;
+        let (start, data_start) = (entry.header_start(), entry.data_start());
+        let (name, is_dir, mode) = (entry.name().to_string(), entry.is_dir(), entry.unix_mode());
+        let (crc, compressed_size, uncompressed_size) = (entry.crc32(), entry.compressed_size(), entry.size());
+        drop(entry);
+
+        let file = &mut source.file;
+        let mut fixed = [0_u8; 30];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut fixed)?;
+        if fixed[..4] != LOCAL_HEADER_SIGNATURE.to_le_bytes() {
+            return Err(ZipError::Other(format!("{}: local header not found at offset {}", name, start)));
+        }
+
+        let u16_at = |at: usize| u16::from_le_bytes([fixed[at], fixed[at + 1]]);
+        let flags = u16_at(6);
+        let name_len = u16_at(26) as u64;
+        let mut extra = vec![0_u8; u16_at(28) as usize];
+        file.seek(SeekFrom::Start(start + 30 + name_len))?;
+        file.read_exact(&mut extra)?;
+
+        // The descriptor's signature is optional and its sizes are 64-bit alongside a ZIP64 field
+        let mut end = data_start + compressed_size;
+        if flags & FLAG_DATA_DESCRIPTOR != 0 {
+            let mut signature = [0_u8; 4];
+            file.seek(SeekFrom::Start(end))?;
+            file.read_exact(&mut signature)?;
+
+            let sizes = if extra_field(&extra, ZIP64_EXTRA).is_some() { 16 } else { 8 };
+            let signed = signature == DATA_DESCRIPTOR_SIGNATURE.to_le_bytes();
+            end += if signed { 4 } else { 0 } + 4 + sizes;
+        }
+
+        let dos_attributes = if is_dir { 0x10 } else { 0 };
+        let central = CentralEntry {
+            name,
+            flags,
+            version_needed: u16_at(4),
+            method: u16_at(8),
+            dos_time: u16_at(10),
+            dos_date: u16_at(12),
+            crc,
+            compressed_size,
+            uncompressed_size,
+            offset: 0,
+            external_attributes: mode.map_or(dos_attributes, |mode| (mode << 16) | dos_attributes),
+            extra: central_extra(&extra),
+        };
+
+        file.seek(SeekFrom::Start(start))?;
+        self.writer.add_raw(&mut file.take(end - start), central)
+    }
+
+    /// Replace the target with what was written, keeping its permissions
+    pub fn finish(self) -> Result<()> {
+        let staging = self.writer.finish()?
+            .into_inner()
+            .map_err(|e| ZipError::Io(e.into_error()))?;
+        replace_with_staged(staging, &self.target)
+    }
+}
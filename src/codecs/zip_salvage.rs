@@ -0,0 +1,316 @@
+use crate::codecs::zip_stream::{CentralEntry, ZipStreamWriter, DATA_DESCRIPTOR_SIGNATURE, FLAG_DATA_DESCRIPTOR, LOCAL_HEADER_SIGNATURE};
+use crate::codecs::zip::CompressionMethod;
+use crate::Result;
+use log::{info, warn};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use zip::ZipArchive;
+
+const FLAG_ENCRYPTED: u16 = 0x0001;
+pub(super) const ZIP64_EXTRA: u16 = 0x0001;
+/// WinZip AES parameters; readers look for it in the central directory
+const AES_EXTRA: u16 = 0x9901;
+const SCAN_CHUNK: usize = 64 * 1024;
+
+/// What a salvage pass found
+#[derive(Debug, Default)]
+pub struct SalvageReport {
+    pub recovered: Vec<String>,
+    /// Encrypted entries kept without checking their contents (no password given)
+    pub unverified: Vec<String>,
+    /// Entries found but damaged, or headers whose data is cut off
+    pub lost: Vec<String>,
+}
+
+/// An entry located in the damaged file
+struct Candidate {
+    start: u64,
+    end: u64,
+    entry: CentralEntry,
+}
+
+/// Rebuild the zip in `source` into `output`, keeping every entry that still
+/// reads back intact. `password` lets encrypted entries be checked too.
+pub fn salvage<W: Write>(source: &mut File, output: W, password: Option<&str>) -> Result<SalvageReport> {
+    let mut report = SalvageReport::default();
+    let candidates = scan(source, &mut report)?;
+    info!("Found {} entries with intact headers", candidates.len());
+
+    // First rebuild everything, then let the zip reader judge each entry
+    let mut trial = ZipStreamWriter::new(tempfile::tempfile()?, CompressionMethod::Deflated, 6)?;
+    for candidate in &candidates {
+        copy_candidate(source, candidate, &mut trial)?;
+    }
+    let mut archive = ZipArchive::new(trial.finish()?)?;
+
+    let mut keep = Vec::with_capacity(candidates.len());
+    for (i, candidate) in candidates.iter().enumerate() {
+        let name = &candidate.entry.name;
+        let encrypted = candidate.entry.flags & FLAG_ENCRYPTED != 0;
+
+        let intact = match (encrypted, password) {
+            (true, None) => {
+                report.unverified.push(name.clone());
+                keep.push(candidate);
+                continue;
+            }
+            (true, Some(password)) => archive.by_index_decrypt(i, password.as_bytes())
+                .map_err(io::Error::other)
+                .and_then(|mut file| io::copy(&mut file, &mut io::sink())),
+            (false, _) => archive.by_index(i)
+                .map_err(io::Error::other)
+                .and_then(|mut file| io::copy(&mut file, &mut io::sink())),
+        };
+
+        match intact {
+            Ok(_) => {
+                report.recovered.push(name.clone());
+                keep.push(candidate);
+            }
+            Err(e) => {
+                warn!("Entry {} is damaged: {}", name, e);
+                report.lost.push(name.clone());
+            }
+        }
+    }
+
+    let mut writer = ZipStreamWriter::new(output, CompressionMethod::Deflated, 6)?;
+    for candidate in keep {
+        copy_candidate(source, candidate, &mut writer)?;
+    }
+    writer.finish()?;
+
+    Ok(report)
+}
+
+fn copy_candidate<W: Write>(source: &mut File, candidate: &Candidate, writer: &mut ZipStreamWriter<W>) -> Result<()> {
+    source.seek(SeekFrom::Start(candidate.start))?;
+    let mut raw = (&mut *source).take(candidate.end - candidate.start);
+
+    writer.add_raw(&mut raw, candidate.entry.clone())
+}
+
+/// Walk the file from the start, picking up every parseable local header
+fn scan(source: &mut File, report: &mut SalvageReport) -> Result<Vec<Candidate>> {
+    let len = source.metadata()?.len();
+    let mut candidates = Vec::new();
+    let mut offset = 0;
+
+    while let Some(start) = find_signature(source, offset, LOCAL_HEADER_SIGNATURE)? {
+        match parse_entry(source, start, len)? {
+            Parsed::Entry(candidate) => {
+                offset = candidate.end;
+                candidates.push(candidate);
+            }
+            Parsed::Truncated(name) => {
+                warn!("Entry {} is cut off", name);
+                report.lost.push(name);
+                offset = start + 4;
+            }
+            Parsed::NotAHeader => offset = start + 4,
+        }
+    }
+
+    Ok(candidates)
+}
+
+enum Parsed {
+    Entry(Candidate),
+    /// The header is readable but the entry's data does not fit in the file
+    Truncated(String),
+    NotAHeader,
+}
+
+fn parse_entry(source: &mut File, start: u64, len: u64) -> Result<Parsed> {
+    let mut fixed = [0_u8; 30];
+    source.seek(SeekFrom::Start(start))?;
+    if source.read_exact(&mut fixed).is_err() {
+        return Ok(Parsed::NotAHeader);
+    }
+
+    let u16_at = |at: usize| u16::from_le_bytes([fixed[at], fixed[at + 1]]);
+    let u32_at = |at: usize| u32::from_le_bytes(fixed[at..at + 4].try_into().unwrap());
+
+    let flags = u16_at(6);
+    let name_len = u16_at(26) as usize;
+    let extra_len = u16_at(28) as usize;
+
+    let mut variable = vec![0_u8; name_len + extra_len];
+    if name_len == 0 || source.read_exact(&mut variable).is_err() {
+        return Ok(Parsed::NotAHeader);
+    }
+    let name = String::from_utf8_lossy(&variable[..name_len]).into_owned();
+    let (mut crc, mut compressed, mut uncompressed) = (u32_at(14), u32_at(18) as u64, u32_at(22) as u64);
+
+    // ZIP64 sizes replace the 32-bit fields that are saturated, in this order
+    let extra = &variable[name_len..];
+    if let Some(zip64) = extra_field(extra, ZIP64_EXTRA) {
+        let mut values = zip64.chunks_exact(8).map(|c| u64::from_le_bytes(c.try_into().unwrap()));
+        if uncompressed == u32::MAX as u64 {
+            uncompressed = values.next().unwrap_or(uncompressed);
+        }
+        if compressed == u32::MAX as u64 {
+            compressed = values.next().unwrap_or(compressed);
+        }
+    }
+
+    let data_start = start + 30 + (name_len + extra_len) as u64;
+    let end = if flags & FLAG_DATA_DESCRIPTOR != 0 {
+        match find_descriptor(source, data_start)? {
+            Some(descriptor) => {
+                (crc, compressed, uncompressed) = (descriptor.crc, descriptor.compressed, descriptor.uncompressed);
+                descriptor.end
+            }
+            None => return Ok(Parsed::Truncated(name)),
+        }
+    } else {
+        data_start + compressed
+    };
+
+    if end > len {
+        return Ok(Parsed::Truncated(name));
+    }
+
+    let is_dir = name.ends_with('/');
+    let entry = CentralEntry {
+        name,
+        flags,
+        version_needed: u16_at(4),
+        method: u16_at(8),
+        dos_time: u16_at(10),
+        dos_date: u16_at(12),
+        crc,
+        compressed_size: compressed,
+        uncompressed_size: uncompressed,
+        offset: 0,
+        // Permissions live only in the lost central directory
+        external_attributes: if is_dir { 0x10 } else { 0 },
+        extra: central_extra(extra),
+    };
+
+    Ok(Parsed::Entry(Candidate { start, end, entry }))
+}
+
+/// Extra fields from a local header that readers expect in the central directory too
+pub(super) fn central_extra(local_extra: &[u8]) -> Vec<u8> {
+    let mut central = Vec::new();
+    if let Some(aes) = extra_field(local_extra, AES_EXTRA) {
+        central.extend_from_slice(&AES_EXTRA.to_le_bytes());
+        central.extend_from_slice(&(aes.len() as u16).to_le_bytes());
+        central.extend_from_slice(aes);
+    }
+    central
+}
+
+/// Payload of the extra field with `id`, if present
+pub(super) fn extra_field(extra: &[u8], id: u16) -> Option<&[u8]> {
+    let mut rest = extra;
+    while rest.len() >= 4 {
+        let field = u16::from_le_bytes([rest[0], rest[1]]);
+        let size = u16::from_le_bytes([rest[2], rest[3]]) as usize;
+        let payload = rest.get(4..4 + size)?;
+        if field == id {
+            return Some(payload);
+        }
+        rest = &rest[4 + size..];
+    }
+    None
+}
+
+/// Data descriptor following an entry's data
+struct Descriptor {
+    /// Offset just past the descriptor
+    end: u64,
+    crc: u32,
+    compressed: u64,
+    uncompressed: u64,
+}
+
+/// Find the data descriptor closing an entry whose data starts at `data_start`,
+/// i.e. the first whose compressed size matches the distance travelled
+fn find_descriptor(source: &mut File, data_start: u64) -> Result<Option<Descriptor>> {
+    let mut from = data_start;
+
+    while let Some(at) = find_signature(source, from, DATA_DESCRIPTOR_SIGNATURE)? {
+        let distance = at - data_start;
+        let mut body = [0_u8; 20];
+        source.seek(SeekFrom::Start(at + 4))?;
+        let read = read_up_to(source, &mut body)?;
+
+        let crc = u32::from_le_bytes(body[..4].try_into().unwrap());
+        if read >= 20 && u64::from_le_bytes(body[4..12].try_into().unwrap()) == distance {
+            let uncompressed = u64::from_le_bytes(body[12..20].try_into().unwrap());
+            return Ok(Some(Descriptor { end: at + 24, crc, compressed: distance, uncompressed }));
+        }
+        if read >= 12 && u32::from_le_bytes(body[4..8].try_into().unwrap()) as u64 == distance {
+            let uncompressed = u32::from_le_bytes(body[8..12].try_into().unwrap()) as u64;
+            return Ok(Some(Descriptor { end: at + 16, crc, compressed: distance, uncompressed }));
+        }
+        from = at + 1;
+    }
+
+    Ok(None)
+}
+
+/// Offset of the next little-endian `signature` at or after `from`
+fn find_signature(source: &mut File, from: u64, signature: u32) -> io::Result<Option<u64>> {
+    let needle = signature.to_le_bytes();
+    let mut buffer = vec![0_u8; SCAN_CHUNK + 3];
+    let mut base = from;
+
+    loop {
+        source.seek(SeekFrom::Start(base))?;
+        let read = read_up_to(source, &mut buffer)?;
+        if read < needle.len() {
+            return Ok(None);
+        }
+
+        if let Some(at) = buffer[..read].windows(4).position(|w| w == needle) {
+            return Ok(Some(base + at as u64));
+        }
+        // Overlap by three bytes so a signature across the boundary is not missed
+        base += (read - 3) as u64;
+    }
+}
+
+pub(super) fn read_up_to<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovers_entries_before_a_truncation() {
+        let mut writer = ZipStreamWriter::new(Vec::new(), CompressionMethod::Deflated, 6).unwrap();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            writer.add_file(name, &mut name.repeat(1000).as_bytes(), None, 0o644).unwrap();
+        }
+        let bytes = writer.finish().unwrap();
+
+        // Lose the central directory and the tail of the last entry
+        let cut = bytes.windows(5).position(|w| w == b"c.txt").unwrap() + 40;
+        let mut damaged = tempfile::tempfile().unwrap();
+        damaged.write_all(&bytes[..cut]).unwrap();
+
+        let mut rebuilt = Vec::new();
+        let report = salvage(&mut damaged, &mut rebuilt, None).unwrap();
+        assert_eq!(report.recovered, ["a.txt", "b.txt"]);
+        assert_eq!(report.lost, ["c.txt"]);
+
+        let mut archive = ZipArchive::new(io::Cursor::new(rebuilt)).unwrap();
+        let mut text = String::new();
+        archive.by_name("b.txt").unwrap().read_to_string(&mut text).unwrap();
+        assert_eq!(text, "b.txt".repeat(1000));
+    }
+}
@@ -0,0 +1,397 @@
+use crate::codecs::zip::CompressionMethod;
+use crate::{Result, ZipError};
+use chrono::{DateTime, Datelike, Local, Timelike};
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+use std::io::{self, Read, Write};
+use std::time::SystemTime;
+
+pub(super) const LOCAL_HEADER_SIGNATURE: u32 = 0x04034b50;
+pub(super) const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x08074b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x02014b50;
+const ZIP64_END_SIGNATURE: u32 = 0x06064b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x07064b50;
+const END_SIGNATURE: u32 = 0x06054b50;
+
+/// General purpose flags: sizes follow in a data descriptor, names are UTF-8
+pub(super) const FLAG_DATA_DESCRIPTOR: u16 = 0x0008;
+const FLAG_UTF8: u16 = 0x0800;
+
+const VERSION_DEFAULT: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+/// Upper byte 3 marks the external attributes as unix mode bits
+const VERSION_MADE_BY: u16 = (3 << 8) | VERSION_ZIP64;
+
+const METHOD_STORED: u16 = 0;
+
+/// Sizes and offsets from here on need ZIP64 fields in the central
+/// directory; tests lower it so they need not write 4 GiB
+#[cfg(not(test))]
+const ZIP64_THRESHOLD: u64 = u32::MAX as u64;
+#[cfg(test)]
+const ZIP64_THRESHOLD: u64 = 1 << 20;
+
+/// Permissions for entries read from files and directories
+pub(super) const FILE_MODE: u32 = 0o755;
+/// Permissions for the `-` entry read from stdin, which is never executable
+pub(super) const STDIN_MODE: u32 = 0o644;
+
+/// Central directory record kept for every entry written so far
+#[derive(Clone)]
+pub(super) struct CentralEntry {
+    pub name: String,
+    pub flags: u16,
+    pub version_needed: u16,
+    pub method: u16,
+    pub dos_time: u16,
+    pub dos_date: u16,
+    pub crc: u32,
+    pub compressed_size: u64,
+    pub uncompressed_size: u64,
+    /// Set by the writer
+    pub offset: u64,
+    pub external_attributes: u32,
+    /// Extra fields to repeat in the central directory besides ZIP64
+    pub extra: Vec<u8>,
+}
+
+/// Writer that tracks how many bytes went through it
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Zip writer for non-seekable sinks such as stdout. Entries end in a ZIP64
+/// data descriptor, so inputs of unknown length can exceed 4 GiB.
+pub struct ZipStreamWriter<W: Write> {
+    out: CountingWriter<W>,
+    method: CompressionMethod,
+    level: u8,
+    entries: Vec<CentralEntry>,
+}
+
+impl<W: Write> ZipStreamWriter<W> {
+    /// Create a streaming writer
+    pub fn new(inner: W, method: CompressionMethod, level: u8) -> Result<Self> {
+        if let CompressionMethod::Bzip2 = method {
+            return Err(ZipError::UnsupportedOperation(
+                "bzip2 is not supported when streaming zip output".to_string()
+            ));
+        }
+
+        Ok(Self {
+            out: CountingWriter { inner, count: 0 },
+            method,
+            level,
+            entries: Vec::new(),
+        })
+    }
+
+    /// Add a file entry, compressing `reader` until EOF
+    pub fn add_file<R: Read + ?Sized>(
+        &mut self,
+        name: &str,
+        reader: &mut R,
+        modified: Option<SystemTime>,
+        mode: u32,
+    ) -> Result<()> {
+        let (dos_time, dos_date) = dos_datetime(modified);
+        let method = self.method.header_id();
+        let flags = FLAG_DATA_DESCRIPTOR | FLAG_UTF8;
+        let offset = self.out.count;
+
+        // Sizes are unknown up front: 0xFFFFFFFF plus a zeroed ZIP64 extra field
+        let mut header = Vec::with_capacity(30 + name.len() + 20);
+        put_u32(&mut header, LOCAL_HEADER_SIGNATURE);
+        put_u16(&mut header, VERSION_ZIP64);
+        put_u16(&mut header, flags);
+        put_u16(&mut header, method);
+        put_u16(&mut header, dos_time);
+        put_u16(&mut header, dos_date);
+        put_u32(&mut header, 0);
+        put_u32(&mut header, u32::MAX);
+        put_u32(&mut header, u32::MAX);
+        put_u16(&mut header, name.len() as u16);
+        put_u16(&mut header, 20);
+        header.extend_from_slice(name.as_bytes());
+        put_u16(&mut header, 0x0001);
+        put_u16(&mut header, 16);
+        put_u64(&mut header, 0);
+        put_u64(&mut header, 0);
+        self.out.write_all(&header)?;
+
+        let data_start = self.out.count;
+        let mut crc = Crc::new();
+
+        let uncompressed_size = match self.method {
+            CompressionMethod::Deflated => {
+                let mut encoder = DeflateEncoder::new(&mut self.out, Compression::new(self.level as u32));
+                let copied = pump(reader, &mut encoder, &mut crc)?;
+                encoder.finish()?;
+                copied
+            }
+            CompressionMethod::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(&mut self.out, self.level as i32)?;
+                let copied = pump(reader, &mut encoder, &mut crc)?;
+                encoder.finish()?;
+                copied
+            }
+            CompressionMethod::Bzip2 => unreachable!("rejected in ZipStreamWriter::new"),
+        };
+
+        let compressed_size = self.out.count - data_start;
+
+        let mut descriptor = Vec::with_capacity(24);
+        put_u32(&mut descriptor, DATA_DESCRIPTOR_SIGNATURE);
+        put_u32(&mut descriptor, crc.sum());
+        put_u64(&mut descriptor, compressed_size);
+        put_u64(&mut descriptor, uncompressed_size);
+        self.out.write_all(&descriptor)?;
+
+        self.entries.push(CentralEntry {
+            name: name.to_string(),
+            flags,
+            version_needed: VERSION_ZIP64,
+            method,
+            dos_time,
+            dos_date,
+            crc: crc.sum(),
+            compressed_size,
+            uncompressed_size,
+            offset,
+            external_attributes: (0o100000 | mode) << 16,
+            extra: Vec::new(),
+        });
+
+        Ok(())
+    }
+
+    /// Add a directory entry; `name` should end with `/`
+    pub fn add_directory(&mut self, name: &str, modified: Option<SystemTime>, mode: u32) -> Result<()> {
+        let (dos_time, dos_date) = dos_datetime(modified);
+        let offset = self.out.count;
+
+        let mut header = Vec::with_capacity(30 + name.len());
+        put_u32(&mut header, LOCAL_HEADER_SIGNATURE);
+        put_u16(&mut header, VERSION_DEFAULT);
+        put_u16(&mut header, FLAG_UTF8);
+        put_u16(&mut header, METHOD_STORED);
+        put_u16(&mut header, dos_time);
+        put_u16(&mut header, dos_date);
+        put_u32(&mut header, 0);
+        put_u32(&mut header, 0);
+        put_u32(&mut header, 0);
+        put_u16(&mut header, name.len() as u16);
+        put_u16(&mut header, 0);
+        header.extend_from_slice(name.as_bytes());
+        self.out.write_all(&header)?;
+
+        self.entries.push(CentralEntry {
+            name: name.to_string(),
+            flags: FLAG_UTF8,
+            version_needed: VERSION_DEFAULT,
+            method: METHOD_STORED,
+            dos_time,
+            dos_date,
+            crc: 0,
+            compressed_size: 0,
+            uncompressed_size: 0,
+            offset,
+            // MS-DOS directory bit alongside the unix mode
+            external_attributes: ((0o040000 | mode) << 16) | 0x10,
+            extra: Vec::new(),
+        });
+
+        Ok(())
+    }
+
+    /// Copy an already encoded entry (local header, data and any data
+    /// descriptor) verbatim and record it in the central directory
+    pub(super) fn add_raw<R: Read + ?Sized>(&mut self, raw: &mut R, mut entry: CentralEntry) -> Result<()> {
+        entry.offset = self.out.count;
+        io::copy(raw, &mut self.out)?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Write the central directory and return the underlying sink
+    pub fn finish(mut self) -> Result<W> {
+        let cd_offset = self.out.count;
+
+        for entry in &self.entries {
+            let needs_size64 = entry.uncompressed_size >= ZIP64_THRESHOLD
+                || entry.compressed_size >= ZIP64_THRESHOLD;
+            let needs_offset64 = entry.offset >= ZIP64_THRESHOLD;
+
+            let mut extra = Vec::new();
+            if needs_size64 || needs_offset64 {
+                put_u16(&mut extra, 0x0001);
+                put_u16(&mut extra, if needs_size64 { 16 } else { 0 } + if needs_offset64 { 8 } else { 0 });
+                if needs_size64 {
+                    put_u64(&mut extra, entry.uncompressed_size);
+                    put_u64(&mut extra, entry.compressed_size);
+                }
+                if needs_offset64 {
+                    put_u64(&mut extra, entry.offset);
+                }
+            }
+            extra.extend_from_slice(&entry.extra);
+
+            let mut record = Vec::with_capacity(46 + entry.name.len() + extra.len());
+            put_u32(&mut record, CENTRAL_HEADER_SIGNATURE);
+            put_u16(&mut record, VERSION_MADE_BY);
+            put_u16(&mut record, entry.version_needed);
+            put_u16(&mut record, entry.flags);
+            put_u16(&mut record, entry.method);
+            put_u16(&mut record, entry.dos_time);
+            put_u16(&mut record, entry.dos_date);
+            put_u32(&mut record, entry.crc);
+            put_u32(&mut record, if needs_size64 { u32::MAX } else { entry.compressed_size as u32 });
+            put_u32(&mut record, if needs_size64 { u32::MAX } else { entry.uncompressed_size as u32 });
+            put_u16(&mut record, entry.name.len() as u16);
+            put_u16(&mut record, extra.len() as u16);
+            put_u16(&mut record, 0);
+            put_u16(&mut record, 0);
+            put_u16(&mut record, 0);
+            put_u32(&mut record, entry.external_attributes);
+            put_u32(&mut record, if needs_offset64 { u32::MAX } else { entry.offset as u32 });
+            record.extend_from_slice(entry.name.as_bytes());
+            record.extend_from_slice(&extra);
+            self.out.write_all(&record)?;
+        }
+
+        let cd_size = self.out.count - cd_offset;
+        let count = self.entries.len() as u64;
+        let needs_zip64 = count >= u16::MAX as u64
+            || cd_size >= ZIP64_THRESHOLD
+            || cd_offset >= ZIP64_THRESHOLD;
+
+        let mut tail = Vec::new();
+        if needs_zip64 {
+            let zip64_end_offset = self.out.count;
+
+            put_u32(&mut tail, ZIP64_END_SIGNATURE);
+            put_u64(&mut tail, 44);
+            put_u16(&mut tail, VERSION_MADE_BY);
+            put_u16(&mut tail, VERSION_ZIP64);
+            put_u32(&mut tail, 0);
+            put_u32(&mut tail, 0);
+            put_u64(&mut tail, count);
+            put_u64(&mut tail, count);
+            put_u64(&mut tail, cd_size);
+            put_u64(&mut tail, cd_offset);
+
+            put_u32(&mut tail, ZIP64_LOCATOR_SIGNATURE);
+            put_u32(&mut tail, 0);
+            put_u64(&mut tail, zip64_end_offset);
+            put_u32(&mut tail, 1);
+        }
+
+        put_u32(&mut tail, END_SIGNATURE);
+        put_u16(&mut tail, 0);
+        put_u16(&mut tail, 0);
+        // With a ZIP64 record every field defers to it
+        let count16 = if needs_zip64 { u16::MAX } else { count as u16 };
+        put_u16(&mut tail, count16);
+        put_u16(&mut tail, count16);
+        put_u32(&mut tail, if needs_zip64 { u32::MAX } else { cd_size as u32 });
+        put_u32(&mut tail, if needs_zip64 { u32::MAX } else { cd_offset as u32 });
+        put_u16(&mut tail, 0);
+        self.out.write_all(&tail)?;
+        self.out.flush()?;
+
+        Ok(self.out.inner)
+    }
+}
+
+/// Copy `reader` into `writer` while updating `crc`, returning the bytes copied
+fn pump<R: Read + ?Sized, W: Write>(reader: &mut R, writer: &mut W, crc: &mut Crc) -> io::Result<u64> {
+    let mut buffer = [0_u8; 64 * 1024];
+    let mut total = 0;
+
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(total),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        crc.update(&buffer[..read]);
+        writer.write_all(&buffer[..read])?;
+        total += read as u64;
+    }
+}
+
+/// MS-DOS (time, date) pair for a modification time, defaulting to now
+fn dos_datetime(modified: Option<SystemTime>) -> (u16, u16) {
+    let local: DateTime<Local> = modified.map(DateTime::from).unwrap_or_else(Local::now);
+    let year = local.year().clamp(1980, 2107) as u16;
+
+    let time = ((local.hour() as u16) << 11) | ((local.minute() as u16) << 5) | (local.second() as u16 / 2);
+    let date = ((year - 1980) << 9) | ((local.month() as u16) << 5) | local.day() as u16;
+
+    (time, date)
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn stdin_entries_past_the_zip64_threshold_read_back() {
+        const HUGE: u64 = ZIP64_THRESHOLD + 17;
+
+        let mut writer = ZipStreamWriter::new(Vec::new(), CompressionMethod::Zstd, 1).unwrap();
+        writer.add_file("-", &mut io::repeat(b'z').take(HUGE), None, STDIN_MODE).unwrap();
+        writer.add_file("after", &mut &b"tail"[..], None, FILE_MODE).unwrap();
+        writer.add_directory("dir/", None, FILE_MODE).unwrap();
+        let bytes = writer.finish().unwrap();
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(archive.len(), 3);
+
+        // ZIP64 sizes in the central directory; reading the data back would
+        // only repeat the CRC that `after` checks below
+        let huge = archive.by_index(0).unwrap();
+        assert_eq!((huge.name(), huge.size()), ("-", HUGE));
+        assert!(huge.compressed_size() < HUGE);
+        assert_eq!(huge.unix_mode(), Some(0o100000 | STDIN_MODE));
+        drop(huge);
+
+        // Located through the data descriptor offsets that follow the huge entry
+        let mut after = archive.by_name("after").unwrap();
+        let mut data = String::new();
+        after.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "tail");
+        assert_eq!(after.unix_mode(), Some(0o100000 | FILE_MODE));
+        drop(after);
+
+        assert!(archive.by_name("dir/").unwrap().is_dir());
+    }
+}
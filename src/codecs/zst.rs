@@ -0,0 +1,150 @@
+use crate::codecs::limits::{CountingReader, ExtractLimits, LimitTracker, RatioBase};
+use crate::codecs::safe_path::{PathGuard, PathPolicy};
+use crate::codecs::tarball::{self, TarEntryWriter};
+use crate::codecs::update::{source_entries, DirNaming};
+use crate::codecs::{Codec, EntryVisitor, EntryWriter};
+use crate::utils::{create_output, ensure_directory_exists, is_stdio, open_input, replace_with_staged, staging_file};
+use crate::{Result, ZipError};
+use log::info;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use tar::{Archive, Builder};
+use zstd::stream::read::Decoder;
+use zstd::stream::write::Encoder;
+
+/// Zstandard codec: tarballs, or a single stream from stdin
+pub struct ZstCodec {
+    compression_level: u8,
+    limits: ExtractLimits,
+    path_policy: PathPolicy,
+}
+
+impl Default for ZstCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ZstCodec {
+    /// Create a new zstd codec
+    pub fn new() -> Self {
+        Self {
+            compression_level: 3,
+            limits: ExtractLimits::default(),
+            path_policy: PathPolicy::default(),
+        }
+    }
+
+    fn decoder<'a, R: Read + 'a>(input: R) -> Result<Decoder<'a, BufReader<R>>> {
+        Ok(Decoder::new(input)?)
+    }
+
+    fn encoder<'a, W: Write>(&self, output: W) -> Result<Encoder<'a, W>> {
+        Ok(Encoder::new(output, self.compression_level as i32)?)
+    }
+}
+
+impl Codec for ZstCodec {
+    fn extract(&mut self, source: &[&Path], target: &Path) -> Result<()> {
+        let input = CountingReader::new(open_input(source[0])?);
+        let compressed = input.counter();
+        let tracker = LimitTracker::new(self.limits);
+        let mut decoder = Self::decoder(input)?;
+
+        // Single-stream mode: decompress as-is without unpacking a tarball
+        if is_stdio(target) {
+            let mut stdout = create_output(target)?;
+            tracker.copy("stdin", &mut decoder, &mut stdout, RatioBase::Stream(&compressed))?;
+            stdout.flush()?;
+            return Ok(());
+        }
+
+        ensure_directory_exists(target)?;
+        let guard = PathGuard::new(target, self.path_policy)?;
+        tracker.guard(tarball::unpack_entries(&mut Archive::new(decoder), &guard, &tracker, &compressed))?;
+        guard.finish()
+    }
+
+    fn visit_entries(&mut self, source: &Path, visitor: &mut EntryVisitor) -> Result<()> {
+        let mut archive = Archive::new(Self::decoder(open_input(source)?)?);
+
+        for entry_result in archive.entries()? {
+            let mut entry = entry_result?;
+            visitor(&tarball::entry_meta(&entry)?, &mut entry)?;
+        }
+
+        Ok(())
+    }
+
+    fn add_entries(&mut self, archive: &Path, source: &[&Path], only_changed: bool) -> Result<()> {
+        tarball::reject_stdio(archive)?;
+        let plan = tarball::plan_additions(
+            &mut Archive::new(Self::decoder(File::open(archive)?)?),
+            source_entries(source, DirNaming::UnderDirName)?,
+            only_changed,
+        )?;
+
+        if plan.files.is_empty() {
+            info!("{:?} is up to date", archive);
+            return Ok(());
+        }
+
+        // Like xz, the tar end marker is inside the compressed stream, so the whole tarball is re-encoded
+        let mut builder = Builder::new(self.encoder(BufWriter::new(staging_file(archive)?))?);
+        tarball::rewrite(&mut Archive::new(Self::decoder(File::open(archive)?)?), &mut builder, &plan)?;
+
+        let staging = builder.into_inner()?
+            .finish()?
+            .into_inner()
+            .map_err(|e| ZipError::Io(e.into_error()))?;
+        replace_with_staged(staging, archive)?;
+
+        info!("Added {} and replaced {} entries in {:?}", plan.files.len().saturating_sub(plan.replaced.len()), plan.replaced.len(), archive);
+        Ok(())
+    }
+
+    fn entry_writer(&mut self, target: &Path) -> Result<Box<dyn EntryWriter + '_>> {
+        let encoder = self.encoder(BufWriter::new(tarball::create_target(target)?))?;
+        Ok(Box::new(TarEntryWriter::new(encoder, |encoder| Ok(encoder.finish()?.flush()?))))
+    }
+
+    fn compress(&mut self, source: &[&Path], target: &Path, _exclude: Option<&[&Path]>) -> Result<()> {
+        let mut encoder = self.encoder(BufWriter::new(tarball::create_target(target)?))?;
+
+        // stdin has no name or metadata to put in a tar header, so it becomes a plain .zst stream
+        if source.iter().any(|p| is_stdio(p)) {
+            if source.len() > 1 {
+                return Err(ZipError::UnsupportedOperation(
+                    "stdin cannot be combined with other sources for zst".to_string()
+                ));
+            }
+
+            io::copy(&mut io::stdin().lock(), &mut encoder)?;
+            encoder.finish()?.flush()?;
+            return Ok(());
+        }
+
+        let mut builder = Builder::new(encoder);
+        tarball::append_files(&mut builder, &source_entries(source, DirNaming::UnderDirName)?)?;
+        builder.into_inner()?.finish()?.flush()?;
+
+        Ok(())
+    }
+
+    fn compression_level_range(&self) -> (u8, u8) {
+        (1, 22)
+    }
+
+    fn set_compression_level(&mut self, level: u8) {
+        self.compression_level = level;
+    }
+
+    fn set_limits(&mut self, limits: ExtractLimits) {
+        self.limits = limits;
+    }
+
+    fn set_path_policy(&mut self, policy: PathPolicy) {
+        self.path_policy = policy;
+    }
+}
@@ -0,0 +1,341 @@
+use crate::checksum::Checksum;
+use crate::codecs::{Codec, EntryLink, EntryMeta};
+use crate::Result;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use walkdir::WalkDir;
+
+/// Zip stores times in two-second steps, so closer times count as equal
+const TIME_TOLERANCE: Duration = Duration::from_secs(2);
+
+/// What kind of thing an entry is; links carry their target
+#[derive(Clone, Debug, PartialEq)]
+pub enum Kind {
+    File,
+    Directory,
+    Symlink(String),
+    HardLink(String),
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::File => "file",
+            Self::Directory => "directory",
+            Self::Symlink(_) => "symlink",
+            Self::HardLink(_) => "hard link",
+        }
+    }
+}
+
+/// The parts of an entry a diff looks at
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub kind: Kind,
+    pub size: u64,
+    pub mode: Option<u32>,
+    pub modified: Option<SystemTime>,
+    /// SHA-256 of the contents, for files
+    pub sha256: Option<String>,
+}
+
+/// Every entry of one side of a diff
+#[derive(Default)]
+pub struct Tree {
+    pub entries: BTreeMap<String, Snapshot>,
+}
+
+impl Tree {
+    pub fn of_archive(codec: &mut dyn Codec, archive: &Path) -> Result<Self> {
+        let mut tree = Self::default();
+
+        codec.visit_entries(archive, &mut |meta: &EntryMeta, reader: &mut dyn Read| {
+            let Some(name) = normalize(&meta.name) else { return Ok(()) };
+
+            let kind = match &meta.link {
+                Some(EntryLink::Symbolic(target)) => Kind::Symlink(target.clone()),
+                Some(EntryLink::Hard(target)) => Kind::HardLink(normalize(target).unwrap_or_default()),
+                None if meta.is_dir => Kind::Directory,
+                None => Kind::File,
+            };
+
+            let mut size = 0;
+            let sha256 = if kind == Kind::File {
+                let mut counted = Counted { inner: reader, count: 0 };
+                let digest = Checksum::Sha256.digest(&mut counted)?;
+                size = counted.count;
+                Some(digest)
+            } else {
+                None
+            };
+
+            tree.entries.insert(name, Snapshot {
+                kind,
+                size,
+                mode: meta.mode.map(|mode| mode & 0o7777),
+                modified: meta.modified,
+                sha256,
+            });
+            Ok(())
+        })?;
+
+        Ok(tree)
+    }
+
+    /// Snapshot of a directory tree; symlinks are compared as links, not followed
+    pub fn of_dir(dir: &Path) -> Result<Self> {
+        let mut tree = Self::default();
+
+        for entry in WalkDir::new(dir).min_depth(1) {
+            let entry = entry.map_err(std::io::Error::from)?;
+            let name = entry.path().strip_prefix(dir)?
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            let metadata = entry.metadata().map_err(std::io::Error::from)?;
+
+            let (kind, sha256) = if metadata.is_symlink() {
+                (Kind::Symlink(fs::read_link(entry.path())?.to_string_lossy().into_owned()), None)
+            } else if metadata.is_dir() {
+                (Kind::Directory, None)
+            } else {
+                (Kind::File, Some(Checksum::Sha256.digest(&mut File::open(entry.path())?)?))
+            };
+
+            #[cfg(unix)]
+            let mode = {
+                use std::os::unix::fs::PermissionsExt;
+                Some(metadata.permissions().mode() & 0o7777)
+            };
+            #[cfg(not(unix))]
+            let mode = None;
+
+            tree.entries.insert(name, Snapshot {
+                size: if kind == Kind::File { metadata.len() } else { 0 },
+                kind,
+                mode,
+                modified: metadata.modified().ok(),
+                sha256,
+            });
+        }
+
+        Ok(tree)
+    }
+}
+
+/// What to leave out of a comparison
+#[derive(Clone, Copy, Default)]
+pub struct DiffOptions {
+    pub ignore_mtime: bool,
+    pub ignore_mode: bool,
+}
+
+/// How one path differs between the two sides; each pair is `[old, new]`
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Change {
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<[String; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<[String; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<[u64; 2]>,
+    /// Set when file contents differ
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub content: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<[String; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<[String; 2]>,
+}
+
+/// Result of comparing two trees
+#[derive(Serialize, Debug, Default)]
+pub struct Diff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<Change>,
+    pub unchanged: usize,
+}
+
+impl Diff {
+    pub fn between(old: &Tree, new: &Tree, options: DiffOptions) -> Self {
+        let mut diff = Self::default();
+
+        for (path, before) in &old.entries {
+            match new.entries.get(path) {
+                None => diff.removed.push(path.clone()),
+                Some(after) => match compare(path, before, after, options) {
+                    Some(change) => diff.changed.push(change),
+                    None => diff.unchanged += 1,
+                },
+            }
+        }
+        diff.added = new.entries.keys().filter(|path| !old.entries.contains_key(*path)).cloned().collect();
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// One line per difference followed by a summary, like `diff --brief`
+    pub fn to_text(&self) -> String {
+        let mut lines = Vec::new();
+        lines.extend(self.added.iter().map(|path| format!("+ {}", path)));
+        lines.extend(self.removed.iter().map(|path| format!("- {}", path)));
+
+        for change in &self.changed {
+            let mut parts = Vec::new();
+            if let Some([old, new]) = &change.kind {
+                parts.push(format!("{} -> {}", old, new));
+            }
+            if let Some([old, new]) = &change.target {
+                parts.push(format!("target {} -> {}", old, new));
+            }
+            if let Some([old, new]) = &change.size {
+                parts.push(format!("size {} -> {}", old, new));
+            }
+            if change.content {
+                parts.push("content".to_string());
+            }
+            if let Some([old, new]) = &change.mode {
+                parts.push(format!("mode {} -> {}", old, new));
+            }
+            if let Some([old, new]) = &change.mtime {
+                parts.push(format!("mtime {} -> {}", old, new));
+            }
+            lines.push(format!("M {}: {}", change.path, parts.join(", ")));
+        }
+
+        lines.push(format!(
+            "{} added, {} removed, {} changed, {} unchanged",
+            self.added.len(), self.removed.len(), self.changed.len(), self.unchanged
+        ));
+        lines.join("\n")
+    }
+}
+
+/// How `after` differs from `before`, if it does
+fn compare(path: &str, before: &Snapshot, after: &Snapshot, options: DiffOptions) -> Option<Change> {
+    let mut change = Change {
+        path: path.to_string(),
+        kind: None,
+        target: None,
+        size: None,
+        content: false,
+        mode: None,
+        mtime: None,
+    };
+
+    // Once the kind differs, nothing else is comparable
+    if std::mem::discriminant(&before.kind) != std::mem::discriminant(&after.kind) {
+        change.kind = Some([before.kind.as_str().to_string(), after.kind.as_str().to_string()]);
+        return Some(change);
+    }
+
+    match (&before.kind, &after.kind) {
+        (Kind::Symlink(old), Kind::Symlink(new)) | (Kind::HardLink(old), Kind::HardLink(new)) if old != new => {
+            change.target = Some([old.clone(), new.clone()]);
+        }
+        (Kind::File, Kind::File) => {
+            if before.size != after.size {
+                change.size = Some([before.size, after.size]);
+            }
+            change.content = before.sha256 != after.sha256;
+
+            // Directory times change whenever their contents do, so only files are compared
+            if let (Some(old), Some(new), false) = (before.modified, after.modified, options.ignore_mtime) {
+                let apart = old.duration_since(new).or_else(|_| new.duration_since(old)).unwrap_or_default();
+                if apart >= TIME_TOLERANCE {
+                    change.mtime = Some([format_time(old), format_time(new)]);
+                }
+            }
+        }
+        _ => {}
+    }
+
+    // Link permissions carry no meaning, and a side that does not record modes is not compared
+    let is_link = matches!(before.kind, Kind::Symlink(_) | Kind::HardLink(_));
+    if let (Some(old), Some(new), false, false) = (before.mode, after.mode, is_link, options.ignore_mode)
+        && old != new
+    {
+        change.mode = Some([format!("{:04o}", old), format!("{:04o}", new)]);
+    }
+
+    let changed = change.target.is_some() || change.size.is_some() || change.content
+        || change.mode.is_some() || change.mtime.is_some();
+    changed.then_some(change)
+}
+
+fn format_time(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Entry name as a relative path without `./` or a trailing `/`; `None` for the root itself
+fn normalize(name: &str) -> Option<String> {
+    let name = name.trim_start_matches("./").trim_end_matches('/');
+    (!name.is_empty() && name != ".").then(|| name.to_string())
+}
+
+/// Counts the bytes read through it
+struct Counted<'a> {
+    inner: &'a mut dyn Read,
+    count: u64,
+}
+
+impl Read for Counted<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(content: &str, mode: u32, secs: u64) -> Snapshot {
+        Snapshot {
+            kind: Kind::File,
+            size: content.len() as u64,
+            mode: Some(mode),
+            modified: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)),
+            sha256: Some(Checksum::Sha256.digest(&mut content.as_bytes()).unwrap()),
+        }
+    }
+
+    #[test]
+    fn reports_each_kind_of_difference() {
+        let mut old = Tree::default();
+        old.entries.insert("same".into(), file("a", 0o644, 100));
+        old.entries.insert("touched".into(), file("a", 0o644, 100));
+        old.entries.insert("edited".into(), file("a", 0o644, 100));
+        old.entries.insert("gone".into(), file("a", 0o644, 100));
+
+        let mut new = Tree::default();
+        new.entries.insert("same".into(), file("a", 0o644, 101));
+        new.entries.insert("touched".into(), file("a", 0o755, 200));
+        new.entries.insert("edited".into(), file("bb", 0o644, 100));
+        new.entries.insert("fresh".into(), Snapshot { kind: Kind::Directory, size: 0, mode: None, modified: None, sha256: None });
+
+        let diff = Diff::between(&old, &new, DiffOptions::default());
+        assert_eq!((diff.added.as_slice(), diff.removed.as_slice(), diff.unchanged), (&["fresh".to_string()][..], &["gone".to_string()][..], 1));
+        assert_eq!(diff.changed[0].size, Some([1, 2]));
+        assert!(diff.changed[0].content);
+        assert_eq!(diff.changed[1].mode, Some(["0644".to_string(), "0755".to_string()]));
+        assert!(diff.changed[1].mtime.is_some() && !diff.changed[1].content);
+
+        let lenient = Diff::between(&old, &new, DiffOptions { ignore_mtime: true, ignore_mode: true });
+        assert_eq!((lenient.changed.len(), lenient.unchanged), (1, 2));
+        assert_eq!(normalize("./dir/"), Some("dir".to_string()));
+    }
+}
@@ -0,0 +1,306 @@
+use crate::utils::{create_output, from_hex, is_stdio, to_hex};
+use crate::{Result, ZipError};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+const MAGIC: &[u8; 8] = b"CAZIPENV";
+const VERSION: u8 = 1;
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const WRAPPED_KEY_SIZE: usize = 32 + TAG_SIZE;
+
+const PUBLIC_PREFIX: &str = "x25519:";
+const SECRET_PREFIX: &str = "x25519-secret:";
+
+/// A recipient's public key, written as `x25519:<hex>`
+#[derive(Clone)]
+pub struct Recipient(PublicKey);
+
+impl FromStr for Recipient {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let hex = s.trim().strip_prefix(PUBLIC_PREFIX)
+            .ok_or_else(|| format!("recipient must start with {}", PUBLIC_PREFIX))?;
+        Ok(Self(PublicKey::from(decode_key(hex)?)))
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", PUBLIC_PREFIX, to_hex(self.0.as_bytes()))
+    }
+}
+
+/// A private key able to open envelopes addressed to its public key
+pub struct Identity(StaticSecret);
+
+impl Identity {
+    /// Create a new random identity
+    pub fn generate() -> Self {
+        Self(StaticSecret::random_from_rng(OsRng))
+    }
+
+    /// Public key to hand out to senders
+    pub fn recipient(&self) -> Recipient {
+        Recipient(PublicKey::from(&self.0))
+    }
+
+    /// Load every identity in a key file; `#` lines are comments
+    pub fn load(path: &Path) -> Result<Vec<Self>> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| ZipError::Other(format!("Cannot read identity file {:?}: {}", path, e)))?;
+
+        let identities = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let hex = line.strip_prefix(SECRET_PREFIX).ok_or_else(|| {
+                    ZipError::Other(format!("{:?} is not an identity file", path))
+                })?;
+                Ok(Self(StaticSecret::from(decode_key(hex).map_err(ZipError::Other)?)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if identities.is_empty() {
+            return Err(ZipError::Other(format!("No identity found in {:?}", path)));
+        }
+        Ok(identities)
+    }
+
+    /// Write the identity to a new key file readable only by its owner
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let mut file = options.open(path)?;
+        writeln!(file, "# public key: {}", self.recipient())?;
+        writeln!(file, "{}{}", SECRET_PREFIX, to_hex(&self.0.to_bytes()))?;
+        Ok(())
+    }
+}
+
+/// Whether the file at `path` starts with the envelope header
+pub fn is_envelope(path: &Path) -> bool {
+    let mut magic = [0_u8; 8];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .is_ok_and(|_| &magic == MAGIC)
+}
+
+/// Encrypt the file at `source` to `recipients`, writing the envelope to `target` (`-` for stdout)
+/// in ChaCha20-Poly1305 chunks under a random file key wrapped for each recipient via X25519
+pub fn seal(source: &Path, target: &Path, recipients: &[Recipient]) -> Result<()> {
+    if recipients.is_empty() {
+        return Err(ZipError::Other("No recipients given".to_string()));
+    }
+
+    let mut file_key = [0_u8; 32];
+    OsRng.fill_bytes(&mut file_key);
+    let mut salt = [0_u8; 16];
+    OsRng.fill_bytes(&mut salt);
+
+    let mut out = create_output(target)?;
+    out.write_all(MAGIC)?;
+    out.write_all(&[VERSION])?;
+    out.write_all(&(recipients.len() as u16).to_le_bytes())?;
+
+    for recipient in recipients {
+        let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral_public = PublicKey::from(&ephemeral);
+        let shared = ephemeral.diffie_hellman(&recipient.0);
+
+        let wrap = wrap_cipher(shared.as_bytes(), &ephemeral_public, &recipient.0);
+        let wrapped = wrap.encrypt(&Nonce::default(), file_key.as_slice())
+            .map_err(|_| ZipError::Other("Failed to wrap file key".to_string()))?;
+
+        out.write_all(ephemeral_public.as_bytes())?;
+        out.write_all(&wrapped)?;
+    }
+
+    out.write_all(&salt)?;
+
+    let cipher = payload_cipher(&file_key, &salt);
+    let mut input = File::open(source)?;
+    let mut chunk = vec![0_u8; CHUNK_SIZE];
+    let mut filled = read_full(&mut input, &mut chunk)?;
+    let mut counter = 0_u64;
+
+    loop {
+        // Read ahead so the final chunk can be flagged
+        let mut next = vec![0_u8; CHUNK_SIZE];
+        let next_filled = if filled == CHUNK_SIZE { read_full(&mut input, &mut next)? } else { 0 };
+        let last = next_filled == 0;
+
+        let sealed = cipher.encrypt(&chunk_nonce(counter, last), &chunk[..filled])
+            .map_err(|_| ZipError::Other("Failed to encrypt payload".to_string()))?;
+        out.write_all(&sealed)?;
+
+        if last {
+            break;
+        }
+        chunk = next;
+        filled = next_filled;
+        counter += 1;
+    }
+
+    out.flush()?;
+    Ok(())
+}
+
+/// Decrypt the envelope at `source` (`-` for stdin) with any matching identity into `output`
+pub fn open<W: Write>(source: &Path, identities: &[Identity], output: &mut W) -> Result<()> {
+    let mut input: Box<dyn Read> = if is_stdio(source) {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(File::open(source)?)
+    };
+
+    let mut header = [0_u8; 11];
+    input.read_exact(&mut header)?;
+    if &header[..8] != MAGIC {
+        return Err(ZipError::Other(format!("{:?} is not an encrypted envelope", source)));
+    }
+    if header[8] != VERSION {
+        return Err(ZipError::UnsupportedOperation(format!("Envelope version {}", header[8])));
+    }
+
+    let count = u16::from_le_bytes([header[9], header[10]]);
+    let mut file_key = None;
+
+    for _ in 0..count {
+        let mut stanza = [0_u8; 32 + WRAPPED_KEY_SIZE];
+        input.read_exact(&mut stanza)?;
+
+        if file_key.is_some() {
+            continue;
+        }
+
+        let ephemeral_public = PublicKey::from(<[u8; 32]>::try_from(&stanza[..32]).unwrap());
+        file_key = identities.iter().find_map(|identity| {
+            let shared = identity.0.diffie_hellman(&ephemeral_public);
+            let wrap = wrap_cipher(shared.as_bytes(), &ephemeral_public, &identity.recipient().0);
+            wrap.decrypt(&Nonce::default(), &stanza[32..]).ok()
+        });
+    }
+
+    let file_key = file_key.ok_or_else(|| {
+        ZipError::Other("None of the given identities can open this envelope".to_string())
+    })?;
+
+    let mut salt = [0_u8; 16];
+    input.read_exact(&mut salt)?;
+
+    let cipher = payload_cipher(&file_key, &salt);
+    let mut chunk = vec![0_u8; CHUNK_SIZE + TAG_SIZE];
+    let mut filled = read_full(&mut input, &mut chunk)?;
+    let mut counter = 0_u64;
+
+    loop {
+        let mut next = vec![0_u8; CHUNK_SIZE + TAG_SIZE];
+        let next_filled = if filled == chunk.len() { read_full(&mut input, &mut next)? } else { 0 };
+        let last = next_filled == 0;
+
+        let plain = cipher.decrypt(&chunk_nonce(counter, last), &chunk[..filled])
+            .map_err(|_| ZipError::Other("Envelope is corrupted or truncated".to_string()))?;
+        output.write_all(&plain)?;
+
+        if last {
+            break;
+        }
+        chunk = next;
+        filled = next_filled;
+        counter += 1;
+    }
+
+    output.flush()?;
+    Ok(())
+}
+
+fn wrap_cipher(shared: &[u8], ephemeral: &PublicKey, recipient: &PublicKey) -> ChaCha20Poly1305 {
+    let mut salt = [0_u8; 64];
+    salt[..32].copy_from_slice(ephemeral.as_bytes());
+    salt[32..].copy_from_slice(recipient.as_bytes());
+    derive_cipher(&salt, shared, b"ca-zip envelope x25519")
+}
+
+fn payload_cipher(file_key: &[u8], salt: &[u8]) -> ChaCha20Poly1305 {
+    derive_cipher(salt, file_key, b"ca-zip envelope payload")
+}
+
+fn derive_cipher(salt: &[u8], ikm: &[u8], info: &[u8]) -> ChaCha20Poly1305 {
+    let mut key = [0_u8; 32];
+    Hkdf::<Sha256>::new(Some(salt), ikm)
+        .expand(info, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+fn chunk_nonce(counter: u64, last: bool) -> Nonce {
+    let mut nonce = [0_u8; 12];
+    nonce[3..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+    Nonce::from(nonce)
+}
+
+/// Fill `buf` as far as the reader allows, returning the number of bytes read
+fn read_full<R: Read + ?Sized>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+fn decode_key(hex: &str) -> std::result::Result<[u8; 32], String> {
+    from_hex(hex).ok_or_else(|| "key must be 64 hex digits".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn seal_and_open_round_trip() {
+        let identity = Identity::generate();
+        let other = Identity::generate();
+        let recipients = [other.recipient(), identity.recipient()];
+
+        // Spans several chunks and ends exactly on a chunk boundary
+        let payload: Vec<u8> = (0..CHUNK_SIZE * 3).map(|i| (i % 251) as u8).collect();
+        let mut plain = NamedTempFile::new().unwrap();
+        plain.write_all(&payload).unwrap();
+        let sealed = NamedTempFile::new().unwrap();
+
+        seal(plain.path(), sealed.path(), &recipients).unwrap();
+        assert!(is_envelope(sealed.path()));
+
+        let mut opened = Vec::new();
+        open(sealed.path(), &[identity], &mut opened).unwrap();
+        assert_eq!(opened, payload);
+
+        assert!(open(sealed.path(), &[Identity::generate()], &mut Vec::new()).is_err());
+    }
+}
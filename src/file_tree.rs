@@ -1,10 +1,12 @@
-use std::io::{Error, ErrorKind};
+use std::fs::File;
+use std::io::Error;
 use std::path::Path;
 use std::process::Command;
 use chrono::{DateTime, NaiveDateTime, Utc};
 use log::info;
 use serde::{Deserialize, Serialize};
-use regex::Regex;
+use zip::ZipArchive;
+use crate::codecs::zip::ZipEncryption;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct FileEntry {
@@ -15,6 +17,8 @@ pub struct FileEntry {
     modified_time: Option<String>,
     is_directory: bool,
     permissions: Option<String>,
+    /// Encryption scheme of the entry, where the format reports one
+    encryption: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -25,57 +29,45 @@ pub struct ArchiveContents {
     files: Vec<FileEntry>,
 }
 
-// 列出ZIP文件内容并转为JSON
+// 列出ZIP文件内容并转为JSON（原生读取，可报告每个条目的加密方式）
 fn list_zip_contents_json(archive_path: &Path, debug: bool) -> Result<String, Error> {
-    let output = Command::new("unzip")
-        .arg("-l")
-        .arg(archive_path)
-        .output()?;
+    let to_io = |e: crate::ZipError| Error::other(format!("Failed to list ZIP contents: {}", e));
 
-    if debug {
-        println!("Command executed: unzip -l {}", archive_path.display());
-    }
+    let mut archive = ZipArchive::new(File::open(archive_path)?)
+        .map_err(|e| to_io(e.into()))?;
 
-    if !output.status.success() {
-        let error_msg = String::from_utf8_lossy(&output.stderr);
-        return Err(Error::new(ErrorKind::Other, format!("Failed to list ZIP contents: {}", error_msg)));
+    if debug {
+        println!("Listing {} entries of {}", archive.len(), archive_path.display());
     }
 
-    let content = String::from_utf8_lossy(&output.stdout);
-
     let mut files = Vec::new();
     let mut total_size: u64 = 0;
 
-    // Length   Date    Time    Name
-    // ------   ----    ----    ----
-    // 123456  01-02-03 12:34   path/to/file.txt
-    let lines: Vec<&str> = content.lines().collect();
-    let data_lines = if lines.len() > 4 {
-        &lines[3..lines.len()-2]
-    } else {
-        &[]
-    };
-
-    for line in data_lines {
-        let line = line.split_whitespace().map(|e| e.trim()).collect::<Vec<&str>>();
-
-        let size = line[0].parse::<u64>().unwrap_or(0);
-        let path = Path::new(line[3]);
-        let name = path.file_name().unwrap().to_string_lossy().into_owned();
-        let date = line[1];
-        let time = line[2];
+    for i in 0..archive.len() {
+        let encryption = ZipEncryption::of_entry(&mut archive, i).map_err(to_io)?;
+        let file = archive.by_index_raw(i).map_err(|e| to_io(e.into()))?;
+
+        let path = file.name().to_string();
+        let name = Path::new(&path)
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.clone());
+        let modified_time = file.last_modified().map(|t| {
+            format!("{:04}-{:02}-{:02} {:02}:{:02}", t.year(), t.month(), t.day(), t.hour(), t.minute())
+        });
 
         files.push(FileEntry {
             name,
-            path: line[3].to_string(),
-            modified_time: Some(format!("{} {}", date, time)),
-            size,
-            compressed_size: None,
-            is_directory: path.is_dir(),
-            permissions: None,
+            path,
+            size: file.size(),
+            compressed_size: Some(file.compressed_size()),
+            modified_time,
+            is_directory: file.is_dir(),
+            permissions: file.unix_mode().map(|mode| format!("{:o}", mode & 0o7777)),
+            encryption: encryption.map(|e| e.as_str().to_string()),
         });
 
-        total_size += size;
+        total_size += file.size();
     }
 
     let archive_contents = ArchiveContents {
@@ -101,7 +93,7 @@ fn list_7z_contents_json(archive_path: &Path, debug: bool) -> Result<String, Err
 
     if !output.status.success() {
         let error_msg = String::from_utf8_lossy(&output.stderr);
-        return Err(Error::new(ErrorKind::Other, format!("Failed to list 7Z contents: {}", error_msg)));
+        return Err(Error::other(format!("Failed to list 7Z contents: {}", error_msg)));
     }
 
     let content = String::from_utf8_lossy(&output.stdout);
@@ -137,6 +129,7 @@ fn list_7z_contents_json(archive_path: &Path, debug: bool) -> Result<String, Err
                             modified_time: None,
                             is_directory: false,
                             permissions: None,
+                            encryption: None,
                         });
                     } else if let Some(entry) = &mut current_entry {
                         let path = Path::new(value);
@@ -155,10 +148,10 @@ fn list_7z_contents_json(archive_path: &Path, debug: bool) -> Result<String, Err
                     }
                 },
                 "Modified" => {
-                    if let Some(entry) = &mut current_entry {
-                        if let Ok(dt) = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S") {
-                            entry.modified_time = Some(DateTime::<Utc>::from_naive_utc_and_offset(dt, Utc).to_rfc3339());
-                        }
+                    if let Some(entry) = &mut current_entry
+                        && let Ok(dt) = NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S")
+                    {
+                        entry.modified_time = Some(DateTime::<Utc>::from_naive_utc_and_offset(dt, Utc).to_rfc3339());
                     }
                 },
                 "Folder" => {
@@ -171,6 +164,11 @@ fn list_7z_contents_json(archive_path: &Path, debug: bool) -> Result<String, Err
                         entry.permissions = Some(value.to_string());
                     }
                 },
+                "Method" if value.contains("7zAES") => {
+                    if let Some(entry) = &mut current_entry {
+                        entry.encryption = Some("aes256".to_string());
+                    }
+                },
                 _ => {}
             }
         }
@@ -206,7 +204,7 @@ fn list_tar_contents_json(archive_path: &Path, format: &str, debug: bool) -> Res
 
     if !output.status.success() {
         let error_msg = String::from_utf8_lossy(&output.stderr);
-        return Err(Error::new(ErrorKind::Other, format!("Failed to list {} contents: {}", format, error_msg)));
+        return Err(Error::other(format!("Failed to list {} contents: {}", format, error_msg)));
     }
 
     let content = String::from_utf8_lossy(&output.stdout);
@@ -246,6 +244,7 @@ fn list_tar_contents_json(archive_path: &Path, format: &str, debug: bool) -> Res
                 modified_time: datetime,
                 is_directory,
                 permissions: Some(permissions.to_string()),
+                encryption: None,
             });
 
             total_size += size;
@@ -269,7 +268,9 @@ pub fn list_archive_contents_json(archive_path: &Path, format: &str, debug: bool
         "7z" => list_7z_contents_json(archive_path, debug),
         "gz" | "tar.gz" | "tgz" => list_tar_contents_json(archive_path, "gz", debug),
         "xz" | "tar.xz" => list_tar_contents_json(archive_path, "xz", debug),
-        _ => Err(Error::new(ErrorKind::Other, format!("Unsupported archive format: {}", format))),
+        "tar" => list_tar_contents_json(archive_path, "tar", debug),
+        "zst" | "tar.zst" => list_tar_contents_json(archive_path, "zst", debug),
+        _ => Err(Error::other(format!("Unsupported archive format: {}", format))),
     }
 }
 
@@ -0,0 +1,237 @@
+use crate::codecs::limits::{ExtractLimits, LimitTracker, RatioBase};
+use crate::codecs::{CodecFactory, EntryMeta, Format};
+use crate::{Result, ZipError};
+use log::warn;
+use regex::Regex;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Archives inside archives are not followed deeper than this
+const MAX_NESTING: usize = 8;
+
+/// Enough of an entry to recognise an archive, tar's magic being at offset 257
+const MAGIC_LEN: u64 = 262;
+
+/// What to look for and where
+pub struct GrepOptions {
+    pub pattern: regex::bytes::Regex,
+    /// Only entries matching one of these are searched; all of them when empty
+    pub entries: Vec<Regex>,
+    /// Search inside entries that are archives themselves
+    pub nested: bool,
+    /// Stop at the first match in an entry, as only its name is wanted
+    pub names_only: bool,
+    pub password: Option<String>,
+    /// Limits on what nested archives may spool to disk
+    pub limits: ExtractLimits,
+}
+
+/// Matches within one entry
+pub struct Found {
+    pub path: String,
+    /// Line number and text of every matching line
+    pub lines: Vec<(usize, String)>,
+    /// The entry looks binary, so its lines are not shown
+    pub binary: bool,
+}
+
+/// Search every entry of `archive`, handing each entry's matches to `report` as soon
+/// as it is done, in no particular order. Returns how many entries matched.
+pub fn search(format: Format, archive: &Path, options: &GrepOptions, report: &(dyn Fn(Found) + Sync)) -> Result<usize> {
+    let search = Search {
+        options,
+        report,
+        matched: AtomicUsize::new(0),
+        spooled: LimitTracker::new(options.limits),
+    };
+    search.archive(format, archive, &archive.to_string_lossy(), 0)?;
+    Ok(search.matched.into_inner())
+}
+
+/// State shared by every level of one search
+struct Search<'a> {
+    options: &'a GrepOptions,
+    report: &'a (dyn Fn(Found) + Sync),
+    matched: AtomicUsize,
+    /// What nested archives have spooled so far, across all levels
+    spooled: LimitTracker,
+}
+
+impl Search<'_> {
+    /// Search the entries of one archive, reporting them under `label`
+    fn archive(&self, format: Format, archive: &Path, label: &str, depth: usize) -> Result<()> {
+        let options = self.options;
+        let mut codec = CodecFactory::new(format, None, options.password.clone(), None, false, None).create_codec()?;
+
+        codec.visit_entries_parallel(archive, &|meta: &EntryMeta, data: &mut dyn Read| {
+            let path = format!("{}!{}", label, meta.name);
+            if meta.is_dir || meta.link.is_some() {
+                return Ok(());
+            }
+
+            let mut head = Vec::new();
+            data.take(MAGIC_LEN).read_to_end(&mut head)?;
+            let mut data = head.as_slice().chain(data);
+
+            let Some(inner) = Format::from_magic(&head).filter(|_| options.nested && depth < MAX_NESTING) else {
+                return self.entry(meta, path, &mut data);
+            };
+
+            // Codecs read from paths, so the entry is spooled under its own name
+            let dir = tempfile::tempdir()?;
+            let spooled = dir.path().join(Path::new(&meta.name).file_name().unwrap_or("entry".as_ref()));
+            self.spooled.begin_entry(&meta.name)?;
+            self.spooled.copy(&path, &mut data, &mut File::create(&spooled)?, RatioBase::Unknown)?;
+
+            match self.archive(inner, &spooled, &path, depth + 1) {
+                Err(e @ ZipError::LimitExceeded(_)) => Err(e),
+                Err(e) => {
+                    warn!("Searching {} as plain data, it cannot be read as {:?}: {}", path, inner, e);
+                    self.entry(meta, path, &mut File::open(&spooled)?)
+                }
+                Ok(()) => Ok(()),
+            }
+        })
+    }
+
+    /// Search an entry that is not followed as an archive
+    fn entry(&self, meta: &EntryMeta, path: String, data: &mut dyn Read) -> Result<()> {
+        if !wanted(&meta.name, &self.options.entries) {
+            // Entries of a solid block share one stream, so skipped data still has to be consumed
+            io::copy(data, &mut io::sink())?;
+            return Ok(());
+        }
+
+        let found = search_entry(path, data, self.options)?;
+        if found.binary || !found.lines.is_empty() {
+            self.matched.fetch_add(1, Ordering::Relaxed);
+            (self.report)(found);
+        }
+        Ok(())
+    }
+}
+
+/// Search one entry's contents line by line
+fn search_entry(path: String, data: &mut dyn Read, options: &GrepOptions) -> Result<Found> {
+    let mut reader = BufReader::new(data);
+    // Like grep, a NUL byte near the start marks a binary file
+    let binary = reader.fill_buf()?.contains(&0);
+    let mut found = Found { path, lines: Vec::new(), binary: false };
+
+    let mut line = Vec::new();
+    let mut number = 0;
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        number += 1;
+        if options.pattern.is_match(&line) {
+            if binary {
+                found.binary = true;
+                break;
+            }
+            let text = String::from_utf8_lossy(&line);
+            found.lines.push((number, text.trim_end_matches(['\n', '\r']).to_string()));
+            if options.names_only {
+                break;
+            }
+        }
+        line.clear();
+    }
+
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(found)
+}
+
+/// Whether an entry name passes the entry globs
+fn wanted(name: &str, globs: &[Regex]) -> bool {
+    let name = name.trim_start_matches("./");
+    let file_name = name.rsplit('/').next().unwrap_or(name);
+    globs.is_empty() || globs.iter().any(|glob| glob.is_match(name) || glob.is_match(file_name))
+}
+
+/// Compile a shell glob over entry names: `*` and `?` stay within one path
+/// component, `**` crosses them. A glob without `/` also matches file names alone.
+pub fn glob_to_regex(glob: &str) -> std::result::Result<Regex, regex::Error> {
+    let mut pattern = String::from("^");
+    let mut chars = glob.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                pattern.push_str(".*");
+            }
+            '*' => pattern.push_str("[^/]*"),
+            '?' => pattern.push_str("[^/]"),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    pattern.push('$');
+    Regex::new(&pattern)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn globs_match_whole_names_or_file_names() {
+        let logs = [glob_to_regex("*.log").unwrap()];
+        assert!(wanted("var/log/app.log", &logs));
+        assert!(!wanted("var/log/app.log.1", &logs));
+
+        let nested = [glob_to_regex("var/**/a?p.log").unwrap()];
+        assert!(wanted("./var/log/old/app.log", &nested));
+        assert!(!wanted("srv/log/app.log", &nested));
+        assert!(wanted("anything", &[]));
+    }
+
+    fn zip_of(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        use std::io::Write;
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        for (name, data) in entries {
+            zip.start_file(*name, zip::write::SimpleFileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn options(limits: ExtractLimits) -> GrepOptions {
+        GrepOptions {
+            pattern: regex::bytes::Regex::new("needle").unwrap(),
+            entries: Vec::new(),
+            nested: true,
+            names_only: false,
+            password: None,
+            limits,
+        }
+    }
+
+    fn grep(archive: &Path, options: &GrepOptions) -> Result<Vec<String>> {
+        let found = std::sync::Mutex::new(Vec::new());
+        search(Format::Zip, archive, options, &|f: Found| found.lock().unwrap().push(f.path))?;
+        let mut found = found.into_inner().unwrap();
+        found.sort();
+        Ok(found)
+    }
+
+    #[test]
+    fn nested_archives_are_limited_and_fall_back_to_plain_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let inner = zip_of(&[("deep.txt", b"a needle here")]);
+        let mut broken = b"PK\x03\x04 not really a zip, but a needle\n".to_vec();
+        broken.resize(MAGIC_LEN as usize + 10, b' ');
+
+        let archive = dir.path().join("outer.zip");
+        fs::write(&archive, zip_of(&[("inner.zip", &inner), ("broken.zip", &broken)])).unwrap();
+        let label = archive.to_string_lossy();
+
+        let found = grep(&archive, &options(ExtractLimits::default())).unwrap();
+        assert_eq!(found, [format!("{}!broken.zip", label), format!("{}!inner.zip!deep.txt", label)]);
+
+        let limited = ExtractLimits { max_total_size: Some(64), ..ExtractLimits::default() };
+        assert!(matches!(grep(&archive, &options(limited)), Err(ZipError::LimitExceeded(_))));
+    }
+}
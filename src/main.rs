@@ -2,15 +2,27 @@ use clap::Parser;
 use env_logger::Env;
 use log::error;
 use std::process;
-use std::path::{Path, PathBuf};
 use thiserror::Error;
 
 pub mod codecs;
 pub mod file_tree;
 pub mod utils;
+mod backup;
+mod batch;
+mod checksum;
 mod cli;
+mod diff;
+mod envelope;
+mod grep;
+mod parity;
+mod password;
+mod recursive;
+mod rotate;
 mod script;
+mod signature;
+mod store;
 mod venv;
+mod watch;
 
 /// Result type for zip operations
 pub type Result<T> = std::result::Result<T, ZipError>;
@@ -32,6 +44,10 @@ pub enum ZipError {
     UnknownFormat,
     #[error("Unsupported operation: {0}")]
     UnsupportedOperation(String),
+    #[error("Extraction limit exceeded: {0}")]
+    LimitExceeded(String),
+    #[error("Unsafe entry path: {0}")]
+    UnsafePath(String),
     #[error("External command error: {0}")]
     ExternalCommand(String),
     #[error("Other error: {0}")]
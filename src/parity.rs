@@ -0,0 +1,448 @@
+use crate::{Result, ZipError};
+use log::{info, warn};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+const MAGIC: &[u8; 8] = b"CAZIPPAR";
+const VERSION: u8 = 1;
+const HASH_SIZE: usize = 32;
+/// GF(2^8) allows at most this many data and parity blocks per stripe
+const MAX_SHARDS: usize = 256;
+
+/// How much recovery data to generate
+#[derive(Clone, Copy, Debug)]
+pub struct ParityOptions {
+    /// Parity as a percentage of the data size
+    pub redundancy: u8,
+    pub block_size: usize,
+}
+
+impl Default for ParityOptions {
+    fn default() -> Self {
+        Self { redundancy: 10, block_size: 64 * 1024 }
+    }
+}
+
+/// Outcome of checking or repairing a protected set
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    pub damaged_blocks: usize,
+    pub repaired_blocks: usize,
+    pub damaged_parity: usize,
+    pub unrecoverable_stripes: usize,
+    /// Files whose length no longer matches and were (or would be) truncated or extended
+    pub resized_files: Vec<PathBuf>,
+}
+
+impl RepairReport {
+    pub fn is_clean(&self) -> bool {
+        self.damaged_blocks == 0 && self.resized_files.is_empty()
+    }
+}
+
+/// Block geometry shared by creation and repair; block `j` goes to stripe `j % stripes`
+/// so a run of damaged blocks is spread over many stripes
+#[derive(Clone, Copy)]
+struct Layout {
+    block_size: usize,
+    total_size: u64,
+    data_per_stripe: usize,
+    parity_per_stripe: usize,
+}
+
+impl Layout {
+    fn new(total_size: u64, options: ParityOptions) -> Result<Self> {
+        if options.block_size == 0 || !(1..=100).contains(&options.redundancy) {
+            return Err(ZipError::Other("Redundancy must be 1-100% and the block size non-zero".to_string()));
+        }
+
+        let blocks = total_size.div_ceil(options.block_size as u64).max(1);
+        let ratio = options.redundancy as usize;
+        // Largest stripe whose parity still fits in the shard limit
+        let widest = MAX_SHARDS * 100 / (100 + ratio);
+        let data_per_stripe = (blocks as usize).min(widest).max(1);
+        let parity_per_stripe = (data_per_stripe * ratio).div_ceil(100).max(1);
+
+        Ok(Self {
+            block_size: options.block_size,
+            total_size,
+            data_per_stripe,
+            parity_per_stripe: parity_per_stripe.min(MAX_SHARDS - data_per_stripe),
+        })
+    }
+
+    fn data_blocks(&self) -> usize {
+        (self.total_size.div_ceil(self.block_size as u64) as usize).max(1)
+    }
+
+    fn stripes(&self) -> usize {
+        self.data_blocks().div_ceil(self.data_per_stripe)
+    }
+
+    /// Data block at `position` of `stripe`, if the stream reaches that far
+    fn block_index(&self, stripe: usize, position: usize) -> Option<usize> {
+        let index = position * self.stripes() + stripe;
+        (index < self.data_blocks()).then_some(index)
+    }
+
+    fn codec(&self) -> Result<ReedSolomon> {
+        ReedSolomon::new(self.data_per_stripe, self.parity_per_stripe)
+            .map_err(|e| ZipError::Other(format!("Reed-Solomon setup failed: {:?}", e)))
+    }
+}
+
+/// The protected files, read and written as one stream
+struct FileSet {
+    files: Vec<(PathBuf, u64)>,
+}
+
+impl FileSet {
+    fn total_size(&self) -> u64 {
+        self.files.iter().map(|(_, size)| size).sum()
+    }
+
+    /// Call `each` with every file overlapping `[offset, offset + len)` and the
+    /// file offset and buffer range that overlap
+    fn spans(&self, offset: u64, len: usize, mut each: impl FnMut(&Path, u64, std::ops::Range<usize>) -> io::Result<()>) -> io::Result<()> {
+        let mut start = 0_u64;
+        for (path, size) in &self.files {
+            let end = start + size;
+            let from = offset.max(start);
+            let to = (offset + len as u64).min(end);
+            if from < to {
+                each(path, from - start, (from - offset) as usize..(to - offset) as usize)?;
+            }
+            start = end;
+        }
+        Ok(())
+    }
+
+    /// Read a block, leaving zeros wherever the files are missing or short
+    fn read_block(&self, index: usize, buf: &mut [u8]) {
+        buf.fill(0);
+        let _ = self.spans(index as u64 * buf.len() as u64, buf.len(), |path, at, range| {
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::Start(at))?;
+            let target = &mut buf[range];
+            let mut filled = 0;
+            while filled < target.len() {
+                match file.read(&mut target[filled..])? {
+                    0 => break,
+                    n => filled += n,
+                }
+            }
+            Ok(())
+        });
+    }
+
+    /// Write a block back, dropping the padding past the end of the stream
+    fn write_block(&self, index: usize, buf: &[u8]) -> io::Result<()> {
+        self.spans(index as u64 * buf.len() as u64, buf.len(), |path, at, range| {
+            let mut file = OpenOptions::new().write(true).create(true).truncate(false).open(path)?;
+            file.seek(SeekFrom::Start(at))?;
+            file.write_all(&buf[range])
+        })
+    }
+}
+
+fn sha256(data: &[u8]) -> [u8; HASH_SIZE] {
+    Sha256::digest(data).into()
+}
+
+/// Default sidecar name for a set starting with `first`
+pub fn sidecar_path(first: &Path) -> PathBuf {
+    let mut name = first.as_os_str().to_os_string();
+    name.push(".par");
+    PathBuf::from(name)
+}
+
+/// Write recovery data for `files` (in order) to `output`.
+/// Files are recorded by name and looked up next to the sidecar on repair.
+pub fn create(files: &[PathBuf], output: &Path, options: ParityOptions) -> Result<()> {
+    let set = FileSet {
+        files: files.iter().map(|p| Ok((p.clone(), fs::metadata(p)?.len()))).collect::<Result<_>>()?,
+    };
+    let layout = Layout::new(set.total_size(), options)?;
+    let rs = layout.codec()?;
+    info!(
+        "Protecting {} bytes in {} stripes of {}+{} blocks of {} bytes",
+        layout.total_size, layout.stripes(), layout.data_per_stripe, layout.parity_per_stripe, layout.block_size
+    );
+
+    let mut data_hashes = vec![[0_u8; HASH_SIZE]; layout.data_blocks()];
+    let mut parity_hashes = Vec::with_capacity(layout.stripes() * layout.parity_per_stripe);
+    let mut parity = BufWriter::new(tempfile::tempfile()?);
+
+    for stripe in 0..layout.stripes() {
+        let mut shards = vec![vec![0_u8; layout.block_size]; layout.data_per_stripe + layout.parity_per_stripe];
+
+        for (position, shard) in shards.iter_mut().take(layout.data_per_stripe).enumerate() {
+            if let Some(index) = layout.block_index(stripe, position) {
+                set.read_block(index, shard);
+                data_hashes[index] = sha256(shard);
+            }
+        }
+
+        rs.encode(&mut shards).map_err(|e| ZipError::Other(format!("Reed-Solomon encoding failed: {:?}", e)))?;
+
+        for shard in &shards[layout.data_per_stripe..] {
+            parity_hashes.push(sha256(shard));
+            parity.write_all(shard)?;
+        }
+    }
+
+    let mut header = Vec::new();
+    header.extend_from_slice(MAGIC);
+    header.push(VERSION);
+    header.extend_from_slice(&(layout.block_size as u32).to_le_bytes());
+    header.extend_from_slice(&(layout.data_per_stripe as u16).to_le_bytes());
+    header.extend_from_slice(&(layout.parity_per_stripe as u16).to_le_bytes());
+    header.extend_from_slice(&(set.files.len() as u16).to_le_bytes());
+    for (path, size) in &set.files {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        header.extend_from_slice(name.as_bytes());
+        header.extend_from_slice(&size.to_le_bytes());
+    }
+    data_hashes.iter().chain(&parity_hashes).for_each(|hash| header.extend_from_slice(hash));
+    let checksum = sha256(&header);
+
+    let mut parity = parity.into_inner().map_err(|e| e.into_error())?;
+    parity.seek(SeekFrom::Start(0))?;
+
+    let mut out = BufWriter::new(File::create(output)?);
+    out.write_all(&header)?;
+    out.write_all(&checksum)?;
+    io::copy(&mut parity, &mut out)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Parsed sidecar, with the offset its parity blocks start at
+struct Sidecar {
+    layout: Layout,
+    set: FileSet,
+    data_hashes: Vec<[u8; HASH_SIZE]>,
+    parity_hashes: Vec<[u8; HASH_SIZE]>,
+    parity_offset: u64,
+}
+
+fn read_sidecar(path: &Path) -> Result<Sidecar> {
+    let damaged = || ZipError::Other(format!("{:?} is not a parity file or its header is damaged", path));
+    let mut input = BufReader::new(File::open(path)?);
+    let mut header = Vec::new();
+
+    let mut take = |len: usize, header: &mut Vec<u8>| -> Result<Vec<u8>> {
+        let mut buf = vec![0_u8; len];
+        input.read_exact(&mut buf).map_err(|_| damaged())?;
+        header.extend_from_slice(&buf);
+        Ok(buf)
+    };
+    let u16_at = |b: &[u8]| u16::from_le_bytes([b[0], b[1]]) as usize;
+
+    let fixed = take(19, &mut header)?;
+    if &fixed[..8] != MAGIC || fixed[8] != VERSION {
+        return Err(damaged());
+    }
+    let block_size = u32::from_le_bytes(fixed[9..13].try_into().unwrap()) as usize;
+    let data_per_stripe = u16_at(&fixed[13..]);
+    let parity_per_stripe = u16_at(&fixed[15..]);
+    let file_count = u16_at(&fixed[17..]);
+
+    // Protected files are looked up next to the sidecar
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut files = Vec::with_capacity(file_count);
+    for _ in 0..file_count {
+        let len = u16_at(&take(2, &mut header)?);
+        let name = String::from_utf8(take(len, &mut header)?).map_err(|_| damaged())?;
+        let size = u64::from_le_bytes(take(8, &mut header)?.try_into().unwrap());
+        // Repair creates and writes these files, so a name must not leave `dir`
+        if !is_plain_file_name(&name) {
+            return Err(ZipError::Other(format!("{:?} names a file outside its directory: {:?}", path, name)));
+        }
+        files.push((dir.join(name), size));
+    }
+
+    let set = FileSet { files };
+    let layout = Layout { block_size, total_size: set.total_size(), data_per_stripe, parity_per_stripe };
+    if block_size == 0 || data_per_stripe == 0 || parity_per_stripe == 0 || data_per_stripe + parity_per_stripe > MAX_SHARDS {
+        return Err(damaged());
+    }
+
+    let mut hashes = |count: usize, header: &mut Vec<u8>| -> Result<Vec<[u8; HASH_SIZE]>> {
+        let raw = take(count * HASH_SIZE, header)?;
+        Ok(raw.chunks_exact(HASH_SIZE).map(|c| c.try_into().unwrap()).collect())
+    };
+    let data_hashes = hashes(layout.data_blocks(), &mut header)?;
+    let parity_hashes = hashes(layout.stripes() * parity_per_stripe, &mut header)?;
+
+    let mut checksum = [0_u8; HASH_SIZE];
+    input.read_exact(&mut checksum).map_err(|_| damaged())?;
+    if sha256(&header) != checksum {
+        return Err(damaged());
+    }
+
+    Ok(Sidecar {
+        layout,
+        set,
+        data_hashes,
+        parity_hashes,
+        parity_offset: (header.len() + HASH_SIZE) as u64,
+    })
+}
+
+/// Whether `name` is a single normal path component
+fn is_plain_file_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!((components.next(), components.next()), (Some(Component::Normal(_)), None))
+        && !name.contains(['/', '\\'])
+}
+
+/// Check the files protected by the sidecar at `path` and, unless `check_only`,
+/// rebuild damaged blocks in place
+pub fn repair(path: &Path, check_only: bool) -> Result<RepairReport> {
+    let sidecar = read_sidecar(path)?;
+    let layout = sidecar.layout;
+    let rs = layout.codec()?;
+    let mut parity_file = File::open(path)?;
+    let mut report = RepairReport::default();
+
+    for stripe in 0..layout.stripes() {
+        let mut shards: Vec<Option<Vec<u8>>> = Vec::with_capacity(layout.data_per_stripe + layout.parity_per_stripe);
+        let mut damaged = Vec::new();
+
+        for position in 0..layout.data_per_stripe {
+            let mut block = vec![0_u8; layout.block_size];
+            match layout.block_index(stripe, position) {
+                Some(index) => {
+                    sidecar.set.read_block(index, &mut block);
+                    if sha256(&block) == sidecar.data_hashes[index] {
+                        shards.push(Some(block));
+                    } else {
+                        damaged.push((position, index));
+                        shards.push(None);
+                    }
+                }
+                // Past the end of the stream: known zero padding
+                None => shards.push(Some(block)),
+            }
+        }
+
+        for position in 0..layout.parity_per_stripe {
+            let slot = stripe * layout.parity_per_stripe + position;
+            let mut block = vec![0_u8; layout.block_size];
+            parity_file.seek(SeekFrom::Start(sidecar.parity_offset + (slot * layout.block_size) as u64))?;
+            let intact = parity_file.read_exact(&mut block).is_ok() && sha256(&block) == sidecar.parity_hashes[slot];
+            if !intact {
+                report.damaged_parity += 1;
+            }
+            shards.push(intact.then_some(block));
+        }
+
+        if damaged.is_empty() {
+            continue;
+        }
+        report.damaged_blocks += damaged.len();
+
+        if rs.reconstruct_data(&mut shards).is_err() {
+            warn!("Stripe {} has {} damaged blocks, more than its parity can rebuild", stripe, damaged.len());
+            report.unrecoverable_stripes += 1;
+            continue;
+        }
+
+        for (position, index) in damaged {
+            let block = shards[position].as_ref().expect("reconstructed");
+            if sha256(block) != sidecar.data_hashes[index] {
+                report.unrecoverable_stripes += 1;
+                break;
+            }
+            if !check_only {
+                sidecar.set.write_block(index, block)?;
+            }
+            report.repaired_blocks += 1;
+        }
+    }
+
+    for (file, size) in &sidecar.set.files {
+        if fs::metadata(file).map(|m| m.len()).ok() != Some(*size) {
+            if !check_only && report.unrecoverable_stripes == 0 {
+                OpenOptions::new().write(true).create(true).truncate(false).open(file)?.set_len(*size)?;
+            }
+            report.resized_files.push(file.clone());
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repairs_damage_within_parity() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("a.001");
+        let second = dir.path().join("a.002");
+        let data: Vec<u8> = (0..10_000_u32).map(|i| (i * 7 % 253) as u8).collect();
+        fs::write(&first, &data[..6000]).unwrap();
+        fs::write(&second, &data[6000..]).unwrap();
+
+        let sidecar = sidecar_path(&first);
+        let options = ParityOptions { redundancy: 20, block_size: 512 };
+        create(&[first.clone(), second.clone()], &sidecar, options).unwrap();
+
+        // Flip bytes in two blocks and lose the tail of the second volume
+        let mut damaged = data[..6000].to_vec();
+        damaged[10] ^= 0xff;
+        damaged[3000] ^= 0xff;
+        fs::write(&first, &damaged).unwrap();
+        fs::write(&second, &data[6000..9500]).unwrap();
+
+        assert!(!repair(&sidecar, true).unwrap().is_clean());
+        let report = repair(&sidecar, false).unwrap();
+        assert_eq!(report.unrecoverable_stripes, 0);
+
+        let mut restored = fs::read(&first).unwrap();
+        restored.extend(fs::read(&second).unwrap());
+        assert_eq!(restored, data);
+        assert!(repair(&sidecar, true).unwrap().is_clean());
+    }
+
+    #[test]
+    fn rejects_file_names_that_leave_the_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("a.001");
+        fs::write(&first, b"data").unwrap();
+        let sidecar = sidecar_path(&first);
+        create(std::slice::from_ref(&first), &sidecar, ParityOptions { redundancy: 50, block_size: 64 }).unwrap();
+
+        let original = fs::read(&sidecar).unwrap();
+        let header_len = read_sidecar(&sidecar).unwrap().parity_offset as usize - HASH_SIZE;
+        let name_len = "a.001".len();
+
+        let forge = |name: &str| {
+            // Swap in another name and recompute the header checksum, as an attacker could
+            let mut header = original[..19].to_vec();
+            header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            header.extend_from_slice(name.as_bytes());
+            header.extend_from_slice(&original[21 + name_len..header_len]);
+            let mut forged = header.clone();
+            forged.extend_from_slice(&sha256(&header));
+            forged.extend_from_slice(&original[header_len + HASH_SIZE..]);
+
+            let path = dir.path().join("forged.par");
+            fs::write(&path, forged).unwrap();
+            path
+        };
+
+        assert!(read_sidecar(&forge("b.001")).is_ok());
+        for name in ["../escaped", "/tmp/escaped", "sub/escaped", "..\\escaped", "..", "."] {
+            assert!(repair(&forge(name), false).is_err(), "{:?} was accepted", name);
+        }
+        assert!(!dir.path().parent().unwrap().join("escaped").exists());
+    }
+}
@@ -0,0 +1,98 @@
+use crate::{Result, ZipError};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::PathBuf;
+
+/// Where a password is read from
+pub enum PasswordSource {
+    /// Given directly on the command line
+    Arg(String),
+    /// First line of a file
+    File(PathBuf),
+    /// Value of an environment variable
+    Env(String),
+    /// First line read from an inherited file descriptor
+    Fd(i32),
+    /// Typed at the terminal without echo
+    Prompt,
+}
+
+impl PasswordSource {
+    /// Read the password; `confirm` asks twice when prompting, for new archives
+    pub fn read(self, confirm: bool) -> Result<String> {
+        let password = match self {
+            Self::Arg(password) => password,
+            Self::File(path) => first_line(File::open(&path).map_err(|e| {
+                ZipError::Other(format!("Cannot read password file {:?}: {}", path, e))
+            })?)?,
+            Self::Env(name) => std::env::var(&name).map_err(|_| {
+                ZipError::Other(format!("Environment variable {} is not set", name))
+            })?,
+            Self::Fd(fd) => first_line(open_fd(fd)?)?,
+            Self::Prompt => prompt(confirm)?,
+        };
+
+        if password.is_empty() {
+            return Err(ZipError::Other("Password is empty".to_string()));
+        }
+        Ok(password)
+    }
+}
+
+/// First line of a reader without its line ending; the rest is ignored
+fn first_line<R: Read>(reader: R) -> Result<String> {
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line)?;
+
+    let trimmed = line.strip_suffix('\n').unwrap_or(&line);
+    Ok(trimmed.strip_suffix('\r').unwrap_or(trimmed).to_string())
+}
+
+#[cfg(unix)]
+fn open_fd(fd: i32) -> Result<File> {
+    use std::os::fd::FromRawFd;
+
+    // SAFETY: F_GETFD only queries descriptor flags
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(ZipError::Other(format!("File descriptor {} is not open", fd)));
+    }
+    // SAFETY: the descriptor is open and handed to us by the caller for reading
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(not(unix))]
+fn open_fd(_fd: i32) -> Result<File> {
+    Err(ZipError::UnsupportedOperation(
+        "Reading a password from a file descriptor is only supported on Unix".to_string()
+    ))
+}
+
+fn prompt(confirm: bool) -> Result<String> {
+    let password = rpassword::prompt_password("Password: ")?;
+
+    if confirm && rpassword::prompt_password("Confirm password: ")? != password {
+        return Err(ZipError::Other("Passwords do not match".to_string()));
+    }
+    Ok(password)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_first_line_from_each_source() {
+        assert_eq!(first_line(&b"secret\r\nignored\n"[..]).unwrap(), "secret");
+        assert_eq!(first_line(&b"no newline"[..]).unwrap(), "no newline");
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("password");
+        std::fs::write(&path, "from file\n").unwrap();
+        assert_eq!(PasswordSource::File(path).read(false).unwrap(), "from file");
+        assert!(PasswordSource::File(dir.path().join("missing")).read(false).is_err());
+
+        assert!(PasswordSource::Env("CAZIP_TEST_UNSET_PASSWORD".to_string()).read(false).is_err());
+        assert!(PasswordSource::Arg(String::new()).read(false).is_err());
+        assert!(PasswordSource::Arg("arg".to_string()).read(true).is_ok());
+    }
+}
@@ -0,0 +1,254 @@
+use crate::codecs::limits::ExtractLimits;
+use crate::codecs::safe_path::PathPolicy;
+use crate::codecs::{CodecFactory, Format};
+use crate::utils;
+use crate::{Result, ZipError};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use walkdir::WalkDir;
+
+/// Where a nested archive's contents go
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Placement {
+    /// A directory next to the archive, named after it without its extensions
+    Sibling,
+    /// A directory that takes the archive's place under the same name
+    InPlace,
+}
+
+/// Size and modification time of every file under a directory
+pub struct Snapshot(HashMap<PathBuf, (u64, Option<SystemTime>)>);
+
+impl Snapshot {
+    /// Record `dir` before extracting into it, so files already there are left alone
+    pub fn of(dir: &Path) -> Result<Self> {
+        if !dir.exists() {
+            return Ok(Self(HashMap::new()));
+        }
+        let files = regular_files(dir)?.into_iter()
+            .map(|(path, metadata)| (path, (metadata.len(), metadata.modified().ok())))
+            .collect();
+        Ok(Self(files))
+    }
+
+    fn unchanged(&self, path: &Path, metadata: &fs::Metadata) -> bool {
+        self.0.get(path) == Some(&(metadata.len(), metadata.modified().ok()))
+    }
+
+    /// Files under `dir` that are new or changed since the snapshot
+    fn written_since(&self, dir: &Path) -> Result<Vec<(PathBuf, fs::Metadata)>> {
+        let mut files = regular_files(dir)?;
+        files.retain(|(path, metadata)| !self.unchanged(path, metadata));
+        Ok(files)
+    }
+}
+
+pub struct RecursiveExtractor {
+    /// How many levels below the outer archive to extract
+    pub depth: usize,
+    pub placement: Placement,
+    pub limits: ExtractLimits,
+    pub path_policy: PathPolicy,
+    pub password: Option<String>,
+}
+
+impl RecursiveExtractor {
+    /// Extract every archive that appeared in `dir` since `before`, then the
+    /// archives inside those, and so on. Returns how many were extracted.
+    pub fn run(&self, dir: &Path, before: &Snapshot) -> Result<usize> {
+        let written = before.written_since(dir)?;
+        let mut budget = self.limits;
+        // The outer archive's output is already on disk and counts too
+        charge(&mut budget, &written, dir)?;
+
+        let mut pending = archives_among(written);
+        let mut extracted = 0;
+
+        for level in 1..=self.depth {
+            let mut found = Vec::new();
+            for (archive, format) in pending.drain(..) {
+                info!("Extracting nested archive {:?} (level {})", archive, level);
+                let output = self.extract(&archive, format, &budget)?;
+                let files = regular_files(&output)?;
+                charge(&mut budget, &files, &archive)?;
+                found.extend(archives_among(files));
+                extracted += 1;
+            }
+            pending = found;
+
+            if pending.is_empty() {
+                break;
+            }
+        }
+
+        if !pending.is_empty() {
+            warn!("{} archives nested deeper than {} levels were left as they are", pending.len(), self.depth);
+        }
+        Ok(extracted)
+    }
+
+    /// Extract one nested archive, returning the directory its contents went to
+    fn extract(&self, archive: &Path, format: Format, budget: &ExtractLimits) -> Result<PathBuf> {
+        let mut codec = CodecFactory::new(format, None, self.password.clone(), None, false, None)
+            .with_limits(*budget)
+            .with_path_policy(self.path_policy)
+            .create_codec()?;
+
+        let parent = archive.parent().unwrap_or(Path::new("."));
+        let name = archive.file_name().unwrap_or_default().to_string_lossy().into_owned();
+
+        match self.placement {
+            Placement::Sibling => {
+                let mut output = parent.join(utils::archive_stem(archive));
+                // Never mix the contents into something that is already there
+                if output == archive || output.exists() {
+                    output = parent.join(format!("{}.extracted", name));
+                }
+                // Created first so that single-file formats such as gz write into it
+                fs::create_dir_all(&output)?;
+                codec.extract(&[archive], &output)?;
+                Ok(output)
+            }
+            Placement::InPlace => {
+                let staging = parent.join(format!(".{}.extracting", name));
+                fs::create_dir_all(&staging)?;
+                if let Err(e) = codec.extract(&[archive], &staging) {
+                    let _ = fs::remove_dir_all(&staging);
+                    return Err(e);
+                }
+                fs::remove_file(archive)?;
+                fs::rename(&staging, archive)?;
+                Ok(archive.to_path_buf())
+            }
+        }
+    }
+}
+
+/// Take the files an extraction wrote off the limits left for the levels below
+fn charge(budget: &mut ExtractLimits, files: &[(PathBuf, fs::Metadata)], archive: &Path) -> Result<()> {
+    let size: u64 = files.iter().map(|(_, metadata)| metadata.len()).sum();
+
+    let exceeded = |left: &mut Option<u64>, used: u64| match left {
+        Some(left) if used > *left => true,
+        Some(left) => {
+            *left -= used;
+            false
+        }
+        None => false,
+    };
+
+    if exceeded(&mut budget.max_total_size, size) || exceeded(&mut budget.max_entries, files.len() as u64) {
+        return Err(ZipError::LimitExceeded(format!(
+            "extracting {:?} exceeds the limits shared by all nested archives", archive
+        )));
+    }
+    Ok(())
+}
+
+/// The files that start with archive magic
+fn archives_among(files: Vec<(PathBuf, fs::Metadata)>) -> Vec<(PathBuf, Format)> {
+    files.into_iter()
+        .filter_map(|(path, _)| Format::detect(&path).map(|format| (path, format)))
+        .collect()
+}
+
+/// Regular files under `dir`, without following symlinks
+fn regular_files(dir: &Path) -> Result<Vec<(PathBuf, fs::Metadata)>> {
+    let mut files = Vec::new();
+
+    for entry in WalkDir::new(dir).min_depth(1) {
+        let entry = entry.map_err(std::io::Error::from)?;
+        let metadata = entry.metadata().map_err(std::io::Error::from)?;
+        if metadata.is_file() {
+            files.push((entry.into_path(), metadata));
+        }
+    }
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+
+    fn zip_of(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        zip.start_file(name, SimpleFileOptions::default()).unwrap();
+        zip.write_all(data).unwrap();
+        zip.finish().unwrap().into_inner()
+    }
+
+    /// `outer.zip` holding `mid.tar` holding `inner.zip` holding `payload.txt`
+    fn nested_fixture(dir: &Path) -> (PathBuf, u64) {
+        let inner = zip_of("payload.txt", &[b'x'; 1000]);
+        let mut tar = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(inner.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, "inner.zip", inner.as_slice()).unwrap();
+        let mid = tar.into_inner().unwrap();
+
+        let outer = dir.join("outer.zip");
+        fs::write(&outer, zip_of("mid.tar", &mid)).unwrap();
+        (outer, (mid.len() + inner.len()) as u64)
+    }
+
+    fn extract_nested(depth: usize, limits: ExtractLimits) -> (tempfile::TempDir, Result<usize>) {
+        let dir = tempfile::tempdir().unwrap();
+        let (outer, _) = nested_fixture(dir.path());
+        let target = dir.path().join("out");
+        fs::create_dir_all(&target).unwrap();
+
+        let before = Snapshot::of(&target).unwrap();
+        CodecFactory::new(Format::Zip, None, None, None, false, None)
+            .with_limits(limits)
+            .create_codec().unwrap()
+            .extract(&[&outer], &target).unwrap();
+
+        let extractor = RecursiveExtractor {
+            depth,
+            placement: Placement::Sibling,
+            limits,
+            path_policy: PathPolicy::Reject,
+            password: None,
+        };
+        let result = extractor.run(&target, &before);
+        (dir, result)
+    }
+
+    #[test]
+    fn stops_at_the_depth_limit() {
+        let (dir, result) = extract_nested(1, ExtractLimits::default());
+        assert_eq!(result.unwrap(), 1);
+        let out = dir.path().join("out");
+        assert!(out.join("mid/inner.zip").is_file());
+        assert!(!out.join("mid/inner").exists());
+
+        let (dir, result) = extract_nested(2, ExtractLimits::default());
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(fs::read(dir.path().join("out/mid/inner/payload.txt")).unwrap().len(), 1000);
+    }
+
+    #[test]
+    fn limits_cover_the_outer_archive_and_every_level() {
+        let (_, outer_and_first_level) = nested_fixture(tempfile::tempdir().unwrap().path());
+
+        // Each level fits on its own, but not together with what came before
+        let size_limited = ExtractLimits { max_total_size: Some(outer_and_first_level + 500), ..ExtractLimits::default() };
+        let (_, result) = extract_nested(2, size_limited);
+        assert!(matches!(result, Err(ZipError::LimitExceeded(_))));
+
+        let entry_limited = ExtractLimits { max_entries: Some(2), ..ExtractLimits::default() };
+        let (_, result) = extract_nested(2, entry_limited);
+        assert!(matches!(result, Err(ZipError::LimitExceeded(_))));
+
+        let enough = ExtractLimits { max_total_size: Some(outer_and_first_level + 1000), max_entries: Some(3), ..ExtractLimits::default() };
+        assert_eq!(extract_nested(2, enough).1.unwrap(), 2);
+    }
+}
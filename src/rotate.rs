@@ -0,0 +1,140 @@
+use crate::{Result, ZipError};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// How many archives to keep per period; the newest archive of each of the
+/// last `daily` days, `weekly` ISO weeks and `monthly` months is kept
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RetentionPolicy {
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+}
+
+/// An archive matching the template, and why it is kept
+#[derive(Debug)]
+pub struct Rotation {
+    pub path: PathBuf,
+    pub time: NaiveDateTime,
+    /// The rules keeping it; empty when it is to be removed
+    pub kept_by: Vec<&'static str>,
+}
+
+/// Expand the date placeholders of `template` for `now`
+pub fn expand_template(template: &str, now: DateTime<Local>) -> Result<String> {
+    let items = parse_template(template)?;
+    Ok(now.format_with_items(items.into_iter()).to_string())
+}
+
+fn parse_template(template: &str) -> Result<Vec<Item<'_>>> {
+    let items: Vec<Item> = StrftimeItems::new(template).collect();
+    if items.contains(&Item::Error) {
+        return Err(ZipError::Other(format!("Invalid date placeholder in template {:?}", template)));
+    }
+    Ok(items)
+}
+
+/// When an archive named `name` was created according to the file name
+/// part of `template`, or `None` if the name does not fit it
+pub fn parse_name(name: &str, template: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(name, template).ok()
+        .or_else(|| NaiveDate::parse_from_str(name, template).ok().and_then(|date| date.and_hms_opt(0, 0, 0)))
+}
+
+/// Every archive in the template's directory whose name fits the template,
+/// newest first, marked with the rules that keep it
+pub fn plan(template: &str, policy: RetentionPolicy) -> Result<Vec<Rotation>> {
+    parse_template(template)?;
+    let template = Path::new(template);
+    let pattern = template.file_name().unwrap_or_default().to_string_lossy();
+    let dir = template.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+
+    if dir.to_string_lossy().contains('%') {
+        return Err(ZipError::Other("Only the file name of a rotated template may hold date placeholders".to_string()));
+    }
+
+    let mut archives = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if let Some(time) = parse_name(&name, &pattern).filter(|_| entry.path().is_file()) {
+            archives.push(Rotation { path: entry.path(), time, kept_by: Vec::new() });
+        }
+    }
+
+    archives.sort_by(|a, b| b.time.cmp(&a.time).then_with(|| b.path.cmp(&a.path)));
+    apply(&mut archives, policy);
+    Ok(archives)
+}
+
+/// Maps a time to the day, week or month it falls in
+type Period = fn(&NaiveDateTime) -> (i32, u32);
+
+/// Mark the archives, sorted newest first, that the policy keeps
+fn apply(archives: &mut [Rotation], policy: RetentionPolicy) {
+    let rules: [(&'static str, usize, Period); 3] = [
+        ("daily", policy.daily, |t| (t.year(), t.ordinal())),
+        ("weekly", policy.weekly, |t| (t.iso_week().year(), t.iso_week().week())),
+        ("monthly", policy.monthly, |t| (t.year(), t.month())),
+    ];
+
+    for (rule, count, period) in rules {
+        let mut seen = BTreeSet::new();
+        for archive in archives.iter_mut() {
+            if seen.len() == count {
+                break;
+            }
+            // The first archive met in a period is its newest
+            if seen.insert(period(&archive.time)) {
+                archive.kept_by.push(rule);
+            }
+        }
+    }
+}
+
+/// Delete the archives `plan` did not keep; returns what was removed
+pub fn remove_unkept(rotations: &[Rotation]) -> Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+
+    for rotation in rotations.iter().filter(|r| r.kept_by.is_empty()) {
+        fs::remove_file(&rotation.path)?;
+        removed.push(rotation.path.clone());
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_newest_archive_of_each_period() {
+        let template = "site-%Y%m%d-%H%M.zip";
+        // Twice a day across about two months, newest first
+        let start = NaiveDate::from_ymd_opt(2024, 3, 31).unwrap().and_hms_opt(0, 0, 0).unwrap();
+        let mut archives: Vec<Rotation> = (0..120)
+            .map(|i| {
+                let time = start - chrono::Duration::hours(12 * i + 1);
+                let name = time.format(template).to_string();
+                assert_eq!(parse_name(&name, template), Some(time));
+                Rotation { path: PathBuf::from(name), time, kept_by: Vec::new() }
+            })
+            .collect();
+
+        apply(&mut archives, RetentionPolicy { daily: 3, weekly: 2, monthly: 2 });
+        let kept: Vec<_> = archives.iter().filter(|a| !a.kept_by.is_empty()).map(|a| a.path.to_string_lossy().into_owned()).collect();
+
+        assert_eq!(kept, [
+            "site-20240330-2300.zip",
+            "site-20240329-2300.zip",
+            "site-20240328-2300.zip",
+            "site-20240324-2300.zip",
+            "site-20240229-2300.zip",
+        ]);
+        assert_eq!(archives[0].kept_by, ["daily", "weekly", "monthly"]);
+        assert_eq!(parse_name("site-latest.zip", template), None);
+    }
+}
@@ -1,6 +1,5 @@
 use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::time::Duration;
 use log::info;
 use crate::{Result};
 use crate::utils::{run_command_with_output_handling};
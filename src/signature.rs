@@ -0,0 +1,302 @@
+use crate::codecs::{Codec, EntryLink, EntryMeta};
+use crate::utils::{from_hex, to_hex};
+use crate::{Result, ZipError};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use rand_core::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const PUBLIC_PREFIX: &str = "ed25519:";
+const SECRET_PREFIX: &str = "ed25519-secret:";
+
+/// A signer's public key, written as `ed25519:<hex>`
+#[derive(Clone, PartialEq)]
+pub struct SignerKey(VerifyingKey);
+
+impl FromStr for SignerKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let hex = s.trim().strip_prefix(PUBLIC_PREFIX)
+            .ok_or_else(|| format!("signing key must start with {}", PUBLIC_PREFIX))?;
+        let bytes = from_hex(hex).ok_or_else(|| "key must be 64 hex digits".to_string())?;
+        VerifyingKey::from_bytes(&bytes).map(Self).map_err(|e| e.to_string())
+    }
+}
+
+impl fmt::Display for SignerKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", PUBLIC_PREFIX, to_hex(self.0.as_bytes()))
+    }
+}
+
+impl SignerKey {
+    /// Parse a key given inline or read the first key from a file
+    pub fn load(value: &str) -> Result<Self> {
+        if value.starts_with(PUBLIC_PREFIX) {
+            return value.parse().map_err(ZipError::Other);
+        }
+
+        let text = fs::read_to_string(value)
+            .map_err(|e| ZipError::Other(format!("Cannot read public key {:?}: {}", value, e)))?;
+        text.lines()
+            .filter_map(|line| line.trim().trim_start_matches("# public key:").trim().parse().ok())
+            .next()
+            .ok_or_else(|| ZipError::Other(format!("No {} key found in {:?}", PUBLIC_PREFIX, value)))
+    }
+}
+
+/// A private signing key
+pub struct SigningIdentity(SigningKey);
+
+impl SigningIdentity {
+    /// Create a new random signing key
+    pub fn generate() -> Self {
+        Self(SigningKey::generate(&mut OsRng))
+    }
+
+    /// Public key to hand out to verifiers
+    pub fn public(&self) -> SignerKey {
+        SignerKey(self.0.verifying_key())
+    }
+
+    /// Load the signing key from a key file; `#` lines are comments
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| ZipError::Other(format!("Cannot read signing key {:?}: {}", path, e)))?;
+
+        text.lines()
+            .map(str::trim)
+            .find_map(|line| line.strip_prefix(SECRET_PREFIX))
+            .and_then(from_hex)
+            .map(|bytes| Self(SigningKey::from_bytes(&bytes)))
+            .ok_or_else(|| ZipError::Other(format!("{:?} is not a signing key file", path)))
+    }
+
+    /// Write the key to a new file readable only by its owner
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let mut file = options.open(path)?;
+        writeln!(file, "# public key: {}", self.public())?;
+        writeln!(file, "{}{}", SECRET_PREFIX, to_hex(self.0.as_bytes()))?;
+        Ok(())
+    }
+}
+
+/// What an archive entry is
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    #[default]
+    File,
+    Dir,
+    Symlink,
+    Hardlink,
+}
+
+/// Everything about one archive entry the signature vouches for
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ManifestEntry {
+    pub path: String,
+    /// Version 1 manifests only listed files
+    #[serde(default)]
+    pub kind: EntryKind,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl ManifestEntry {
+    fn of(meta: &EntryMeta, size: u64, sha256: String) -> Self {
+        let (kind, link) = match &meta.link {
+            Some(EntryLink::Symbolic(target)) => (EntryKind::Symlink, Some(target.clone())),
+            Some(EntryLink::Hard(target)) => (EntryKind::Hardlink, Some(target.clone())),
+            None if meta.is_dir => (EntryKind::Dir, None),
+            None => (EntryKind::File, None),
+        };
+        Self { path: meta.name.clone(), kind, mode: meta.mode, link, size, sha256 }
+    }
+}
+
+/// Everything the signature covers
+#[derive(Serialize, Deserialize)]
+pub struct SignedContent {
+    pub version: u32,
+    pub algorithm: String,
+    pub signer: String,
+    pub archive: String,
+    pub archive_size: u64,
+    pub archive_sha256: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<Vec<ManifestEntry>>,
+}
+
+/// A detached signature file
+#[derive(Serialize, Deserialize)]
+pub struct SignatureFile {
+    #[serde(flatten)]
+    pub content: SignedContent,
+    pub signature: String,
+}
+
+impl SignatureFile {
+    /// Conventional location of an archive's signature
+    pub fn default_path(archive: &Path) -> PathBuf {
+        let mut name = archive.as_os_str().to_os_string();
+        name.push(".sig");
+        PathBuf::from(name)
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| ZipError::Other(format!("Cannot read signature {:?}: {}", path, e)))?;
+        serde_json::from_str(&text)
+            .map_err(|e| ZipError::Other(format!("Malformed signature {:?}: {}", path, e)))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| ZipError::Other(e.to_string()))?;
+        fs::write(path, json + "\n")?;
+        Ok(())
+    }
+
+    /// Check the signature against the key it names, returning that key
+    pub fn check(&self) -> Result<SignerKey> {
+        let signer: SignerKey = self.content.signer.parse().map_err(ZipError::Other)?;
+        let signature = from_hex(&self.signature)
+            .map(|bytes| Signature::from_bytes(&bytes))
+            .ok_or_else(|| ZipError::Other("Malformed signature value".to_string()))?;
+
+        signer.0.verify_strict(&signed_bytes(&self.content)?, &signature)
+            .map_err(|_| ZipError::Other("Signature does not match its contents".to_string()))?;
+        Ok(signer)
+    }
+}
+
+/// Sign the archive at `path`, optionally including an entry manifest
+pub fn sign(path: &Path, key: &SigningIdentity, manifest: Option<Vec<ManifestEntry>>) -> Result<SignatureFile> {
+    let (archive_size, archive_sha256) = hash_file(path)?;
+
+    let content = SignedContent {
+        version: 2,
+        algorithm: "ed25519".to_string(),
+        signer: key.public().to_string(),
+        archive: path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+        archive_size,
+        archive_sha256,
+        manifest,
+    };
+
+    let signature = key.0.sign(&signed_bytes(&content)?);
+    Ok(SignatureFile { content, signature: to_hex(&signature.to_bytes()) })
+}
+
+/// Manifest of every entry, directories and links included, in archive order
+pub fn build_manifest(codec: &mut dyn Codec, archive: &Path) -> Result<Vec<ManifestEntry>> {
+    let mut manifest = Vec::new();
+
+    codec.visit_entries(archive, &mut |meta: &EntryMeta, reader: &mut dyn Read| {
+        let mut hasher = Sha256::new();
+        let size = io::copy(reader, &mut hasher)?;
+        manifest.push(ManifestEntry::of(meta, size, to_hex(&hasher.finalize())));
+        Ok(())
+    })?;
+
+    Ok(manifest)
+}
+
+/// Describe how `actual` differs from the signed `expected` manifest; empty
+/// when they list the same entries with the same metadata and contents
+pub fn compare_manifest(expected: &[ManifestEntry], actual: &[ManifestEntry]) -> Vec<String> {
+    let mut mismatches: Vec<String> = expected.iter()
+        .filter(|entry| !actual.contains(entry))
+        .map(|entry| format!("{} (changed or missing)", entry.path))
+        .collect();
+    mismatches.extend(actual.iter()
+        .filter(|entry| !expected.iter().any(|e| e.path == entry.path))
+        .map(|entry| format!("{} (not in manifest)", entry.path)));
+    mismatches
+}
+
+/// Size and SHA-256 of a file
+pub fn hash_file(path: &Path) -> Result<(u64, String)> {
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok((size, to_hex(&hasher.finalize())))
+}
+
+fn signed_bytes(content: &SignedContent) -> Result<Vec<u8>> {
+    serde_json::to_vec(content).map_err(|e| ZipError::Other(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codecs::zip::{CompressionMethod, ZipCodec};
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    fn write_zip(path: &Path, mode: u32, link: &str) {
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+        zip.add_directory("d/", SimpleFileOptions::default()).unwrap();
+        zip.start_file("d/a.txt", SimpleFileOptions::default().unix_permissions(mode)).unwrap();
+        zip.write_all(b"hello").unwrap();
+        zip.add_symlink("d/link", link, SimpleFileOptions::default()).unwrap();
+        zip.finish().unwrap();
+    }
+
+    fn manifest_of(path: &Path) -> Vec<ManifestEntry> {
+        build_manifest(&mut ZipCodec::new(CompressionMethod::Deflated, None, None), path).unwrap()
+    }
+
+    #[test]
+    fn signature_covers_archive_and_rejects_tampering() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("a.zip");
+        write_zip(&archive, 0o644, "a.txt");
+
+        let key = SigningIdentity::generate();
+        let mut signed = sign(&archive, &key, Some(manifest_of(&archive))).unwrap();
+        assert!(signed.check().unwrap() == key.public());
+
+        signed.content.archive_size += 1;
+        assert!(signed.check().is_err());
+    }
+
+    #[test]
+    fn manifest_covers_directories_modes_and_link_targets() {
+        let dir = tempfile::tempdir().unwrap();
+        let (original, chmod, relink) = (dir.path().join("a.zip"), dir.path().join("b.zip"), dir.path().join("c.zip"));
+        write_zip(&original, 0o644, "a.txt");
+        write_zip(&chmod, 0o4755, "a.txt");
+        write_zip(&relink, 0o644, "../../etc/passwd");
+
+        let expected = manifest_of(&original);
+        let kinds: Vec<_> = expected.iter().map(|entry| entry.kind).collect();
+        assert_eq!(kinds, [EntryKind::Dir, EntryKind::File, EntryKind::Symlink]);
+        assert!(compare_manifest(&expected, &manifest_of(&original)).is_empty());
+
+        assert_eq!(compare_manifest(&expected, &manifest_of(&chmod)), ["d/a.txt (changed or missing)"]);
+        assert_eq!(compare_manifest(&expected, &manifest_of(&relink)), ["d/link (changed or missing)"]);
+
+        let files_only: Vec<_> = expected.iter().filter(|entry| entry.kind == EntryKind::File).cloned().collect();
+        assert_eq!(compare_manifest(&files_only, &expected), ["d/ (not in manifest)", "d/link (not in manifest)"]);
+    }
+}
@@ -0,0 +1,599 @@
+use crate::backup::restore_entry;
+use crate::codecs::limits::{ExtractLimits, LimitTracker};
+use crate::codecs::safe_path::{PathGuard, PathPolicy};
+use crate::codecs::{EntryLink, EntryMeta};
+use crate::{Result, ZipError};
+use chrono::{Local, SecondsFormat};
+use log::{debug, info};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::SystemTime;
+use walkdir::WalkDir;
+
+const CONFIG_FILE: &str = "config.json";
+const CHUNK_DIR: &str = "chunks";
+const SNAPSHOT_DIR: &str = "snapshots";
+
+/// How chunks are compressed on disk; reading recognises either
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkCompression {
+    #[default]
+    Zstd,
+    Xz,
+}
+
+impl FromStr for ChunkCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "zstd" | "zst" => Ok(Self::Zstd),
+            "xz" => Ok(Self::Xz),
+            _ => Err(format!("unknown chunk compression: {} (expected zstd or xz)", s)),
+        }
+    }
+}
+
+impl ChunkCompression {
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::Zstd => Ok(zstd::bulk::compress(data, 3)?),
+            Self::Xz => {
+                let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+        }
+    }
+
+    fn decompress(stored: &[u8]) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        if stored.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            xz2::read::XzDecoder::new(stored).read_to_end(&mut data)?;
+        } else {
+            zstd::stream::read::Decoder::new(stored)?.read_to_end(&mut data)?;
+        }
+        Ok(data)
+    }
+}
+
+/// Settings fixed when a store is created
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StoreConfig {
+    pub version: u32,
+    pub compression: ChunkCompression,
+    pub min_chunk: usize,
+    pub avg_chunk: usize,
+    pub max_chunk: usize,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        Self {
+            version: 1,
+            compression: ChunkCompression::default(),
+            min_chunk: 256 * 1024,
+            avg_chunk: 1024 * 1024,
+            max_chunk: 4 * 1024 * 1024,
+        }
+    }
+}
+
+/// One path in a snapshot
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotEntry {
+    pub path: String,
+    pub kind: EntryKind,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<SystemTime>,
+    /// Hashes of the file's chunks, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink(String),
+}
+
+/// A snapshot manifest
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Snapshot {
+    pub id: String,
+    pub created: String,
+    pub source: String,
+    pub entries: Vec<SnapshotEntry>,
+}
+
+/// A snapshot without its entries, as listed
+#[derive(Serialize, Debug)]
+pub struct SnapshotSummary {
+    pub id: String,
+    pub created: String,
+    pub source: String,
+    pub files: usize,
+    pub size: u64,
+}
+
+/// What taking a snapshot stored
+#[derive(Debug)]
+pub struct SnapshotReport {
+    pub id: String,
+    pub files: usize,
+    pub size: u64,
+    pub chunks: usize,
+    /// Chunks the store did not have yet, and their size on disk
+    pub new_chunks: usize,
+    pub new_bytes: u64,
+}
+
+/// What pruning removed
+#[derive(Debug, Default)]
+pub struct PruneReport {
+    pub snapshots: Vec<String>,
+    pub chunks: usize,
+    pub bytes: u64,
+}
+
+/// Chunks stored once under their BLAKE3 hash plus JSON snapshot manifests. Writers
+/// do not lock the store, so `prune` must not run alongside `snapshot`.
+pub struct Store {
+    root: PathBuf,
+    config: StoreConfig,
+}
+
+impl Store {
+    /// Open the store at `root`, creating it with `compression` if it does not exist yet
+    pub fn open_or_init(root: &Path, compression: Option<ChunkCompression>) -> Result<Self> {
+        if root.join(CONFIG_FILE).exists() {
+            let store = Self::open(root)?;
+            if compression.is_some_and(|c| c != store.config.compression) {
+                info!("{:?} already compresses chunks with {:?}; keeping that", root, store.config.compression);
+            }
+            return Ok(store);
+        }
+
+        let config = StoreConfig { compression: compression.unwrap_or_default(), ..StoreConfig::default() };
+        fs::create_dir_all(root.join(CHUNK_DIR))?;
+        fs::create_dir_all(root.join(SNAPSHOT_DIR))?;
+        write_json(&root.join(CONFIG_FILE), &config)?;
+        info!("Created store {:?}", root);
+        Ok(Self { root: root.to_path_buf(), config })
+    }
+
+    pub fn open(root: &Path) -> Result<Self> {
+        let config: StoreConfig = read_json(&root.join(CONFIG_FILE))
+            .map_err(|e| ZipError::Other(format!("{:?} is not a snapshot store: {}", root, e)))?;
+        if config.version != 1 {
+            return Err(ZipError::Other(format!("Unsupported store version {}", config.version)));
+        }
+        Ok(Self { root: root.to_path_buf(), config })
+    }
+
+    fn chunk_path(&self, hash: &str) -> PathBuf {
+        self.root.join(CHUNK_DIR).join(&hash[..2]).join(hash)
+    }
+
+    fn snapshot_path(&self, id: &str) -> PathBuf {
+        self.root.join(SNAPSHOT_DIR).join(format!("{}.json", id))
+    }
+
+    /// Store a chunk unless it is already there; returns its hash and the bytes written
+    fn put_chunk(&self, data: &[u8]) -> Result<(String, Option<u64>)> {
+        let hash = blake3::hash(data).to_hex().to_string();
+        let path = self.chunk_path(&hash);
+        if path.exists() {
+            return Ok((hash, None));
+        }
+
+        let compressed = self.config.compression.compress(data)?;
+        let dir = path.parent().unwrap_or(&self.root);
+        fs::create_dir_all(dir)?;
+
+        // Written aside and renamed, so a chunk file is either complete or absent
+        let mut staging = tempfile::NamedTempFile::new_in(dir)?;
+        staging.write_all(&compressed)?;
+        staging.persist(&path).map_err(|e| ZipError::Io(e.error))?;
+        Ok((hash, Some(compressed.len() as u64)))
+    }
+
+    fn get_chunk(&self, hash: &str) -> Result<Vec<u8>> {
+        let stored = fs::read(self.chunk_path(hash))
+            .map_err(|e| ZipError::Other(format!("Chunk {} is missing: {}", hash, e)))?;
+        let data = ChunkCompression::decompress(&stored)?;
+        if blake3::hash(&data).to_hex().as_str() != hash {
+            return Err(ZipError::Other(format!("Chunk {} is corrupt", hash)));
+        }
+        Ok(data)
+    }
+
+    /// Take a snapshot of the directory `source`
+    pub fn snapshot(&self, source: &Path) -> Result<SnapshotReport> {
+        let skip = fs::canonicalize(&self.root).ok();
+        let mut paths = Vec::new();
+
+        // The store itself may live inside the source
+        let walker = WalkDir::new(source).min_depth(1).into_iter()
+            .filter_entry(|entry| skip.is_none() || fs::canonicalize(entry.path()).ok() != skip);
+        for entry in walker {
+            paths.push(entry.map_err(io::Error::from)?.into_path());
+        }
+
+        let new_chunks = AtomicUsize::new(0);
+        let new_bytes = AtomicU64::new(0);
+
+        // Files are chunked in parallel; a chunk stored twice at once is written to the same name
+        let mut entries = paths.par_iter().map(|path| {
+            let name = path.strip_prefix(source)?
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            let metadata = fs::symlink_metadata(path)?;
+            let mut entry = SnapshotEntry {
+                path: name,
+                kind: EntryKind::File,
+                size: 0,
+                mode: mode_of(&metadata),
+                modified: metadata.modified().ok(),
+                chunks: Vec::new(),
+            };
+
+            if metadata.is_symlink() {
+                entry.kind = EntryKind::Symlink(fs::read_link(path)?.to_string_lossy().into_owned());
+            } else if metadata.is_dir() {
+                entry.kind = EntryKind::Directory;
+            } else {
+                debug!("Chunking {:?}", path);
+                let mut chunker = Chunker::new(File::open(path)?, &self.config);
+                while let Some(chunk) = chunker.next_chunk()? {
+                    let (hash, written) = self.put_chunk(&chunk)?;
+                    if let Some(bytes) = written {
+                        new_chunks.fetch_add(1, Ordering::Relaxed);
+                        new_bytes.fetch_add(bytes, Ordering::Relaxed);
+                    }
+                    entry.size += chunk.len() as u64;
+                    entry.chunks.push(hash);
+                }
+            }
+            Ok(entry)
+        }).collect::<Result<Vec<_>>>()?;
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let snapshot = Snapshot {
+            id: self.new_id(),
+            created: Local::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            source: fs::canonicalize(source).unwrap_or_else(|_| source.to_path_buf()).to_string_lossy().into_owned(),
+            entries,
+        };
+        write_json(&self.snapshot_path(&snapshot.id), &snapshot)?;
+
+        let files = snapshot.entries.iter().filter(|e| e.kind == EntryKind::File);
+        Ok(SnapshotReport {
+            id: snapshot.id.clone(),
+            files: files.clone().count(),
+            size: files.clone().map(|e| e.size).sum(),
+            chunks: files.map(|e| e.chunks.len()).sum(),
+            new_chunks: new_chunks.into_inner(),
+            new_bytes: new_bytes.into_inner(),
+        })
+    }
+
+    /// Snapshot ids are their creation time, so they sort in order
+    fn new_id(&self) -> String {
+        let base = Local::now().format("%Y%m%d-%H%M%S").to_string();
+        let mut id = base.clone();
+        let mut n = 1;
+        while self.snapshot_path(&id).exists() {
+            n += 1;
+            id = format!("{}-{}", base, n);
+        }
+        id
+    }
+
+    pub fn load_snapshot(&self, id: &str) -> Result<Snapshot> {
+        let path = self.snapshot_path(id);
+        if !path.exists() {
+            return Err(ZipError::Other(format!("No snapshot {} in {:?}", id, self.root)));
+        }
+        read_json(&path)
+    }
+
+    /// Ids of every snapshot, oldest first
+    pub fn snapshot_ids(&self) -> Result<Vec<String>> {
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(self.root.join(SNAPSHOT_DIR))? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                ids.push(path.file_stem().unwrap_or_default().to_string_lossy().into_owned());
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    pub fn list(&self) -> Result<Vec<SnapshotSummary>> {
+        self.snapshot_ids()?.iter().map(|id| {
+            let snapshot = self.load_snapshot(id)?;
+            let files = snapshot.entries.iter().filter(|e| e.kind == EntryKind::File);
+            Ok(SnapshotSummary {
+                files: files.clone().count(),
+                size: files.map(|e| e.size).sum(),
+                id: snapshot.id,
+                created: snapshot.created,
+                source: snapshot.source,
+            })
+        }).collect()
+    }
+
+    /// Restore snapshot `id` (the latest when `None`) into `target` within
+    /// `limits`; returns its id
+    pub fn restore(&self, id: Option<&str>, target: &Path, limits: ExtractLimits) -> Result<String> {
+        let id = match id {
+            Some(id) => id.to_string(),
+            None => self.snapshot_ids()?.pop().ok_or_else(|| ZipError::Other(format!("{:?} has no snapshots", self.root)))?,
+        };
+        let snapshot = self.load_snapshot(&id)?;
+
+        fs::create_dir_all(target)?;
+        let guard = PathGuard::new(target, PathPolicy::Reject)?;
+        let tracker = LimitTracker::new(limits);
+        tracker.guard(self.restore_entries(&snapshot, &guard, &tracker))?;
+
+        guard.finish()?;
+        Ok(id)
+    }
+
+    fn restore_entries(&self, snapshot: &Snapshot, guard: &PathGuard, tracker: &LimitTracker) -> Result<()> {
+        for entry in &snapshot.entries {
+            let Some(path) = guard.resolve(&entry.path) else { continue };
+            info!("Restoring: {}", entry.path);
+
+            let meta = EntryMeta {
+                name: entry.path.clone(),
+                is_dir: entry.kind == EntryKind::Directory,
+                size: Some(entry.size),
+                modified: entry.modified,
+                mode: entry.mode,
+                link: match &entry.kind {
+                    EntryKind::Symlink(target) => Some(EntryLink::Symbolic(target.clone())),
+                    _ => None,
+                },
+            };
+            let mut reader = ChunkReader { store: self, chunks: entry.chunks.iter(), current: Cursor::new(Vec::new()) };
+            restore_entry(&path, &meta, &mut reader, guard, tracker)?;
+        }
+        Ok(())
+    }
+
+    /// Delete the given snapshots, or all but the newest `keep_last`, then
+    /// every chunk no remaining snapshot uses. With `dry_run` nothing is deleted.
+    pub fn prune(&self, ids: &[String], keep_last: Option<usize>, dry_run: bool) -> Result<PruneReport> {
+        let all = self.snapshot_ids()?;
+        for id in ids {
+            if !all.contains(id) {
+                return Err(ZipError::Other(format!("No snapshot {} in {:?}", id, self.root)));
+            }
+        }
+
+        let keep_from = keep_last.map_or(0, |keep| all.len().saturating_sub(keep));
+        let (doomed, kept): (Vec<_>, Vec<_>) = all.into_iter()
+            .enumerate()
+            .partition(|(index, id)| ids.contains(id) || *index < keep_from);
+
+        let mut used = HashSet::new();
+        for (_, id) in &kept {
+            used.extend(self.load_snapshot(id)?.entries.into_iter().flat_map(|e| e.chunks));
+        }
+
+        let mut report = PruneReport { snapshots: doomed.into_iter().map(|(_, id)| id).collect(), ..PruneReport::default() };
+        if !dry_run {
+            for id in &report.snapshots {
+                fs::remove_file(self.snapshot_path(id))?;
+            }
+        }
+
+        for entry in WalkDir::new(self.root.join(CHUNK_DIR)).min_depth(2) {
+            let entry = entry.map_err(io::Error::from)?;
+            let hash = entry.file_name().to_string_lossy();
+            // Leftovers of interrupted writes are not chunks, and go too
+            if !used.contains(hash.as_ref()) {
+                report.chunks += 1;
+                report.bytes += entry.metadata().map_err(io::Error::from)?.len();
+                if !dry_run {
+                    fs::remove_file(entry.path())?;
+                }
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+/// Reads a file back from its chunks, fetching each one as it is reached
+struct ChunkReader<'a> {
+    store: &'a Store,
+    chunks: std::slice::Iter<'a, String>,
+    current: Cursor<Vec<u8>>,
+}
+
+impl Read for ChunkReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            let Some(hash) = self.chunks.next() else { return Ok(0) };
+            let data = self.store.get_chunk(hash).map_err(io::Error::other)?;
+            self.current = Cursor::new(data);
+        }
+    }
+}
+
+/// Random values the rolling hash adds per byte value. They decide where
+/// chunks are cut, so changing them stops new snapshots sharing chunks with old ones.
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < 256 {
+        // splitmix64
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// FastCDC-style chunking: a cut falls where a rolling hash has its top bits clear,
+/// harder to meet below the average size and easier above it
+struct Chunker<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    eof: bool,
+    min: usize,
+    avg: usize,
+    max: usize,
+    mask_small: u64,
+    mask_large: u64,
+}
+
+impl<R: Read> Chunker<R> {
+    fn new(reader: R, config: &StoreConfig) -> Self {
+        let bits = config.avg_chunk.max(2).ilog2();
+        Self {
+            reader,
+            buffer: Vec::with_capacity(config.max_chunk),
+            eof: false,
+            min: config.min_chunk,
+            avg: config.avg_chunk,
+            max: config.max_chunk,
+            mask_small: !0 << (64 - (bits + 2)),
+            mask_large: !0 << (64 - (bits - 2)),
+        }
+    }
+
+    fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        // Keep a full maximum-size window to look for the cut in
+        while !self.eof && self.buffer.len() < self.max {
+            let start = self.buffer.len();
+            self.buffer.resize(self.max, 0);
+            let read = self.reader.read(&mut self.buffer[start..])?;
+            self.buffer.truncate(start + read);
+            self.eof = read == 0;
+        }
+
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
+        let cut = self.cut_point(&self.buffer);
+        let rest = self.buffer.split_off(cut);
+        Ok(Some(std::mem::replace(&mut self.buffer, rest)))
+    }
+
+    fn cut_point(&self, data: &[u8]) -> usize {
+        if data.len() <= self.min {
+            return data.len();
+        }
+
+        let mut hash: u64 = 0;
+        let normal = self.avg.min(data.len());
+        // Bytes before the minimum only warm up the hash
+        for &byte in &data[self.min.saturating_sub(64)..self.min] {
+            hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+        }
+        for (i, &byte) in data.iter().enumerate().take(normal).skip(self.min) {
+            hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+            if hash & self.mask_small == 0 {
+                return i + 1;
+            }
+        }
+        for (i, &byte) in data.iter().enumerate().skip(normal) {
+            hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+            if hash & self.mask_large == 0 {
+                return i + 1;
+            }
+        }
+        data.len()
+    }
+}
+
+#[cfg(unix)]
+fn mode_of(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn mode_of(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T> {
+    let text = fs::read_to_string(path)?;
+    serde_json::from_str(&text).map_err(|e| ZipError::Other(format!("Invalid {:?}: {}", path, e)))
+}
+
+/// Write JSON through a temp file, so readers never see half of it
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let text = serde_json::to_vec_pretty(value).map_err(|e| ZipError::Other(e.to_string()))?;
+    let mut staging = tempfile::NamedTempFile::new_in(path.parent().unwrap_or(Path::new(".")))?;
+    staging.write_all(&text)?;
+    staging.persist(path).map_err(|e| ZipError::Io(e.error))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn small_chunks() -> StoreConfig {
+        StoreConfig { min_chunk: 1024, avg_chunk: 4096, max_chunk: 16384, ..StoreConfig::default() }
+    }
+
+    fn chunks(data: &[u8], config: &StoreConfig) -> Vec<Vec<u8>> {
+        let mut chunker = Chunker::new(data, config);
+        std::iter::from_fn(|| chunker.next_chunk().unwrap()).collect()
+    }
+
+    #[test]
+    fn an_insertion_only_changes_nearby_chunks() {
+        let config = small_chunks();
+        let mut state = 1_u64;
+        let data: Vec<u8> = (0..200_000).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect();
+
+        let before = chunks(&data, &config);
+        assert_eq!(before.concat(), data);
+        assert!(before.iter().all(|c| c.len() <= config.max_chunk));
+
+        let mut edited = data.clone();
+        edited.splice(100_000..100_000, *b"inserted");
+        let after = chunks(&edited, &config);
+        assert_eq!(after.concat(), edited);
+
+        let shared = after.iter().filter(|c| before.contains(c)).count();
+        assert!(shared + 3 >= after.len(), "only {} of {} chunks shared", shared, after.len());
+    }
+}
@@ -1,11 +1,12 @@
 use std::{fs, thread};
 use std::fs::File;
-use std::io::{BufRead, BufReader, Read};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use crossbeam::channel::{unbounded, Sender};
-use log::{error, info};
+use crossbeam::channel::unbounded;
+use log::info;
 use tar::Archive;
+use tempfile::NamedTempFile;
 use crate::{Result, ZipError};
 
 /// Ensure a directory exists, creating it if necessary
@@ -18,7 +19,7 @@ pub fn ensure_directory_exists(path: &Path) -> Result<()> {
 
 /// Ensure a path has the correct extension
 pub fn ensure_extension(p: &Path, extension: &str) -> PathBuf {
-    if p.extension().map_or(true, |ext| ext != extension) {
+    if p.extension().is_none_or(|ext| ext != extension) {
         let mut new_name = p.file_name().unwrap_or_default().to_os_string();
         new_name.push(".".to_string() + extension);
         p.with_file_name(new_name)
@@ -27,6 +28,104 @@ pub fn ensure_extension(p: &Path, extension: &str) -> PathBuf {
     }
 }
 
+/// File name of an archive with its archive extensions removed (`a.tar.xz` -> `a`)
+pub fn archive_stem(path: &Path) -> String {
+    const ARCHIVE_EXTENSIONS: &[&str] = &["zip", "gz", "tgz", "xz", "txz", "7z", "tar"];
+
+    let mut stem = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+
+    while let Some((base, ext)) = stem.rsplit_once('.') {
+        if base.is_empty() || !ARCHIVE_EXTENSIONS.contains(&ext.to_lowercase().as_str()) {
+            break;
+        }
+        stem = base.to_string();
+    }
+
+    stem
+}
+
+/// Whether a path argument stands for stdin/stdout (`-`)
+pub fn is_stdio(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+/// Open a source for reading, `-` meaning stdin
+pub fn open_input(path: &Path) -> Result<Box<dyn Read>> {
+    if is_stdio(path) {
+        Ok(Box::new(io::stdin().lock()))
+    } else {
+        Ok(Box::new(File::open(path)?))
+    }
+}
+
+/// Create a target for writing, `-` meaning stdout
+pub fn create_output(path: &Path) -> Result<Box<dyn Write>> {
+    if is_stdio(path) {
+        Ok(Box::new(io::stdout().lock()))
+    } else {
+        Ok(Box::new(File::create(path)?))
+    }
+}
+
+/// Temporary file next to `target` for writing its replacement
+pub fn staging_file(target: &Path) -> Result<NamedTempFile> {
+    let dir = target.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    Ok(NamedTempFile::new_in(dir)?)
+}
+
+/// Move a finished staging file over `target`, keeping the target's permissions
+pub fn replace_with_staged(staging: NamedTempFile, target: &Path) -> Result<()> {
+    let permissions = fs::metadata(target)?.permissions();
+    fs::set_permissions(staging.path(), permissions)?;
+    staging.persist(target).map_err(|e| ZipError::Io(e.error))?;
+    Ok(())
+}
+
+/// Copy stdin into an anonymous temporary file so seek-only formats can read it
+pub fn spool_stdin() -> Result<File> {
+    let mut spooled = tempfile::tempfile()?;
+    io::copy(&mut io::stdin().lock(), &mut spooled)?;
+    spooled.seek(SeekFrom::Start(0))?;
+    Ok(spooled)
+}
+
+/// Parse a byte size with an optional binary suffix (`512`, `64K`, `10G`)
+pub fn parse_size(text: &str) -> std::result::Result<u64, String> {
+    let text = text.trim();
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (digits, suffix) = text.split_at(split);
+
+    let value: u64 = digits.parse().map_err(|_| format!("invalid size: {}", text))?;
+    let shift = match suffix.trim().to_ascii_uppercase().trim_end_matches(['B', 'I']) {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("invalid size suffix: {}", suffix)),
+    };
+
+    value.checked_mul(1 << shift).ok_or_else(|| format!("size too large: {}", text))
+}
+
+/// Lowercase hex encoding of `bytes`
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decode hex into exactly `N` bytes
+pub fn from_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut bytes = [0_u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
 pub fn is_tar_file(path: &Path) -> bool {
     if !path.exists() {
         return false;
@@ -34,10 +133,7 @@ pub fn is_tar_file(path: &Path) -> bool {
     
     if let Ok(file) = File::open(path) {
         let mut archive = Archive::new(file);
-        match archive.entries() {
-            Ok(_) => true,
-            Err(_) => false,
-        } 
+        archive.entries().is_ok()
     } else {
         false
     }
@@ -67,20 +163,16 @@ where F: FnMut(String) + Send + 'static {
     let stdout_thread = thread::spawn(move || {
         let reader = BufReader::new(stdout);
 
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                tx_stdout.send(OutputLine::Stdout(line)).ok();
-            }
+        for line in reader.lines().map_while(io::Result::ok) {
+            tx_stdout.send(OutputLine::Stdout(line)).ok();
         }
     });
 
     let stderr_thread = thread::spawn(move || {
         let reader = BufReader::new(stderr);
 
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                tx.send(OutputLine::Stderr(line)).ok();
-            }
+        for line in reader.lines().map_while(io::Result::ok) {
+            tx.send(OutputLine::Stderr(line)).ok();
         }
     });
 
@@ -1,6 +1,4 @@
 use std::path::{Path, PathBuf};
-use log::info;
-use crate::{Result, ZipError};
 
 pub struct VirtualEnv {
     pub path: PathBuf,
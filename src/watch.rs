@@ -0,0 +1,479 @@
+use crate::codecs::Codec;
+use crate::grep::glob_to_regex;
+use crate::{Result, ZipError};
+use log::{debug, info, warn};
+use regex::Regex;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use walkdir::WalkDir;
+
+/// Which paths under the watched directory go into the archive
+#[derive(Default)]
+pub struct PathFilter {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+}
+
+impl PathFilter {
+    /// Globs as `grep --glob` takes them; one without `/` also matches file names alone
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        let compile = |globs: &[String]| globs.iter()
+            .map(|glob| glob_to_regex(glob).map_err(|e| ZipError::Other(e.to_string())))
+            .collect::<Result<Vec<_>>>();
+        Ok(Self { include: compile(include)?, exclude: compile(exclude)? })
+    }
+
+    /// Whether `relative`, a `/`-separated path below the watched directory, is archived.
+    /// Excluding a directory excludes everything below it; includes only apply to files.
+    pub fn allows(&self, relative: &str, is_dir: bool) -> bool {
+        let matches = |globs: &[Regex], path: &str| {
+            let file_name = path.rsplit('/').next().unwrap_or(path);
+            globs.iter().any(|glob| glob.is_match(path) || glob.is_match(file_name))
+        };
+
+        let mut ancestors = relative.match_indices('/').map(|(i, _)| &relative[..i]);
+        if matches(&self.exclude, relative) || ancestors.any(|dir| matches(&self.exclude, dir)) {
+            return false;
+        }
+        is_dir || self.include.is_empty() || matches(&self.include, relative)
+    }
+}
+
+pub struct WatchOptions {
+    /// How long the tree must stay quiet before a cycle runs
+    pub debounce: Duration,
+    /// Update the archive in place instead of rebuilding it
+    pub update: bool,
+    pub filter: PathFilter,
+}
+
+/// Watch `source` and keep `target` up to date until interrupted, rebuilding or updating
+/// the archive once the tree has been quiet for the debounce interval
+pub fn watch(make_codec: &dyn Fn() -> Result<Box<dyn Codec>>, source: &Path, target: &Path, options: &WatchOptions) -> Result<()> {
+    let source = fs::canonicalize(source)?;
+    let mut inotify = Inotify::new()?;
+    inotify.add_tree(&source)?;
+
+    // The archive would otherwise end up in itself, and each cycle would start another
+    let target_dir = fs::canonicalize(target.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new(".")))?;
+    if target_dir.starts_with(&source) {
+        return Err(ZipError::Other(format!("The archive {:?} must be outside the watched directory", target)));
+    }
+
+    info!("Watching {:?} for changes to archive into {:?}", source, target);
+    let mut cycle = 1;
+    run_cycle(make_codec, &source, target, options, cycle, &[])?;
+
+    loop {
+        let changed = inotify.collect(options.debounce, &source, &options.filter)?;
+        if changed.is_empty() {
+            continue;
+        }
+
+        cycle += 1;
+        // A failed cycle is retried with the next change rather than ending the watch
+        if let Err(e) = run_cycle(make_codec, &source, target, options, cycle, &changed) {
+            warn!("Cycle {} failed: {}", cycle, e);
+        }
+    }
+}
+
+fn run_cycle(
+    make_codec: &dyn Fn() -> Result<Box<dyn Codec>>,
+    source: &Path,
+    target: &Path,
+    options: &WatchOptions,
+    cycle: usize,
+    changed: &[PathBuf],
+) -> Result<()> {
+    let start = Instant::now();
+    let mut codec = make_codec()?;
+
+    if options.update && target.exists() {
+        match update(codec.as_mut(), source, target, changed, &options.filter) {
+            Err(ZipError::UnsupportedOperation(reason)) => info!("Rebuilding instead of updating: {}", reason),
+            result => {
+                result?;
+                info!("Cycle {}: updated {:?} for {} changed paths in {:?}", cycle, target, changed.len(), start.elapsed());
+                return Ok(());
+            }
+        }
+    }
+
+    let written = rebuild(codec.as_mut(), source, target, &options.filter)?;
+    info!("Cycle {}: rebuilt {:?} with {} entries for {} changed paths in {:?}", cycle, target, written, changed.len(), start.elapsed());
+    Ok(())
+}
+
+/// Compress the directory the way `compress` would to a staging file and
+/// move it over the target, so the target is never seen half written
+fn rebuild(codec: &mut dyn Codec, source: &Path, target: &Path, filter: &PathFilter) -> Result<usize> {
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    let staging = target.with_file_name(format!(".{}.watch-tmp", name));
+
+    let result = (|| {
+        // Compress a tree of links to the files that pass the filter, named like the source
+        let tree = tempfile::tempdir_in(staging.parent().unwrap_or(Path::new(".")))?;
+        let root = tree.path().join(source.file_name().unwrap_or_default());
+        let linked = link_tree(source, &root, filter)?;
+        codec.compress(&[root.as_path()], &staging, None)?;
+        Ok(linked)
+    })();
+
+    match result {
+        Ok(written) => {
+            fs::rename(&staging, target)?;
+            Ok(written)
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&staging);
+            Err(e)
+        }
+    }
+}
+
+/// Hard link (or copy) the files of `source` that pass `filter` under `root`, following
+/// symlinks only while they stay inside `source`. Returns how many there were.
+fn link_tree(source: &Path, root: &Path, filter: &PathFilter) -> Result<usize> {
+    let mut linked = 0;
+    fs::create_dir_all(root)?;
+    let real_source = fs::canonicalize(source)?;
+
+    let mut walk = WalkDir::new(source).min_depth(1).follow_links(true).into_iter();
+    while let Some(entry) = walk.next() {
+        let entry = entry.map_err(io::Error::from)?;
+        if entry.path_is_symlink() && !fs::canonicalize(entry.path()).is_ok_and(|real| real.starts_with(&real_source)) {
+            warn!("Leaving out {:?}: it links outside {:?}", entry.path(), source);
+            if entry.file_type().is_dir() {
+                walk.skip_current_dir();
+            }
+            continue;
+        }
+        let relative = entry.path().strip_prefix(source)?;
+        let is_dir = entry.file_type().is_dir();
+        if !filter.allows(&slash_path(relative), is_dir) {
+            if is_dir {
+                walk.skip_current_dir();
+            }
+            continue;
+        }
+
+        let path = root.join(relative);
+        if is_dir {
+            fs::create_dir_all(&path)?;
+        } else if fs::hard_link(entry.path(), &path).is_err() {
+            fs::copy(entry.path(), &path)?;
+        }
+        linked += 1;
+    }
+    Ok(linked)
+}
+
+/// Add what changed and delete what disappeared, the way `update` and `delete` do.
+/// Only paths that `link_tree` takes are added, as in `rebuild`.
+fn update(codec: &mut dyn Codec, source: &Path, target: &Path, changed: &[PathBuf], filter: &PathFilter) -> Result<()> {
+    let gone: Vec<String> = changed.iter()
+        .filter(|path| fs::symlink_metadata(path).is_err())
+        .filter_map(|path| path.strip_prefix(source).ok())
+        .map(slash_path)
+        .collect();
+
+    if !gone.is_empty() {
+        // Formats differ in whether entries start with the directory's name
+        let root = source.file_name().unwrap_or_default().to_string_lossy();
+        let mut names = Vec::new();
+        codec.visit_entries(target, &mut |meta, _| {
+            names.push(meta.name.trim_end_matches('/').to_string());
+            Ok(())
+        })?;
+
+        let deleted: Vec<String> = gone.iter()
+            .flat_map(|relative| [relative.clone(), format!("{}/{}", root, relative)])
+            .filter(|name| names.contains(name))
+            .collect();
+        if !deleted.is_empty() {
+            codec.delete_entries(target, &deleted)?;
+        }
+    }
+
+    // Links keep the files' times, so unchanged ones are still recognised as such
+    let tree = tempfile::tempdir_in(target.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new(".")))?;
+    let root = tree.path().join(source.file_name().unwrap_or_default());
+    link_tree(source, &root, filter)?;
+    codec.add_entries(target, &[root.as_path()], true)
+}
+
+/// `path` with `/` between its components, as entry names and filters have them
+fn slash_path(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Longest a burst of changes can hold a cycle back, in debounce intervals
+const MAX_DELAY_INTERVALS: u32 = 10;
+
+#[cfg(target_os = "linux")]
+struct Inotify {
+    fd: std::os::fd::OwnedFd,
+    /// Watched directory of each watch descriptor
+    watches: std::collections::HashMap<i32, PathBuf>,
+}
+
+#[cfg(target_os = "linux")]
+impl Inotify {
+    const MASK: u32 = libc::IN_CREATE | libc::IN_DELETE | libc::IN_MODIFY | libc::IN_CLOSE_WRITE | libc::IN_ATTRIB
+        | libc::IN_MOVED_FROM | libc::IN_MOVED_TO | libc::IN_DELETE_SELF;
+
+    fn new() -> Result<Self> {
+        use std::os::fd::FromRawFd;
+
+        // SAFETY: inotify_init1 takes no pointers and only returns a new descriptor
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        // SAFETY: the descriptor was just opened and nothing else owns it
+        Ok(Self { fd: unsafe { std::os::fd::OwnedFd::from_raw_fd(fd) }, watches: Default::default() })
+    }
+
+    /// Watch `dir` and every directory below it
+    fn add_tree(&mut self, dir: &Path) -> Result<()> {
+        use std::os::fd::AsRawFd;
+        use std::os::unix::ffi::OsStrExt;
+
+        for entry in WalkDir::new(dir) {
+            let entry = entry.map_err(io::Error::from)?;
+            if !entry.file_type().is_dir() {
+                continue;
+            }
+
+            let path = std::ffi::CString::new(entry.path().as_os_str().as_bytes())
+                .map_err(|e| ZipError::Other(e.to_string()))?;
+            // SAFETY: the path is a NUL-terminated string that outlives the call
+            let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), Self::MASK) };
+            if wd < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            debug!("Watching {:?}", entry.path());
+            self.watches.insert(wd, entry.into_path());
+        }
+        Ok(())
+    }
+
+    /// Wait for a change that passes `filter`, then keep collecting until
+    /// the tree has been quiet for `debounce`. Returns the paths that changed.
+    fn collect(&mut self, debounce: Duration, source: &Path, filter: &PathFilter) -> Result<Vec<PathBuf>> {
+        let mut changed = Vec::new();
+        let mut first = None;
+
+        loop {
+            let timeout = match first {
+                None => None,
+                Some(first) if Instant::now().duration_since(first) >= debounce * MAX_DELAY_INTERVALS => break,
+                Some(_) => Some(debounce),
+            };
+            if !self.poll(timeout)? {
+                break;
+            }
+
+            let events = self.read_events()?;
+            // Nothing is watched once the directory itself is gone, and poll would wait forever
+            if !self.watches.values().any(|dir| dir == source) {
+                return Err(ZipError::Other(format!("{:?} was removed; stopping the watch", source)));
+            }
+
+            for (path, is_dir, overflow) in events {
+                if overflow {
+                    // Events were lost, so the whole tree counts as changed
+                    warn!("Too many changes at once; treating {:?} as changed", source);
+                    changed.push(source.to_path_buf());
+                    first.get_or_insert_with(Instant::now);
+                    continue;
+                }
+                if !filter.allows(&slash_path(path.strip_prefix(source).unwrap_or(&path)), is_dir) {
+                    continue;
+                }
+
+                // Files may appear in a new directory before it is watched, so its contents count too
+                if is_dir && path.is_dir() {
+                    self.add_tree(&path)?;
+                }
+                debug!("Changed: {:?}", path);
+                changed.push(path);
+                first.get_or_insert_with(Instant::now);
+            }
+        }
+
+        changed.sort();
+        changed.dedup();
+        Ok(changed)
+    }
+
+    /// Wait until events can be read; `false` if `timeout` passed first
+    fn poll(&self, timeout: Option<Duration>) -> Result<bool> {
+        use std::os::fd::AsRawFd;
+
+        let mut pollfd = libc::pollfd { fd: self.fd.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        let millis = timeout.map_or(-1, |t| t.as_millis().min(i32::MAX as u128) as i32);
+        loop {
+            // SAFETY: poll reads and writes the one pollfd it is given
+            let ready = unsafe { libc::poll(&mut pollfd, 1, millis) };
+            if ready >= 0 {
+                return Ok(ready > 0);
+            }
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error.into());
+            }
+        }
+    }
+
+    /// Read pending events as (path, is a directory, queue overflowed)
+    fn read_events(&mut self) -> Result<Vec<(PathBuf, bool, bool)>> {
+        use std::os::fd::AsRawFd;
+        use std::os::unix::ffi::OsStrExt;
+
+        let mut buffer = [0_u8; 64 * 1024];
+        // SAFETY: read writes at most `buffer.len()` bytes into the buffer
+        let read = unsafe { libc::read(self.fd.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len()) };
+        if read < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let header = std::mem::size_of::<libc::inotify_event>();
+        let mut events = Vec::new();
+        let mut offset = 0;
+        while offset + header <= read as usize {
+            // SAFETY: the loop condition leaves a whole header at `offset`, and
+            // inotify_event is plain data that any bytes form a valid value of
+            let event: libc::inotify_event = unsafe { std::ptr::read_unaligned(buffer[offset..].as_ptr().cast()) };
+            let name = &buffer[offset + header..offset + header + event.len as usize];
+            offset += header + event.len as usize;
+
+            if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                events.push((PathBuf::new(), false, true));
+                continue;
+            }
+            if event.mask & libc::IN_IGNORED != 0 {
+                self.watches.remove(&event.wd);
+                continue;
+            }
+
+            let Some(dir) = self.watches.get(&event.wd) else { continue };
+            // The name is padded with NULs to the record length
+            let name = name.split(|&b| b == 0).next().unwrap_or_default();
+            let path = if name.is_empty() { dir.clone() } else { dir.join(std::ffi::OsStr::from_bytes(name)) };
+            events.push((path, event.mask & libc::IN_ISDIR != 0, false));
+        }
+        Ok(events)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+struct Inotify;
+
+#[cfg(not(target_os = "linux"))]
+impl Inotify {
+    fn new() -> Result<Self> {
+        Err(ZipError::UnsupportedOperation("Watching needs inotify, which only Linux has".to_string()))
+    }
+
+    fn add_tree(&mut self, _dir: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn collect(&mut self, _debounce: Duration, _source: &Path, _filter: &PathFilter) -> Result<Vec<PathBuf>> {
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn excluded_directories_hide_their_contents() {
+        let filter = PathFilter::new(&["*.txt".to_string()], &["build".to_string(), "*.tmp".to_string()]).unwrap();
+        assert!(filter.allows("docs/readme.txt", false));
+        assert!(!filter.allows("docs/image.png", false));
+        assert!(filter.allows("docs", true));
+        assert!(!filter.allows("build/out.txt", false));
+        assert!(!filter.allows("notes.txt.tmp", false));
+        assert!(PathFilter::default().allows("anything", false));
+    }
+
+    #[test]
+    fn updates_leave_out_filtered_paths() {
+        use crate::codecs::zip::{CompressionMethod, ZipCodec};
+
+        let dir = tempfile::tempdir().unwrap();
+        let (source, target) = (dir.path().join("site"), dir.path().join("site.zip"));
+        fs::create_dir_all(source.join("build")).unwrap();
+        fs::write(source.join("index.html"), "one").unwrap();
+
+        let filter = PathFilter::new(&[], &["build".to_string(), "*.tmp".to_string()]).unwrap();
+        let mut codec = ZipCodec::new(CompressionMethod::Deflated, None, None);
+        rebuild(&mut codec, &source, &target, &filter).unwrap();
+
+        fs::write(source.join("build/out.js"), "generated").unwrap();
+        fs::write(source.join("draft.tmp"), "scratch").unwrap();
+        fs::write(source.join("about.html"), "two").unwrap();
+        let changed = [source.join("build/out.js"), source.join("draft.tmp"), source.join("about.html")];
+        update(&mut codec, &source, &target, &changed, &filter).unwrap();
+
+        let mut names = Vec::new();
+        codec.visit_entries(&target, &mut |meta, _| {
+            names.push(meta.name.clone());
+            Ok(())
+        }).unwrap();
+        names.sort();
+        assert_eq!(names, ["about.html", "index.html"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_out_of_the_folder_are_left_out() {
+        use crate::codecs::zip::{CompressionMethod, ZipCodec};
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let (source, target, outside) = (dir.path().join("drop"), dir.path().join("drop.zip"), dir.path().join("secret"));
+        fs::create_dir_all(source.join("docs")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("key"), "private").unwrap();
+        fs::write(source.join("docs/a.txt"), "shared").unwrap();
+        symlink(&outside, source.join("escape")).unwrap();
+        symlink(outside.join("key"), source.join("key")).unwrap();
+        symlink(source.join("docs"), source.join("alias")).unwrap();
+
+        let mut codec = ZipCodec::new(CompressionMethod::Deflated, None, None);
+        rebuild(&mut codec, &source, &target, &PathFilter::default()).unwrap();
+
+        let mut names = Vec::new();
+        codec.visit_entries(&target, &mut |meta, _| {
+            names.push(meta.name.clone());
+            Ok(())
+        }).unwrap();
+        names.sort();
+        assert_eq!(names, ["alias/", "alias/a.txt", "docs/", "docs/a.txt"]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn removing_the_watched_folder_ends_the_watch() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = fs::canonicalize(dir.path()).unwrap().join("drop");
+        fs::create_dir_all(source.join("sub")).unwrap();
+
+        let mut inotify = Inotify::new().unwrap();
+        inotify.add_tree(&source).unwrap();
+        fs::remove_dir_all(&source).unwrap();
+
+        let error = inotify.collect(Duration::from_millis(10), &source, &PathFilter::default()).unwrap_err();
+        assert!(error.to_string().contains("was removed"), "{}", error);
+    }
+}